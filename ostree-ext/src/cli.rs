@@ -1211,6 +1211,7 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                         &src,
                         &dest,
                         contents.as_deref(),
+                        None,
                     )
                     .await?;
                     println!("Pushed: {}", digest);
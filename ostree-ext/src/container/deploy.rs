@@ -4,12 +4,13 @@ use std::collections::HashSet;
 use std::os::fd::BorrowedFd;
 use std::process::Command;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bootc_utils::CommandRunExt;
+use camino::Utf8Path;
 use cap_std_ext::cmdext::CapStdExtCommandExt;
 use fn_error_context::context;
 use ocidir::cap_std::fs::Dir;
-use ostree::glib;
+use ostree::{gio, glib};
 
 use super::store::{gc_image_layers, LayeredImageState};
 use super::{ImageReference, OstreeImageReference};
@@ -164,6 +165,43 @@ pub async fn deploy(
     Ok(state)
 }
 
+/// Import a container image and deploy it into the sysroot at `sysroot_path`.
+///
+/// This is intended for first boot and provisioning tooling.  The physical root
+/// is initialized as an ostree sysroot if it is not already one, and the
+/// `osname` stateroot is created if it does not exist.  The sysroot is then
+/// locked and the image imported and deployed via [`deploy()`], which is in turn
+/// a wrapper for [`super::store::ImageImporter`] and the ostree deployment APIs;
+/// the same options apply.
+///
+/// The caller must be able to write to `sysroot_path`, which usually means
+/// running as root.  When the target is the booted system, write access to
+/// `/sysroot` and `/boot` is also required (e.g. a remounted read-write sysroot
+/// in a privileged mount namespace).
+#[context("Importing and deploying {imgref} to {sysroot_path}")]
+pub async fn import_and_deploy(
+    imgref: &OstreeImageReference,
+    sysroot_path: &Utf8Path,
+    osname: &str,
+    options: Option<DeployOpts<'_>>,
+) -> Result<Box<LayeredImageState>> {
+    let cancellable = gio::Cancellable::NONE;
+    let sysroot = &ostree::Sysroot::new(Some(&gio::File::for_path(sysroot_path)));
+    sysroot
+        .ensure_initialized(cancellable)
+        .context("Initializing sysroot")?;
+    sysroot.load(cancellable)?;
+    let sysroot_dir = Dir::reopen_dir(&sysroot_fd(sysroot))?;
+    if !sysroot_dir.try_exists(format!("ostree/deploy/{osname}"))? {
+        sysroot
+            .init_osname(osname, cancellable)
+            .context("Initializing stateroot")?;
+        sysroot.load(cancellable)?;
+    }
+    let sysroot = &SysrootLock::new_from_sysroot(sysroot).await?;
+    deploy(sysroot, osname, imgref, options).await
+}
+
 /// Query the container image reference for a deployment
 fn deployment_origin_container(
     deploy: &ostree::Deployment,
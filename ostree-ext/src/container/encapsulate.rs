@@ -9,17 +9,21 @@ use anyhow::{anyhow, Context, Result};
 use camino::Utf8Path;
 use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use containers_image_proxy::oci_spec;
 use flate2::Compression;
 use fn_error_context::context;
 use gio::glib;
+use gio::prelude::*;
 use oci_spec::image as oci_image;
 use ocidir::{Layer, OciDir};
 use ostree::gio;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
 use std::num::NonZeroU32;
+use std::str::FromStr;
 use tracing::instrument;
 
 /// The label which may be used in addition to the standard OCI label.
@@ -29,6 +33,20 @@ pub const LEGACY_VERSION_LABEL: &str = "version";
 pub const DIFFID_LABEL: &str = "ostree.final-diffid";
 /// The label for bootc.
 pub const BOOTC_LABEL: &str = "containers.bootc";
+/// Annotation on a delta layer with the ostree commit it applies to.
+pub const DELTA_BASE_ANNOTATION: &str = "ostree.delta.base";
+/// Annotation on a delta layer with the ostree commit it produces.
+pub const DELTA_TARGET_ANNOTATION: &str = "ostree.delta.target";
+/// Manifest annotation with the version of the kernel in the image, i.e. the name
+/// of its directory in `/usr/lib/modules`; see [`ExportOpts::emit_boot_metadata`].
+pub const BOOT_KERNEL_VERSION_ANNOTATION: &str = "ostree.boot.kernel-version";
+/// Manifest annotation with the absolute path of the kernel binary in the image.
+pub const BOOT_KERNEL_ANNOTATION: &str = "ostree.boot.kernel";
+/// Manifest annotation with the absolute path of the initramfs in the image, if any.
+pub const BOOT_INITRAMFS_ANNOTATION: &str = "ostree.boot.initramfs";
+/// Manifest annotation with the boot checksum computed by ostree for the kernel,
+/// which is used to name its directory in `/boot`.
+pub const BOOT_CHECKSUM_ANNOTATION: &str = "ostree.boot.bootcsum";
 
 /// Annotation injected into the layer to say that this is an ostree commit.
 /// However, because this gets lost when converted to D2S2 https://docs.docker.com/registry/spec/manifest-v2-2/
@@ -81,26 +99,177 @@ fn commit_meta_to_labels<'a>(
     Ok(())
 }
 
+/// The version of the export checkpoint state format.
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// A completed layer recorded in an export checkpoint.
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointLayer {
+    digest: String,
+    size: u64,
+    diff_id: String,
+    media_type: oci_image::MediaType,
+}
+
+/// The serialized state of an export checkpoint; see [`ExportOpts::checkpoint`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CheckpointState {
+    version: u32,
+    key: String,
+    layers: BTreeMap<String, CheckpointLayer>,
+}
+
+/// Tracks the layers written by an export, so that an interrupted export
+/// of the same input can reuse them.
+#[derive(Debug)]
+struct ExportCheckpoint {
+    path: std::path::PathBuf,
+    state: CheckpointState,
+}
+
+/// Parse a `sha256:` digest as stored in the checkpoint state.
+fn parse_checkpoint_digest(s: &str) -> Result<oci_image::Sha256Digest> {
+    let digest = s
+        .strip_prefix("sha256:")
+        .ok_or_else(|| anyhow!("Invalid digest in checkpoint: {s}"))?;
+    Ok(oci_image::Sha256Digest::from_str(digest)?)
+}
+
+impl ExportCheckpoint {
+    /// Load the checkpoint at `path` for an export of `commit`; the recorded
+    /// layers are discarded if they were written for a different input.
+    #[context("Loading export checkpoint")]
+    fn load(path: &std::path::Path, commit: &str, opts: &ExportOpts) -> Result<Self> {
+        let mut h = openssl::sha::Sha256::new();
+        h.update(commit.as_bytes());
+        h.update(&opts.compression().level().to_le_bytes());
+        let key = hex::encode(h.finish());
+        let state = match std::fs::read(path) {
+            Ok(buf) => serde_json::from_slice::<CheckpointState>(&buf)
+                .with_context(|| format!("Parsing {path:?}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let state = if state.version == CHECKPOINT_VERSION && state.key == key {
+            state
+        } else {
+            CheckpointState {
+                version: CHECKPOINT_VERSION,
+                key,
+                layers: Default::default(),
+            }
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            state,
+        })
+    }
+
+    /// Compute the key for a layer with the provided content.
+    fn layer_key(kind: &str, content: &crate::chunking::ChunkMapping) -> String {
+        let mut h = openssl::sha::Sha256::new();
+        h.update(kind.as_bytes());
+        for (checksum, (_, paths)) in content {
+            h.update(b"\n");
+            h.update(checksum.as_bytes());
+            for path in paths {
+                h.update(b"\0");
+                h.update(path.as_str().as_bytes());
+            }
+        }
+        hex::encode(h.finish())
+    }
+
+    /// Return the recorded layer for `key`, if its blob is still present.
+    fn get(&self, ociw: &OciDir, key: &str) -> Result<Option<Layer>> {
+        let Some(layer) = self.state.layers.get(key) else {
+            return Ok(None);
+        };
+        let blob = ocidir::Blob {
+            sha256: parse_checkpoint_digest(&layer.digest)?,
+            size: layer.size,
+        };
+        let desc = blob
+            .descriptor()
+            .media_type(layer.media_type.clone())
+            .build()?;
+        if !ociw.has_blob(&desc)? {
+            return Ok(None);
+        }
+        tracing::debug!("Reusing layer {} from checkpoint", layer.digest);
+        Ok(Some(Layer {
+            blob,
+            uncompressed_sha256: parse_checkpoint_digest(&layer.diff_id)?,
+        }))
+    }
+
+    /// Record a completed layer, and atomically replace the state file.
+    #[context("Writing export checkpoint")]
+    fn record(&mut self, key: String, layer: &Layer) -> Result<()> {
+        let v = CheckpointLayer {
+            digest: format!("sha256:{}", layer.blob.sha256.digest()),
+            size: layer.blob.size,
+            diff_id: format!("sha256:{}", layer.uncompressed_sha256.digest()),
+            media_type: layer.descriptor().build()?.media_type().clone(),
+        };
+        self.state.layers.insert(key, v);
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.state)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
 fn export_chunks(
     repo: &ostree::Repo,
     commit: &str,
     ociw: &mut OciDir,
     chunks: Vec<Chunk>,
     opts: &ExportOpts,
+    mut checkpoint: Option<&mut ExportCheckpoint>,
 ) -> Result<Vec<(Layer, String, Vec<String>)>> {
     chunks
         .into_iter()
         .enumerate()
         .map(|(i, chunk)| -> Result<_> {
+            let key = ExportCheckpoint::layer_key("chunk", &chunk.content);
+            if let Some(checkpoint) = checkpoint.as_deref() {
+                if let Some(layer) = checkpoint.get(ociw, &key)? {
+                    return Ok((layer, chunk.name, chunk.packages));
+                }
+            }
             let mut w = ociw.create_layer(Some(opts.compression()))?;
             ostree_tar::export_chunk(repo, commit, chunk.content, &mut w)
                 .with_context(|| format!("Exporting chunk {i}"))?;
             let w = w.into_inner()?;
-            Ok((w.complete()?, chunk.name, chunk.packages))
+            let layer = w.complete()?;
+            if let Some(checkpoint) = checkpoint.as_deref_mut() {
+                checkpoint.record(key, &layer)?;
+            }
+            Ok((layer, chunk.name, chunk.packages))
         })
         .collect()
 }
 
+/// The creation time recorded in the history entry of each layer of an export of
+/// `commit`: the commit timestamp if `reproducible` is set, otherwise the current time.
+pub(crate) fn layer_timestamp(
+    repo: &ostree::Repo,
+    commit: &str,
+    reproducible: bool,
+) -> Result<DateTime<Utc>> {
+    if !reproducible {
+        return Ok(Utc::now());
+    }
+    let (commit_v, _) = repo.load_commit(commit)?;
+    let ts = ostree::commit_get_timestamp(&commit_v);
+    i64::try_from(ts)
+        .ok()
+        .and_then(|ts| DateTime::from_timestamp(ts, 0))
+        .ok_or_else(|| anyhow!("Invalid timestamp {ts} in commit {commit}"))
+}
+
 /// Write an ostree commit to an OCI blob
 #[context("Writing ostree root to blob")]
 #[allow(clippy::too_many_arguments)]
@@ -115,14 +284,40 @@ pub(crate) fn export_chunked(
     opts: &ExportOpts,
     description: &str,
 ) -> Result<()> {
-    let layers = export_chunks(repo, commit, ociw, chunking.take_chunks(), opts)?;
-    let compression = Some(opts.compression());
+    let mut checkpoint = opts
+        .checkpoint
+        .as_deref()
+        .map(|path| ExportCheckpoint::load(path, commit, opts))
+        .transpose()?;
+    let layers = export_chunks(
+        repo,
+        commit,
+        ociw,
+        chunking.take_chunks(),
+        opts,
+        checkpoint.as_mut(),
+    )?;
 
     // In V1, the ostree layer comes first
-    let mut w = ociw.create_layer(compression)?;
-    ostree_tar::export_final_chunk(repo, commit, chunking.remainder, &mut w)?;
-    let w = w.into_inner()?;
-    let ostree_layer = w.complete()?;
+    let key = ExportCheckpoint::layer_key("final", &chunking.remainder.content);
+    let reused = checkpoint
+        .as_ref()
+        .map(|checkpoint| checkpoint.get(ociw, &key))
+        .transpose()?
+        .flatten();
+    let created = layer_timestamp(repo, commit, opts.reproducible)?;
+    let ostree_layer = if let Some(layer) = reused {
+        layer
+    } else {
+        let mut w = ociw.create_layer(Some(opts.compression()))?;
+        ostree_tar::export_final_chunk(repo, commit, chunking.remainder, &mut w)?;
+        let w = w.into_inner()?;
+        let layer = w.complete()?;
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            checkpoint.record(key, &layer)?;
+        }
+        layer
+    };
 
     // Then, we have a label that points to the last chunk.
     // Note in the pathological case of a single layer chunked v1 image, this could be the ostree layer.
@@ -134,7 +329,14 @@ pub(crate) fn export_chunked(
         .clone();
 
     // Add the ostree layer
-    ociw.push_layer(manifest, imgcfg, ostree_layer, description, None);
+    ociw.push_layer_full(
+        manifest,
+        imgcfg,
+        ostree_layer,
+        None::<HashMap<String, String>>,
+        description,
+        created,
+    );
     // Add the component/content layers
     let mut buf = [0; 8];
     let sep = COMPONENT_SEPARATOR.encode_utf8(&mut buf);
@@ -142,12 +344,13 @@ pub(crate) fn export_chunked(
         let mut annotation_component_layer = HashMap::new();
         packages.sort();
         annotation_component_layer.insert(CONTENT_ANNOTATION.to_string(), packages.join(sep));
-        ociw.push_layer(
+        ociw.push_layer_full(
             manifest,
             imgcfg,
             layer,
-            name.as_str(),
             Some(annotation_component_layer),
+            name.as_str(),
+            created,
         );
     }
 
@@ -171,6 +374,12 @@ fn build_oci(
     config: &Config,
     opts: ExportOpts,
 ) -> Result<()> {
+    if let Some(platform) = opts.platform.as_ref() {
+        validate_platform(platform)?;
+    }
+    if opts.signature_annotations.contains_key(OSTREE_COMMIT_LABEL) {
+        anyhow::bail!("Signature annotations must not include {OSTREE_COMMIT_LABEL}");
+    }
     let commit = repo.require_rev(rev)?;
     let commit = commit.as_str();
     let (commit_v, _) = repo.load_commit(commit)?;
@@ -191,6 +400,11 @@ fn build_oci(
 
     let mut ctrcfg = opts.container_config.clone().unwrap_or_default();
     let mut imgcfg = oci_image::ImageConfiguration::default();
+    if let Some(platform) = opts.platform.as_ref() {
+        imgcfg.set_architecture(platform.architecture().clone());
+        imgcfg.set_os(platform.os().clone());
+        imgcfg.set_variant(platform.variant().clone());
+    }
 
     let created_at = opts
         .created
@@ -276,8 +490,17 @@ fn build_oci(
     imgcfg.set_config(Some(ctrcfg));
     let ctrcfg = writer.write_config(imgcfg)?;
     manifest.set_config(ctrcfg);
-    manifest.set_annotations(Some(labels));
-    let platform = oci_image::Platform::default();
+    let mut annotations = labels;
+    if opts.emit_boot_metadata {
+        annotations.extend(boot_metadata(repo, commit)?);
+    }
+    annotations.extend(
+        opts.signature_annotations
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone())),
+    );
+    manifest.set_annotations(Some(annotations));
+    let platform = opts.platform.clone().unwrap_or_default();
     if let Some(tag) = tag {
         writer.insert_manifest(manifest, Some(tag), platform)?;
     } else {
@@ -287,6 +510,64 @@ fn build_oci(
     Ok(())
 }
 
+/// Describe the kernel of a commit, as manifest annotations.
+#[context("Finding boot metadata")]
+fn boot_metadata(repo: &ostree::Repo, commit: &str) -> Result<HashMap<String, String>> {
+    let cancellable = gio::Cancellable::NONE;
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let kdir = crate::bootabletree::find_kernel_dir(&root, cancellable)?
+        .ok_or_else(|| anyhow!("No kernel found in commit {commit}"))?;
+    let kver = kdir
+        .basename()
+        .and_then(|n| n.to_str().map(ToOwned::to_owned))
+        .ok_or_else(|| anyhow!("Invalid kernel directory name"))?;
+    let kdir_path = format!("/usr/lib/modules/{kver}");
+    let mut r = HashMap::new();
+    // Like ostree, the boot checksum covers the kernel, and the initramfs and
+    // devicetree if present.
+    let mut bootcsum = openssl::sha::Sha256::new();
+    for name in ["vmlinuz", "initramfs.img", "devicetree"] {
+        let f = kdir.child(name);
+        if !f.query_exists(cancellable) {
+            continue;
+        }
+        let path = format!("{kdir_path}/{name}");
+        let mut src = f.read(cancellable)?.into_read();
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = src
+                .read(&mut buf)
+                .with_context(|| format!("Reading {path}"))?;
+            if n == 0 {
+                break;
+            }
+            bootcsum.update(&buf[..n]);
+        }
+        if name == "vmlinuz" {
+            r.insert(BOOT_KERNEL_ANNOTATION.to_string(), path);
+        } else if name == "initramfs.img" {
+            r.insert(BOOT_INITRAMFS_ANNOTATION.to_string(), path);
+        }
+    }
+    r.insert(
+        BOOT_CHECKSUM_ANNOTATION.to_string(),
+        hex::encode(bootcsum.finish()),
+    );
+    r.insert(BOOT_KERNEL_VERSION_ANNOTATION.to_string(), kver);
+    Ok(r)
+}
+
+/// Ensure the architecture and operating system of a platform are known values.
+fn validate_platform(platform: &oci_image::Platform) -> Result<()> {
+    if let oci_image::Arch::Other(arch) = platform.architecture() {
+        anyhow::bail!("Unknown architecture: {arch}");
+    }
+    if let oci_image::Os::Other(os) = platform.os() {
+        anyhow::bail!("Unknown operating system: {os}");
+    }
+    Ok(())
+}
+
 /// Interpret a filesystem path as optionally including a tag.  Paths
 /// such as `/foo/bar` will return `("/foo/bar"`, None)`, whereas
 /// e.g. `/foo/bar:latest` will return `("/foo/bar", Some("latest"))`.
@@ -297,6 +578,17 @@ pub(crate) fn parse_oci_path_and_tag(path: &str) -> (&str, Option<&str>) {
     }
 }
 
+/// Create a temporary directory in `tmpdir`, or in `/var/tmp` by default, to
+/// hold an image which is then copied by `skopeo`.
+pub(crate) fn create_tempdir(
+    tmpdir: Option<&std::path::Path>,
+) -> Result<cap_std_ext::cap_tempfile::TempDir> {
+    let tmpdir = tmpdir.unwrap_or_else(|| std::path::Path::new("/var/tmp"));
+    Dir::open_ambient_dir(tmpdir, cap_std::ambient_authority())
+        .and_then(|d| cap_std_ext::cap_tempfile::tempdir_in(&d))
+        .with_context(|| format!("Creating temporary directory in {}", tmpdir.display()))
+}
+
 /// Helper for `build()` that avoids generics
 #[instrument(level = "debug", skip_all)]
 async fn build_impl(
@@ -310,6 +602,9 @@ async fn build_impl(
     if dest.transport == Transport::ContainerStorage {
         opts.skip_compression = true;
     }
+    if opts.checkpoint.is_some() && dest.transport != Transport::OciDir {
+        anyhow::bail!("Export checkpoints require an OCI directory destination");
+    }
     let digest = if dest.transport == Transport::OciDir {
         let (path, tag) = parse_oci_path_and_tag(dest.name.as_str());
         tracing::debug!("using OCI path={path} tag={tag:?}");
@@ -321,14 +616,14 @@ async fn build_impl(
         build_oci(repo, ostree_ref, &mut ocidir, tag, config, opts)?;
         None
     } else {
-        let tempdir = {
-            let vartmp = Dir::open_ambient_dir("/var/tmp", cap_std::ambient_authority())?;
-            cap_std_ext::cap_tempfile::tempdir_in(&vartmp)?
-        };
+        // Created before building the image, so that an unusable directory fails early
+        let tempdir = create_tempdir(opts.tmpdir.as_deref())?;
         let mut ocidir = OciDir::ensure(&tempdir)?;
 
         // Minor TODO: refactor to avoid clone
         let authfile = opts.authfile.clone();
+        let copy_concurrency = opts.copy_concurrency;
+        let skopeo_retry_times = opts.skopeo_retry_times;
         build_oci(repo, ostree_ref, &mut ocidir, None, config, opts)?;
         drop(ocidir);
 
@@ -344,6 +639,8 @@ async fn build_impl(
             authfile.as_deref(),
             Some((std::sync::Arc::new(tempdir.try_clone()?.into()), target_fd)),
             false,
+            copy_concurrency,
+            skopeo_retry_times,
         )
         .await?;
         Some(digest)
@@ -390,6 +687,56 @@ pub struct ExportOpts<'m, 'o> {
     pub contentmeta: Option<&'o ObjectMetaSized>,
     /// Sets the created tag in the image manifest.
     pub created: Option<String>,
+    /// The platform (operating system, architecture and variant) of the image,
+    /// written to the image configuration and the image index.  Defaults to
+    /// the host platform.
+    pub platform: Option<oci_image::Platform>,
+    /// Maximum number of layers `skopeo copy` pushes simultaneously when the
+    /// destination is not an OCI directory.  This only helps images with multiple
+    /// layers, and requires skopeo 1.14 or newer.
+    pub copy_concurrency: Option<NonZeroU32>,
+    /// The number of times `skopeo copy` retries an operation (e.g. a blob upload)
+    /// which fails with a transient error, when the destination is not an OCI
+    /// directory; passed as `--retry-times`.  This only covers individual blobs:
+    /// retrying the push as a whole on failure is up to the caller.
+    pub skopeo_retry_times: Option<u32>,
+    /// Additional annotations for the image manifest (but not the configuration),
+    /// e.g. a reference to a detached signature, for use by external signing and
+    /// verification tools.  These override other annotations with the same key,
+    /// but must not include [`OSTREE_COMMIT_LABEL`].  Note that the image is not
+    /// signed; this only carries the metadata.
+    pub signature_annotations: HashMap<String, String>,
+    /// Add annotations describing the kernel in `/usr/lib/modules/<kver>` to the
+    /// image manifest, so that boot tooling can locate it without reading the
+    /// layers: [`BOOT_KERNEL_VERSION_ANNOTATION`], [`BOOT_KERNEL_ANNOTATION`],
+    /// [`BOOT_INITRAMFS_ANNOTATION`] (if there is an `initramfs.img`) and
+    /// [`BOOT_CHECKSUM_ANNOTATION`].  It is an error if the commit has no kernel.
+    pub emit_boot_metadata: bool,
+    /// Path to a file recording the layers which have been completely written, so
+    /// that if the export fails, running it again skips those layers.  This is
+    /// only supported when exporting to an OCI directory, where the layers are
+    /// kept.
+    ///
+    /// The file is JSON with a `version` (currently 1), a `key` which identifies
+    /// the input (the commit checksum and the compression level), and `layers`,
+    /// which maps a key derived from the objects and paths in a layer to its
+    /// `digest`, `size`, `diff_id` and `media_type`.  If the input key does not
+    /// match, the recorded layers are discarded; a recorded layer is only reused
+    /// if its blob is still present.  To force a clean export, delete the file.
+    pub checkpoint: Option<std::path::PathBuf>,
+    /// Make the export reproducible, so that exporting the same commit with the
+    /// same options always yields the same image manifest digest.  The layer
+    /// history entries then record the commit timestamp instead of the time of
+    /// the export.  The rest of the image does not depend on the time of the
+    /// export: the tar streams use the timestamps from the commit, gzip headers
+    /// carry no timestamp, JSON blobs are written in canonical form (so labels
+    /// and annotations are sorted), and the image configuration defaults to the
+    /// commit timestamp for its creation time (see [`Self::created`]).
+    pub reproducible: bool,
+    /// Directory in which the image is written before `skopeo copy` pushes it,
+    /// when the destination is not an OCI directory; this needs room for the
+    /// whole image.  Defaults to `/var/tmp`.
+    pub tmpdir: Option<std::path::PathBuf>,
 }
 
 impl ExportOpts<'_, '_> {
@@ -416,6 +763,89 @@ pub async fn encapsulate<S: AsRef<str>>(
     build_impl(repo, ostree_ref.as_ref(), config, opts, dest).await
 }
 
+/// Append a layer with the changes from `base_rev` to `target_rev` to the
+/// container image in the OCI directory `oci_dir`, and write the result as
+/// a new image with the provided tag.
+///
+/// The first image in the directory is used as the base; it must be an
+/// encapsulation of `base_rev` (e.g. as generated by [`encapsulate`]).  The new
+/// layer contains only added and changed content, with removals represented as
+/// whiteouts; it is annotated with the base and target commits.  Importing the
+/// resulting image yields the content of `target_rev`, and its `ostree.commit`
+/// label and manifest annotation name `target_rev` as well.
+///
+/// Returns the digest of the new image manifest.
+#[context("Exporting delta to OCI")]
+pub fn export_delta_oci(
+    repo: &ostree::Repo,
+    base_rev: &str,
+    target_rev: &str,
+    oci_dir: &Dir,
+    tag: &str,
+    opts: Option<ExportOpts>,
+) -> Result<oci_image::Digest> {
+    let opts = opts.unwrap_or_default();
+    let base = repo.require_rev(base_rev)?;
+    let target = repo.require_rev(target_rev)?;
+    let ociw = OciDir::open(oci_dir)?;
+    let idx = ociw
+        .read_index()?
+        .ok_or_else(|| anyhow!("Missing image index"))?;
+    let manifest_descriptor = idx
+        .manifests()
+        .first()
+        .ok_or_else(|| anyhow!("No manifests in index"))?;
+    let mut manifest: oci_image::ImageManifest = ociw.read_json_blob(manifest_descriptor)?;
+    let mut imgcfg: oci_image::ImageConfiguration = ociw.read_json_blob(manifest.config())?;
+    let image_commit = imgcfg
+        .config()
+        .as_ref()
+        .and_then(|c| c.labels().as_ref())
+        .and_then(|l| l.get(OSTREE_COMMIT_LABEL));
+    if image_commit.map(|c| c.as_str()) != Some(base.as_str()) {
+        anyhow::bail!(
+            "Base image is for commit {}, not {base}",
+            image_commit.map(|c| c.as_str()).unwrap_or("<none>")
+        );
+    }
+    // The new image is an encapsulation of the target commit, not the base.
+    let mut ctrcfg = imgcfg.config().clone().unwrap_or_default();
+    ctrcfg
+        .labels_mut()
+        .get_or_insert_with(Default::default)
+        .insert(OSTREE_COMMIT_LABEL.into(), target.to_string());
+    imgcfg.set_config(Some(ctrcfg));
+    if let Some(annotations) = manifest.annotations_mut() {
+        if let Some(commit) = annotations.get_mut(OSTREE_COMMIT_LABEL) {
+            *commit = target.to_string();
+        }
+    }
+
+    let mut w = ociw.create_gzip_layer(Some(opts.compression()))?;
+    ostree_tar::export_commit_delta(repo, &base, &target, &mut w)?;
+    let layer = w.complete()?;
+    let annotations: HashMap<_, _> = [
+        (DELTA_BASE_ANNOTATION.to_string(), base.to_string()),
+        (DELTA_TARGET_ANNOTATION.to_string(), target.to_string()),
+    ]
+    .into_iter()
+    .collect();
+    let description = format!("ostree delta from {base} to {target}");
+    let created = layer_timestamp(repo, &target, opts.reproducible)?;
+    ociw.push_layer_full(
+        &mut manifest,
+        &mut imgcfg,
+        layer,
+        Some(annotations),
+        &description,
+        created,
+    );
+    let imgcfg = ociw.write_config(imgcfg)?;
+    manifest.set_config(imgcfg);
+    let descriptor = ociw.insert_manifest(manifest, Some(tag), oci_image::Platform::default())?;
+    Ok(descriptor.digest().clone())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -25,7 +25,7 @@
 //! A key feature of container images is support for layering.  At the moment, support
 //! for this is [planned but not implemented](https://github.com/ostreedev/ostree-rs-ext/issues/12).
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use cap_std_ext::cap_std;
 use cap_std_ext::cap_std::fs::Dir;
 use containers_image_proxy::oci_spec;
@@ -140,13 +140,102 @@ impl Transport {
     }
 }
 
+impl ImageReference {
+    /// Return the image name embedded in this reference, if any.  For the
+    /// transports which refer to local files (other than `dir:`), this is the
+    /// optional reference following the path.
+    fn embedded_name(&self) -> Option<&str> {
+        match self.transport {
+            Transport::Registry | Transport::ContainerStorage => Some(self.name.as_str()),
+            Transport::OciDir | Transport::OciArchive | Transport::DockerArchive => self
+                .name
+                .split_once(':')
+                .map(|(_, name)| name)
+                .filter(|name| !name.is_empty()),
+            Transport::Dir => None,
+        }
+    }
+
+    /// Return a reference to the same image via another transport which
+    /// identifies images by name, i.e. [`Transport::Registry`] or
+    /// [`Transport::ContainerStorage`].  For the transports referring to local
+    /// files, the image name is taken from the reference following the path
+    /// (e.g. `oci-archive:/path/to/foo.ociarchive:quay.io/exampleos/foo:latest`).
+    pub fn with_transport(&self, transport: Transport) -> Result<Self> {
+        if !matches!(transport, Transport::Registry | Transport::ContainerStorage) {
+            return Err(anyhow!(
+                "Converting to transport {} requires a path",
+                transport.serializable_name()
+            ));
+        }
+        let name = self
+            .embedded_name()
+            .ok_or_else(|| anyhow!("No image name in '{}'", self))?;
+        if transport == Transport::Registry {
+            validate_registry_name(name).with_context(|| format!("Converting {self}"))?;
+        }
+        Ok(Self {
+            transport,
+            name: name.to_string(),
+        })
+    }
+
+    /// Return a reference to the same image stored at `path` via a transport
+    /// which refers to local files, i.e. [`Transport::OciDir`],
+    /// [`Transport::OciArchive`], [`Transport::DockerArchive`] or
+    /// [`Transport::Dir`].  The image name (if any) is carried over as the
+    /// reference following the path, except for `dir:` which does not support one.
+    pub fn with_path(&self, transport: Transport, path: &str) -> Result<Self> {
+        if matches!(transport, Transport::Registry | Transport::ContainerStorage) {
+            return Err(anyhow!(
+                "Transport {} does not refer to a path",
+                transport.serializable_name()
+            ));
+        }
+        if path.is_empty() {
+            return Err(anyhow!("Invalid empty path"));
+        }
+        let name = match (transport, self.embedded_name()) {
+            (Transport::Dir, _) | (_, None) => path.to_string(),
+            (_, Some(_)) if path.contains(':') => {
+                return Err(anyhow!(
+                    "Path '{path}' must not contain ':' when followed by an image reference"
+                ));
+            }
+            (_, Some(name)) => format!("{path}:{name}"),
+        };
+        Ok(Self { transport, name })
+    }
+}
+
+impl OstreeImageReference {
+    /// Return a reference to the same image via another transport which
+    /// identifies images by name, preserving the signature source.
+    /// See [`ImageReference::with_transport`].
+    pub fn with_transport(&self, transport: Transport) -> Result<Self> {
+        Ok(Self {
+            sigverify: self.sigverify.clone(),
+            imgref: self.imgref.with_transport(transport)?,
+        })
+    }
+
+    /// Return a reference to the same image stored at `path`, preserving the
+    /// signature source.  See [`ImageReference::with_path`].
+    pub fn with_path(&self, transport: Transport, path: &str) -> Result<Self> {
+        Ok(Self {
+            sigverify: self.sigverify.clone(),
+            imgref: self.imgref.with_path(transport, path)?,
+        })
+    }
+}
+
 impl TryFrom<&str> for ImageReference {
     type Error = anyhow::Error;
 
     fn try_from(value: &str) -> Result<Self> {
         let (transport_name, mut name) = value
             .split_once(':')
-            .ok_or_else(|| anyhow!("Missing ':' in {}", value))?;
+            .ok_or_else(|| anyhow!("Missing transport (e.g. docker://) in '{}'", value))?;
         let transport: Transport = transport_name.try_into()?;
         if name.is_empty() {
             return Err(anyhow!("Invalid empty name in {}", value));
@@ -156,6 +245,9 @@ impl TryFrom<&str> for ImageReference {
                 .strip_prefix("//")
                 .ok_or_else(|| anyhow!("Missing // in docker:// in {}", value))?;
         }
+        if transport == Transport::Registry {
+            validate_registry_name(name).with_context(|| format!("Parsing {value}"))?;
+        }
         Ok(Self {
             transport,
             name: name.to_string(),
@@ -163,6 +255,26 @@ impl TryFrom<&str> for ImageReference {
     }
 }
 
+/// Basic sanity checks for an image name in a registry, to catch common mistakes
+/// before they result in more obscure errors from the image fetching code.
+fn validate_registry_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("Invalid empty image name"));
+    }
+    if let Some(c) = name.chars().find(|c| c.is_whitespace() || c.is_control()) {
+        return Err(anyhow!("Invalid character {c:?} in image name '{name}'"));
+    }
+    if name.contains("://") {
+        return Err(anyhow!(
+            "Unexpected transport prefix in image name '{name}'"
+        ));
+    }
+    if name.starts_with('/') || name.ends_with('/') || name.contains("//") {
+        return Err(anyhow!("Invalid image name '{name}'"));
+    }
+    Ok(())
+}
+
 impl FromStr for ImageReference {
     type Err = anyhow::Error;
 
@@ -180,7 +292,7 @@ impl TryFrom<&str> for SignatureSource {
             "ostree-unverified-image" => Ok(Self::ContainerPolicyAllowInsecure),
             o => match o.strip_prefix("ostree-remote-image:") {
                 Some(rest) => Ok(Self::OstreeRemote(rest.to_string())),
-                _ => Err(anyhow!("Invalid signature source: {}", o)),
+                _ => Err(anyhow!("Unknown signature source '{}'", o)),
             },
         }
     }
@@ -200,7 +312,7 @@ impl TryFrom<&str> for OstreeImageReference {
     fn try_from(value: &str) -> Result<Self> {
         let (first, second) = value
             .split_once(':')
-            .ok_or_else(|| anyhow!("Missing ':' in {}", value))?;
+            .ok_or_else(|| anyhow!("Missing signature source in '{}'", value))?;
         let (sigverify, rest) = match first {
             "ostree-image-signed" => (SignatureSource::ContainerPolicy, Cow::Borrowed(second)),
             "ostree-unverified-image" => (
@@ -216,7 +328,7 @@ impl TryFrom<&str> for OstreeImageReference {
             "ostree-remote-registry" => {
                 let (remote, rest) = second
                     .split_once(':')
-                    .ok_or_else(|| anyhow!("Missing second ':' in {}", value))?;
+                    .ok_or_else(|| anyhow!("Missing remote name in '{}'", value))?;
                 (
                     SignatureSource::OstreeRemote(remote.to_string()),
                     Cow::Owned(format!("registry:{rest}")),
@@ -225,16 +337,28 @@ impl TryFrom<&str> for OstreeImageReference {
             "ostree-remote-image" => {
                 let (remote, rest) = second
                     .split_once(':')
-                    .ok_or_else(|| anyhow!("Missing second ':' in {}", value))?;
+                    .ok_or_else(|| anyhow!("Missing remote name in '{}'", value))?;
                 (
                     SignatureSource::OstreeRemote(remote.to_string()),
                     Cow::Borrowed(rest),
                 )
             }
+            // A common mistake is to omit the signature source entirely
+            o if Transport::try_from(o).is_ok() => {
+                return Err(anyhow!(
+                    "Missing signature source (e.g. ostree-unverified-image:) in '{}'",
+                    value
+                ));
+            }
             o => {
-                return Err(anyhow!("Invalid ostree image reference scheme: {}", o));
+                return Err(anyhow!("Unknown signature source '{}'", o));
             }
         };
+        if let SignatureSource::OstreeRemote(remote) = &sigverify {
+            if remote.is_empty() {
+                return Err(anyhow!("Invalid empty remote name in '{}'", value));
+            }
+        }
         let imgref = rest.deref().try_into()?;
         Ok(Self { sigverify, imgref })
     }
@@ -478,6 +602,7 @@ pub use encapsulate::*;
 mod unencapsulate;
 pub use unencapsulate::*;
 mod skopeo;
+pub use skopeo::{version as skopeo_version, SkopeoVersion};
 pub mod store;
 mod update_detachedmeta;
 pub use update_detachedmeta::*;
@@ -567,6 +692,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_imgref_errors() {
+        #[track_caller]
+        fn assert_err_contains(s: &str, expected: &str) {
+            let msg = format!("{:#}", OstreeImageReference::from_str(s).unwrap_err());
+            assert!(msg.contains(expected), "{msg} does not contain {expected}");
+        }
+        assert_err_contains("quay.io/exampleos/blah", "Missing signature source");
+        assert_err_contains(
+            "docker://quay.io/exampleos/blah",
+            "Missing signature source (e.g. ostree-unverified-image:)",
+        );
+        assert_err_contains(
+            "quay.io/exampleos/blah:latest",
+            "Unknown signature source 'quay.io/exampleos/blah'",
+        );
+        assert_err_contains("ostree-unverified-image:foo:bar", "Unknown transport 'foo'");
+        assert_err_contains("ostree-unverified-image:quay.io", "Missing transport");
+        assert_err_contains(
+            "ostree-unverified-registry:quay.io/exampleos/ blah",
+            "Invalid character ' ' in image name",
+        );
+        assert_err_contains(
+            "ostree-unverified-image:registry:docker://quay.io/exampleos/blah",
+            "Unexpected transport prefix",
+        );
+        assert_err_contains("ostree-remote-registry:quay.io", "Missing remote name");
+        assert_err_contains(
+            "ostree-remote-registry::quay.io/exampleos/blah",
+            "Invalid empty remote name",
+        );
+        assert_err_contains(
+            "ostree-unverified-registry:/exampleos",
+            "Invalid image name",
+        );
+        assert_err_contains(
+            "ostree-remote-imag:myremote:docker://quay.io/exampleos/blah",
+            "Unknown signature source 'ostree-remote-imag'",
+        );
+    }
+
     #[test]
     fn test_ostreeimagereference() {
         // Test both long form `ostree-remote-image:$myremote:registry` and the
@@ -618,6 +784,89 @@ mod tests {
         assert_eq!(format!("{:#}", &ir), "docker://quay.io/exampleos/blah");
     }
 
+    #[test]
+    fn test_imgref_transport_conversion() {
+        let ir: ImageReference = "docker://quay.io/exampleos/blah:latest".parse().unwrap();
+        let cases = [
+            (
+                ir.with_transport(Transport::Registry).unwrap(),
+                "docker://quay.io/exampleos/blah:latest",
+            ),
+            (
+                ir.with_transport(Transport::ContainerStorage).unwrap(),
+                "containers-storage:quay.io/exampleos/blah:latest",
+            ),
+            (
+                ir.with_path(Transport::OciDir, "/path/to/foo").unwrap(),
+                "oci:/path/to/foo:quay.io/exampleos/blah:latest",
+            ),
+            (
+                ir.with_path(Transport::OciArchive, "/path/to/foo.ociarchive")
+                    .unwrap(),
+                "oci-archive:/path/to/foo.ociarchive:quay.io/exampleos/blah:latest",
+            ),
+            (
+                ir.with_path(Transport::DockerArchive, "/path/to/foo.dockerarchive")
+                    .unwrap(),
+                "docker-archive:/path/to/foo.dockerarchive:quay.io/exampleos/blah:latest",
+            ),
+            (
+                ir.with_path(Transport::Dir, "/some/dir").unwrap(),
+                "dir:/some/dir",
+            ),
+        ];
+        for (converted, expected) in cases {
+            assert_eq!(converted.to_string(), expected);
+            // The result re-parses to the same value
+            assert_eq!(ImageReference::try_from(expected).unwrap(), converted);
+            // And converts back, except for dir: which has no image name
+            match converted.with_transport(Transport::Registry) {
+                Ok(back) => assert_eq!(back, ir),
+                Err(_) => assert_eq!(converted.transport, Transport::Dir),
+            }
+        }
+
+        // Local references without an image name can only be moved to another path
+        let ir: ImageReference = "oci-archive:/path/to/foo.ociarchive".parse().unwrap();
+        assert!(ir.with_transport(Transport::ContainerStorage).is_err());
+        assert_eq!(
+            ir.with_path(Transport::OciDir, "/path/to/foo")
+                .unwrap()
+                .to_string(),
+            "oci:/path/to/foo"
+        );
+        let ir: ImageReference = "dir:/some/dir".parse().unwrap();
+        assert!(ir.with_transport(Transport::Registry).is_err());
+
+        // Validation of the reference shape
+        let ir: ImageReference = "containers-storage:localhost/someimage".parse().unwrap();
+        assert!(ir.with_transport(Transport::OciArchive).is_err());
+        assert!(ir.with_path(Transport::Registry, "/path").is_err());
+        assert!(ir.with_path(Transport::OciDir, "").is_err());
+        assert!(ir
+            .with_path(Transport::OciDir, "/path:with:colons")
+            .is_err());
+        let ir: ImageReference = "oci:/path/to/foo:some image".parse().unwrap();
+        assert!(ir.with_transport(Transport::ContainerStorage).is_ok());
+        assert!(ir.with_transport(Transport::Registry).is_err());
+
+        // The signature source is preserved
+        let ir: OstreeImageReference =
+            "ostree-remote-image:myremote:docker://quay.io/exampleos/blah"
+                .parse()
+                .unwrap();
+        let converted = ir.with_transport(Transport::ContainerStorage).unwrap();
+        assert_eq!(
+            converted.to_string(),
+            "ostree-remote-image:myremote:containers-storage:quay.io/exampleos/blah"
+        );
+        let converted = ir
+            .with_path(Transport::OciArchive, "/path/to/foo.ociarchive")
+            .unwrap();
+        assert_eq!(converted.sigverify, ir.sigverify);
+        assert_eq!(converted.with_transport(Transport::Registry).unwrap(), ir);
+    }
+
     #[test]
     fn test_merge_authopts() {
         // Verify idempotence of authentication processing
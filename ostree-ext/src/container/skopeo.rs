@@ -1,13 +1,17 @@
 //! Fork skopeo as a subprocess
 
-use super::ImageReference;
+use super::{ImageReference, Transport};
 use anyhow::{Context, Result};
 use cap_std_ext::cmdext::CapStdExtCommandExt;
 use containers_image_proxy::oci_spec::image as oci_image;
+use containers_image_proxy::{ImageProxy, ImageProxyConfig};
 use fn_error_context::context;
 use io_lifetimes::OwnedFd;
+use once_cell::sync::OnceCell;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::io::Read;
+use std::num::NonZeroU32;
 use std::path::Path;
 use std::process::Stdio;
 use std::str::FromStr;
@@ -28,6 +32,38 @@ struct PolicyEntry {
 #[derive(Deserialize)]
 struct ContainerPolicy {
     default: Option<Vec<PolicyEntry>>,
+    /// Map from transport name to scope to requirements
+    transports: Option<HashMap<String, HashMap<String, Vec<PolicyEntry>>>>,
+}
+
+/// The name of a transport in the policy file.
+fn policy_transport_name(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Registry => "docker",
+        o => o.serializable_name(),
+    }
+}
+
+/// The scopes which may match an image name, from most to least specific.
+/// See the `docker` transport section of `man containers-policy.json`.
+fn policy_scopes(transport: Transport, name: &str) -> Vec<&str> {
+    let mut r = vec![name];
+    if transport == Transport::Registry {
+        // Strip off any digest or tag
+        let untagged = name.split_once('@').map(|v| v.0).unwrap_or(name);
+        let untagged = match untagged.rsplit_once(':') {
+            Some((base, tag)) if !tag.contains('/') => base,
+            _ => untagged,
+        };
+        r.push(untagged);
+        let mut ns = untagged;
+        while let Some((parent, _)) = ns.rsplit_once('/') {
+            r.push(parent);
+            ns = parent;
+        }
+    }
+    r.dedup();
+    r
 }
 
 impl ContainerPolicy {
@@ -41,12 +77,45 @@ impl ContainerPolicy {
             false
         }
     }
+
+    /// Find the requirements which apply to this image.
+    fn requirements_for(&self, imgref: &ImageReference) -> &[PolicyEntry] {
+        let transport_scopes = self
+            .transports
+            .as_ref()
+            .and_then(|t| t.get(policy_transport_name(imgref.transport)));
+        if let Some(scopes) = transport_scopes {
+            let found = policy_scopes(imgref.transport, &imgref.name)
+                .into_iter()
+                .chain(std::iter::once(""))
+                .find_map(|scope| scopes.get(scope));
+            if let Some(found) = found {
+                return found;
+            }
+        }
+        self.default.as_deref().unwrap_or_default()
+    }
+
+    /// Returns true if the policy requires that this image be signed.
+    fn requires_signature(&self, imgref: &ImageReference) -> bool {
+        self.requirements_for(imgref)
+            .iter()
+            .any(|e| e.ty != INSECURE_ACCEPT_ANYTHING)
+    }
 }
 
-pub(crate) fn container_policy_is_default_insecure() -> Result<bool> {
+fn load_container_policy() -> Result<ContainerPolicy> {
     let r = std::io::BufReader::new(std::fs::File::open(POLICY_PATH)?);
-    let policy: ContainerPolicy = serde_json::from_reader(r)?;
-    Ok(policy.is_default_insecure())
+    serde_json::from_reader(r).with_context(|| format!("Parsing {POLICY_PATH}"))
+}
+
+pub(crate) fn container_policy_is_default_insecure() -> Result<bool> {
+    Ok(load_container_policy()?.is_default_insecure())
+}
+
+/// Returns true if the containers policy requires the target image to be signed.
+pub(crate) fn container_policy_requires_signature(imgref: &ImageReference) -> Result<bool> {
+    Ok(load_container_policy()?.requires_signature(imgref))
 }
 
 /// Create a Command builder for skopeo.
@@ -56,13 +125,127 @@ pub(crate) fn new_cmd() -> std::process::Command {
     cmd
 }
 
+/// The version of the installed skopeo binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SkopeoVersion {
+    /// Major version
+    pub major: u32,
+    /// Minor version
+    pub minor: u32,
+    /// Patch version
+    pub patch: u32,
+}
+
+impl std::fmt::Display for SkopeoVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for SkopeoVersion {
+    type Err = anyhow::Error;
+
+    /// Parse the output of `skopeo --version`, e.g. `skopeo version 1.14.2`.
+    fn from_str(s: &str) -> Result<Self> {
+        let v = s
+            .trim()
+            .strip_prefix("skopeo version ")
+            .and_then(|v| v.split_whitespace().next())
+            .ok_or_else(|| anyhow::anyhow!("Failed to parse skopeo version from '{s}'"))?;
+        // Ignore any suffix such as `-dev`
+        let v = v.split_once('-').map(|v| v.0).unwrap_or(v);
+        let mut parts = v.split('.').map(|p| {
+            p.parse::<u32>()
+                .with_context(|| format!("Invalid skopeo version '{v}'"))
+        });
+        let major = parts
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Invalid skopeo version '{v}'"))??;
+        let minor = parts.next().transpose()?.unwrap_or_default();
+        let patch = parts.next().transpose()?.unwrap_or_default();
+        Ok(Self {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+/// The first skopeo version which supports fetching layer information, which is
+/// required to pull from `containers-storage:`.
+pub(crate) const CONTAINERS_STORAGE_MIN_VERSION: SkopeoVersion = SkopeoVersion {
+    major: 1,
+    minor: 14,
+    patch: 0,
+};
+
+/// The first skopeo version which supports `copy --image-parallel-copies`.
+pub(crate) const PARALLEL_COPIES_MIN_VERSION: SkopeoVersion = SkopeoVersion {
+    major: 1,
+    minor: 14,
+    patch: 0,
+};
+
+/// Detect the version of the installed skopeo.  This is only computed once
+/// per process.
+#[context("Detecting skopeo version")]
+pub fn version() -> Result<SkopeoVersion> {
+    static VERSION: OnceCell<SkopeoVersion> = OnceCell::new();
+    VERSION
+        .get_or_try_init(|| {
+            let o = new_cmd()
+                .arg("--version")
+                .stderr(Stdio::inherit())
+                .output()
+                .context("Failed to exec skopeo")?;
+            if !o.status.success() {
+                anyhow::bail!("skopeo --version failed: {:?}", o.status);
+            }
+            SkopeoVersion::from_str(&String::from_utf8_lossy(&o.stdout))
+        })
+        .copied()
+}
+
+/// Return an error if the installed skopeo is older than `min`, which is
+/// required for `feature`.
+pub(crate) fn require_version(min: SkopeoVersion, feature: &str) -> Result<()> {
+    let v = version()?;
+    if v < min {
+        anyhow::bail!("{feature} requires skopeo {min} or newer, but skopeo {v} is installed");
+    }
+    Ok(())
+}
+
 /// Spawn the child process
 pub(crate) fn spawn(mut cmd: Command) -> Result<tokio::process::Child> {
     let cmd = cmd.stdin(Stdio::null()).stderr(Stdio::piped());
     cmd.spawn().context("Failed to exec skopeo")
 }
 
-/// Use skopeo to copy a container image.
+/// Add the options for `skopeo copy` to `cmd`; see [`copy`].
+fn append_copy_options(
+    cmd: &mut std::process::Command,
+    authfile: Option<&Path>,
+    parallel_copies: Option<NonZeroU32>,
+    retry_times: Option<u32>,
+) -> Result<()> {
+    if let Some(authfile) = authfile {
+        cmd.arg("--authfile");
+        cmd.arg(authfile);
+    }
+    if let Some(n) = parallel_copies {
+        require_version(PARALLEL_COPIES_MIN_VERSION, "Parallel layer copies")?;
+        cmd.arg(format!("--image-parallel-copies={n}"));
+    }
+    if let Some(n) = retry_times {
+        cmd.arg(format!("--retry-times={n}"));
+    }
+    Ok(())
+}
+
+/// Use skopeo to copy a container image; `parallel_copies` limits the number
+/// of layers copied simultaneously, and `retry_times` is the number of times
+/// skopeo retries each operation which fails with a transient error.
 #[context("Skopeo copy")]
 pub(crate) async fn copy(
     src: &ImageReference,
@@ -70,6 +253,8 @@ pub(crate) async fn copy(
     authfile: Option<&Path>,
     add_fd: Option<(std::sync::Arc<OwnedFd>, i32)>,
     progress: bool,
+    parallel_copies: Option<NonZeroU32>,
+    retry_times: Option<u32>,
 ) -> Result<oci_image::Digest> {
     let digestfile = tempfile::NamedTempFile::new()?;
     let mut cmd = new_cmd();
@@ -82,10 +267,7 @@ pub(crate) async fn copy(
     if let Some((add_fd, n)) = add_fd {
         cmd.take_fd_n(add_fd, n);
     }
-    if let Some(authfile) = authfile {
-        cmd.arg("--authfile");
-        cmd.arg(authfile);
-    }
+    append_copy_options(&mut cmd, authfile, parallel_copies, retry_times)?;
     cmd.args(&[src.to_string(), dest.to_string()]);
     let mut cmd = tokio::process::Command::from(cmd);
     cmd.kill_on_drop(true);
@@ -101,6 +283,94 @@ pub(crate) async fn copy(
     Ok(oci_image::Digest::from_str(r.trim())?)
 }
 
+/// The `skopeo` process behind an [`ImageProxy`].  `containers-image-proxy` does
+/// not expose it, and dropping the proxy does not stop it while a request is
+/// pending, e.g. one stuck on an unresponsive registry.
+#[derive(Debug)]
+pub(crate) struct ProxyProcess {
+    pidfd: OwnedFd,
+}
+
+impl ProxyProcess {
+    /// Kill the process, and wait until it has been reaped.
+    pub(crate) async fn kill(self) -> Result<()> {
+        use rustix::io::Errno;
+        use rustix::process::{Signal, WaitId, WaitidOptions};
+        use std::os::fd::AsFd;
+        match rustix::process::pidfd_send_signal(&self.pidfd, Signal::Kill) {
+            // It already exited
+            Ok(()) | Err(Errno::SRCH) => {}
+            Err(e) => return Err(e).context("Killing skopeo"),
+        }
+        tokio::task::spawn_blocking(move || {
+            // The proxy also waits for the process; if it reaped it first, there
+            // is no child left to wait for.
+            let pidfd = WaitId::PidFd(self.pidfd.as_fd());
+            match rustix::process::waitid(pidfd, WaitidOptions::EXITED) {
+                Ok(_) | Err(Errno::CHILD) => Ok(()),
+                Err(e) => Err(e).context("Waiting for skopeo"),
+            }
+        })
+        .await?
+    }
+}
+
+/// Start `containers-image-proxy` with `config`, along with a handle to its
+/// process, unless it could not be determined (e.g. on kernels without pidfd).
+#[allow(unsafe_code)]
+pub(crate) async fn new_proxy(
+    mut config: ImageProxyConfig,
+) -> Result<(ImageProxy, Option<ProxyProcess>)> {
+    use std::os::fd::{AsRawFd, BorrowedFd};
+    use std::os::unix::process::CommandExt;
+    // The same default as containers-image-proxy, which binds the lifecycle of
+    // the child process to ours.
+    let mut cmd = config.skopeo_cmd.take().unwrap_or_else(|| {
+        let mut cmd = std::process::Command::new("skopeo");
+        // SAFETY: prctl() is async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                rustix::process::set_parent_process_death_signal(Some(
+                    rustix::process::Signal::Term,
+                ))
+                .map_err(Into::into)
+            });
+        }
+        cmd
+    });
+    // The child process sends its pid over this socket before executing skopeo;
+    // both ends are closed on exec.
+    let (pid_reader, pid_writer) = std::os::unix::net::UnixStream::pair()?;
+    let pid_writer_fd = pid_writer.as_raw_fd();
+    // SAFETY: getpid() and write() are async-signal-safe, and the socket is open
+    // until the process has been spawned.
+    unsafe {
+        cmd.pre_exec(move || {
+            let pid = rustix::process::getpid().as_raw_nonzero().get();
+            let fd = BorrowedFd::borrow_raw(pid_writer_fd);
+            rustix::io::write(fd, &pid.to_ne_bytes())?;
+            Ok(())
+        });
+    }
+    config.skopeo_cmd = Some(cmd);
+    let proxy = ImageProxy::new_with_config(config).await?;
+    drop(pid_writer);
+    let mut pid = [0u8; 4];
+    let process = (&pid_reader)
+        .read_exact(&mut pid)
+        .map_err(anyhow::Error::from)
+        .and_then(|()| {
+            let pid = rustix::process::Pid::from_raw(i32::from_ne_bytes(pid))
+                .ok_or_else(|| anyhow::anyhow!("Invalid pid"))?;
+            let pidfd = rustix::process::pidfd_open(pid, rustix::process::PidfdFlags::empty())?;
+            Ok(ProxyProcess { pidfd })
+        });
+    let process = process
+        .map_err(|e| tracing::debug!("Failed to find the skopeo process: {e:#}"))
+        .ok();
+    Ok((proxy, process))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,6 +414,94 @@ mod tests {
     }
     "#};
 
+    const SIGNED_REGISTRY: &str = indoc::indoc! { r#"
+    {
+        "default": [{"type": "insecureAcceptAnything"}],
+        "transports": {
+            "docker": {
+                "quay.io/exampleos/blah:unsigned": [{"type": "insecureAcceptAnything"}],
+                "quay.io/exampleos": [
+                    {
+                        "type": "sigstoreSigned",
+                        "keyPath": "/path/to/key.pub"
+                    }
+                ],
+                "localhost:5000/exampleos": [{"type": "reject"}]
+            }
+        }
+    }
+    "#};
+
+    #[test]
+    fn parse_version() {
+        let v = |major, minor, patch| SkopeoVersion {
+            major,
+            minor,
+            patch,
+        };
+        for (s, expected) in [
+            ("skopeo version 1.14.2\n", v(1, 14, 2)),
+            ("skopeo version 1.16.0-dev commit: 0123abcd", v(1, 16, 0)),
+            ("skopeo version 1.9", v(1, 9, 0)),
+        ] {
+            assert_eq!(SkopeoVersion::from_str(s).unwrap(), expected, "{s}");
+        }
+        for s in ["", "skopeo", "skopeo version ", "skopeo version a.b.c"] {
+            assert!(SkopeoVersion::from_str(s).is_err(), "{s}");
+        }
+        assert!(v(1, 14, 2) > v(1, 9, 10));
+        assert_eq!(v(1, 14, 2).to_string(), "1.14.2");
+    }
+
+    #[test]
+    fn policy_requires_signature() {
+        let imgref = |s: &str| ImageReference::try_from(s).unwrap();
+        let p: ContainerPolicy = serde_json::from_str(DEFAULT_POLICY).unwrap();
+        assert!(!p.requires_signature(&imgref("docker://quay.io/exampleos/blah")));
+
+        let p: ContainerPolicy = serde_json::from_str(REASONABLY_LOCKED_DOWN).unwrap();
+        assert!(!p.requires_signature(&imgref("dir:/some/dir")));
+        // The default is reject
+        assert!(p.requires_signature(&imgref("oci:/some/dir")));
+
+        let p: ContainerPolicy = serde_json::from_str(SIGNED_REGISTRY).unwrap();
+        for v in [
+            "docker://quay.io/exampleos/blah",
+            "docker://quay.io/exampleos/blah:latest",
+            "docker://quay.io/exampleos/blah@sha256:0000000000000000000000000000000000000000000000000000000000000000",
+            "docker://localhost:5000/exampleos/other:latest",
+        ] {
+            assert!(p.requires_signature(&imgref(v)), "{v}");
+        }
+        for v in [
+            "docker://quay.io/exampleos/blah:unsigned",
+            "docker://quay.io/otheros/blah",
+            "docker://localhost:5000/foo",
+            "oci:/some/dir",
+        ] {
+            assert!(!p.requires_signature(&imgref(v)), "{v}");
+        }
+    }
+
+    #[test]
+    fn copy_options() -> Result<()> {
+        let args = |authfile: Option<&Path>, retry_times| -> Result<Vec<String>> {
+            let mut cmd = new_cmd();
+            append_copy_options(&mut cmd, authfile, None, retry_times)?;
+            Ok(cmd
+                .get_args()
+                .map(|a| a.to_str().unwrap().to_owned())
+                .collect())
+        };
+        assert!(args(None, None)?.is_empty());
+        assert_eq!(args(None, Some(3))?, ["--retry-times=3"]);
+        assert_eq!(
+            args(Some(Path::new("/run/auth.json")), Some(0))?,
+            ["--authfile", "/run/auth.json", "--retry-times=0"]
+        );
+        Ok(())
+    }
+
     #[test]
     fn policy_is_insecure() {
         let p: ContainerPolicy = serde_json::from_str(DEFAULT_POLICY).unwrap();
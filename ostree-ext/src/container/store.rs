@@ -25,8 +25,10 @@ use oci_spec::image::{
 };
 use ostree::prelude::{Cast, FileEnumeratorExt, FileExt, ToVariant};
 use ostree::{gio, glib};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::{Receiver, Sender};
 
 /// Configuration for the proxy.
@@ -101,6 +103,10 @@ impl ImportProgress {
     }
 }
 
+/// The share of [`LayerProgress::fraction`] for fetching a layer; the
+/// remainder is for completing its import.
+pub const LAYER_FETCH_WEIGHT: f64 = 0.9;
+
 /// Sent across a channel to track the byte-level progress of a layer fetch.
 #[derive(Clone, Debug)]
 pub struct LayerProgress {
@@ -110,6 +116,65 @@ pub struct LayerProgress {
     pub fetched: u64,
     /// Total number of bytes outstanding
     pub total: u64,
+    /// Whether the layer has been imported, which completes after it has been
+    /// fetched.
+    pub imported: bool,
+    /// Number of bytes downloaded across all layers fetched by the import so far
+    pub fetched_bytes: u64,
+    /// Total (compressed) size of all layers to be fetched by the import, as
+    /// listed in the manifest.  This is sent before any layer is fetched.
+    pub total_bytes: u64,
+}
+
+impl LayerProgress {
+    /// The progress of fetching and importing the layer, as a value from 0.0
+    /// to 1.0 which does not decrease.
+    ///
+    /// A layer is decompressed and imported while it is fetched, so until it
+    /// has been fetched completely, this is the fraction of its (compressed)
+    /// size fetched, weighted by [`LAYER_FETCH_WEIGHT`].  The remainder is for
+    /// completing the import, i.e. processing any buffered data and committing
+    /// the objects to the repository.  The number of objects in a layer is not
+    /// known in advance, so that phase is reported in a single step, once
+    /// [`Self::imported`] is set.
+    pub fn fraction(&self) -> f64 {
+        if self.imported {
+            return 1.0;
+        }
+        let fetched = if self.total == 0 {
+            1.0
+        } else {
+            (self.fetched as f64 / self.total as f64).min(1.0)
+        };
+        fetched * LAYER_FETCH_WEIGHT
+    }
+
+    /// Progress of the layer at `layer_index`, updating the totals for the
+    /// import from the previous progress `prev`.
+    pub(crate) fn next(
+        prev: Option<&Self>,
+        layer_index: usize,
+        fetched: u64,
+        total: u64,
+        imported: bool,
+    ) -> Self {
+        let (fetched_before, total_bytes) = match prev {
+            // Still the same layer, which may also be fetched again on retry
+            Some(p) if p.layer_index == layer_index && !p.imported => {
+                (p.fetched_bytes.saturating_sub(p.fetched), p.total_bytes)
+            }
+            Some(p) => (p.fetched_bytes, p.total_bytes),
+            None => (0, 0),
+        };
+        Self {
+            layer_index,
+            fetched,
+            total,
+            imported,
+            fetched_bytes: fetched_before + fetched,
+            total_bytes,
+        }
+    }
 }
 
 /// State of an already pulled layered image.
@@ -161,23 +226,177 @@ impl CachedImageUpdate {
     }
 }
 
+/// Where the manifest and layers of an image are fetched from.
+#[derive(Debug)]
+pub(crate) enum ImageSource {
+    /// The default, using `containers-image-proxy`.
+    Proxy {
+        proxy: ImageProxy,
+        img: OpenedImage,
+        /// The `skopeo` process, if known
+        process: Option<super::skopeo::ProxyProcess>,
+    },
+    /// A caller-provided fetcher.
+    Fetcher(Arc<dyn LayerFetcher>),
+}
+
+impl ImageSource {
+    /// Start the proxy with `config`, and open `imgref`.
+    pub(crate) async fn open_proxy(
+        config: ImageProxyConfig,
+        imgref: &ImageReference,
+    ) -> Result<Self> {
+        let (proxy, process) = super::skopeo::new_proxy(config).await?;
+        let img = proxy.open_image(&imgref.to_string()).await?;
+        Ok(Self::Proxy {
+            proxy,
+            img,
+            process,
+        })
+    }
+
+    /// Fetch the manifest and its digest.
+    pub(crate) async fn fetch_manifest(
+        &self,
+        imgref: &ImageReference,
+    ) -> Result<(Digest, ImageManifest)> {
+        let (digest, manifest) = match self {
+            Self::Proxy { proxy, img, .. } => {
+                let (digest, manifest) = proxy.fetch_manifest(img).await?;
+                (Digest::from_str(&digest)?, manifest)
+            }
+            Self::Fetcher(fetcher) => {
+                let (manifest, digest) = fetcher.fetch_manifest(imgref).await?;
+                super::unencapsulate::verify_pinned_digest(imgref, &digest)?;
+                (digest, manifest)
+            }
+        };
+        Ok((digest, manifest))
+    }
+
+    /// Fetch the image configuration referenced by `manifest`.
+    pub(crate) async fn fetch_config(
+        &self,
+        imgref: &ImageReference,
+        manifest: &ImageManifest,
+    ) -> Result<ImageConfiguration> {
+        use tokio::io::AsyncReadExt;
+        match self {
+            Self::Proxy { proxy, img, .. } => Ok(proxy.fetch_config(img).await?),
+            Self::Fetcher(fetcher) => {
+                let mut blob = fetcher.fetch_layer(imgref, manifest.config()).await?;
+                let mut buf = Vec::new();
+                blob.read_to_end(&mut buf).await?;
+                serde_json::from_slice(&buf).context("Parsing image configuration")
+            }
+        }
+    }
+
+    /// Query the layer information from the proxy; this is only
+    /// used when fetching from `containers-storage`.
+    pub(crate) async fn get_layer_info(
+        &self,
+    ) -> Result<Option<Vec<containers_image_proxy::ConvertedLayerInfo>>> {
+        match self {
+            Self::Proxy { proxy, img, .. } => Ok(proxy.get_layer_info(img).await?),
+            Self::Fetcher(_) => Ok(None),
+        }
+    }
+
+    /// If the image reference now resolves to a different manifest than
+    /// `digest`, return the new digest, and ensure subsequent fetches use it.
+    async fn refresh(
+        &mut self,
+        imgref: &ImageReference,
+        digest: &Digest,
+    ) -> Result<Option<Digest>> {
+        match self {
+            Self::Proxy { proxy, img, .. } => {
+                // The opened image caches its manifest, so we need to open it again.
+                let new_img = proxy.open_image(&imgref.to_string()).await?;
+                let (new_digest, _) = proxy.fetch_manifest(&new_img).await?;
+                let new_digest = Digest::from_str(&new_digest)?;
+                if &new_digest == digest {
+                    proxy.close_image(&new_img).await?;
+                    return Ok(None);
+                }
+                let old_img = std::mem::replace(img, new_img);
+                proxy.close_image(&old_img).await?;
+                Ok(Some(new_digest))
+            }
+            Self::Fetcher(fetcher) => {
+                let (_, new_digest) = fetcher.fetch_manifest(imgref).await?;
+                Ok((&new_digest != digest).then_some(new_digest))
+            }
+        }
+    }
+
+    /// Release the opened image.
+    async fn close_image(&self) -> Result<()> {
+        if let Self::Proxy { proxy, img, .. } = self {
+            // TODO change the imageproxy API to ensure this happens automatically when
+            // the image reference is dropped
+            proxy.close_image(img).await?;
+        }
+        Ok(())
+    }
+
+    /// Release the opened image, and check the proxy did not have any errors.
+    pub(crate) async fn finalize(self) -> Result<()> {
+        self.close_image().await?;
+        if let Self::Proxy { proxy, .. } = self {
+            proxy.finalize().await?;
+            tracing::debug!("finalized proxy");
+        }
+        Ok(())
+    }
+}
+
 /// Context for importing a container image.
 #[derive(Debug)]
 pub struct ImageImporter {
     repo: ostree::Repo,
-    pub(crate) proxy: ImageProxy,
+    pub(crate) source: ImageSource,
     imgref: OstreeImageReference,
     target_imgref: Option<OstreeImageReference>,
     no_imgref: bool,  // If true, do not write final image ref
     disable_gc: bool, // If true, don't prune unused image layers
     /// If true, require the image has the bootable flag
     require_bootable: bool,
+    /// If true, require that the image signature is verified
+    require_signed: bool,
+    /// If set, the manifest digest the image must have
+    expected_digest: Option<Digest>,
     /// If true, we have ostree v2024.3 or newer.
     ostree_v2024_3: bool,
-    pub(crate) proxy_img: OpenedImage,
+    /// Prefix for temporary directories created while committing layers
+    tmp_prefix: Option<String>,
+    /// If true, prepare again and retry if the image changed while fetching layers
+    refetch_on_tag_move: bool,
+    /// If set, the media types allowed for image layers
+    allowed_media_types: Option<HashSet<String>>,
+    /// If set, invoked for each object in the ostree layers
+    object_callback: Option<crate::tar::ImportObjectCallback>,
+    /// If set, only import this (half-open) range of the ostree layers
+    layer_range: Option<(usize, usize)>,
+    /// How to handle non-UTF8 paths in derived layers
+    non_utf8: crate::tar::NonUtf8Policy,
+    /// If set, invoked for each entry of the derived layers
+    layer_transform: Option<crate::tar::LayerTransform>,
+    /// If set, the maximum ratio of decompressed to compressed layer bytes
+    max_expansion_ratio: Option<f64>,
+    /// If set, the runtime used for blocking work and child processes
+    runtime: Option<tokio::runtime::Handle>,
+    /// If set, the maximum time for fetching the manifest and configuration, and the layers
+    timeout: Option<Duration>,
+    /// The blocking tasks reading fetched layers, stopped on timeout
+    import_tasks: ImportTasks,
 
     layer_progress: Option<Sender<ImportProgress>>,
     layer_byte_progress: Option<tokio::sync::watch::Sender<Option<LayerProgress>>>,
+    /// The minimum number of bytes fetched between byte-level progress updates
+    progress_interval_bytes: Option<u64>,
+    retry: RetryPolicy,
 }
 
 /// Result of invoking [`ImageImporter::prepare`].
@@ -373,6 +592,48 @@ fn layer_from_diffid<'a>(
     })
 }
 
+/// The diffid of an empty tar archive (i.e. 1024 zero bytes), as commonly used
+/// for empty layers.
+const EMPTY_LAYER_DIFFID: &str =
+    "sha256:5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef";
+
+/// Return the diff-id of `layer` as recorded in the image configuration.
+fn layer_diff_id(
+    manifest: &ImageManifest,
+    config: &ImageConfiguration,
+    layer: &Descriptor,
+) -> Result<String> {
+    let idx = manifest
+        .layers()
+        .iter()
+        .position(|l| l == layer)
+        .ok_or_else(|| anyhow!("Layer {} not found in manifest", layer.digest()))?;
+    config
+        .rootfs()
+        .diff_ids()
+        .get(idx)
+        .cloned()
+        .ok_or_else(|| anyhow!("Missing diffid for layer {}", layer.digest()))
+}
+
+/// Return true if `layer` has no content, i.e. is zero-sized or an empty tar archive.
+fn layer_is_empty(
+    manifest: &ImageManifest,
+    config: &ImageConfiguration,
+    layer: &Descriptor,
+) -> bool {
+    if layer.size() == 0 {
+        return true;
+    }
+    let diffids = config.rootfs().diff_ids();
+    manifest
+        .layers()
+        .iter()
+        .position(|l| l == layer)
+        .and_then(|i| diffids.get(i))
+        .map_or(false, |diffid| diffid == EMPTY_LAYER_DIFFID)
+}
+
 #[context("Parsing manifest layout")]
 pub(crate) fn parse_manifest_layout<'a>(
     manifest: &'a ImageManifest,
@@ -384,9 +645,11 @@ pub(crate) fn parse_manifest_layout<'a>(
 )> {
     let config_labels = super::labels_of(config);
 
+    // Empty layers (e.g. from a base image) may precede the ostree layer.
     let first_layer = manifest
         .layers()
-        .first()
+        .iter()
+        .find(|l| !layer_is_empty(manifest, config, l))
         .ok_or_else(|| anyhow!("No layers in manifest"))?;
     let Some(target_diffid) = config_labels.and_then(|labels| labels.get(DIFFID_LABEL)) else {
         return Ok((None, Vec::new(), manifest.layers().iter().collect()));
@@ -408,7 +671,7 @@ pub(crate) fn parse_manifest_layout<'a>(
                 chunk_layers.push(layer);
             }
         } else if !after_target {
-            if layer != ostree_layer {
+            if layer != ostree_layer && !layer_is_empty(manifest, config, layer) {
                 chunk_layers.push(layer);
             }
         } else {
@@ -455,6 +718,27 @@ fn timestamp_of_manifest_or_config(
         .log_err_default()
 }
 
+/// Whether `config` is the default, i.e. the caller did not customize the proxy.
+fn is_default_proxy_config(config: &ImageProxyConfig) -> bool {
+    // Destructured so that new fields must be considered here
+    let ImageProxyConfig {
+        authfile,
+        auth_data,
+        auth_anonymous,
+        certificate_directory,
+        decryption_keys,
+        insecure_skip_tls_verification,
+        skopeo_cmd,
+    } = config;
+    authfile.is_none()
+        && auth_data.is_none()
+        && !auth_anonymous
+        && certificate_directory.is_none()
+        && decryption_keys.is_none()
+        && insecure_skip_tls_verification.is_none()
+        && skopeo_cmd.is_none()
+}
+
 impl ImageImporter {
     /// The metadata key used in ostree commit metadata to serialize
     const CACHED_KEY_MANIFEST_DIGEST: &'static str = "ostree-ext.cached.manifest-digest";
@@ -462,12 +746,36 @@ impl ImageImporter {
     const CACHED_KEY_CONFIG: &'static str = "ostree-ext.cached.config";
 
     /// Create a new importer.
+    ///
+    /// Single-platform images in an `oci:` layout directory are read directly,
+    /// without `skopeo`, when `imgref` uses
+    /// [`SignatureSource::ContainerPolicyAllowInsecure`] and `config` is the
+    /// default; any customized `config`, e.g. one with a `skopeo_cmd`, forces
+    /// the use of the proxy.
     #[context("Creating importer")]
     pub async fn new(
         repo: &ostree::Repo,
         imgref: &OstreeImageReference,
         mut config: ImageProxyConfig,
     ) -> Result<Self> {
+        // Images in a local OCI layout are read directly, without skopeo; this is
+        // only done when there is no container signature policy to apply, and
+        // the caller did not configure the proxy, which would be ignored.
+        if imgref.imgref.transport == Transport::OciDir
+            && imgref.sigverify == SignatureSource::ContainerPolicyAllowInsecure
+            && is_default_proxy_config(&config)
+        {
+            if let Some(fetcher) = OciLayoutFetcher::open(&imgref.imgref)? {
+                tracing::debug!("Reading OCI layout {} directly", imgref.imgref.name);
+                system_repo_journal_print(
+                    repo,
+                    libsystemd::logging::Priority::Info,
+                    &format!("Fetching {}", imgref),
+                );
+                let source = ImageSource::Fetcher(Arc::new(fetcher));
+                return Ok(Self::new_with_source(repo, imgref, source));
+            }
+        }
         if imgref.imgref.transport == Transport::ContainerStorage {
             // Fetching from containers-storage, may require privileges to read files
             merge_default_container_proxy_opts_with_isolation(&mut config, None)?;
@@ -475,29 +783,102 @@ impl ImageImporter {
             // Apply our defaults to the proxy config
             merge_default_container_proxy_opts(&mut config)?;
         }
-        let proxy = ImageProxy::new_with_config(config).await?;
+        system_repo_journal_print(
+            repo,
+            libsystemd::logging::Priority::Info,
+            &format!("Fetching {}", imgref),
+        );
+
+        let source = ImageSource::open_proxy(config, &imgref.imgref).await?;
+        Ok(Self::new_with_source(repo, imgref, source))
+    }
 
+    /// Create a new importer which uses `fetcher` to retrieve the image, instead
+    /// of `containers-image-proxy`.
+    ///
+    /// As the fetcher is responsible for verifying the image, the signature
+    /// source of `imgref` must be [`SignatureSource::ContainerPolicyAllowInsecure`].
+    pub fn new_with_fetcher(
+        repo: &ostree::Repo,
+        imgref: &OstreeImageReference,
+        fetcher: Arc<dyn LayerFetcher>,
+    ) -> Result<Self> {
+        if imgref.sigverify != SignatureSource::ContainerPolicyAllowInsecure {
+            anyhow::bail!("Signature verification is not supported with a custom fetcher");
+        }
         system_repo_journal_print(
             repo,
             libsystemd::logging::Priority::Info,
             &format!("Fetching {}", imgref),
         );
+        Ok(Self::new_with_source(
+            repo,
+            imgref,
+            ImageSource::Fetcher(fetcher),
+        ))
+    }
 
-        let proxy_img = proxy.open_image(&imgref.imgref.to_string()).await?;
+    fn new_with_source(
+        repo: &ostree::Repo,
+        imgref: &OstreeImageReference,
+        source: ImageSource,
+    ) -> Self {
         let repo = repo.clone();
-        Ok(ImageImporter {
+        ImageImporter {
             repo,
-            proxy,
-            proxy_img,
+            source,
             target_imgref: None,
             no_imgref: false,
             ostree_v2024_3: ostree::check_version(2024, 3),
             disable_gc: false,
             require_bootable: false,
+            require_signed: false,
+            expected_digest: None,
+            tmp_prefix: None,
+            refetch_on_tag_move: false,
+            allowed_media_types: None,
+            object_callback: None,
+            layer_range: None,
+            non_utf8: Default::default(),
+            layer_transform: None,
+            max_expansion_ratio: None,
+            runtime: None,
+            timeout: None,
+            import_tasks: Default::default(),
             imgref: imgref.clone(),
             layer_progress: None,
             layer_byte_progress: None,
-        })
+            progress_interval_bytes: None,
+            retry: Default::default(),
+        }
+    }
+
+    /// Retry requests which the registry rejects with a rate limit error (HTTP 429),
+    /// waiting for the `Retry-After` delay from the error if present, or
+    /// [`DEFAULT_RATE_LIMIT_WAIT`] otherwise.  `handler` is invoked with the wait
+    /// time before each retry, e.g. for logging.  By default, these errors are
+    /// returned directly.  A custom fetcher reports a rate limit with a
+    /// [`crate::container::RegistryStatusError`].
+    ///
+    /// This applies to fetching the manifest, configuration and the start of each
+    /// layer; a failure partway through a layer is not retried.
+    pub fn set_rate_limit_handler(&mut self, handler: impl Fn(Duration) + Send + Sync + 'static) {
+        self.retry.rate_limit_handler = Some(RateLimitHandler(Arc::new(handler)));
+    }
+
+    /// Retry requests which fail with an error that is likely transient, such as
+    /// a connection failure or an HTTP 5xx or 429 status, up to `retries` times.
+    /// The first retry waits for `initial_wait`, which is doubled for each further
+    /// retry, up to a minute.  Errors which are not caused by the registry, or
+    /// the image being rejected by the signature policy, are never retried.
+    /// By default, these errors are returned directly.
+    ///
+    /// Like [`Self::set_rate_limit_handler`], which takes precedence for rate
+    /// limited requests, this applies to fetching the manifest, configuration
+    /// and the start of each layer.
+    pub fn set_transient_retries(&mut self, retries: u32, initial_wait: Duration) {
+        self.retry.transient_retries = retries;
+        self.retry.transient_wait = initial_wait;
     }
 
     /// Write cached data as if the image came from this source.
@@ -517,6 +898,120 @@ impl ImageImporter {
         self.require_bootable = true;
     }
 
+    /// Require that the image is signed, i.e. fail if the signature verification
+    /// configuration would accept an unsigned image.  With [`SignatureSource::ContainerPolicy`],
+    /// this means `containers-policy.json` must require a signature for the image.
+    ///
+    /// Note that if signature verification is configured but fails, that is reported
+    /// as an error when fetching the image.
+    pub fn require_signed(&mut self) {
+        self.require_signed = true;
+    }
+
+    /// Require that the manifest of the image has the digest `digest`, failing
+    /// before any layers are fetched otherwise.  This guards against importing a
+    /// different image when the image is pinned by digest, but referenced by a
+    /// tag which may have been moved.
+    pub fn set_expected_digest(&mut self, digest: Digest) {
+        self.expected_digest = Some(digest);
+    }
+
+    /// Use the provided prefix for temporary directories created while committing
+    /// derived layers, for example to include a request identifier so that leftover temporary
+    /// files can be traced back to a specific operation.
+    pub fn set_tmp_prefix(&mut self, prefix: impl Into<String>) {
+        self.tmp_prefix = Some(prefix.into());
+    }
+
+    /// If fetching layers fails, check whether the image reference now resolves to
+    /// a different manifest (e.g. because a new image was pushed to the tag while
+    /// fetching), and if so, prepare and retry the import once against the new
+    /// manifest.
+    pub fn refetch_on_tag_move(&mut self) {
+        self.refetch_on_tag_move = true;
+    }
+
+    /// Only allow image layers with these media types (e.g.
+    /// `application/vnd.oci.image.layer.v1.tar+zstd`); preparing the import fails
+    /// for an image with any other layer, before anything is downloaded.  By
+    /// default, all supported media types are allowed.
+    pub fn set_allowed_media_types(&mut self, media_types: HashSet<String>) {
+        self.allowed_media_types = Some(media_types);
+    }
+
+    /// Invoke `callback` for each object in the ostree layers of the image as it
+    /// is imported; see [`crate::tar::ImportObjectCallback`].  Layers which were
+    /// already imported, and derived (non-ostree) layers, are not reported.
+    pub fn set_object_callback(&mut self, callback: crate::tar::ImportObjectCallback) {
+        self.object_callback = Some(callback);
+    }
+
+    /// Only fetch and import the ostree layers with an index in `start..end`, where
+    /// the layers are numbered in manifest order starting with the ostree commit
+    /// layer at 0; derived layers are not fetched.  This is intended for debugging
+    /// and inspecting large images: each imported layer is written to its layer ref,
+    /// but a partial set of layers does not make a valid commit, so no image is
+    /// created, and [`Self::import`] and [`Self::unencapsulate`] return an error
+    /// once the selected layers have been imported.
+    ///
+    /// Preparing the import fails if the range is empty or exceeds the number of
+    /// ostree layers in the image.
+    pub fn set_layer_range(&mut self, start: usize, end: usize) {
+        self.layer_range = Some((start, end));
+    }
+
+    /// Set how paths (and link targets) which are not valid UTF-8 are handled
+    /// in derived (non-ostree) layers; by default, they are an error.  See
+    /// [`crate::tar::NonUtf8Policy`].
+    pub fn set_non_utf8_policy(&mut self, policy: crate::tar::NonUtf8Policy) {
+        self.non_utf8 = policy;
+    }
+
+    /// Set a hook deciding which entries of derived (non-ostree) layers are
+    /// imported.  See [`crate::tar::LayerTransform`]; note in particular that
+    /// this changes the resulting merge commit.  The ostree layers are not
+    /// affected, since their content is verified against the commit checksum.
+    pub fn set_layer_transform(&mut self, transform: crate::tar::LayerTransform) {
+        self.layer_transform = Some(transform);
+    }
+
+    /// Abort the import if the decompressed size of a compressed layer exceeds
+    /// `ratio` times its compressed size, failing with [`ExpansionRatioExceeded`].
+    /// This protects against resource exhaustion from maliciously crafted
+    /// layers ("decompression bombs") when importing untrusted images.
+    ///
+    /// The ratio is checked continuously while decompressing, so a layer which
+    /// starts with highly compressible content may be rejected even if its
+    /// overall ratio is lower.  Uncompressed layers are not affected.
+    pub fn set_max_expansion_ratio(&mut self, ratio: f64) {
+        self.max_expansion_ratio = Some(ratio);
+    }
+
+    /// Use `runtime` to spawn the blocking threads which write to the repository,
+    /// and the `ostree` child processes which commit derived layers.  By default,
+    /// the runtime which is current when these are started is used, and it is an
+    /// error (a panic in Tokio) if there is none.
+    ///
+    /// The container image proxy (i.e. `skopeo`) is started when the importer is
+    /// created, so it always uses the runtime which was current at that point.
+    pub fn set_runtime(&mut self, runtime: tokio::runtime::Handle) {
+        self.runtime = Some(runtime);
+    }
+
+    /// Fail with [`ImportTimedOut`] if fetching the manifest and configuration in
+    /// [`Self::prepare`], or fetching and importing the layers in [`Self::import`],
+    /// does not complete within `timeout`, for example because the registry stopped
+    /// responding.  When [`Self::refetch_on_tag_move`] is used, each attempt
+    /// has its own timeout; the image is not prepared again after a timeout.
+    ///
+    /// If [`Self::import`] times out, reading the fetched layers fails, the image
+    /// is closed, the `skopeo` process of the proxy is killed and reaped, and the
+    /// import only returns once the tasks writing to the repository have stopped.
+    /// After [`Self::prepare`] times out, the importer must not be used further.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
     /// Override the ostree version being targeted
     pub fn set_ostree_version(&mut self, year: u32, v: u32) {
         self.ostree_v2024_3 = (year > 2024) || (year == 2024 && v >= 3)
@@ -554,6 +1049,75 @@ impl ImageImporter {
         r
     }
 
+    /// Report that `layer` has been imported; see [`LayerProgress::imported`].
+    fn report_layer_imported(&self, manifest: &ImageManifest, layer: &Descriptor) {
+        let Some(progress) = self.layer_byte_progress.as_ref() else {
+            return;
+        };
+        let Some(layer_index) = manifest.layers().iter().position(|l| l == layer) else {
+            return;
+        };
+        progress.send_modify(|p| {
+            let (fetched, total) = match p.as_ref() {
+                Some(p) if p.layer_index == layer_index => (p.fetched, p.total),
+                _ => (layer.size(), layer.size()),
+            };
+            *p = Some(LayerProgress::next(
+                p.as_ref(),
+                layer_index,
+                fetched,
+                total,
+                true,
+            ));
+        });
+    }
+
+    /// Send the initial byte-level progress for fetching the layers of `import`,
+    /// with the total size of the layers to be fetched, before any are fetched.
+    fn report_fetch_started(&self, import: &PreparedImport) {
+        let Some(progress) = self.layer_byte_progress.as_ref() else {
+            return;
+        };
+        let in_range = |i: usize| {
+            self.layer_range
+                .map_or(true, |(start, end)| (start..end).contains(&i))
+        };
+        // In the order they are fetched; derived layers are not fetched with a layer range
+        let to_fetch = import
+            .ostree_layers
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| in_range(i + 1))
+            .map(|(_, l)| l)
+            .chain(import.ostree_commit_layer.iter().filter(|_| in_range(0)))
+            .chain(import.layers.iter().filter(|_| self.layer_range.is_none()))
+            .filter(|l| l.commit.is_none())
+            .map(|l| &l.layer)
+            .collect::<Vec<_>>();
+        let Some(first) = to_fetch.first() else {
+            return;
+        };
+        let Some(layer_index) = import.manifest.layers().iter().position(|l| l == *first) else {
+            return;
+        };
+        progress.send_replace(Some(LayerProgress {
+            layer_index,
+            fetched: 0,
+            total: first.size(),
+            imported: false,
+            fetched_bytes: 0,
+            total_bytes: to_fetch.iter().map(|l| l.size()).sum(),
+        }));
+    }
+
+    /// Only send byte-level progress updates (see [`Self::request_layer_progress`])
+    /// once at least `bytes` have been fetched since the previous update for the
+    /// layer, rather than for every read.  An update is always sent once the
+    /// layer has been fetched completely.
+    pub fn set_progress_interval_bytes(&mut self, bytes: u64) {
+        self.progress_interval_bytes = Some(bytes);
+    }
+
     /// Serialize the metadata about a pending fetch as detached metadata on the commit object,
     /// so it can be retrieved later offline
     #[context("Writing cached pending manifest")]
@@ -577,7 +1141,8 @@ impl ImageImporter {
         // Clone these to move into blocking method
         let commit = commit.to_string();
         let repo = self.repo.clone();
-        crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+        let runtime = self.runtime.as_ref();
+        crate::tokio_util::spawn_blocking_cancellable_flatten_on(runtime, move |cancellable| {
             repo.write_commit_detached_metadata(&commit, Some(&commitmeta), Some(cancellable))
                 .map_err(anyhow::Error::msg)
         })
@@ -610,8 +1175,28 @@ impl ImageImporter {
             }
         }
 
+        if let Some(allowed) = self.allowed_media_types.as_ref() {
+            for layer in manifest.layers() {
+                let media_type = layer.media_type().to_string();
+                if !allowed.contains(&media_type) {
+                    anyhow::bail!(
+                        "Layer {} has disallowed media type {media_type}",
+                        layer.digest()
+                    );
+                }
+            }
+        }
+
         let (commit_layer, component_layers, remaining_layers) =
             parse_manifest_layout(&manifest, &config)?;
+        if let Some((start, end)) = self.layer_range {
+            let n = usize::from(commit_layer.is_some()) + component_layers.len();
+            if start >= end || end > n {
+                anyhow::bail!(
+                    "Invalid layer range {start}..{end}; the image has {n} ostree layers"
+                );
+            }
+        }
 
         let query = |l: &Descriptor| query_layer(&self.repo, l.clone());
         let commit_layer = commit_layer.map(query).transpose()?;
@@ -639,9 +1224,41 @@ impl ImageImporter {
         Ok(Box::new(imp))
     }
 
+    /// Once the layers selected by [`Self::set_layer_range`] have been imported,
+    /// stop with an error rather than creating an image.
+    fn check_no_layer_range(&self) -> Result<()> {
+        if let Some((start, end)) = self.layer_range {
+            anyhow::bail!("Imported ostree layers {start}..{end} only; not creating an image");
+        }
+        Ok(())
+    }
+
+    /// Verify that the signature verification configuration does not accept unsigned images.
+    #[context("Checking signature requirement")]
+    fn check_require_signed(&self) -> Result<()> {
+        let imgref = &self.imgref.imgref;
+        match &self.imgref.sigverify {
+            // The ostree commit signature is verified via the remote when importing.
+            SignatureSource::OstreeRemote(_) => Ok(()),
+            SignatureSource::ContainerPolicy => {
+                if skopeo::container_policy_requires_signature(imgref)? {
+                    Ok(())
+                } else {
+                    Err(anyhow!("Signed image required, but containers-policy.json allows unsigned images for {imgref}"))
+                }
+            }
+            SignatureSource::ContainerPolicyAllowInsecure => Err(anyhow!(
+                "Signed image required, but signature verification is disabled for {imgref}"
+            )),
+        }
+    }
+
     /// Determine if there is a new manifest, and if so return its digest.
     #[context("Fetching manifest")]
     pub(crate) async fn prepare_internal(&mut self, verify_layers: bool) -> Result<PrepareResult> {
+        if self.require_signed {
+            self.check_require_signed()?;
+        }
         match &self.imgref.sigverify {
             SignatureSource::ContainerPolicy if skopeo::container_policy_is_default_insecure()? => {
                 return Err(anyhow!("containers-policy.json specifies a default of `insecureAcceptAnything`; refusing usage"));
@@ -654,8 +1271,20 @@ impl ImageImporter {
             _ => {}
         }
 
-        let (manifest_digest, manifest) = self.proxy.fetch_manifest(&self.proxy_img).await?;
-        let manifest_digest = Digest::from_str(&manifest_digest)?;
+        let (manifest_digest, manifest) = with_timeout(
+            self.timeout,
+            retry_fetch(&self.retry, || {
+                self.source.fetch_manifest(&self.imgref.imgref)
+            }),
+        )
+        .await?;
+        if let Some(expected) = self.expected_digest.as_ref() {
+            if *expected != manifest_digest {
+                anyhow::bail!(
+                    "Manifest digest mismatch: expected {expected}, found {manifest_digest}"
+                );
+            }
+        }
         let new_imageid = manifest.config().digest();
 
         // Query for previous stored state
@@ -677,7 +1306,13 @@ impl ImageImporter {
                 (None, None)
             };
 
-        let config = self.proxy.fetch_config(&self.proxy_img).await?;
+        let config = with_timeout(
+            self.timeout,
+            retry_fetch(&self.retry, || {
+                self.source.fetch_config(&self.imgref.imgref, &manifest)
+            }),
+        )
+        .await?;
 
         // If there is a currently fetched image, cache the new pending manifest+config
         // as detached commit metadata, so that future fetches can query it offline.
@@ -701,14 +1336,16 @@ impl ImageImporter {
         Ok(PrepareResult::Ready(imp))
     }
 
-    /// Extract the base ostree commit.
+    /// Extract the base ostree commit, returning the diff-id of the commit layer
+    /// if it was imported, and the number of objects which were skipped because
+    /// they were already imported from a previous layer.
     #[context("Unencapsulating base")]
     pub(crate) async fn unencapsulate_base(
         &mut self,
         import: &mut store::PreparedImport,
         require_ostree: bool,
         write_refs: bool,
-    ) -> Result<()> {
+    ) -> Result<(Option<String>, u64)> {
         tracing::debug!("Fetching base");
         if matches!(self.imgref.sigverify, SignatureSource::ContainerPolicy)
             && skopeo::container_policy_is_default_insecure()?
@@ -727,37 +1364,61 @@ impl ImageImporter {
                     "No {DIFFID_LABEL} label found, not an ostree encapsulated container"
                 );
             }
-            return Ok(());
+            return Ok((None, 0));
         };
-        let des_layers = self.proxy.get_layer_info(&self.proxy_img).await?;
-        for layer in import.ostree_layers.iter_mut() {
-            if layer.commit.is_some() {
+        let layer_range = self.layer_range;
+        let in_range =
+            |i: usize| layer_range.map_or(true, |(start, end)| (start..end).contains(&i));
+        let des_layers = self.source.get_layer_info().await?;
+        // The same object may be (inefficiently) included in multiple layers
+        let seen_objects = crate::tar::SeenObjects::default();
+        let mut duplicates = 0;
+        for (i, layer) in import.ostree_layers.iter_mut().enumerate() {
+            // The commit layer is first
+            if layer.commit.is_some() || !in_range(i + 1) {
                 continue;
             }
             if let Some(p) = self.layer_progress.as_ref() {
                 p.send(ImportProgress::OstreeChunkStarted(layer.layer.clone()))
                     .await?;
             }
-            let (blob, driver, media_type) = fetch_layer(
-                &self.proxy,
-                &self.proxy_img,
-                &import.manifest,
-                &layer.layer,
-                self.layer_byte_progress.as_ref(),
-                des_layers.as_ref(),
-                self.imgref.imgref.transport,
-            )
+            let (blob, driver, media_type) = retry_fetch(&self.retry, || {
+                fetch_layer(
+                    &self.source,
+                    &self.imgref.imgref,
+                    &import.manifest,
+                    &layer.layer,
+                    self.layer_byte_progress.as_ref(),
+                    self.progress_interval_bytes,
+                    des_layers.as_ref(),
+                )
+            })
             .await?;
+            let blob = self.import_tasks.track(blob);
+            let task_guard = self.import_tasks.guard();
             let repo = self.repo.clone();
             let target_ref = layer.ostree_ref.clone();
-            let import_task =
-                crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+            let object_callback = self.object_callback.clone();
+            let seen_objects = seen_objects.clone();
+            let limit = self.max_expansion_ratio.map(ExpansionLimit::new);
+            let task_limit = limit.clone();
+            let runtime = self.runtime.as_ref();
+            let import_task = crate::tokio_util::spawn_blocking_cancellable_flatten_on(
+                runtime,
+                move |cancellable| {
+                    // Dropped last, once the transaction is committed or aborted
+                    let _task_guard = task_guard;
                     let txn = repo.auto_transaction(Some(cancellable))?;
                     let mut importer = crate::tar::Importer::new_for_object_set(&repo);
+                    importer.set_object_callback(object_callback);
+                    importer.set_seen_objects(seen_objects);
                     let blob = tokio_util::io::SyncIoBridge::new(blob);
-                    let blob = super::unencapsulate::decompressor(&media_type, blob)?;
+                    let blob = super::unencapsulate::decompressor(&media_type, blob, task_limit)?;
+                    let mut blob = std::io::BufReader::new(blob);
+                    crate::tar::check_not_json(&mut blob)?;
                     let mut archive = tar::Archive::new(blob);
                     importer.import_objects(&mut archive, Some(cancellable))?;
+                    let n = importer.duplicate_objects_skipped();
                     let commit = if write_refs {
                         let commit = importer.finish_import_object_set()?;
                         repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
@@ -767,43 +1428,73 @@ impl ImageImporter {
                         None
                     };
                     txn.commit(Some(cancellable))?;
-                    Ok::<_, anyhow::Error>(commit)
-                })
-                .map_err(|e| e.context(format!("Layer {}", layer.layer.digest())));
-            let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
+                    Ok::<_, anyhow::Error>((commit, n))
+                },
+            )
+            .map_err(|e| ExpansionLimit::map_err(limit.as_deref(), e))
+            .map_err(|e| e.context(format!("Layer {}", layer.layer.digest())));
+            let (commit, n) = super::unencapsulate::join_fetch(import_task, driver).await?;
             layer.commit = commit;
+            duplicates += n;
+            self.report_layer_imported(&import.manifest, &layer.layer);
             if let Some(p) = self.layer_progress.as_ref() {
                 p.send(ImportProgress::OstreeChunkCompleted(layer.layer.clone()))
                     .await?;
             }
         }
-        if commit_layer.commit.is_none() {
+        if commit_layer.commit.is_none() && in_range(0) {
             if let Some(p) = self.layer_progress.as_ref() {
                 p.send(ImportProgress::OstreeChunkStarted(
                     commit_layer.layer.clone(),
                 ))
                 .await?;
             }
-            let (blob, driver, media_type) = fetch_layer(
-                &self.proxy,
-                &self.proxy_img,
-                &import.manifest,
-                &commit_layer.layer,
-                self.layer_byte_progress.as_ref(),
-                des_layers.as_ref(),
-                self.imgref.imgref.transport,
-            )
+            let (blob, driver, media_type) = retry_fetch(&self.retry, || {
+                fetch_layer(
+                    &self.source,
+                    &self.imgref.imgref,
+                    &import.manifest,
+                    &commit_layer.layer,
+                    self.layer_byte_progress.as_ref(),
+                    self.progress_interval_bytes,
+                    des_layers.as_ref(),
+                )
+            })
             .await?;
+            let blob = self.import_tasks.track(blob);
+            let task_guard = self.import_tasks.guard();
+            let expected_diff_id =
+                layer_diff_id(&import.manifest, &import.config, &commit_layer.layer)?;
             let repo = self.repo.clone();
             let target_ref = commit_layer.ostree_ref.clone();
-            let import_task =
-                crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+            let object_callback = self.object_callback.clone();
+            let limit = self.max_expansion_ratio.map(ExpansionLimit::new);
+            let task_limit = limit.clone();
+            let runtime = self.runtime.as_ref();
+            let import_task = crate::tokio_util::spawn_blocking_cancellable_flatten_on(
+                runtime,
+                move |cancellable| {
+                    // Dropped last, once the transaction is committed or aborted
+                    let _task_guard = task_guard;
                     let txn = repo.auto_transaction(Some(cancellable))?;
                     let mut importer = crate::tar::Importer::new_for_commit(&repo, remote);
+                    importer.set_object_callback(object_callback);
+                    importer.set_seen_objects(seen_objects);
                     let blob = tokio_util::io::SyncIoBridge::new(blob);
-                    let blob = super::unencapsulate::decompressor(&media_type, blob)?;
+                    let blob = super::unencapsulate::decompressor(&media_type, blob, task_limit)?;
+                    let mut blob = std::io::BufReader::new(blob);
+                    crate::tar::check_not_json(&mut blob)?;
+                    let blob = super::unencapsulate::DigestReader::new(blob);
                     let mut archive = tar::Archive::new(blob);
                     importer.import_commit(&mut archive, Some(cancellable))?;
+                    let diff_id = archive.into_inner().finish()?;
+                    // Abort the transaction if the layer does not match the configuration
+                    if diff_id != expected_diff_id {
+                        return Err(anyhow!(
+                            "Layer diff-id mismatch; expected {expected_diff_id}, found {diff_id}"
+                        ));
+                    }
+                    let n = importer.duplicate_objects_skipped();
                     let commit = importer.finish_import_commit();
                     if write_refs {
                         repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
@@ -811,18 +1502,27 @@ impl ImageImporter {
                     }
                     repo.mark_commit_partial(&commit, false)?;
                     txn.commit(Some(cancellable))?;
-                    Ok::<_, anyhow::Error>(commit)
-                });
-            let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
+                    Ok::<_, anyhow::Error>((commit, diff_id, n))
+                },
+            )
+            .map_err(|e| ExpansionLimit::map_err(limit.as_deref(), e));
+            let (commit, diff_id, n) =
+                super::unencapsulate::join_fetch(import_task, driver).await?;
+            duplicates += n;
             commit_layer.commit = Some(commit);
+            self.report_layer_imported(&import.manifest, &commit_layer.layer);
             if let Some(p) = self.layer_progress.as_ref() {
                 p.send(ImportProgress::OstreeChunkCompleted(
                     commit_layer.layer.clone(),
                 ))
                 .await?;
             }
+            if duplicates > 0 {
+                tracing::debug!("Skipped {duplicates} objects included in multiple layers");
+            }
+            return Ok((Some(diff_id), duplicates));
         };
-        Ok(())
+        Ok((None, duplicates))
     }
 
     /// Retrieve an inner ostree commit.
@@ -836,42 +1536,54 @@ impl ImageImporter {
             }
             PrepareResult::Ready(r) => r,
         };
-        if !prep.layers.is_empty() {
-            anyhow::bail!("Image has {} non-ostree layers", prep.layers.len());
+        let n_layers = prep
+            .layers
+            .iter()
+            .filter(|l| !layer_is_empty(&prep.manifest, &prep.config, &l.layer))
+            .count();
+        if n_layers > 0 {
+            anyhow::bail!("Image has {n_layers} non-ostree layers");
         }
         let deprecated_warning = prep.deprecated_warning().map(ToOwned::to_owned);
-        self.unencapsulate_base(&mut prep, true, false).await?;
-        // TODO change the imageproxy API to ensure this happens automatically when
-        // the image reference is dropped
-        self.proxy.close_image(&self.proxy_img).await?;
+        self.report_fetch_started(&prep);
+        let (diff_id, duplicate_objects_skipped) =
+            self.unencapsulate_base(&mut prep, true, false).await?;
+        self.source.close_image().await?;
+        self.check_no_layer_range()?;
         // SAFETY: We know we have a commit
-        let ostree_commit = prep.ostree_commit_layer.unwrap().commit.unwrap();
+        let commit_layer = prep.ostree_commit_layer.unwrap();
+        // If the layer was already present, fall back to the diff-id in the config
+        let layer_diff_id = match diff_id {
+            Some(d) => d,
+            None => layer_diff_id(&prep.manifest, &prep.config, &commit_layer.layer)?,
+        };
+        let ostree_commit = commit_layer.commit.unwrap();
         let image_digest = prep.manifest_digest;
         Ok(Import {
             ostree_commit,
             image_digest,
+            layer_diff_id,
+            mirror: None,
+            duplicate_objects_skipped,
             deprecated_warning,
         })
     }
 
-    /// Import a layered container image.
-    ///
-    /// If enabled, this will also prune unused container image layers.
-    #[context("Importing")]
-    pub async fn import(
-        mut self,
-        mut import: Box<PreparedImport>,
-    ) -> Result<Box<LayeredImageState>> {
-        if let Some(status) = import.format_layer_status() {
-            system_repo_journal_print(&self.repo, libsystemd::logging::Priority::Info, &status);
-        }
+    /// Fetch all layers of the image which are not already present, returning
+    /// the base commit (if any), the commits for the derived layers, and the
+    /// content filtered out of the derived layers.
+    async fn fetch_layers(
+        &mut self,
+        import: &mut PreparedImport,
+    ) -> Result<(Option<String>, Vec<String>, MetaFilteredData)> {
+        self.report_fetch_started(import);
         // First download all layers for the base image (if necessary) - we need the SELinux policy
         // there to label all following layers.
-        self.unencapsulate_base(&mut import, false, true).await?;
-        let des_layers = self.proxy.get_layer_info(&self.proxy_img).await?;
-        let proxy = self.proxy;
-        let proxy_img = self.proxy_img;
-        let target_imgref = self.target_imgref.as_ref().unwrap_or(&self.imgref);
+        self.unencapsulate_base(import, false, true).await?;
+        if self.layer_range.is_some() {
+            return Ok((None, Vec::new(), HashMap::new()));
+        }
+        let des_layers = self.source.get_layer_info().await?;
         let base_commit = import
             .ostree_commit_layer
             .as_ref()
@@ -887,13 +1599,10 @@ impl ImageImporter {
         };
         tracing::debug!("Base rootfs is transient: {root_is_transient}");
 
-        let ostree_ref = ref_for_image(&target_imgref.imgref)?;
-
         let mut layer_commits = Vec::new();
         let mut layer_filtered_content: MetaFilteredData = HashMap::new();
-        let have_derived_layers = !import.layers.is_empty();
-        for layer in import.layers {
-            if let Some(c) = layer.commit {
+        for layer in import.layers.iter_mut() {
+            if let Some(c) = layer.commit.as_ref() {
                 tracing::debug!("Reusing fetched commit {}", c);
                 layer_commits.push(c.to_string());
             } else {
@@ -901,16 +1610,19 @@ impl ImageImporter {
                     p.send(ImportProgress::DerivedLayerStarted(layer.layer.clone()))
                         .await?;
                 }
-                let (blob, driver, media_type) = super::unencapsulate::fetch_layer(
-                    &proxy,
-                    &proxy_img,
-                    &import.manifest,
-                    &layer.layer,
-                    self.layer_byte_progress.as_ref(),
-                    des_layers.as_ref(),
-                    self.imgref.imgref.transport,
-                )
+                let (blob, driver, media_type) = retry_fetch(&self.retry, || {
+                    super::unencapsulate::fetch_layer(
+                        &self.source,
+                        &self.imgref.imgref,
+                        &import.manifest,
+                        &layer.layer,
+                        self.layer_byte_progress.as_ref(),
+                        self.progress_interval_bytes,
+                        des_layers.as_ref(),
+                    )
+                })
                 .await?;
+                let blob = self.import_tasks.track(blob);
                 // An important aspect of this is that we SELinux label the derived layers using
                 // the base policy.
                 let opts = crate::tar::WriteTarOptions {
@@ -918,6 +1630,11 @@ impl ImageImporter {
                     selinux: true,
                     allow_nonusr: root_is_transient,
                     retain_var: self.ostree_v2024_3,
+                    tmp_prefix: self.tmp_prefix.clone(),
+                    non_utf8: self.non_utf8,
+                    layer_transform: self.layer_transform.clone(),
+                    max_expansion_ratio: self.max_expansion_ratio,
+                    runtime: self.runtime.clone(),
                 };
                 let r = crate::tar::write_tar(
                     &self.repo,
@@ -929,7 +1646,9 @@ impl ImageImporter {
                 let r = super::unencapsulate::join_fetch(r, driver)
                     .await
                     .with_context(|| format!("Parsing layer blob {}", layer.layer.digest()))?;
-                layer_commits.push(r.commit);
+                layer_commits.push(r.commit.clone());
+                layer.commit = Some(r.commit);
+                self.report_layer_imported(&import.manifest, &layer.layer);
                 if !r.filtered.is_empty() {
                     let filtered = HashMap::from_iter(r.filtered.into_iter());
                     tracing::debug!("Found {} filtered toplevels", filtered.len());
@@ -944,13 +1663,107 @@ impl ImageImporter {
             }
         }
 
-        // TODO change the imageproxy API to ensure this happens automatically when
-        // the image reference is dropped
-        proxy.close_image(&proxy_img).await?;
+        Ok((base_commit, layer_commits, layer_filtered_content))
+    }
+
+    /// If the image reference now resolves to a different manifest than the one
+    /// `import` was prepared from (e.g. because a tag was pushed concurrently),
+    /// reopen the image and prepare the import again.  Returns `None` if the
+    /// manifest did not change.
+    #[context("Checking for image update")]
+    async fn prepare_if_tag_moved(
+        &mut self,
+        import: &PreparedImport,
+    ) -> Result<Option<PrepareResult>> {
+        let Some(manifest_digest) = self
+            .source
+            .refresh(&self.imgref.imgref, &import.manifest_digest)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let msg = format!(
+            "Image {} moved from {} to {manifest_digest} during fetch; retrying",
+            self.imgref.imgref, import.manifest_digest
+        );
+        system_repo_journal_print(&self.repo, libsystemd::logging::Priority::Info, &msg);
+        self.prepare_internal(false).await.map(Some)
+    }
+
+    /// Clean up after fetching the layers timed out: fail reads of the fetched
+    /// layers, close the image, kill `skopeo` and wait for it to be reaped, then
+    /// wait for the tasks which were importing the layers, so that none of them
+    /// still writes to the repository once the import returns.
+    async fn stop_fetching(self, timeout: Duration) {
+        let Self {
+            source,
+            import_tasks,
+            ..
+        } = self;
+        import_tasks.cancel();
+        // The proxy may itself be stuck on the registry, so bound this too
+        match tokio::time::timeout(timeout, source.close_image()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::debug!("Failed to close image: {e:#}"),
+            Err(_) => tracing::debug!("Timed out closing image"),
+        }
+        let process = match source {
+            ImageSource::Proxy { process, .. } => process,
+            ImageSource::Fetcher(_) => None,
+        };
+        if let Some(process) = process {
+            if let Err(e) = process.kill().await {
+                tracing::warn!("Failed to stop skopeo: {e:#}");
+            }
+        }
+        import_tasks.wait().await;
+    }
+
+    /// Import a layered container image.
+    ///
+    /// If enabled, this will also prune unused container image layers.
+    #[context("Importing")]
+    pub async fn import(
+        mut self,
+        mut import: Box<PreparedImport>,
+    ) -> Result<Box<LayeredImageState>> {
+        if let Some(status) = import.format_layer_status() {
+            system_repo_journal_print(&self.repo, libsystemd::logging::Priority::Info, &status);
+        }
+        let fetched = match with_timeout(self.timeout, self.fetch_layers(&mut import)).await {
+            Err(e) if self.refetch_on_tag_move && !e.is::<ImportTimedOut>() => {
+                match self.prepare_if_tag_moved(&import).await {
+                    Ok(None) => Err(e),
+                    Ok(Some(PrepareResult::AlreadyPresent(state))) => return Ok(state),
+                    Ok(Some(PrepareResult::Ready(prep))) => {
+                        import = prep;
+                        with_timeout(self.timeout, self.fetch_layers(&mut import)).await
+                    }
+                    Err(check_err) => {
+                        tracing::debug!("Failed to check for image update: {check_err:#}");
+                        Err(e)
+                    }
+                }
+            }
+            r => r,
+        };
+        let (base_commit, layer_commits, layer_filtered_content) = match fetched {
+            Ok(r) => r,
+            Err(e) => {
+                if let Some(timeout) = e.downcast_ref::<ImportTimedOut>() {
+                    let timeout = timeout.timeout;
+                    self.stop_fetching(timeout).await;
+                }
+                return Err(e);
+            }
+        };
+        self.check_no_layer_range()?;
+        let have_derived_layers = !import.layers.is_empty();
+        let target_imgref = self.target_imgref.as_ref().unwrap_or(&self.imgref);
+        let ostree_ref = ref_for_image(&target_imgref.imgref)?;
 
-        // We're done with the proxy, make sure it didn't have any errors.
-        proxy.finalize().await?;
-        tracing::debug!("finalized proxy");
+        // We're done with fetching, make sure there weren't any errors.
+        self.source.finalize().await?;
 
         // Disconnect progress notifiers to signal we're done with fetching.
         let _ = self.layer_byte_progress.take();
@@ -977,7 +1790,8 @@ impl ImageImporter {
             .unwrap_or_else(|| chrono::offset::Utc::now().timestamp() as u64);
         // Destructure to transfer ownership to thread
         let repo = self.repo;
-        let state = crate::tokio_util::spawn_blocking_cancellable_flatten(
+        let state = crate::tokio_util::spawn_blocking_cancellable_flatten_on(
+            self.runtime.as_ref(),
             move |cancellable| -> Result<Box<LayeredImageState>> {
                 use rustix::fd::AsRawFd;
 
@@ -1293,6 +2107,20 @@ pub struct ExportToOCIOpts {
     pub authfile: Option<std::path::PathBuf>,
     /// Output progress to stdout
     pub progress_to_stdout: bool,
+    /// Maximum number of layers `skopeo copy` transfers simultaneously when copying
+    /// to a destination other than an OCI directory.  This only helps images with
+    /// multiple layers, and requires skopeo 1.14 or newer.
+    pub copy_concurrency: Option<std::num::NonZeroU32>,
+    /// The number of times `skopeo copy` retries each operation which fails with
+    /// a transient error.  See [`ExportOpts::skopeo_retry_times`].
+    pub skopeo_retry_times: Option<u32>,
+    /// Record the timestamp of the ostree commit instead of the current time in
+    /// the layer history, so that exporting the same image to an OCI directory
+    /// always yields the same manifest digest.  See [`ExportOpts::reproducible`].
+    pub reproducible: bool,
+    /// Directory in which the image is written before `skopeo copy` pushes it,
+    /// when the destination is not an OCI directory.  See [`ExportOpts::tmpdir`].
+    pub tmpdir: Option<std::path::PathBuf>,
 }
 
 /// The way we store "chunk" layers in ostree is by writing a commit
@@ -1357,6 +2185,7 @@ pub(crate) fn export_to_oci(
     let opts = ExportOpts {
         skip_compression: opts.skip_compression,
         authfile: opts.authfile,
+        reproducible: opts.reproducible,
         ..Default::default()
     };
 
@@ -1379,6 +2208,7 @@ pub(crate) fn export_to_oci(
     // Now, handle the non-ostree layers; this is a simple conversion of
     //
     let compression = opts.skip_compression.then_some(Compression::none());
+    let created = layer_timestamp(repo, &srcinfo.base_commit, opts.reproducible)?;
     for (i, layer) in remaining_layers.iter().enumerate() {
         let layer_ref = &ref_for_layer(layer)?;
         let mut target_blob = dest_oci.create_gzip_layer(compression)?;
@@ -1407,12 +2237,13 @@ pub(crate) fn export_to_oci(
             .get(i)
             .and_then(|h| h.comment().as_deref())
             .unwrap_or_default();
-        dest_oci.push_layer(
+        dest_oci.push_layer_full(
             &mut new_manifest,
             &mut new_config,
             layer,
-            previous_description,
             previous_annotations,
+            previous_description,
+            created,
         )
     }
 
@@ -1422,6 +2253,24 @@ pub(crate) fn export_to_oci(
     Ok(dest_oci.insert_manifest(new_manifest, tag, oci_image::Platform::default())?)
 }
 
+/// Export an imported container image to a local OCI directory, returning the
+/// digest of the written manifest (`sha256:...`).
+///
+/// Unlike [`export`], this never invokes `skopeo`; the digest is computed from
+/// the serialized manifest, so the resulting directory can be referenced
+/// immediately without pushing it anywhere.
+pub fn export_to_oci_dir(
+    repo: &ostree::Repo,
+    src_imgref: &ImageReference,
+    dest_oci: &Dir,
+    tag: Option<&str>,
+    opts: Option<ExportToOCIOpts>,
+) -> Result<oci_image::Digest> {
+    let opts = opts.unwrap_or_default();
+    let descriptor = export_to_oci(repo, src_imgref, dest_oci, tag, opts)?;
+    Ok(descriptor.digest().clone())
+}
+
 /// Given a container image reference which is stored in `repo`, export it to the
 /// target image location.
 #[context("Export")]
@@ -1434,12 +2283,12 @@ pub async fn export(
     let opts = opts.unwrap_or_default();
     let target_oci = dest_imgref.transport == Transport::OciDir;
     let tempdir = if !target_oci {
-        let vartmp = cap_std::fs::Dir::open_ambient_dir("/var/tmp", cap_std::ambient_authority())?;
-        let td = cap_std_ext::cap_tempfile::TempDir::new_in(&vartmp)?;
+        let td = super::encapsulate::create_tempdir(opts.tmpdir.as_deref())?;
         // Always skip compression when making a temporary copy
         let opts = ExportToOCIOpts {
             skip_compression: true,
             progress_to_stdout: opts.progress_to_stdout,
+            reproducible: opts.reproducible,
             ..Default::default()
         };
         export_to_oci(repo, src_imgref, &td, None, opts)?;
@@ -1449,8 +2298,7 @@ pub async fn export(
         tracing::debug!("using OCI path={path} tag={tag:?}");
         let path = Dir::open_ambient_dir(path, cap_std::ambient_authority())
             .with_context(|| format!("Opening {path}"))?;
-        let descriptor = export_to_oci(repo, src_imgref, &path, tag, opts)?;
-        return Ok(descriptor.digest().clone());
+        return export_to_oci_dir(repo, src_imgref, &path, tag, Some(opts));
     };
     // Pass the temporary oci directory as the current working directory for the skopeo process
     let target_fd = 3i32;
@@ -1465,10 +2313,95 @@ pub async fn export(
         authfile,
         Some((std::sync::Arc::new(tempdir.try_clone()?.into()), target_fd)),
         opts.progress_to_stdout,
+        opts.copy_concurrency,
+        opts.skopeo_retry_times,
     )
     .await
 }
 
+/// Options for [`import_and_checkout`].
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct ImportCheckoutOpts {
+    /// Configuration for fetching containers.
+    pub proxy_cfg: Option<ImageProxyConfig>,
+    /// Leave `/usr/etc` as is; by default it is renamed to `/etc` if the latter
+    /// does not exist.
+    pub keep_usr_etc: bool,
+}
+
+/// Import a container image into a temporary repository, and check out the
+/// resulting merge commit to `dest`, which must not exist.
+///
+/// This is intended for callers that just want the image content as a plain
+/// directory; the temporary repository is created alongside `dest` (so that
+/// checkout can use hardlinks) and removed afterward.
+#[context("Importing {imgref} and checking out to {dest}")]
+pub async fn import_and_checkout(
+    imgref: &OstreeImageReference,
+    dest: &Utf8Path,
+    options: Option<ImportCheckoutOpts>,
+) -> Result<Box<LayeredImageState>> {
+    use rustix::fd::{AsFd, AsRawFd};
+
+    let options = options.unwrap_or_default();
+    let name = dest
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid destination: {dest}"))?
+        .to_owned();
+    let parent = dest
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."));
+    let parent = Dir::open_ambient_dir(parent, cap_std::ambient_authority())
+        .with_context(|| format!("Opening {parent}"))?;
+    if parent.try_exists(&name)? {
+        anyhow::bail!("Destination already exists: {dest}");
+    }
+    let td = cap_std_ext::cap_tempfile::TempDir::new_in(&parent)?;
+    let repo = &ostree::Repo::create_at_dir(td.as_fd(), "repo", ostree::RepoMode::BareUser, None)
+        .context("Creating temporary repo")?;
+
+    let mut imp = ImageImporter::new(repo, imgref, options.proxy_cfg.unwrap_or_default()).await?;
+    let state = match imp.prepare().await? {
+        PrepareResult::AlreadyPresent(r) => r,
+        PrepareResult::Ready(prep) => imp.import(prep).await?,
+    };
+
+    let repo = repo.clone();
+    let commit = state.merge_commit.clone();
+    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| -> Result<()> {
+        let cancellable = Some(cancellable);
+        // Only root can preserve file ownership
+        let mode = if rustix::process::getuid().is_root() {
+            ostree::RepoCheckoutMode::None
+        } else {
+            ostree::RepoCheckoutMode::User
+        };
+        let opts = ostree::RepoCheckoutAtOptions {
+            mode,
+            ..Default::default()
+        };
+        repo.checkout_at(
+            Some(&opts),
+            parent.as_raw_fd(),
+            name.as_str(),
+            &commit,
+            cancellable,
+        )
+        .context("Checking out")?;
+        let root = parent.open_dir(&name)?;
+        if !options.keep_usr_etc && root.try_exists("usr/etc")? && !root.try_exists("etc")? {
+            root.rename("usr/etc", &root, "etc")
+                .context("Renaming usr/etc")?;
+        }
+        Ok(())
+    })
+    .await?;
+    td.close()?;
+    Ok(state)
+}
+
 /// Iterate over deployment commits, returning the manifests from
 /// commits which point to a container image.
 #[context("Listing deployment manifests")]
@@ -1881,6 +2814,51 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_layer_progress_fraction() {
+        let progress = |fetched, total, imported| LayerProgress {
+            layer_index: 0,
+            fetched,
+            total,
+            imported,
+            fetched_bytes: fetched,
+            total_bytes: total,
+        };
+        assert_eq!(progress(0, 100, false).fraction(), 0.0);
+        assert_eq!(
+            progress(50, 100, false).fraction(),
+            LAYER_FETCH_WEIGHT / 2.0
+        );
+        assert_eq!(progress(100, 100, false).fraction(), LAYER_FETCH_WEIGHT);
+        // The size in the manifest may be wrong
+        assert_eq!(progress(200, 100, false).fraction(), LAYER_FETCH_WEIGHT);
+        assert_eq!(progress(0, 0, false).fraction(), LAYER_FETCH_WEIGHT);
+        assert_eq!(progress(100, 100, true).fraction(), 1.0);
+    }
+
+    #[test]
+    fn test_layer_progress_next() {
+        let start = LayerProgress {
+            layer_index: 1,
+            fetched: 0,
+            total: 100,
+            imported: false,
+            fetched_bytes: 0,
+            total_bytes: 150,
+        };
+        let p = LayerProgress::next(Some(&start), 1, 60, 100, false);
+        assert_eq!((p.fetched_bytes, p.total_bytes), (60, 150));
+        let p = LayerProgress::next(Some(&p), 1, 100, 100, false);
+        assert_eq!(p.fetched_bytes, 100);
+        let p = LayerProgress::next(Some(&p), 1, 100, 100, true);
+        assert_eq!(p.fetched_bytes, 100);
+        // The same layer may be fetched again after it was imported
+        let p = LayerProgress::next(Some(&p), 1, 20, 50, false);
+        assert_eq!((p.fetched_bytes, p.total_bytes), (120, 150));
+        let p = LayerProgress::next(Some(&p), 2, 30, 50, false);
+        assert_eq!((p.fetched_bytes, p.total_bytes), (150, 150));
+    }
+
     #[test]
     fn test_ref_for_descriptor() {
         let d = DescriptorBuilder::default()
@@ -31,15 +31,20 @@
 // Once we have the manifest, we expect it to point to a single `application/vnd.oci.image.layer.v1.tar+gzip` layer,
 // which is exactly what is exported by the [`crate::tar::export`] process.
 
-use crate::container::store::LayerProgress;
+use crate::container::store::{ImageSource, LayerProgress};
 
 use super::*;
-use containers_image_proxy::{ImageProxy, OpenedImage};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use containers_image_proxy::ImageProxy;
 use fn_error_context::context;
+use futures_util::future::BoxFuture;
 use futures_util::{Future, FutureExt};
 use oci_spec::image::{self as oci_image, Digest};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use std::io::Read;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::{
     io::{AsyncBufRead, AsyncRead},
     sync::watch::{Receiver, Sender},
@@ -51,6 +56,12 @@ use tracing::instrument;
 /// TODO: change the skopeo code to shield us from this correctly
 const DOCKER_TYPE_LAYER_TAR: &str = "application/vnd.docker.image.rootfs.diff.tar";
 
+/// The MIME types of the legacy docker schema1 manifest format.
+const DOCKER_TYPES_MANIFEST_SCHEMA1: &[&str] = &[
+    "application/vnd.docker.distribution.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v1+prettyjws",
+];
+
 type Progress = tokio::sync::watch::Sender<u64>;
 
 /// A read wrapper that updates the download progress.
@@ -61,13 +72,27 @@ pub(crate) struct ProgressReader<T> {
     pub(crate) reader: T,
     #[pin]
     pub(crate) progress: Arc<Mutex<Progress>>,
+    /// The total number of bytes read
+    read: u64,
+    /// The minimum number of bytes to read between updates
+    interval: u64,
 }
 
 impl<T: AsyncRead> ProgressReader<T> {
-    pub(crate) fn new(reader: T) -> (Self, Receiver<u64>) {
-        let (progress, r) = tokio::sync::watch::channel(1);
+    /// Create a reader which sends an update once at least `interval` bytes (by
+    /// default, any) have been read since the previous one, and at the end of
+    /// the stream.
+    pub(crate) fn new(reader: T, interval: Option<u64>) -> (Self, Receiver<u64>) {
+        let (progress, r) = tokio::sync::watch::channel(0);
         let progress = Arc::new(Mutex::new(progress));
-        (ProgressReader { reader, progress }, r)
+        let interval = interval.unwrap_or(0).max(1);
+        let reader = ProgressReader {
+            reader,
+            progress,
+            read: 0,
+            interval,
+        };
+        (reader, r)
     }
 }
 
@@ -82,16 +107,16 @@ impl<T: AsyncRead> AsyncRead for ProgressReader<T> {
         match this.reader.poll_read(cx, buf) {
             v @ std::task::Poll::Ready(Ok(_)) => {
                 let progress = this.progress.lock().unwrap();
-                let state = {
-                    let mut state = *progress.borrow();
-                    let newlen = buf.filled().len();
-                    debug_assert!(newlen >= len);
-                    let read = (newlen - len) as u64;
-                    state += read;
-                    state
-                };
-                // Ignore errors, if the caller disconnected from progress that's OK.
-                let _ = progress.send(state);
+                let newlen = buf.filled().len();
+                debug_assert!(newlen >= len);
+                let read = (newlen - len) as u64;
+                *this.read += read;
+                let eof = read == 0 && buf.remaining() > 0;
+                let pending = this.read.saturating_sub(*progress.borrow());
+                if pending > 0 && (eof || pending >= *this.interval) {
+                    // Ignore errors, if the caller disconnected from progress that's OK.
+                    let _ = progress.send(*this.read);
+                }
                 v
             }
             o => o,
@@ -99,6 +124,35 @@ impl<T: AsyncRead> AsyncRead for ProgressReader<T> {
     }
 }
 
+/// The digest an image reference is pinned to, e.g. for
+/// `quay.io/exampleos/exampleos@sha256:...`.
+fn pinned_digest(imgref: &ImageReference) -> Result<Option<Digest>> {
+    if imgref.transport != Transport::Registry {
+        return Ok(None);
+    }
+    imgref
+        .name
+        .split_once('@')
+        .map(|(_, digest)| {
+            Digest::from_str(digest).with_context(|| format!("Parsing pinned digest {digest}"))
+        })
+        .transpose()
+}
+
+/// If the image reference is pinned to a digest, verify that the fetched manifest has it.
+///
+/// This is only done for manifests from a [`LayerFetcher`].  The proxy already
+/// verifies pinned references itself, and returns the manifest for the current
+/// platform, whose digest differs if the reference pins a manifest list.
+pub(crate) fn verify_pinned_digest(imgref: &ImageReference, digest: &Digest) -> Result<()> {
+    if let Some(pinned) = pinned_digest(imgref)? {
+        if pinned != *digest {
+            anyhow::bail!("Manifest digest mismatch for {imgref}: pinned {pinned}, found {digest}");
+        }
+    }
+    Ok(())
+}
+
 async fn fetch_manifest_impl(
     proxy: &mut ImageProxy,
     imgref: &OstreeImageReference,
@@ -135,6 +189,454 @@ pub async fn fetch_manifest_and_config(
     Ok((manifest, digest, config))
 }
 
+/// The fields common to all manifest formats, used to detect the format.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestHeader {
+    schema_version: Option<u32>,
+    media_type: Option<String>,
+}
+
+/// Parse a serialized OCI or docker schema2 image manifest.
+///
+/// Docker schema1 manifests have a different structure and are not supported; this
+/// returns an error naming the format for them, rather than an opaque parse failure.
+/// Note that fetching via `containers-image-proxy` converts manifests to OCI already.
+pub fn parse_manifest(buf: &[u8]) -> Result<oci_image::ImageManifest> {
+    let header: ManifestHeader = serde_json::from_slice(buf).context("Parsing manifest")?;
+    let is_schema1 = match header.media_type.as_deref() {
+        Some(t) => DOCKER_TYPES_MANIFEST_SCHEMA1.contains(&t),
+        None => header.schema_version == Some(1),
+    };
+    if is_schema1 {
+        anyhow::bail!("Docker schema1 manifests are not supported; the image must be converted to schema2 or OCI (e.g. by pushing it again)");
+    }
+    serde_json::from_slice(buf).context("Parsing manifest")
+}
+
+/// A stream of blob content returned by a [`LayerFetcher`].
+pub type FetchedBlob = Box<dyn AsyncBufRead + Send + Unpin>;
+
+/// Fetches image manifests and blobs, replacing the default of using
+/// `containers-image-proxy` (i.e. `skopeo`).  This can be used to embed
+/// a native registry client, e.g. in environments which cannot spawn
+/// subprocesses; see [`store::ImageImporter::new_with_fetcher`].
+///
+/// Implementations are responsible for verifying the content they return;
+/// in particular no signature verification is performed for it.
+pub trait LayerFetcher: std::fmt::Debug + Send + Sync {
+    /// Fetch the manifest for an image, along with its digest.  Implementations
+    /// can use [`parse_manifest`] to parse the fetched manifest.
+    fn fetch_manifest<'a>(
+        &'a self,
+        imgref: &'a ImageReference,
+    ) -> BoxFuture<'a, Result<(oci_image::ImageManifest, Digest)>>;
+
+    /// Fetch a blob referenced by the manifest of an image; this is used for
+    /// both the layers and the image configuration.  Compressed layers
+    /// should be returned as is.
+    fn fetch_layer<'a>(
+        &'a self,
+        imgref: &'a ImageReference,
+        descriptor: &'a oci_image::Descriptor,
+    ) -> BoxFuture<'a, Result<FetchedBlob>>;
+}
+
+/// A read wrapper which verifies the size and sha256 digest of a blob; reaching
+/// the end of the stream fails if they do not match the descriptor.
+#[pin_project::pin_project]
+struct VerifyingReader<T> {
+    #[pin]
+    reader: T,
+    hasher: openssl::sha::Sha256,
+    /// The total number of bytes read
+    read: u64,
+    /// The expected size
+    size: u64,
+    /// The expected sha256 digest, in hexadecimal
+    digest: String,
+}
+
+impl<T: AsyncRead> VerifyingReader<T> {
+    /// Create a verifying reader for a blob with this descriptor, which must
+    /// use a sha256 digest.
+    fn new(reader: T, descriptor: &oci_image::Descriptor) -> Self {
+        let digest = descriptor.digest();
+        debug_assert_eq!(digest.algorithm(), &oci_image::DigestAlgorithm::Sha256);
+        Self {
+            reader,
+            hasher: openssl::sha::Sha256::new(),
+            read: 0,
+            size: descriptor.size(),
+            digest: digest.digest().to_string(),
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for VerifyingReader<T> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.project();
+        let len = buf.filled().len();
+        match this.reader.poll_read(cx, buf) {
+            std::task::Poll::Ready(Ok(())) => {
+                let data = &buf.filled()[len..];
+                this.hasher.update(data);
+                *this.read += data.len() as u64;
+                let eof = data.is_empty() && buf.remaining() > 0;
+                if *this.read > *this.size || (eof && *this.read != *this.size) {
+                    let msg = format!(
+                        "Blob size mismatch for sha256:{}; expected {}, found {}",
+                        this.digest, this.size, this.read
+                    );
+                    return std::task::Poll::Ready(Err(std::io::Error::other(msg)));
+                }
+                if eof {
+                    let actual = hex::encode(this.hasher.clone().finish());
+                    if actual != *this.digest {
+                        let msg = format!(
+                            "Blob digest mismatch; expected sha256:{}, found sha256:{actual}",
+                            this.digest
+                        );
+                        return std::task::Poll::Ready(Err(std::io::Error::other(msg)));
+                    }
+                }
+                std::task::Poll::Ready(Ok(()))
+            }
+            o => o,
+        }
+    }
+}
+
+/// Reads an image directly from an OCI image layout directory (the `oci:`
+/// transport), rather than via `containers-image-proxy`.
+///
+/// The digests of the manifest and of the blobs are verified.
+#[derive(Debug)]
+pub(crate) struct OciLayoutFetcher {
+    dir: ocidir::OciDir,
+    manifest: oci_image::Descriptor,
+}
+
+impl OciLayoutFetcher {
+    /// Open the image referenced by `imgref`, which must use the `oci:` transport.
+    /// Returns `None` if the image is not found, or is not a single-platform image;
+    /// these cases are left to the proxy.
+    #[context("Opening OCI layout {}", imgref.name)]
+    pub(crate) fn open(imgref: &ImageReference) -> Result<Option<Self>> {
+        let (path, tag) = parse_oci_path_and_tag(&imgref.name);
+        let dir = Dir::open_ambient_dir(path, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {path}"))?;
+        let dir = ocidir::OciDir::open(&dir)?;
+        let Some(idx) = dir.read_index()? else {
+            return Ok(None);
+        };
+        let manifests = idx.manifests();
+        let desc = match tag {
+            Some(tag) => manifests.iter().find(|d| {
+                d.annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(oci_image::ANNOTATION_REF_NAME))
+                    .is_some_and(|name| name == tag)
+            }),
+            None => match manifests.as_slice() {
+                [d] => Some(d),
+                _ => None,
+            },
+        };
+        let Some(desc) = desc.filter(|d| d.media_type() == &oci_image::MediaType::ImageManifest)
+        else {
+            return Ok(None);
+        };
+        let manifest = desc.clone();
+        Ok(Some(Self { dir, manifest }))
+    }
+}
+
+impl LayerFetcher for OciLayoutFetcher {
+    fn fetch_manifest<'a>(
+        &'a self,
+        _imgref: &'a ImageReference,
+    ) -> BoxFuture<'a, Result<(oci_image::ImageManifest, Digest)>> {
+        async move {
+            let mut buf = Vec::new();
+            self.dir.read_blob(&self.manifest)?.read_to_end(&mut buf)?;
+            let digest = self.manifest.digest();
+            if digest.algorithm() == &oci_image::DigestAlgorithm::Sha256 {
+                let actual = hex::encode(openssl::sha::sha256(&buf));
+                if actual != digest.digest() {
+                    anyhow::bail!(
+                        "Manifest digest mismatch; expected {digest}, found sha256:{actual}"
+                    );
+                }
+            }
+            Ok((parse_manifest(&buf)?, digest.clone()))
+        }
+        .boxed()
+    }
+
+    fn fetch_layer<'a>(
+        &'a self,
+        _imgref: &'a ImageReference,
+        descriptor: &'a oci_image::Descriptor,
+    ) -> BoxFuture<'a, Result<FetchedBlob>> {
+        async move {
+            let f = tokio::fs::File::from_std(self.dir.read_blob(descriptor)?);
+            let blob: FetchedBlob =
+                if descriptor.digest().algorithm() == &oci_image::DigestAlgorithm::Sha256 {
+                    let f = VerifyingReader::new(f, descriptor);
+                    Box::new(tokio::io::BufReader::new(f))
+                } else {
+                    Box::new(tokio::io::BufReader::new(f))
+                };
+            Ok(blob)
+        }
+        .boxed()
+    }
+}
+
+/// How long to wait before retrying a rate limited request, if the registry
+/// did not provide a `Retry-After` delay.
+pub const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(10);
+
+/// The maximum number of times a rate limited request is retried.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// A callback invoked with the wait time before a rate limited request is retried.
+#[derive(Clone)]
+pub(crate) struct RateLimitHandler(pub(crate) Arc<dyn Fn(Duration) + Send + Sync>);
+
+impl std::fmt::Debug for RateLimitHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimitHandler").finish_non_exhaustive()
+    }
+}
+
+/// The maximum wait before retrying a request which failed with a transient error.
+const MAX_TRANSIENT_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// How requests to the registry which fail are retried.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RetryPolicy {
+    /// If set, rate limited requests are retried, and this is invoked before each retry.
+    pub(crate) rate_limit_handler: Option<RateLimitHandler>,
+    /// The number of times a request which failed with a transient error is retried.
+    pub(crate) transient_retries: u32,
+    /// The wait before the first retry of a transient error; this is doubled
+    /// for each further retry.
+    pub(crate) transient_wait: Duration,
+}
+
+/// An error which a [`LayerFetcher`] can return for a request which the
+/// registry answered with an HTTP error status, so that it is classified by the
+/// status rather than by its message; e.g. a status of 429 with a `Retry-After`
+/// delay is retried as described in [`store::ImageImporter::set_rate_limit_handler`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RegistryStatusError {
+    /// The HTTP status of the response.
+    pub status: u16,
+    /// The delay from the `Retry-After` header of the response, if any.
+    pub retry_after: Option<Duration>,
+}
+
+impl RegistryStatusError {
+    /// Create an error for a response with the HTTP status `status`.
+    pub fn new(status: u16, retry_after: Option<Duration>) -> Self {
+        Self {
+            status,
+            retry_after,
+        }
+    }
+}
+
+impl std::fmt::Display for RegistryStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Registry returned HTTP status {}", self.status)?;
+        if let Some(wait) = self.retry_after {
+            write!(f, " (Retry-After: {}s)", wait.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RegistryStatusError {}
+
+/// The kind of failure of a request to the registry, as classified by
+/// [`classify_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorKind {
+    /// The registry rate limited the request (HTTP 429); retry after the wait.
+    RateLimited(Duration),
+    /// A failure which may not happen again, e.g. a connection failure or an
+    /// HTTP 5xx status.
+    Transient,
+    /// The registry does not have the image (e.g. HTTP 404), which a mirror may.
+    NotFound,
+    /// Any other error, e.g. a rejection by the signature policy or a failure
+    /// to write to the repository.
+    Other,
+}
+
+impl ErrorKind {
+    /// Whether the error indicates a problem with the registry, such that a
+    /// mirror may succeed.
+    fn is_registry_error(self) -> bool {
+        self != Self::Other
+    }
+}
+
+/// Classify an error from a request to the registry.  The causes of the error
+/// are considered first: a [`RegistryStatusError`] by its status, I/O errors by
+/// their kind, and errors from the proxy by their variant.  The proxy does not
+/// provide the HTTP status of a failed request, only the error message of
+/// `skopeo`, so that message (or, failing any typed cause, the whole error
+/// chain) is classified by [`classify_message`].
+fn classify_error(e: &anyhow::Error) -> ErrorKind {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<RegistryStatusError>() {
+            return match e.status {
+                429 => ErrorKind::RateLimited(e.retry_after.unwrap_or(DEFAULT_RATE_LIMIT_WAIT)),
+                500..=599 => ErrorKind::Transient,
+                404 => ErrorKind::NotFound,
+                _ => ErrorKind::Other,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            if let Some(kind) = classify_io_error(e) {
+                return kind;
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<containers_image_proxy::Error>() {
+            use containers_image_proxy::Error as ProxyError;
+            match e {
+                // Note the message is not part of the `Display` of the latter two.
+                ProxyError::RequestInitiationFailure { error: msg, .. }
+                | ProxyError::RequestReturned(msg)
+                | ProxyError::Other(msg) => return classify_message(msg),
+                ProxyError::Io(e) => {
+                    if let Some(kind) = classify_io_error(e) {
+                        return kind;
+                    }
+                }
+                // e.g. an invalid reply, or a configuration error
+                _ => return ErrorKind::Other,
+            }
+        }
+    }
+    classify_message(&format!("{e:#}"))
+}
+
+/// Classify an I/O error by its kind; errors without a specific kind (e.g.
+/// those created from a message) are left to the caller.
+fn classify_io_error(e: &std::io::Error) -> Option<ErrorKind> {
+    use std::io::ErrorKind as IoErrorKind;
+    match e.kind() {
+        IoErrorKind::ConnectionRefused
+        | IoErrorKind::ConnectionReset
+        | IoErrorKind::ConnectionAborted
+        | IoErrorKind::NotConnected
+        | IoErrorKind::TimedOut
+        | IoErrorKind::BrokenPipe
+        | IoErrorKind::UnexpectedEof => Some(ErrorKind::Transient),
+        IoErrorKind::Other => None,
+        _ => Some(ErrorKind::Other),
+    }
+}
+
+/// Classify an error message from a request to the registry, as the fallback of
+/// [`classify_error`] for errors which are only available as text.  Only HTTP
+/// statuses, registry error codes and network failures are considered, so that
+/// e.g. a missing local file or a number in the message is not mistaken for a
+/// registry error.  Rejections by the signature policy are always
+/// [`ErrorKind::Other`].
+fn classify_message(msg: &str) -> ErrorKind {
+    static STATUS: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?i)\bstatus(?: ?code)?:? *(\d{3})\b").unwrap());
+    static RATE_LIMITED: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?i)too ?many ?requests").unwrap());
+    static RETRY_AFTER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?i)retry-after:?\s*(\d+)").unwrap());
+    static NOT_FOUND: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?i)\b(manifest|name|blob) unknown\b").unwrap());
+    static NETWORK: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(concat!(
+            r"(?i)connection (refused|reset)|i/o timeout|handshake timeout|deadline exceeded|",
+            r"unexpected eof|broken pipe|no such host|temporary failure in name resolution"
+        ))
+        .unwrap()
+    });
+    static REJECTED: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?i)rejected|signature|policy").unwrap());
+    if REJECTED.is_match(msg) {
+        return ErrorKind::Other;
+    }
+    let status = STATUS.captures(msg).and_then(|c| c[1].parse::<u16>().ok());
+    if status == Some(429) || RATE_LIMITED.is_match(msg) {
+        let wait = RETRY_AFTER
+            .captures(msg)
+            .and_then(|c| c[1].parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RATE_LIMIT_WAIT);
+        return ErrorKind::RateLimited(wait);
+    }
+    if status.map_or(false, |s| (500..600).contains(&s)) || NETWORK.is_match(msg) {
+        return ErrorKind::Transient;
+    }
+    if status == Some(404) || NOT_FOUND.is_match(msg) {
+        return ErrorKind::NotFound;
+    }
+    ErrorKind::Other
+}
+
+/// Invoke `f`, and retry it while it fails because of rate limiting (if a
+/// handler is set) or with a transient error, as configured by `policy`.
+pub(crate) async fn retry_fetch<T, Fut>(
+    policy: &RetryPolicy,
+    mut f: impl FnMut() -> Fut,
+) -> Result<T>
+where
+    Fut: Future<Output = Result<T>>,
+{
+    let mut rate_limited = 0;
+    let mut transient = 0;
+    loop {
+        let e = match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        let kind = classify_error(&e);
+        if let Some(handler) = policy.rate_limit_handler.as_ref() {
+            if let ErrorKind::RateLimited(wait) = kind {
+                rate_limited += 1;
+                if rate_limited > MAX_RATE_LIMIT_RETRIES {
+                    return Err(e.context(format!(
+                        "Rate limited after {MAX_RATE_LIMIT_RETRIES} retries"
+                    )));
+                }
+                tracing::debug!("Rate limited; retrying in {wait:?}");
+                (handler.0)(wait);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+        }
+        let is_transient = matches!(kind, ErrorKind::RateLimited(_) | ErrorKind::Transient);
+        if transient >= policy.transient_retries || !is_transient {
+            return Err(e);
+        }
+        let wait = policy
+            .transient_wait
+            .saturating_mul(1 << transient.min(16))
+            .min(MAX_TRANSIENT_RETRY_WAIT);
+        transient += 1;
+        tracing::debug!("Transient error: {e:#}; retrying in {wait:?}");
+        tokio::time::sleep(wait).await;
+    }
+}
+
 /// The result of an import operation
 #[derive(Debug)]
 pub struct Import {
@@ -142,6 +644,19 @@ pub struct Import {
     pub ostree_commit: String,
     /// The image digest retrieved
     pub image_digest: Digest,
+    /// The digest of the uncompressed tar stream of the layer holding the
+    /// commit (its "diff-id", e.g. `sha256:...`), as used by container
+    /// runtime stores.  This is distinct from the digest of the (compressed)
+    /// layer in the manifest.  If the layer is fetched, the import fails unless
+    /// its diff-id matches the one in the image configuration.
+    pub layer_diff_id: String,
+    /// If the image was fetched from one of [`UnencapsulateOpts::mirrors`],
+    /// that mirror.
+    pub mirror: Option<OstreeImageReference>,
+    /// Number of objects which were included in more than one layer of the
+    /// image, and were only written once.  A nonzero value indicates an
+    /// inefficiently built image.
+    pub duplicate_objects_skipped: u64,
 
     /// Any deprecation warning
     pub deprecated_warning: Option<String>,
@@ -191,19 +706,342 @@ pub async fn unencapsulate(repo: &ostree::Repo, imgref: &OstreeImageReference) -
     importer.unencapsulate().await
 }
 
+/// Options for [`unencapsulate_with_opts`].
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct UnencapsulateOpts {
+    /// Further references to the same image, e.g. on registry mirrors.  If
+    /// fetching the image fails because of the registry (it is unreachable,
+    /// responds with a server error, or does not have the image), these are
+    /// tried in order, and the first to succeed is used.
+    pub mirrors: Vec<OstreeImageReference>,
+}
+
+/// Fetch a container image and import its embedded OSTree commit, falling back
+/// to [`UnencapsulateOpts::mirrors`] if fetching from `imgref` fails.
+///
+/// Each source is tried once.  Requests which are rate limited are not retried,
+/// but fail over to the next mirror; to retry rate limited requests against a
+/// single source, use [`store::ImageImporter::set_rate_limit_handler`].
+/// Errors which are not caused by the registry (e.g. a failure to verify the
+/// image signature, or to write to the repository) are returned immediately.
+pub async fn unencapsulate_with_opts(
+    repo: &ostree::Repo,
+    imgref: &OstreeImageReference,
+    options: Option<UnencapsulateOpts>,
+) -> Result<Import> {
+    let options = options.unwrap_or_default();
+    let mut e = match unencapsulate(repo, imgref).await {
+        Ok(r) => return Ok(r),
+        Err(e) => e,
+    };
+    for mirror in options.mirrors {
+        if !classify_error(&e).is_registry_error() {
+            return Err(e);
+        }
+        tracing::debug!("Trying mirror {mirror}: {e:#}");
+        match unencapsulate(repo, &mirror).await {
+            Ok(mut r) => {
+                r.mirror = Some(mirror);
+                return Ok(r);
+            }
+            Err(next) => e = next,
+        }
+    }
+    Err(e)
+}
+
+/// The error for a layer whose decompressed size exceeds the maximum ratio to
+/// its compressed size; see [`store::ImageImporter::set_max_expansion_ratio`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ExpansionRatioExceeded {
+    /// Number of compressed bytes read.
+    pub compressed: u64,
+    /// Number of bytes decompressed from them.
+    pub decompressed: u64,
+    /// The maximum ratio of decompressed to compressed bytes.
+    pub max_ratio: f64,
+}
+
+impl std::fmt::Display for ExpansionRatioExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Decompressed {} bytes from {} compressed bytes, exceeding the maximum expansion ratio {}",
+            self.decompressed, self.compressed, self.max_ratio
+        )
+    }
+}
+
+impl std::error::Error for ExpansionRatioExceeded {}
+
+/// The error for an import which did not complete in time; see
+/// [`store::ImageImporter::set_timeout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ImportTimedOut {
+    /// The configured timeout.
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for ImportTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Import timed out after {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for ImportTimedOut {}
+
+/// Await `f`, failing with [`ImportTimedOut`] if it does not complete within `timeout`.
+pub(crate) async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    f: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, f)
+            .await
+            .map_err(|_| anyhow::Error::new(ImportTimedOut { timeout }))?,
+        None => f.await,
+    }
+}
+
+/// Tracks the blocking tasks which read fetched layers, so that they can be
+/// stopped and waited for when an import times out.
+#[derive(Debug)]
+pub(crate) struct ImportTasks {
+    cancel: tokio_util::sync::CancellationToken,
+    /// Cloned into each fetched layer; the receiver is closed once all are dropped
+    running: tokio::sync::mpsc::Sender<()>,
+    finished: tokio::sync::mpsc::Receiver<()>,
+}
+
+impl Default for ImportTasks {
+    fn default() -> Self {
+        let (running, finished) = tokio::sync::mpsc::channel(1);
+        Self {
+            cancel: Default::default(),
+            running,
+            finished,
+        }
+    }
+}
+
+impl ImportTasks {
+    /// Wrap a fetched layer so that reading it fails once [`Self::cancel`] is
+    /// called, even if a read is pending.  The task reading it is considered
+    /// finished when the returned stream is dropped.
+    pub(crate) fn track(&self, blob: FetchedBlob) -> FetchedBlob {
+        let reader = StoppableReader {
+            reader: blob,
+            stopped: self.cancel.clone().cancelled_owned(),
+            _running: self.running.clone(),
+        };
+        Box::new(Box::pin(reader))
+    }
+
+    /// A guard which keeps [`Self::wait`] waiting until it is dropped, for tasks
+    /// which should be considered running for longer than they read a layer.
+    pub(crate) fn guard(&self) -> impl Send + 'static {
+        self.running.clone()
+    }
+
+    /// Fail all reads of the tracked layers, even those which are pending.
+    pub(crate) fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Wait until all tracked layers and guards are dropped.
+    pub(crate) async fn wait(self) {
+        let Self {
+            running,
+            mut finished,
+            ..
+        } = self;
+        drop(running);
+        // Nothing is ever sent; this completes once all senders are dropped.
+        let _ = finished.recv().await;
+    }
+}
+
+/// A read wrapper which fails once its import is stopped; see [`ImportTasks`].
+#[pin_project::pin_project]
+struct StoppableReader<T> {
+    #[pin]
+    reader: T,
+    #[pin]
+    stopped: tokio_util::sync::WaitForCancellationFutureOwned,
+    _running: tokio::sync::mpsc::Sender<()>,
+}
+
+impl<T> StoppableReader<T> {
+    /// Fail if the import was stopped; otherwise, wake the task when it is.
+    fn check_stopped(
+        stopped: std::pin::Pin<&mut tokio_util::sync::WaitForCancellationFutureOwned>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::io::Result<()> {
+        match stopped.poll(cx) {
+            std::task::Poll::Ready(()) => Err(std::io::Error::other("Import was stopped")),
+            std::task::Poll::Pending => Ok(()),
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for StoppableReader<T> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.project();
+        Self::check_stopped(this.stopped, cx)?;
+        this.reader.poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncBufRead> AsyncBufRead for StoppableReader<T> {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.project();
+        Self::check_stopped(this.stopped, cx)?;
+        this.reader.poll_fill_buf(cx)
+    }
+
+    fn consume(self: std::pin::Pin<&mut Self>, amt: usize) {
+        self.project().reader.consume(amt)
+    }
+}
+
+/// Enforces a maximum ratio of decompressed to compressed bytes for a layer.
+#[derive(Debug)]
+pub(crate) struct ExpansionLimit {
+    max_ratio: f64,
+    compressed: std::sync::atomic::AtomicU64,
+    exceeded: once_cell::sync::OnceCell<ExpansionRatioExceeded>,
+}
+
+impl ExpansionLimit {
+    pub(crate) fn new(max_ratio: f64) -> Arc<Self> {
+        Arc::new(Self {
+            max_ratio,
+            compressed: Default::default(),
+            exceeded: Default::default(),
+        })
+    }
+
+    /// If the limit was exceeded, return the error describing it instead of
+    /// `e`, which is then only a consequence of aborting the decompression.
+    pub(crate) fn map_err(limit: Option<&Self>, e: anyhow::Error) -> anyhow::Error {
+        match limit.and_then(|l| l.exceeded.get()) {
+            Some(exceeded) => exceeded.clone().into(),
+            None => e,
+        }
+    }
+}
+
+/// Counts the compressed bytes read for an [`ExpansionLimit`].
+struct CompressedCounter<R> {
+    inner: R,
+    limit: Option<Arc<ExpansionLimit>>,
+}
+
+impl<R: Read> Read for CompressedCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(limit) = self.limit.as_ref() {
+            limit
+                .compressed
+                .fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+}
+
+/// Computes the SHA-256 digest of the data read through it.
+pub(crate) struct DigestReader<R> {
+    inner: R,
+    hasher: openssl::sha::Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: openssl::sha::Sha256::new(),
+        }
+    }
+
+    /// Read any remaining data, and return its digest in the form `sha256:<hex>`.
+    pub(crate) fn finish(mut self) -> Result<String> {
+        std::io::copy(&mut self, &mut std::io::sink())?;
+        Ok(format!("sha256:{}", hex::encode(self.hasher.finish())))
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Fails once the decompressed bytes read exceed an [`ExpansionLimit`].
+struct ExpansionChecker<R> {
+    inner: R,
+    decompressed: u64,
+    limit: Arc<ExpansionLimit>,
+}
+
+impl<R: Read> Read for ExpansionChecker<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.decompressed += n as u64;
+        let limit = &self.limit;
+        let compressed = limit.compressed.load(std::sync::atomic::Ordering::Relaxed);
+        if self.decompressed as f64 > limit.max_ratio * compressed as f64 {
+            let e = ExpansionRatioExceeded {
+                compressed,
+                decompressed: self.decompressed,
+                max_ratio: limit.max_ratio,
+            };
+            let e = limit.exceeded.get_or_init(|| e).clone();
+            return Err(std::io::Error::other(e));
+        }
+        Ok(n)
+    }
+}
+
 /// Create a decompressor for this MIME type, given a stream of input.
+/// If `limit` is set, decompressing a compressed layer fails once it is exceeded.
 pub(crate) fn decompressor(
     media_type: &oci_image::MediaType,
     src: impl Read + Send + 'static,
+    limit: Option<Arc<ExpansionLimit>>,
 ) -> Result<Box<dyn Read + Send + 'static>> {
+    let src = CompressedCounter {
+        inner: src,
+        limit: limit.clone(),
+    };
     let r: Box<dyn std::io::Read + Send + 'static> = match media_type {
         m @ (oci_image::MediaType::ImageLayerGzip | oci_image::MediaType::ImageLayerZstd) => {
-            if matches!(m, oci_image::MediaType::ImageLayerZstd) {
-                Box::new(zstd::stream::read::Decoder::new(src)?)
-            } else {
-                Box::new(flate2::bufread::GzDecoder::new(std::io::BufReader::new(
-                    src,
-                )))
+            let r: Box<dyn std::io::Read + Send + 'static> =
+                if matches!(m, oci_image::MediaType::ImageLayerZstd) {
+                    Box::new(zstd::stream::read::Decoder::new(src)?)
+                } else {
+                    Box::new(flate2::bufread::GzDecoder::new(std::io::BufReader::new(
+                        src,
+                    )))
+                };
+            match limit {
+                Some(limit) => Box::new(ExpansionChecker {
+                    inner: r,
+                    decompressed: 0,
+                    limit,
+                }),
+                None => r,
             }
         }
         oci_image::MediaType::ImageLayer => Box::new(src),
@@ -215,62 +1053,968 @@ pub(crate) fn decompressor(
 
 /// A wrapper for [`get_blob`] which fetches a layer and decompresses it.
 pub(crate) async fn fetch_layer<'a>(
-    proxy: &'a ImageProxy,
-    img: &OpenedImage,
+    source: &'a ImageSource,
+    imgref: &'a ImageReference,
     manifest: &oci_image::ImageManifest,
     layer: &'a oci_image::Descriptor,
     progress: Option<&'a Sender<Option<store::LayerProgress>>>,
+    progress_interval: Option<u64>,
     layer_info: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
-    transport_src: Transport,
 ) -> Result<(
-    Box<dyn AsyncBufRead + Send + Unpin>,
+    FetchedBlob,
     impl Future<Output = Result<()>> + 'a,
     oci_image::MediaType,
 )> {
     use futures_util::future::Either;
     tracing::debug!("fetching {}", layer.digest());
     let layer_index = manifest.layers().iter().position(|x| x == layer).unwrap();
-    let (blob, driver, size);
-    let media_type: oci_image::MediaType;
-    match transport_src {
-        Transport::ContainerStorage => {
-            let layer_info = layer_info
-                .ok_or_else(|| anyhow!("skopeo too old to pull from containers-storage"))?;
-            let n_layers = layer_info.len();
-            let layer_blob = layer_info.get(layer_index).ok_or_else(|| {
-                anyhow!("blobid position {layer_index} exceeds diffid count {n_layers}")
-            })?;
-            size = layer_blob.size;
-            media_type = layer_blob.media_type.clone();
-            (blob, driver) = proxy.get_blob(img, &layer_blob.digest, size).await?;
-        }
-        _ => {
-            size = layer.size();
-            media_type = layer.media_type().clone();
-            (blob, driver) = proxy.get_blob(img, layer.digest(), size).await?;
+    let (blob, driver, size, media_type) = match source {
+        ImageSource::Proxy { proxy, img, .. } => {
+            let (digest, size, media_type) = match imgref.transport {
+                Transport::ContainerStorage => {
+                    let Some(layer_info) = layer_info else {
+                        super::skopeo::require_version(
+                            super::skopeo::CONTAINERS_STORAGE_MIN_VERSION,
+                            "Pulling from containers-storage",
+                        )?;
+                        // The version check passed, so the proxy should have provided this
+                        anyhow::bail!("Missing converted layer information for {imgref}");
+                    };
+                    let n_layers = layer_info.len();
+                    let layer_blob = layer_info.get(layer_index).ok_or_else(|| {
+                        anyhow!("blobid position {layer_index} exceeds diffid count {n_layers}")
+                    })?;
+                    let media_type = layer_blob.media_type.clone();
+                    (&layer_blob.digest, layer_blob.size, media_type)
+                }
+                _ => (layer.digest(), layer.size(), layer.media_type().clone()),
+            };
+            let (blob, driver) = proxy.get_blob(img, digest, size).await?;
+            let blob: FetchedBlob = Box::new(blob);
+            let driver = async { Ok::<_, anyhow::Error>(driver.await?) };
+            (blob, Either::Left(driver), size, media_type)
+        }
+        // Custom fetchers complete the transfer through the returned stream
+        ImageSource::Fetcher(fetcher) => {
+            let blob = fetcher.fetch_layer(imgref, layer).await?;
+            let driver = futures_util::future::ready(Ok(()));
+            (
+                blob,
+                Either::Right(driver),
+                layer.size(),
+                layer.media_type().clone(),
+            )
         }
     };
 
-    let driver = async { driver.await.map_err(Into::into) };
-
     if let Some(progress) = progress {
-        let (readprogress, mut readwatch) = ProgressReader::new(blob);
+        let (readprogress, mut readwatch) = ProgressReader::new(blob, progress_interval);
         let readprogress = tokio::io::BufReader::new(readprogress);
         let readproxy = async move {
             while let Ok(()) = readwatch.changed().await {
-                let fetched = readwatch.borrow_and_update();
-                let status = LayerProgress {
-                    layer_index,
-                    fetched: *fetched,
-                    total: size,
-                };
-                progress.send_replace(Some(status));
+                let fetched = *readwatch.borrow_and_update();
+                progress.send_modify(|p| {
+                    *p = Some(LayerProgress::next(
+                        p.as_ref(),
+                        layer_index,
+                        fetched,
+                        size,
+                        false,
+                    ))
+                });
             }
         };
         let reader = Box::new(readprogress);
         let driver = futures_util::future::join(readproxy, driver).map(|r| r.1);
         Ok((reader, Either::Left(driver), media_type))
     } else {
-        Ok((Box::new(blob), Either::Right(driver), media_type))
+        Ok((blob, Either::Right(driver), media_type))
+    }
+}
+
+/// Options for [`verify_image`].
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct VerifyImageOpts {
+    /// Configuration for the container image proxy.
+    pub proxy_cfg: Option<containers_image_proxy::ImageProxyConfig>,
+}
+
+/// The result of [`verify_image`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ImageVerifyReport {
+    /// The digest of the verified manifest.
+    pub manifest_digest: Digest,
+    /// The verification result for each ostree layer, in manifest order.
+    pub layers: Vec<(Digest, crate::tar::TarVerifyReport)>,
+}
+
+impl ImageVerifyReport {
+    /// Total number of objects whose checksum was computed.
+    pub fn objects_checked(&self) -> u64 {
+        self.layers.iter().map(|(_, r)| r.objects_checked).sum()
+    }
+
+    /// Returns true if no checksum mismatches were found in any layer.
+    pub fn is_ok(&self) -> bool {
+        self.layers.iter().all(|(_, r)| r.is_ok())
+    }
+}
+
+/// Fetch the ostree layers of an encapsulated container image and recompute the checksum
+/// of every object they contain, without writing anything to a repository.
+///
+/// Derived (non-ostree) layers are not fetched.
+#[context("Verifying {}", imgref)]
+pub async fn verify_image(
+    imgref: &ImageReference,
+    options: Option<VerifyImageOpts>,
+) -> Result<ImageVerifyReport> {
+    use futures_util::TryFutureExt;
+    let options = options.unwrap_or_default();
+    let mut config = options.proxy_cfg.unwrap_or_default();
+    if imgref.transport == Transport::ContainerStorage {
+        // Fetching from containers-storage, may require privileges to read files
+        merge_default_container_proxy_opts_with_isolation(&mut config, None)?;
+    } else {
+        merge_default_container_proxy_opts(&mut config)?;
+    }
+    let source = ImageSource::open_proxy(config, imgref).await?;
+    let (manifest_digest, manifest) = source.fetch_manifest(imgref).await?;
+    let config = source.fetch_config(imgref, &manifest).await?;
+    let (commit_layer, component_layers, _) =
+        store::parse_ostree_manifest_layout(&manifest, &config)?;
+    let layer_info = source.get_layer_info().await?;
+    let mut layers = Vec::new();
+    for layer in std::iter::once(commit_layer).chain(component_layers) {
+        let (blob, driver, media_type) = fetch_layer(
+            &source,
+            imgref,
+            &manifest,
+            layer,
+            None,
+            None,
+            layer_info.as_ref(),
+        )
+        .await?;
+        let verify_task = crate::tokio_util::spawn_blocking_cancellable_flatten(move |_| {
+            let blob = tokio_util::io::SyncIoBridge::new(blob);
+            let blob = decompressor(&media_type, blob, None)?;
+            let mut archive = tar::Archive::new(blob);
+            crate::tar::Verifier::default().verify(&mut archive)
+        })
+        .map_err(|e| e.context(format!("Layer {}", layer.digest())));
+        let report = join_fetch(verify_task, driver).await?;
+        layers.push((layer.digest().clone(), report));
+    }
+    source.finalize().await?;
+    Ok(ImageVerifyReport {
+        manifest_digest,
+        layers,
+    })
+}
+
+/// Options for [`inspect_image`] and [`commit_matches_image`].
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct InspectImageOpts {
+    /// Configuration for the container image proxy.
+    pub proxy_cfg: Option<containers_image_proxy::ImageProxyConfig>,
+}
+
+/// The severity of an [`ImageIssue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum IssueSeverity {
+    /// The image can be imported, but possibly not as intended.
+    Warning,
+    /// The image cannot be imported.
+    Error,
+}
+
+impl std::fmt::Display for IssueSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IssueSeverity::Warning => f.write_str("warning"),
+            IssueSeverity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// A problem found by [`inspect_image`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ImageIssue {
+    /// How the issue affects importing the image.
+    pub severity: IssueSeverity,
+    /// A description of the issue.
+    pub message: String,
+}
+
+impl ImageIssue {
+    fn new(severity: IssueSeverity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// The result of [`inspect_image`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ImageReport {
+    /// The digest of the inspected manifest.
+    pub manifest_digest: Digest,
+    /// Every issue found, in the order checked.
+    pub issues: Vec<ImageIssue>,
+}
+
+impl ImageReport {
+    /// Returns true if no issue of [`IssueSeverity::Error`] was found.
+    pub fn is_ok(&self) -> bool {
+        !self
+            .issues
+            .iter()
+            .any(|i| i.severity == IssueSeverity::Error)
+    }
+}
+
+/// Check the manifest and configuration of an image for problems which would
+/// prevent importing it as an encapsulated ostree commit.  If the configuration
+/// could not be fetched, only the manifest is checked.
+fn check_image(
+    manifest: &oci_image::ImageManifest,
+    config: Option<&oci_image::ImageConfiguration>,
+) -> Vec<ImageIssue> {
+    use IssueSeverity::{Error, Warning};
+    let mut issues = Vec::new();
+    let mut push = |severity, message: String| issues.push(ImageIssue::new(severity, message));
+
+    if manifest.schema_version() != 2 {
+        push(
+            Error,
+            format!("Unsupported schema version {}", manifest.schema_version()),
+        );
+    }
+    if let Some(t) = manifest.media_type() {
+        if *t != oci_image::MediaType::ImageManifest {
+            push(Error, format!("Unexpected manifest media type {t}"));
+        }
+    }
+    let config_desc = manifest.config();
+    if *config_desc.media_type() != oci_image::MediaType::ImageConfig {
+        push(
+            Error,
+            format!(
+                "Unexpected configuration media type {}",
+                config_desc.media_type()
+            ),
+        );
+    }
+    if config_desc.size() == 0 {
+        push(Error, "Configuration has size zero".into());
+    }
+
+    let layers = manifest.layers();
+    if layers.is_empty() {
+        push(Error, "No layers in manifest".into());
+    }
+    for layer in layers {
+        let digest = layer.digest();
+        match layer.media_type() {
+            oci_image::MediaType::ImageLayer
+            | oci_image::MediaType::ImageLayerGzip
+            | oci_image::MediaType::ImageLayerZstd => {}
+            oci_image::MediaType::Other(t) if t.as_str() == DOCKER_TYPE_LAYER_TAR => {}
+            o => push(
+                Error,
+                format!("Layer {digest} has unsupported media type {o}"),
+            ),
+        }
+        if layer.size() == 0 {
+            push(
+                Warning,
+                format!("Layer {digest} has size zero, and will be skipped"),
+            );
+        }
+    }
+
+    let Some(config) = config else {
+        return issues;
+    };
+    let diffids = config.rootfs().diff_ids();
+    if diffids.len() != layers.len() {
+        push(
+            Error,
+            format!(
+                "Manifest has {} layers, but the configuration has {} diffids",
+                layers.len(),
+                diffids.len()
+            ),
+        );
+    }
+
+    if *config.os() != oci_image::Os::Linux {
+        push(
+            Error,
+            format!("Unsupported operating system {}", config.os()),
+        );
+    }
+    let target_arch = &oci_image::Arch::default();
+    if config.architecture() != target_arch {
+        push(
+            Warning,
+            format!(
+                "Image has architecture {}; expected {target_arch}",
+                config.architecture()
+            ),
+        );
+    }
+
+    let labels = super::labels_of(config);
+    match labels.and_then(|l| l.get(OSTREE_COMMIT_LABEL)) {
+        Some(commit) => {
+            if ostree::validate_checksum_string(commit).is_err() {
+                push(
+                    Error,
+                    format!("Invalid {OSTREE_COMMIT_LABEL} label value {commit:?}"),
+                );
+            }
+        }
+        None => push(Warning, format!("Missing {OSTREE_COMMIT_LABEL} label")),
+    }
+    if !layers.is_empty() && diffids.len() == layers.len() {
+        if let Err(e) = store::parse_ostree_manifest_layout(manifest, config) {
+            push(Error, format!("{e:#}"));
+        }
+    }
+    issues
+}
+
+/// Open `imgref` for [`inspect_image`] and [`commit_matches_image`].
+async fn open_image_source(
+    imgref: &ImageReference,
+    options: InspectImageOpts,
+) -> Result<ImageSource> {
+    let mut config = options.proxy_cfg.unwrap_or_default();
+    if imgref.transport == Transport::ContainerStorage {
+        // Fetching from containers-storage, may require privileges to read files
+        merge_default_container_proxy_opts_with_isolation(&mut config, None)?;
+    } else {
+        merge_default_container_proxy_opts(&mut config)?;
+    }
+    ImageSource::open_proxy(config, imgref).await
+}
+
+/// Fetch the manifest and configuration of a container image, and check them for
+/// problems which would prevent importing it as an encapsulated ostree commit:
+/// media types, the number of layers, labels, platform and layer sizes.  All of
+/// the issues found are returned, rather than only the first.
+///
+/// Nothing is written, and no layers are fetched; an image with no issues may
+/// still fail to import.
+#[context("Inspecting {}", imgref)]
+pub async fn inspect_image(
+    imgref: &ImageReference,
+    options: Option<InspectImageOpts>,
+) -> Result<ImageReport> {
+    let source = open_image_source(imgref, options.unwrap_or_default()).await?;
+    let (manifest_digest, manifest) = source.fetch_manifest(imgref).await?;
+    let mut issues = Vec::new();
+    let config = match source.fetch_config(imgref, &manifest).await {
+        Ok(config) => Some(config),
+        Err(e) => {
+            issues.push(ImageIssue::new(
+                IssueSeverity::Error,
+                format!("Fetching configuration: {e:#}"),
+            ));
+            None
+        }
+    };
+    source.finalize().await?;
+    issues.extend(check_image(&manifest, config.as_ref()));
+    Ok(ImageReport {
+        manifest_digest,
+        issues,
+    })
+}
+
+/// Check whether the container image `imgref` is an encapsulation of the local
+/// commit `local_rev` (a ref or checksum in `repo`), e.g. to find out whether an
+/// update is available.  Only the manifest is fetched: this compares the
+/// [`OSTREE_COMMIT_LABEL`] annotation of the manifest, which is written by
+/// [`encapsulate`](super::encapsulate), with the commit checksum.
+///
+/// It is an error if the manifest has no such annotation, e.g. because the image
+/// was not generated from an ostree commit, or is derived from such an image.  The
+/// label in the image configuration is not used as a fallback, because derived
+/// images inherit it from their base image.  Like [`inspect_image`], this does not
+/// verify signatures.
+#[context("Comparing {local_rev} with {imgref}")]
+pub async fn commit_matches_image(
+    repo: &ostree::Repo,
+    local_rev: &str,
+    imgref: &ImageReference,
+    options: Option<InspectImageOpts>,
+) -> Result<bool> {
+    let local = repo.require_rev(local_rev)?;
+    let source = open_image_source(imgref, options.unwrap_or_default()).await?;
+    let (_, manifest) = source.fetch_manifest(imgref).await?;
+    source.finalize().await?;
+    let image_commit = manifest
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(OSTREE_COMMIT_LABEL))
+        .ok_or_else(|| anyhow!("No {OSTREE_COMMIT_LABEL} annotation in image manifest"))?;
+    Ok(image_commit.as_str() == local.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_message() {
+        use ErrorKind::*;
+        let digest = "sha256:4292d86b0e45b4da2d1b7b0d2a7c86b429a1c3e5e5d0e77a3e2a8b0f4e429b1c";
+        let cases = [
+            (
+                "toomanyrequests: You have reached your pull rate limit",
+                RateLimited(DEFAULT_RATE_LIMIT_WAIT),
+            ),
+            (
+                "received unexpected HTTP status: 429 Too Many Requests (Retry-After: 42)",
+                RateLimited(Duration::from_secs(42)),
+            ),
+            ("received unexpected HTTP status: 502 Bad Gateway", Transient),
+            ("received unexpected HTTP status: 503 Service Unavailable", Transient),
+            (
+                "pinging container registry quay.io: Get \"https://quay.io/v2/\": dial tcp: connect: connection refused",
+                Transient,
+            ),
+            ("read tcp 10.0.0.1:443: connection reset by peer", Transient),
+            ("dial tcp: i/o timeout", Transient),
+            ("reading blob: unexpected EOF", Transient),
+            ("dial tcp: lookup quay.io: no such host", Transient),
+            (
+                "reading manifest latest in quay.io/example/os: manifest unknown",
+                NotFound,
+            ),
+            (
+                "reading manifest latest in quay.io/example/os: name unknown: repository name not known to registry",
+                NotFound,
+            ),
+            ("fetching blob: StatusCode: 404", NotFound),
+            (&format!("reading blob {digest}: EOF"), Other),
+            (
+                "Source image rejected: Signature for identity quay.io/example/os is not accepted",
+                Other,
+            ),
+            (
+                "Source image rejected: A signature was required, but no signature exists",
+                Other,
+            ),
+            ("Writing content object: No space left on device", Other),
+            ("Expected commitmeta object", Other),
+            (&format!("Layer {digest}: Invalid checksum"), Other),
+            ("Commit object not found", Other),
+            (
+                "open /var/lib/images/os.oci/index.json: no such file or directory",
+                Other,
+            ),
+            ("Imported 404 objects, skipped 500", Other),
+            ("Import timed out after 1s", Other),
+        ];
+        for (msg, kind) in cases {
+            assert_eq!(classify_message(msg), kind, "{msg}");
+        }
+    }
+
+    #[test]
+    fn test_classify_error() {
+        use containers_image_proxy::Error as ProxyError;
+        use std::io::ErrorKind as IoErrorKind;
+        use ErrorKind::*;
+        let digest = "sha256:4292d86b0e45b4da2d1b7b0d2a7c86b429a1c3e5e5d0e77a3e2a8b0f4e429b1c";
+        // Failures of requests to the proxy, with the messages of `skopeo`
+        let request = |method: &str, error: &str| {
+            anyhow::Error::new(ProxyError::RequestInitiationFailure {
+                method: method.into(),
+                error: error.into(),
+            })
+        };
+        let cases = [
+            (
+                request(
+                    "GetManifest",
+                    "reading manifest latest in docker.io/library/fedora: toomanyrequests: You have reached your pull rate limit. You may increase the limit by authenticating and upgrading: https://www.docker.com/increase-rate-limit",
+                ),
+                RateLimited(DEFAULT_RATE_LIMIT_WAIT),
+            ),
+            (
+                request(
+                    "GetBlob",
+                    &format!("reading blob {digest}: fetching blob: received unexpected HTTP status: 503 Service Unavailable"),
+                ),
+                Transient,
+            ),
+            (
+                request(
+                    "OpenImage",
+                    "pinging container registry localhost:5000: Get \"https://localhost:5000/v2/\": dial tcp [::1]:5000: connect: connection refused",
+                ),
+                Transient,
+            ),
+            (
+                request(
+                    "OpenImage",
+                    "pinging container registry quay.io: Get \"https://quay.io/v2/\": dial tcp: lookup quay.io on 127.0.0.53:53: no such host",
+                ),
+                Transient,
+            ),
+            (
+                request(
+                    "GetManifest",
+                    "reading manifest latest in quay.io/exampleos/exampleos: manifest unknown",
+                ),
+                NotFound,
+            ),
+            (
+                request(
+                    "OpenImage",
+                    "reading manifest latest in quay.io/exampleos/exampleos: unauthorized: access to the requested resource is not authorized",
+                ),
+                Other,
+            ),
+            (
+                request(
+                    "OpenImage",
+                    "Source image rejected: Running image docker://quay.io/exampleos/exampleos:latest is rejected by policy.",
+                ),
+                Other,
+            ),
+            // The message of these variants is not part of the formatted error
+            (
+                anyhow::Error::new(ProxyError::RequestReturned(
+                    "proxy failed: exit status: 1\ntime=\"2024-01-01T00:00:00Z\" level=fatal msg=\"reading blob: received unexpected HTTP status: 502 Bad Gateway\"".into(),
+                )),
+                Transient,
+            ),
+            (
+                anyhow::Error::new(ProxyError::Other(
+                    "skopeo proxy unexpectedly exited during request method GetBlob: exit status: 1\nread tcp 10.0.0.1:40112->3.216.34.172:443: read: connection reset by peer".into(),
+                )),
+                Transient,
+            ),
+            (
+                anyhow::Error::new(ProxyError::Configuration(
+                    "Conflicting authentication options".into(),
+                )),
+                Other,
+            ),
+            // I/O errors, e.g. reading the blob stream, by their kind
+            (
+                anyhow::Error::new(std::io::Error::from(IoErrorKind::ConnectionReset))
+                    .context(format!("Layer {digest}")),
+                Transient,
+            ),
+            (
+                anyhow::Error::new(ProxyError::Io(IoErrorKind::UnexpectedEof.into())),
+                Transient,
+            ),
+            // Even if the context mentions e.g. a status
+            (
+                anyhow::Error::new(std::io::Error::from(IoErrorKind::PermissionDenied))
+                    .context("Writing status: 503"),
+                Other,
+            ),
+            // Otherwise the message is classified
+            (
+                anyhow::Error::new(std::io::Error::other(
+                    "read tcp 10.0.0.1:443: connection reset by peer",
+                )),
+                Transient,
+            ),
+            (
+                anyhow::anyhow!("received unexpected HTTP status: 502 Bad Gateway"),
+                Transient,
+            ),
+            (
+                anyhow::Error::new(ImportTimedOut {
+                    timeout: Duration::from_secs(1),
+                }),
+                Other,
+            ),
+            // HTTP statuses from a fetcher, whatever the message
+            (
+                anyhow::Error::new(RegistryStatusError::new(
+                    429,
+                    Some(Duration::from_secs(42)),
+                ))
+                .context("Fetching manifest"),
+                RateLimited(Duration::from_secs(42)),
+            ),
+            (
+                anyhow::Error::new(RegistryStatusError::new(429, None)),
+                RateLimited(DEFAULT_RATE_LIMIT_WAIT),
+            ),
+            (
+                anyhow::Error::new(RegistryStatusError::new(503, None))
+                    .context("Fetching signature"),
+                Transient,
+            ),
+            (
+                anyhow::Error::new(RegistryStatusError::new(404, None)),
+                NotFound,
+            ),
+            (
+                anyhow::Error::new(RegistryStatusError::new(401, None))
+                    .context("status: 503"),
+                Other,
+            ),
+        ];
+        for (e, kind) in cases {
+            assert_eq!(classify_error(&e), kind, "{e:#}");
+        }
+    }
+
+    #[test]
+    fn test_pinned_digest() -> Result<()> {
+        let digest = "sha256:4292d86b0e45b4da2d1b7b0d2a7c86b429a1c3e5e5d0e77a3e2a8b0f4e429b1c";
+        let imgref = |transport, name: &str| ImageReference {
+            transport,
+            name: name.to_string(),
+        };
+        assert_eq!(
+            pinned_digest(&imgref(
+                Transport::Registry,
+                "quay.io/exampleos/exampleos:latest"
+            ))?,
+            None
+        );
+        assert_eq!(
+            pinned_digest(&imgref(Transport::OciDir, "/var/lib/images/os"))?,
+            None
+        );
+        let pinned = imgref(
+            Transport::Registry,
+            &format!("quay.io/exampleos/exampleos@{digest}"),
+        );
+        let expected = Digest::from_str(digest)?;
+        assert_eq!(pinned_digest(&pinned)?, Some(expected.clone()));
+        verify_pinned_digest(&pinned, &expected)?;
+        let other = Digest::from_str(&format!("sha256:{}", "0".repeat(64)))?;
+        assert!(verify_pinned_digest(&pinned, &other).is_err());
+        assert!(pinned_digest(&imgref(Transport::Registry, "quay.io/exampleos@foo")).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_progress_reader_interval() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+        let data = vec![0u8; 8500];
+        let (mut reader, mut rx) = ProgressReader::new(data.as_slice(), Some(4096));
+        let mut buf = [0u8; 1000];
+        let mut updates = Vec::new();
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if rx.has_changed()? {
+                updates.push(*rx.borrow_and_update());
+            }
+            if n == 0 {
+                break;
+            }
+        }
+        // The end of the stream is always reported
+        assert_eq!(updates, [5000, 8500]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_progress_reader_count() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+        // Nothing has been read from an empty stream
+        let (mut reader, rx) = ProgressReader::new(b"".as_slice(), None);
+        reader.read_to_end(&mut Vec::new()).await?;
+        assert_eq!(*rx.borrow(), 0);
+        // The first byte is reported
+        let (mut reader, mut rx) = ProgressReader::new(b"x".as_slice(), None);
+        reader.read_to_end(&mut Vec::new()).await?;
+        assert!(rx.has_changed()?);
+        assert_eq!(*rx.borrow_and_update(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verifying_reader() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+        let data = b"some blob content";
+        let descriptor = |digest: &[u8], size: usize| {
+            let digest = format!("sha256:{}", hex::encode(openssl::sha::sha256(digest)));
+            oci_image::DescriptorBuilder::default()
+                .media_type(oci_image::MediaType::ImageLayer)
+                .digest(Digest::from_str(&digest).unwrap())
+                .size(size as u64)
+                .build()
+                .unwrap()
+        };
+        let read = |descriptor| async move {
+            let mut reader = VerifyingReader::new(data.as_slice(), &descriptor);
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.map(|_| buf)
+        };
+
+        assert_eq!(read(descriptor(data, data.len())).await?, data);
+        let e = read(descriptor(b"other content", data.len()))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("Blob digest mismatch"), "{e}");
+        for size in [data.len() - 1, data.len() + 1] {
+            let e = read(descriptor(data, size)).await.unwrap_err();
+            assert!(e.to_string().contains("Blob size mismatch"), "{e}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_rate_limited() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        let calls = &AtomicU32::new(0);
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let waits = Arc::clone(&waits);
+            RateLimitHandler(Arc::new(move |d| waits.lock().unwrap().push(d)))
+        };
+        let policy = RetryPolicy {
+            rate_limit_handler: Some(handler),
+            ..Default::default()
+        };
+        let f = || async move {
+            if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                anyhow::bail!("429 Too Many Requests; Retry-After: 0")
+            }
+            Ok(42)
+        };
+        assert_eq!(retry_fetch(&policy, f).await.unwrap(), 42);
+        assert_eq!(*waits.lock().unwrap(), vec![Duration::ZERO; 2]);
+
+        // Without a handler, the error is returned directly
+        calls.store(0, Ordering::SeqCst);
+        let r = retry_fetch(&RetryPolicy::default(), f).await;
+        assert!(r.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Other errors are not retried
+        let r = retry_fetch(&policy, || async {
+            Err::<(), _>(anyhow!("manifest unknown"))
+        })
+        .await;
+        assert!(r.is_err());
+        assert_eq!(waits.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        let calls = &AtomicU32::new(0);
+        let policy = RetryPolicy {
+            transient_retries: 3,
+            transient_wait: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let failing = |n: u32, msg: &'static str| {
+            calls.store(0, Ordering::SeqCst);
+            move || async move {
+                if calls.fetch_add(1, Ordering::SeqCst) < n {
+                    anyhow::bail!("{msg}")
+                }
+                Ok(42)
+            }
+        };
+        let unavailable = "received unexpected HTTP status: 503 Service Unavailable";
+        assert_eq!(
+            retry_fetch(&policy, failing(3, unavailable)).await.unwrap(),
+            42
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // The number of retries is limited
+        assert!(retry_fetch(&policy, failing(4, unavailable)).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // By default, nothing is retried
+        let r = retry_fetch(&RetryPolicy::default(), failing(1, unavailable)).await;
+        assert!(r.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        for msg in [
+            "manifest unknown",
+            "Source image rejected: A signature was required, but no signature exists",
+        ] {
+            assert!(retry_fetch(&policy, failing(1, msg)).await.is_err());
+            assert_eq!(calls.load(Ordering::SeqCst), 1, "{msg}");
+        }
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = oci_image::ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(
+                oci_image::DescriptorBuilder::default()
+                    .media_type(oci_image::MediaType::ImageConfig)
+                    .digest(
+                        Digest::from_str(
+                            "sha256:a5b7b1e5e1f6a18c12c95b2e9e0a7b9b47a7d6a3e1cb0b1c1b7a5b1a2f0f7a9b",
+                        )
+                        .unwrap(),
+                    )
+                    .size(42u64)
+                    .build()
+                    .unwrap(),
+            )
+            .layers(Vec::new())
+            .build()
+            .unwrap();
+        let buf = serde_json::to_vec(&manifest).unwrap();
+        assert_eq!(parse_manifest(&buf).unwrap(), manifest);
+
+        let schema1 = [
+            r#"{"schemaVersion": 1, "name": "foo", "tag": "latest", "fsLayers": []}"#,
+            r#"{"schemaVersion": 1, "mediaType": "application/vnd.docker.distribution.manifest.v1+prettyjws", "fsLayers": []}"#,
+        ];
+        for v in schema1 {
+            let e = parse_manifest(v.as_bytes()).unwrap_err();
+            assert!(format!("{e:#}").contains("schema1 manifests are not supported"));
+        }
+        assert!(parse_manifest(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_check_image() {
+        let digest = |c: char| Digest::from_str(&format!("sha256:{}", c.to_string().repeat(64)));
+        let descriptor = |media_type: oci_image::MediaType, c: char, size: u64| {
+            oci_image::DescriptorBuilder::default()
+                .media_type(media_type)
+                .digest(digest(c).unwrap())
+                .size(size)
+                .build()
+                .unwrap()
+        };
+        let manifest = |layers: Vec<oci_image::Descriptor>| {
+            oci_image::ImageManifestBuilder::default()
+                .schema_version(2u32)
+                .config(descriptor(oci_image::MediaType::ImageConfig, 'c', 42))
+                .layers(layers)
+                .build()
+                .unwrap()
+        };
+        let config = |arch: &str, diffids: &[&str], labels: serde_json::Value| {
+            let v = serde_json::json!({
+                "architecture": arch,
+                "os": "linux",
+                "rootfs": { "type": "layers", "diff_ids": diffids },
+                "config": { "Labels": labels },
+            });
+            serde_json::from_value::<oci_image::ImageConfiguration>(v).unwrap()
+        };
+        let diffid = format!("sha256:{}", "d".repeat(64));
+        let commit = "e".repeat(64);
+        let arch = oci_image::Arch::default().to_string();
+
+        let good = manifest(vec![descriptor(
+            oci_image::MediaType::ImageLayerGzip,
+            'a',
+            100,
+        )]);
+        let labels = serde_json::json!({ OSTREE_COMMIT_LABEL: commit, DIFFID_LABEL: diffid });
+        let c = config(&arch, &[&diffid], labels);
+        assert_eq!(check_image(&good, Some(&c)), Vec::new());
+
+        // Every issue is reported
+        let bad = manifest(vec![
+            descriptor(oci_image::MediaType::ImageLayerGzip, 'a', 100),
+            descriptor(oci_image::MediaType::Other("text/plain".into()), 'b', 0),
+        ]);
+        let other_arch = if arch == "arm64" { "amd64" } else { "arm64" };
+        let labels = serde_json::json!({ OSTREE_COMMIT_LABEL: "notachecksum" });
+        let c = config(other_arch, &[&diffid], labels);
+        let issues = check_image(&bad, Some(&c));
+        let severities: Vec<_> = issues.iter().map(|i| i.severity).collect();
+        assert_eq!(
+            severities,
+            [
+                IssueSeverity::Error,
+                IssueSeverity::Warning,
+                IssueSeverity::Error,
+                IssueSeverity::Warning,
+                IssueSeverity::Error
+            ]
+        );
+        assert!(issues[0]
+            .message
+            .contains("unsupported media type text/plain"));
+        assert!(issues[2].message.contains("2 layers"));
+        assert!(issues[3].message.contains(other_arch));
+        assert!(issues[4].message.contains("notachecksum"));
+
+        // Without the configuration, only the manifest is checked
+        let issues = check_image(&bad, None);
+        assert_eq!(issues.len(), 2);
+
+        // Not an encapsulated image
+        let c = config(&arch, &[&diffid], serde_json::json!({}));
+        let issues = check_image(&good, Some(&c));
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].severity, IssueSeverity::Warning);
+        assert_eq!(issues[1].severity, IssueSeverity::Error);
+        assert!(issues[1].message.contains(DIFFID_LABEL));
+        assert_eq!(
+            issues[1].to_string(),
+            format!("error: {}", issues[1].message)
+        );
+    }
+
+    #[test]
+    fn test_max_expansion_ratio() -> Result<()> {
+        use std::io::Write;
+
+        // A layer of zeros compresses extremely well
+        let data = vec![0u8; 4 * 1024 * 1024];
+        let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(&data)?;
+        let layer = enc.finish()?;
+        let gzip = oci_image::MediaType::ImageLayerGzip;
+        let decompress = |media_type: &oci_image::MediaType, src: Vec<u8>, ratio: Option<f64>| {
+            let limit = ratio.map(ExpansionLimit::new);
+            let mut r = decompressor(media_type, std::io::Cursor::new(src), limit.clone())?;
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf)
+                .map_err(|e| ExpansionLimit::map_err(limit.as_deref(), e.into()))?;
+            anyhow::Ok(buf.len())
+        };
+
+        assert_eq!(decompress(&gzip, layer.clone(), None)?, data.len());
+        assert_eq!(decompress(&gzip, layer.clone(), Some(10000.0))?, data.len());
+        let e = decompress(&gzip, layer.clone(), Some(100.0)).unwrap_err();
+        let e = e.downcast_ref::<ExpansionRatioExceeded>().unwrap();
+        assert_eq!(e.max_ratio, 100.0);
+        assert!(e.compressed <= layer.len() as u64);
+        assert!(e.decompressed > 100 * e.compressed);
+        // Uncompressed layers are not affected
+        let uncompressed = oci_image::MediaType::ImageLayer;
+        assert_eq!(
+            decompress(&uncompressed, data.clone(), Some(0.5))?,
+            data.len()
+        );
+        Ok(())
     }
 }
@@ -2,45 +2,54 @@ use super::ImageReference;
 use crate::container::{skopeo, DIFFID_LABEL};
 use crate::container::{store as container_store, Transport};
 use anyhow::{anyhow, Context, Result};
-use camino::Utf8Path;
-use cap_std::fs::Dir;
-use cap_std_ext::cap_std;
 use containers_image_proxy::oci_spec::image as oci_image;
 use std::io::{BufReader, BufWriter};
+use std::sync::Arc;
 
 /// Given an OSTree container image reference, update the detached metadata (e.g. GPG signature)
 /// while preserving all other container image metadata.
 ///
+/// The source image is copied to a temporary directory in `tmpdir`, or in `/var/tmp`
+/// by default, which needs room for the whole image.
+///
 /// The return value is the manifest digest of (e.g. `@sha256:`) the image.
 pub async fn update_detached_metadata(
     src: &ImageReference,
     dest: &ImageReference,
     detached_buf: Option<&[u8]>,
+    tmpdir: Option<&std::path::Path>,
 ) -> Result<oci_image::Digest> {
     // For now, convert the source to a temporary OCI directory, so we can directly
     // parse and manipulate it.  In the future this will be replaced by https://github.com/ostreedev/ostree-rs-ext/issues/153
     // and other work to directly use the containers/image API via containers-image-proxy.
-    let tempdir = tempfile::tempdir_in("/var/tmp")?;
-    let tempsrc = tempdir.path().join("src");
-    let tempsrc_utf8 = Utf8Path::from_path(&tempsrc).ok_or_else(|| anyhow!("Invalid tempdir"))?;
+    let tempdir = super::encapsulate::create_tempdir(tmpdir)?;
+    // Pass the temporary oci directory as the current working directory for the skopeo processes
+    let target_fd = 3i32;
     let tempsrc_ref = ImageReference {
         transport: Transport::OciDir,
-        name: tempsrc_utf8.to_string(),
+        name: format!("/proc/self/fd/{target_fd}"),
     };
+    let tempsrc_fd = || -> Result<_> { Ok((Arc::new(tempdir.try_clone()?.into()), target_fd)) };
 
     // Full copy of the source image
-    let pulled_digest = skopeo::copy(src, &tempsrc_ref, None, None, false)
-        .await
-        .context("Creating temporary copy to OCI dir")?;
+    let pulled_digest = skopeo::copy(
+        src,
+        &tempsrc_ref,
+        None,
+        Some(tempsrc_fd()?),
+        false,
+        None,
+        None,
+    )
+    .await
+    .context("Creating temporary copy to OCI dir")?;
 
     // Copy to the thread
     let detached_buf = detached_buf.map(Vec::from);
-    let tempsrc_ref_path = tempsrc_ref.name.clone();
+    let tempsrc = tempdir.try_clone()?;
     // Fork a thread to do the heavy lifting of filtering the tar stream, rewriting the manifest/config.
     crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
         // Open the temporary OCI directory.
-        let tempsrc = Dir::open_ambient_dir(tempsrc_ref_path, cap_std::ambient_authority())
-            .context("Opening src")?;
         let tempsrc = ocidir::OciDir::open(&tempsrc)?;
 
         // Load the manifest, platform, and config
@@ -133,7 +142,15 @@ pub async fn update_detached_metadata(
 
     // Finally, copy the mutated image back to the target.  For chunked images,
     // because we only changed one layer, skopeo should know not to re-upload shared blobs.
-    crate::container::skopeo::copy(&tempsrc_ref, dest, None, None, false)
-        .await
-        .context("Copying to destination")
+    crate::container::skopeo::copy(
+        &tempsrc_ref,
+        dest,
+        None,
+        Some(tempsrc_fd()?),
+        false,
+        None,
+        None,
+    )
+    .await
+    .context("Copying to destination")
 }
@@ -999,3 +999,215 @@ impl NonOstreeFixture {
         imp.import(prep).await
     }
 }
+
+/// An entry added via [`RepoFixtureBuilder`].
+#[derive(Debug)]
+struct RepoFixtureEntry {
+    def: FileDef,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Builds a [`RepoFixture`], whose commit contains exactly the configured
+/// entries.  This is useful for edge cases (empty directories, long paths,
+/// denormal symbolic links, extended attributes) which the standard fixture
+/// content does not cover.
+///
+/// Entries are owned by root with mode 0644 (0755 for directories) unless
+/// changed with [`Self::owner`] and [`Self::mode`], which like [`Self::xattr`]
+/// apply to the most recently added entry.  Parent directories are created as
+/// needed.
+#[derive(Debug, Default)]
+pub struct RepoFixtureBuilder {
+    entries: Vec<RepoFixtureEntry>,
+}
+
+impl RepoFixtureBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(mut self, path: impl Into<Utf8PathBuf>, ty: FileDefType, mode: u32) -> Self {
+        let def = FileDef {
+            uid: 0,
+            gid: 0,
+            mode,
+            path: Cow::Owned(path.into()),
+            ty,
+        };
+        self.entries.push(RepoFixtureEntry {
+            def,
+            xattrs: Vec::new(),
+        });
+        self
+    }
+
+    fn last(&mut self) -> &mut RepoFixtureEntry {
+        self.entries.last_mut().expect("No entry added")
+    }
+
+    /// Add a regular file.
+    pub fn file(self, path: impl Into<Utf8PathBuf>, contents: impl Into<String>) -> Self {
+        let contents = Cow::Owned(contents.into());
+        self.push(path, FileDefType::Regular(contents), 0o644)
+    }
+
+    /// Add a symbolic link; the target is stored as is, so it may be denormal
+    /// (e.g. `foo//bar`).
+    pub fn symlink(self, path: impl Into<Utf8PathBuf>, target: impl Into<Utf8PathBuf>) -> Self {
+        let target = Cow::Owned(target.into());
+        self.push(path, FileDefType::Symlink(target), 0o777)
+    }
+
+    /// Add a directory, which is empty unless entries are added below it.
+    pub fn dir(self, path: impl Into<Utf8PathBuf>) -> Self {
+        self.push(path, FileDefType::Directory, 0o755)
+    }
+
+    /// Add a configuration file in `/usr/etc`.
+    pub fn usr_etc(self) -> Self {
+        self.file("usr/etc/someconfig.conf", "some config")
+    }
+
+    /// Set the permission bits of the last entry.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.last().def.mode = mode;
+        self
+    }
+
+    /// Set the owner of the last entry.
+    pub fn owner(mut self, uid: u32, gid: u32) -> Self {
+        let def = &mut self.last().def;
+        def.uid = uid;
+        def.gid = gid;
+        self
+    }
+
+    /// Add an extended attribute to the last entry.
+    pub fn xattr(mut self, name: &str, value: impl Into<Vec<u8>>) -> Self {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        self.last().xattrs.push((name, value.into()));
+        self
+    }
+
+    /// Create the repositories, and write the commit to the source repository.
+    #[context("Building repo fixture")]
+    pub fn build(self) -> Result<RepoFixture> {
+        let tempdir = tempfile::tempdir_in("/var/tmp")?;
+        let dir = cap_std::fs::Dir::open_ambient_dir(tempdir.path(), cap_std::ambient_authority())?;
+        let path: &Utf8Path = tempdir.path().try_into().unwrap();
+        let path = path.to_path_buf();
+        dir.create_dir("src")?;
+        let srcrepo =
+            ostree::Repo::create_at_dir(dir.as_fd(), "src/repo", ostree::RepoMode::Archive, None)
+                .context("Creating src/ repo")?;
+        dir.create_dir("dest")?;
+        let destrepo = ostree::Repo::create_at_dir(
+            dir.as_fd(),
+            "dest/repo",
+            ostree::RepoMode::BareUser,
+            None,
+        )?;
+
+        let cancellable = gio::Cancellable::NONE;
+        let tx = srcrepo.auto_transaction(cancellable)?;
+        let root = ostree::MutableTree::new();
+        let default_dirmeta = require_dirmeta(&srcrepo, Utf8Path::new("/"), false)?;
+        root.set_metadata_checksum(&default_dirmeta);
+        for RepoFixtureEntry { def, mut xattrs } in self.entries {
+            let path = def.path.as_ref();
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid path {path}"))?;
+            let parent = match path.parent() {
+                Some(p) if !p.as_str().is_empty() => {
+                    ensure_parent_dirs(&root, path, &default_dirmeta)?
+                }
+                _ => root.clone(),
+            };
+            // The checksum covers the serialized xattrs, so canonicalize their order
+            xattrs.sort();
+            let xattrs = (!xattrs.is_empty()).then(|| xattrs.to_variant());
+            let xattrs = xattrs.as_ref();
+            let checksum = match &def.ty {
+                FileDefType::Regular(contents) => srcrepo.write_regfile_inline(
+                    None,
+                    def.uid,
+                    def.gid,
+                    libc::S_IFREG | def.mode,
+                    xattrs,
+                    contents.as_bytes(),
+                    cancellable,
+                )?,
+                FileDefType::Symlink(target) => srcrepo.write_symlink(
+                    None,
+                    def.uid,
+                    def.gid,
+                    xattrs,
+                    target.as_str(),
+                    cancellable,
+                )?,
+                FileDefType::Directory => {
+                    let finfo = gio::FileInfo::new();
+                    finfo.set_attribute_uint32("unix::uid", def.uid);
+                    finfo.set_attribute_uint32("unix::gid", def.gid);
+                    finfo.set_attribute_uint32("unix::mode", libc::S_IFDIR | def.mode);
+                    let meta = ostree::create_directory_metadata(&finfo, xattrs);
+                    let meta = srcrepo.write_metadata(
+                        ostree::ObjectType::DirMeta,
+                        None,
+                        &meta,
+                        cancellable,
+                    )?;
+                    parent
+                        .ensure_dir(name)?
+                        .set_metadata_checksum(&meta.to_hex());
+                    continue;
+                }
+            };
+            parent
+                .replace_file(name, checksum.as_str())
+                .with_context(|| format!("Writing {path}"))?;
+        }
+        let root = srcrepo.write_mtree(&root, cancellable)?;
+        let root = root.downcast_ref::<ostree::RepoFile>().unwrap();
+        let commit = srcrepo
+            .write_commit_with_time(None, None, None, None, root, 0, cancellable)?
+            .to_string();
+        tx.commit(cancellable)?;
+        Ok(RepoFixture {
+            _tempdir: tempdir,
+            path,
+            srcrepo,
+            destrepo,
+            commit,
+        })
+    }
+}
+
+/// A source repository containing a single commit built by [`RepoFixtureBuilder`],
+/// along with an empty destination repository.
+#[derive(Debug)]
+pub struct RepoFixture {
+    // Just holds a reference
+    _tempdir: tempfile::TempDir,
+    pub path: Utf8PathBuf,
+    srcrepo: ostree::Repo,
+    destrepo: ostree::Repo,
+    /// The checksum of the commit.
+    pub commit: String,
+}
+
+impl RepoFixture {
+    pub fn builder() -> RepoFixtureBuilder {
+        RepoFixtureBuilder::new()
+    }
+
+    pub fn srcrepo(&self) -> &ostree::Repo {
+        &self.srcrepo
+    }
+
+    pub fn destrepo(&self) -> &ostree::Repo {
+        &self.destrepo
+    }
+}
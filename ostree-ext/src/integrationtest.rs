@@ -151,6 +151,20 @@ fn test_proxy_auth() -> Result<()> {
     Ok(())
 }
 
+/// Compute the fs-verity digest of `src` in the same way as the tar import does
+/// with `TarImportOptions::enable_fsverity`, as a hex string.
+pub fn fsverity_digest(src: impl std::io::Read) -> Result<String> {
+    crate::tar::fsverity_digest(src).map(hex::encode)
+}
+
+/// Whether `importer` fetches the image via `containers-image-proxy`.
+pub fn importer_uses_proxy(importer: &crate::container::store::ImageImporter) -> bool {
+    matches!(
+        importer.source,
+        crate::container::store::ImageSource::Proxy { .. }
+    )
+}
+
 /// Create a test fixture in the same way our unit tests does, and print
 /// the location of the temporary directory.  Also export a chunked image.
 /// Useful for debugging things interactively.
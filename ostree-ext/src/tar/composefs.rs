@@ -0,0 +1,404 @@
+//! Compute the composefs image digest of an ostree commit.
+//!
+//! The tree of the commit is described in the composefs dump file format (see
+//! `composefs-dump(5)`), which is passed to `mkcomposefs --print-digest-only`.
+//! In the description, all modification times are zero, and the payload of
+//! each regular file is the relative path of its object in an ostree repository
+//! (e.g. `ab/cdef....file`), as in the composefs images generated by ostree.
+//! Regular files also carry the fs-verity digest of their content, which is
+//! computed here.
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::fmt::Write as _;
+use std::io::{Read, Seek, Write};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+use fn_error_context::context;
+use gio::glib;
+use gio::prelude::*;
+use gvariant::aligned_bytes::TryAsAligned;
+use gvariant::{Marker, Structure};
+use ostree::gio;
+
+use super::export::{load_content, load_metadata};
+use crate::objgv::*;
+
+/// The fs-verity block size used for file digests, as used by composefs.
+const FSVERITY_BLOCK_SIZE: usize = 4096;
+
+/// The size of a SHA-256 digest.
+const SHA256_SIZE: usize = 32;
+
+/// Read a full block from `src`, unless the end of the input is reached first;
+/// returns the number of bytes read.
+fn read_block(src: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match src.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(r) => n += r,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Hash `data` in blocks of [`FSVERITY_BLOCK_SIZE`], zero-padding the last.
+fn hash_blocks(data: &[u8]) -> Vec<u8> {
+    let mut r = Vec::with_capacity(data.len().div_ceil(FSVERITY_BLOCK_SIZE) * SHA256_SIZE);
+    let mut block = [0u8; FSVERITY_BLOCK_SIZE];
+    for chunk in data.chunks(FSVERITY_BLOCK_SIZE) {
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()..].fill(0);
+        r.extend_from_slice(&openssl::sha::sha256(&block));
+    }
+    r
+}
+
+/// Compute the fs-verity digest of the provided content, using SHA-256, a block
+/// size of 4096 and no salt; this is the digest reported by `fsverity digest`.
+pub(crate) fn fsverity_digest(mut src: impl Read) -> Result<[u8; SHA256_SIZE]> {
+    // Hash the data blocks
+    let mut size = 0u64;
+    let mut level = Vec::new();
+    let mut block = vec![0u8; FSVERITY_BLOCK_SIZE];
+    loop {
+        let n = read_block(&mut src, &mut block)?;
+        if n == 0 {
+            break;
+        }
+        size += n as u64;
+        block[n..].fill(0);
+        level.extend_from_slice(&openssl::sha::sha256(&block));
+        if n < FSVERITY_BLOCK_SIZE {
+            break;
+        }
+    }
+    // Then each level of the Merkle tree, until a single hash remains.
+    while level.len() > SHA256_SIZE {
+        level = hash_blocks(&level);
+    }
+    let mut root_hash = [0u8; 64];
+    root_hash[..level.len()].copy_from_slice(&level);
+
+    // struct fsverity_descriptor
+    let mut descriptor = Vec::with_capacity(256);
+    descriptor.push(1); // version
+    descriptor.push(1); // hash_algorithm: SHA-256
+    descriptor.push(FSVERITY_BLOCK_SIZE.trailing_zeros() as u8); // log_blocksize
+    descriptor.push(0); // salt_size
+    descriptor.extend_from_slice(&[0u8; 4]); // reserved
+    descriptor.extend_from_slice(&size.to_le_bytes());
+    descriptor.extend_from_slice(&root_hash);
+    descriptor.extend_from_slice(&[0u8; 32]); // salt
+    descriptor.extend_from_slice(&[0u8; 144]); // reserved
+    debug_assert_eq!(descriptor.len(), 256);
+    Ok(openssl::sha::sha256(&descriptor))
+}
+
+/// `FS_IOC_ENABLE_VERITY`, i.e. `_IOW('f', 133, struct fsverity_enable_arg)`.
+const FS_IOC_ENABLE_VERITY: libc::c_ulong = 0x40806685;
+
+/// `struct fsverity_enable_arg` from `linux/fsverity.h`.
+#[repr(C)]
+struct FsverityEnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+
+/// Enable fs-verity on the file `fd`, which must be opened read-only and not
+/// be open for writing elsewhere, with the same parameters as
+/// [`fsverity_digest`].  Returns `false` if the filesystem does not support
+/// fs-verity (or it is not enabled on it); it is not an error if fs-verity is
+/// already enabled on the file.
+#[allow(unsafe_code)]
+pub(crate) fn enable_fsverity(fd: impl rustix::fd::AsFd) -> std::io::Result<bool> {
+    use rustix::fd::AsRawFd;
+
+    let arg = FsverityEnableArg {
+        version: 1,
+        hash_algorithm: 1, // SHA-256
+        block_size: FSVERITY_BLOCK_SIZE as u32,
+        salt_size: 0,
+        salt_ptr: 0,
+        sig_size: 0,
+        reserved1: 0,
+        sig_ptr: 0,
+        reserved2: [0; 11],
+    };
+    // SAFETY: The argument is a valid `struct fsverity_enable_arg`, without salt
+    // or signature pointers, which outlives the call.
+    let r = unsafe {
+        libc::ioctl(
+            fd.as_fd().as_raw_fd(),
+            FS_IOC_ENABLE_VERITY as _,
+            &arg as *const FsverityEnableArg,
+        )
+    };
+    if r == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EEXIST) => Ok(true),
+        Some(libc::EOPNOTSUPP | libc::ENOTTY) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+/// Append a field to a dump file line, escaping it as required.  An empty
+/// field is written as `-`.
+fn push_escaped(out: &mut String, s: &[u8]) {
+    if s.is_empty() {
+        out.push('-');
+        return;
+    }
+    if s == b"-" {
+        out.push_str("\\x2d");
+        return;
+    }
+    for &c in s {
+        if c.is_ascii_graphic() && c != b'\\' && c != b'=' {
+            out.push(c as char);
+        } else {
+            // Writing to a String cannot fail
+            write!(out, "\\x{c:02x}").unwrap();
+        }
+    }
+}
+
+/// Builds a composefs dump file.
+#[derive(Debug, Default)]
+struct Dumpfile {
+    buf: String,
+}
+
+impl Dumpfile {
+    /// Append an entry; `payload` and `digest` are optional, and `xattrs`
+    /// must be of type `a(ayay)`, as stored by ostree.
+    #[allow(clippy::too_many_arguments)]
+    fn push(
+        &mut self,
+        path: &str,
+        size: u64,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        payload: Option<&str>,
+        digest: Option<&[u8]>,
+        xattrs: Option<&glib::Variant>,
+    ) {
+        let out = &mut self.buf;
+        push_escaped(out, path.as_bytes());
+        write!(out, " {size} {mode:o} 1 {uid} {gid} 0 0.0 ").unwrap();
+        push_escaped(out, payload.unwrap_or_default().as_bytes());
+        // No inline content
+        out.push_str(" - ");
+        match digest {
+            Some(d) => out.push_str(&hex::encode(d)),
+            None => out.push('-'),
+        }
+        for xattr in xattrs.into_iter().flat_map(|x| x.iter()) {
+            let k = xattr.child_value(0).data_as_bytes();
+            let v = xattr.child_value(1).data_as_bytes();
+            // The names are stored with a trailing NUL
+            let k = k.strip_suffix(b"\0").unwrap_or(&k[..]);
+            out.push(' ');
+            push_escaped(out, k);
+            out.push('=');
+            push_escaped(out, &v);
+        }
+        out.push('\n');
+    }
+
+    /// Describe the directory tree `checksum` at `path`, whose metadata is
+    /// `meta_checksum`; `depth` is zero for the root, and may not exceed `max_depth`.
+    fn push_dirtree(
+        &mut self,
+        repo: &ostree::Repo,
+        path: &str,
+        checksum: &str,
+        meta_checksum: &str,
+        depth: u32,
+        max_depth: u32,
+    ) -> Result<()> {
+        if depth > max_depth {
+            anyhow::bail!("Exceeded maximum directory depth {max_depth} at {path}");
+        }
+        let meta_v = load_metadata(repo, ostree::ObjectType::DirMeta, meta_checksum)?;
+        // Safety: We passed the correct variant type just above
+        let meta = ostree::DirMetaParsed::from_variant(&meta_v).unwrap();
+        let xattrs = meta_v.child_value(3);
+        self.push(
+            path,
+            0,
+            meta.mode,
+            meta.uid,
+            meta.gid,
+            None,
+            None,
+            Some(&xattrs),
+        );
+
+        let v = load_metadata(repo, ostree::ObjectType::DirTree, checksum)?;
+        let v = v.data_as_bytes();
+        let v = v.try_as_aligned()?;
+        let v = gv_dirtree!().cast(v);
+        let (files, dirs) = v.to_tuple();
+        let join = |name: &str| {
+            if path == "/" {
+                format!("/{name}")
+            } else {
+                format!("{path}/{name}")
+            }
+        };
+        for file in files {
+            let (name, csum) = file.to_tuple();
+            let subpath = join(name.to_str());
+            let checksum = hex::encode(csum);
+            let (instream, meta, xattrs) = load_content(repo, &checksum)?;
+            let mode = meta.attribute_uint32("unix::mode");
+            let uid = meta.attribute_uint32("unix::uid");
+            let gid = meta.attribute_uint32("unix::gid");
+            if let Some(instream) = instream {
+                let digest = fsverity_digest(instream.into_read())
+                    .with_context(|| format!("Computing fs-verity digest of {checksum}"))?;
+                let (prefix, rest) = checksum.split_at(2);
+                let payload = format!("{prefix}/{rest}.file");
+                self.push(
+                    &subpath,
+                    meta.size() as u64,
+                    mode,
+                    uid,
+                    gid,
+                    Some(&payload),
+                    Some(&digest),
+                    Some(&xattrs),
+                );
+            } else {
+                let target = meta
+                    .symlink_target()
+                    .ok_or_else(|| anyhow!("Missing symlink target"))?;
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Invalid UTF-8 symlink target: {target:?}"))?;
+                self.push(
+                    &subpath,
+                    target.as_bytes().len() as u64,
+                    mode,
+                    uid,
+                    gid,
+                    Some(target),
+                    None,
+                    Some(&xattrs),
+                );
+            }
+        }
+        for item in dirs {
+            let (name, contents_csum, meta_csum) = item.to_tuple();
+            let subpath = join(name.to_str());
+            let contents_csum = hex::encode(contents_csum);
+            let meta_csum = hex::encode(meta_csum);
+            self.push_dirtree(
+                repo,
+                &subpath,
+                &contents_csum,
+                &meta_csum,
+                depth + 1,
+                max_depth,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute the composefs image digest of the tree of a commit object, which
+/// is the fs-verity digest of the image generated by `mkcomposefs`.  Directories
+/// may be nested at most `max_depth` levels deep.
+#[context("Computing composefs digest")]
+pub(crate) fn commit_digest(
+    repo: &ostree::Repo,
+    commit: &glib::Variant,
+    max_depth: u32,
+) -> Result<[u8; SHA256_SIZE]> {
+    let commit_bytes = commit.data_as_bytes();
+    let commit_bytes = commit_bytes.try_as_aligned()?;
+    let commit = gv_commit!().cast(commit_bytes).to_tuple();
+    let contents = hex::encode(commit.6);
+    let metadata_checksum = hex::encode(commit.7);
+    let mut dumpfile = Dumpfile::default();
+    dumpfile.push_dirtree(repo, "/", &contents, &metadata_checksum, 0, max_depth)?;
+
+    let mut tmpf = tempfile::tempfile()?;
+    tmpf.write_all(dumpfile.buf.as_bytes())?;
+    tmpf.seek(std::io::SeekFrom::Start(0))?;
+    let o = Command::new("mkcomposefs")
+        .args(["--from-file", "--print-digest-only", "-"])
+        .stdin(Stdio::from(tmpf))
+        .stderr(Stdio::piped())
+        .output()
+        .context("Spawning mkcomposefs")?;
+    if !o.status.success() {
+        anyhow::bail!(
+            "mkcomposefs failed: {:?}\n{}",
+            o.status,
+            String::from_utf8_lossy(&o.stderr)
+        );
+    }
+    let digest = String::from_utf8(o.stdout).context("Parsing mkcomposefs output")?;
+    let digest = hex::decode(digest.trim()).context("Parsing mkcomposefs output")?;
+    digest
+        .try_into()
+        .map_err(|d: Vec<u8>| anyhow!("Invalid digest length {}", d.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fsverity_digest() -> Result<()> {
+        let digest = |v: &[u8]| fsverity_digest(v).map(hex::encode);
+        // This matches `fsverity digest` for an empty file.
+        assert_eq!(
+            digest(b"")?,
+            "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95"
+        );
+        assert_eq!(
+            digest(b"hello")?,
+            "555b589c26ee43b7a2510e6c67ced9fb3190b6da6e9e683984551f5d77a763de"
+        );
+        // Large enough for two levels of hash blocks
+        assert_eq!(
+            digest(&vec![b'a'; 200 * FSVERITY_BLOCK_SIZE])?,
+            "bb9e4aa4428879f4f1d9479d2a851275cf119291c47879c9cef8bf1b4589a779"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_escaped() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"", "-"),
+            (b"-", "\\x2d"),
+            (b"/usr/bin/foo", "/usr/bin/foo"),
+            (b"a b=c\\d", "a\\x20b\\x3dc\\x5cd"),
+            (b"\xff\n", "\\xff\\x0a"),
+        ];
+        for (input, expected) in cases {
+            let mut s = String::new();
+            push_escaped(&mut s, input);
+            assert_eq!(s.as_str(), *expected);
+        }
+    }
+}
@@ -2,18 +2,25 @@
 
 use crate::chunking;
 use crate::objgv::*;
+use crate::tar::NonUtf8Policy;
 use anyhow::{anyhow, ensure, Context, Result};
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
 use gio::glib;
 use gio::prelude::*;
 use gvariant::aligned_bytes::TryAsAligned;
 use gvariant::{Marker, Structure};
+use indexmap::IndexSet;
 use ostree::gio;
 use std::borrow::Borrow;
 use std::borrow::Cow;
-use std::collections::HashSet;
-use std::io::BufReader;
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Read, Seek, Write};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 /// The repository mode generated by a tar export stream.
 pub const BARE_SPLIT_XATTRS_MODE: &str = "bare-split-xattrs";
@@ -22,6 +29,13 @@ pub const BARE_SPLIT_XATTRS_MODE: &str = "bare-split-xattrs";
 const SYSROOT: &str = "sysroot";
 // This way the default ostree -> sysroot/ostree symlink works.
 const OSTREEDIR: &str = "sysroot/ostree";
+// The alternative location used with `ExportOptions::flat_ostree_dir`.
+const OSTREEDIR_FLAT: &str = "ostree";
+// The directory which is special cased by `ExportOptions::var_policy`.
+const VAR: &str = "var";
+/// The checksum of the empty dirtree object.
+const EMPTY_DIRTREE_CHECKSUM: &str =
+    "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d";
 // The ref added (under ostree/) in the exported OSTree repo pointing at the commit.
 #[allow(dead_code)]
 const OSTREEREF: &str = "encapsulated";
@@ -34,6 +48,10 @@ const OSTREEREF: &str = "encapsulated";
 const TAR_PATH_PREFIX_V0: &str = "./";
 
 /// The base repository configuration that identifies this is a tar export.
+///
+/// This is fixed rather than derived from the export options: `repo_version=1`
+/// is the only repository version which ostree supports, and the importer
+/// and ostree expect the `bare-split-xattrs` mode from a tar export.
 // See https://github.com/ostreedev/ostree/issues/2499
 const REPO_CONFIG: &str = r#"[core]
 repo_version=1
@@ -44,6 +62,50 @@ mode=bare-split-xattrs
 /// System calls are expensive.
 const BUF_CAPACITY: usize = 131072;
 
+/// The granularity at which we look for holes when generating sparse entries.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// The path of the extended attributes sidecar in the checkout view written
+/// when [`ExportOptions::xattrs_sidecar`] is set.
+pub const XATTRS_SIDECAR_PATH: &str = ".ostree-xattrs";
+
+/// The path of the integrity manifest written when
+/// [`ExportOptions::emit_integrity_manifest`] is set, relative to the embedded
+/// repository (e.g. `sysroot/ostree/repo`).
+pub const INTEGRITY_MANIFEST_PATH: &str = "integrity-manifest";
+
+/// The GVariant type of each entry in the xattrs sidecar: a path, and its
+/// extended attributes.
+pub(crate) const XATTRS_SIDECAR_ENTRY_TYPE: &str = "(aya(ayay))";
+
+/// The name of the PAX global extended header entry written when
+/// [`ExportOptions::pax_global`] is set; this is the name used by GNU tar.
+const PAX_GLOBAL_HEADER_PATH: &str = "pax_global_header";
+
+/// The PAX extended header record which marks an entry as compressed when
+/// [`ExportOptions::compress_files`] is set; the value is the compression
+/// format, which is currently always `gzip`.
+pub const COMPRESSION_PAX_KEY: &str = "OSTREE.compression";
+
+/// The key in the detached commit metadata of the tar export which holds the
+/// composefs image digest when [`ExportOptions::composefs`] is set, as a byte
+/// array (`ay`) of 32 bytes.
+pub const COMPOSEFS_DIGEST_KEY: &str = "ostree.tar.composefs-digest";
+
+/// The directory used for the names of per-entry PAX extended headers.
+const PAX_HEADER_DIR: &str = "PaxHeaders";
+
+/// The PAX extended header record used to pad the stream before a content
+/// object when [`ExportOptions::align_content`] is set; its value is ignored.
+const PADDING_PAX_KEY: &str = "OSTREE.padding";
+
+/// The size of a tar header, and the granularity of tar entry data.
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// The default limit on directory nesting used when [`ExportOptions::max_depth`]
+/// is unset; this bounds stack usage while traversing a commit.
+pub const DEFAULT_MAX_DEPTH: u32 = 1024;
+
 /// Convert /usr/etc back to /etc
 fn map_path(p: &Utf8Path) -> std::borrow::Cow<Utf8Path> {
     match p.strip_prefix("./usr/etc") {
@@ -66,44 +128,75 @@ struct OstreeTarWriter<'a, W: std::io::Write> {
     repo: &'a ostree::Repo,
     commit_checksum: &'a str,
     commit_object: glib::Variant,
+    /// The detached metadata of the commit, if any
+    commitmeta: Option<glib::Variant>,
     out: &'a mut tar::Builder<W>,
-    #[allow(dead_code)]
     options: ExportOptions,
     wrote_initdirs: bool,
     /// True if we're only writing directories
     structure_only: bool,
+    /// True if we're writing objects, but not the checkout view
+    omit_checkout: bool,
     wrote_vartmp: bool, // Set if the ostree commit contains /var/tmp
     wrote_dirtree: HashSet<String>,
     wrote_dirmeta: HashSet<String>,
     wrote_content: HashSet<String>,
     wrote_xattrs: HashSet<String>,
+    /// If set, xattrs objects and their links are written here instead of `out`
+    xattrs_out: Option<tar::Builder<&'a mut dyn std::io::Write>>,
+    /// The number of bytes written to `out`, if known
+    position: Option<Rc<Cell<u64>>>,
+    /// Extended attributes by path, if writing an xattrs sidecar
+    sidecar_xattrs: Vec<glib::Variant>,
+    /// The objects written, if writing an object list
+    object_list: Option<Vec<(ostree::ObjectType, String)>>,
+    /// The integrity manifest, if one is being written
+    integrity_manifest: Option<String>,
+    /// The number of content bytes in the last progress update
+    progress_reported: u64,
+    stats: ExportStats,
 }
 
-pub(crate) fn object_path(objtype: ostree::ObjectType, checksum: &str) -> Utf8PathBuf {
-    let suffix = match objtype {
+/// The name of an object type, as used for the suffix of object paths.
+fn objtype_name(objtype: ostree::ObjectType) -> &'static str {
+    match objtype {
         ostree::ObjectType::Commit => "commit",
         ostree::ObjectType::CommitMeta => "commitmeta",
         ostree::ObjectType::DirTree => "dirtree",
         ostree::ObjectType::DirMeta => "dirmeta",
         ostree::ObjectType::File => "file",
         o => panic!("Unexpected object type: {:?}", o),
-    };
-    let (first, rest) = checksum.split_at(2);
-    format!("{}/repo/objects/{}/{}.{}", OSTREEDIR, first, rest, suffix).into()
+    }
+}
+
+pub(crate) fn object_path(objtype: ostree::ObjectType, checksum: &str) -> Utf8PathBuf {
+    object_path_in(OSTREEDIR, ObjectLayout::Default, objtype, checksum)
+}
+
+/// The path of an object in the repository under `ostreedir`.
+fn object_path_in(
+    ostreedir: &str,
+    layout: ObjectLayout,
+    objtype: ostree::ObjectType,
+    checksum: &str,
+) -> Utf8PathBuf {
+    let suffix = objtype_name(objtype);
+    let stem = layout.object_stem(checksum);
+    format!("{}/repo/objects/{}.{}", ostreedir, stem, suffix).into()
 }
 
-fn v1_xattrs_object_path(checksum: &str) -> Utf8PathBuf {
-    let (first, rest) = checksum.split_at(2);
-    format!("{}/repo/objects/{}/{}.file-xattrs", OSTREEDIR, first, rest).into()
+fn v1_xattrs_object_path(ostreedir: &str, layout: ObjectLayout, checksum: &str) -> Utf8PathBuf {
+    let stem = layout.object_stem(checksum);
+    format!("{}/repo/objects/{}.file-xattrs", ostreedir, stem).into()
 }
 
-fn v1_xattrs_link_object_path(checksum: &str) -> Utf8PathBuf {
-    let (first, rest) = checksum.split_at(2);
-    format!(
-        "{}/repo/objects/{}/{}.file-xattrs-link",
-        OSTREEDIR, first, rest
-    )
-    .into()
+fn v1_xattrs_link_object_path(
+    ostreedir: &str,
+    layout: ObjectLayout,
+    checksum: &str,
+) -> Utf8PathBuf {
+    let stem = layout.object_stem(checksum);
+    format!("{}/repo/objects/{}.file-xattrs-link", ostreedir, stem).into()
 }
 
 /// Check for "denormal" symlinks which contain "//"
@@ -117,10 +210,201 @@ fn symlink_is_denormal(target: &str) -> bool {
     target.contains("//")
 }
 
+/// Compute a symlink target for `dest` (a path in the checkout view) which
+/// resolves to `target` (a path relative to the root of the tar stream).
+fn relative_link_target(dest: &Utf8Path, target: &Utf8Path) -> Utf8PathBuf {
+    let depth = dest
+        .parent()
+        .map(|p| {
+            p.components()
+                .filter(|c| matches!(c, Utf8Component::Normal(_)))
+                .count()
+        })
+        .unwrap_or_default();
+    let mut r = Utf8PathBuf::new();
+    for _ in 0..depth {
+        r.push("..");
+    }
+    r.push(target);
+    r
+}
+
+/// Scan the input, returning the `(offset, length)` ranges which contain data.
+/// Everything else is a hole, i.e. zeros.
+fn find_data_segments(mut r: impl std::io::Read) -> Result<Vec<(u64, u64)>> {
+    let mut buf = vec![0u8; SPARSE_BLOCK_SIZE];
+    let mut segments: Vec<(u64, u64)> = Vec::new();
+    let mut offset = 0u64;
+    loop {
+        // Fill the whole block so holes are detected at a consistent granularity.
+        let mut n = 0;
+        while n < buf.len() {
+            let r = r.read(&mut buf[n..])?;
+            if r == 0 {
+                break;
+            }
+            n += r;
+        }
+        if n == 0 {
+            break;
+        }
+        if buf[..n].iter().any(|&b| b != 0) {
+            match segments.last_mut() {
+                Some((start, len)) if *start + *len == offset => *len += n as u64,
+                _ => segments.push((offset, n as u64)),
+            }
+        }
+        offset += n as u64;
+        if n < buf.len() {
+            break;
+        }
+    }
+    Ok(segments)
+}
+
+/// Reads only the data segments of a sparse file.
+struct SparseDataReader<R> {
+    inner: R,
+    pos: u64,
+    /// The end of the current data segment.
+    end: u64,
+    segments: std::vec::IntoIter<(u64, u64)>,
+}
+
+impl<R: std::io::Read> SparseDataReader<R> {
+    fn new(inner: R, segments: Vec<(u64, u64)>) -> Self {
+        Self {
+            inner,
+            pos: 0,
+            end: 0,
+            segments: segments.into_iter(),
+        }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for SparseDataReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.end {
+            let Some((offset, len)) = self.segments.next() else {
+                return Ok(0);
+            };
+            // Skip over the hole
+            let skip = offset - self.pos;
+            let skipped = std::io::copy(&mut (&mut self.inner).take(skip), &mut std::io::sink())?;
+            if skipped != skip {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.pos = offset;
+            self.end = offset + len;
+        }
+        let max = buf.len().min((self.end - self.pos) as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Encode a PAX extended header record, which is prefixed by its own length
+/// in decimal (including the length field itself).
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    // The space, `=` and trailing newline
+    let base = key.as_bytes().len() + value.as_bytes().len() + 3;
+    let mut len = base + 1;
+    while base + len.to_string().len() != len {
+        len = base + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n").into_bytes()
+}
+
+/// The cause of a failed tar export, for callers which need to handle specific
+/// failures.  Errors from the export functions may carry this as a source, which
+/// can be found with e.g. [`anyhow::Error::downcast_ref`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExportError {
+    /// An object referenced by the commit is missing from the repository.
+    MissingObject {
+        /// The checksum of the object.
+        checksum: String,
+    },
+    /// An object in the repository is corrupted.
+    Corrupt {
+        /// The checksum of the object.
+        checksum: String,
+    },
+    /// Reading object content or writing the output failed.
+    Io(std::io::Error),
+    /// The export was stopped via [`ExportOptions::stop_flag`] or
+    /// [`ExportOptions::cancellable`].
+    Cancelled,
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingObject { checksum } => write!(f, "Missing object {checksum}"),
+            Self::Corrupt { checksum } => write!(f, "Corrupted object {checksum}"),
+            Self::Io(_) => f.write_str("I/O error"),
+            Self::Cancelled => f.write_str("Export cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Convert an error loading the object `checksum`, using an [`ExportError`] if
+/// the object is missing or corrupted.
+fn map_load_error(e: glib::Error, checksum: &str) -> anyhow::Error {
+    let checksum = checksum.to_string();
+    if e.matches(gio::IOErrorEnum::NotFound) {
+        ExportError::MissingObject { checksum }.into()
+    } else if e.matches(gio::IOErrorEnum::InvalidData) {
+        anyhow::Error::new(ExportError::Corrupt { checksum }).context(e)
+    } else {
+        e.into()
+    }
+}
+
+/// Load a metadata object, which must be in normal form.
+pub(super) fn load_metadata(
+    repo: &ostree::Repo,
+    objtype: ostree::ObjectType,
+    checksum: &str,
+) -> Result<glib::Variant> {
+    let v = repo
+        .load_variant(objtype, checksum)
+        .map_err(|e| map_load_error(e, checksum))?;
+    if !v.is_normal_form() {
+        let checksum = checksum.to_string();
+        return Err(ExportError::Corrupt { checksum }.into());
+    }
+    Ok(v)
+}
+
+/// Load a content object.
+pub(super) fn load_content(
+    repo: &ostree::Repo,
+    checksum: &str,
+) -> Result<(Option<gio::InputStream>, gio::FileInfo, glib::Variant)> {
+    repo.load_file(checksum, gio::Cancellable::NONE)
+        .map_err(|e| map_load_error(e, checksum))
+}
+
 pub(crate) fn tar_append_default_data(
     out: &mut tar::Builder<impl std::io::Write>,
     path: &Utf8Path,
     buf: &[u8],
+    mtime: Option<u64>,
 ) -> Result<()> {
     let mut h = tar::Header::new_gnu();
     h.set_entry_type(tar::EntryType::Regular);
@@ -128,7 +412,32 @@ pub(crate) fn tar_append_default_data(
     h.set_gid(0);
     h.set_mode(0o644);
     h.set_size(buf.len() as u64);
-    out.append_data(&mut h, path, buf).map_err(Into::into)
+    if let Some(mtime) = mtime {
+        h.set_mtime(mtime);
+    }
+    out.append_data(&mut h, path, buf)
+        .map_err(|e| ExportError::Io(e).into())
+}
+
+/// Add a hardlink entry with default permissions (root/root 0644).
+fn tar_append_default_hardlink(
+    out: &mut tar::Builder<impl std::io::Write>,
+    path: &Utf8Path,
+    link_target: &Utf8Path,
+    mtime: Option<u64>,
+) -> Result<()> {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(tar::EntryType::Link);
+    h.set_uid(0);
+    h.set_gid(0);
+    h.set_mode(0o644);
+    h.set_size(0);
+    if let Some(mtime) = mtime {
+        h.set_mtime(mtime);
+    }
+    out.append_link(&mut h, path, link_target)
+        .map_err(ExportError::Io)?;
+    Ok(())
 }
 
 impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
@@ -138,22 +447,53 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         out: &'a mut tar::Builder<W>,
         options: ExportOptions,
     ) -> Result<Self> {
-        let commit_object = repo.load_commit(commit_checksum)?.0;
-        let r = Self {
+        let commit_object = load_metadata(repo, ostree::ObjectType::Commit, commit_checksum)?;
+        let commitmeta =
+            repo.read_commit_detached_metadata(commit_checksum, gio::Cancellable::NONE)?;
+        Ok(Self::new_for_commit_object(
+            repo,
+            commit_checksum,
+            commit_object,
+            commitmeta,
+            out,
+            options,
+        ))
+    }
+
+    /// Create a writer for a commit object which is not necessarily in the repository.
+    fn new_for_commit_object(
+        repo: &'a ostree::Repo,
+        commit_checksum: &'a str,
+        commit_object: glib::Variant,
+        commitmeta: Option<glib::Variant>,
+        out: &'a mut tar::Builder<W>,
+        options: ExportOptions,
+    ) -> Self {
+        let object_list = options.emit_object_list.as_ref().map(|_| Vec::new());
+        let integrity_manifest = options.emit_integrity_manifest.then(String::new);
+        Self {
             repo,
             commit_checksum,
             commit_object,
+            commitmeta,
             out,
             options,
             wrote_initdirs: false,
             structure_only: false,
+            omit_checkout: false,
             wrote_vartmp: false,
             wrote_dirmeta: HashSet::new(),
             wrote_dirtree: HashSet::new(),
             wrote_content: HashSet::new(),
             wrote_xattrs: HashSet::new(),
-        };
-        Ok(r)
+            xattrs_out: None,
+            position: None,
+            sidecar_xattrs: Vec::new(),
+            object_list,
+            integrity_manifest,
+            progress_reported: 0,
+            stats: Default::default(),
+        }
     }
 
     /// Convert the ostree mode to tar mode.
@@ -163,33 +503,258 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         mode & !libc::S_IFMT
     }
 
+    /// Return an error if the file at `path` in the checkout has the setuid or
+    /// setgid bit set in `mode` and is not in [`ExportOptions::forbid_setuid`].
+    fn check_setuid(&self, path: &Utf8Path, mode: u32) -> Result<()> {
+        let Some(allowed) = self.options.forbid_setuid.as_ref() else {
+            return Ok(());
+        };
+        if mode & (libc::S_ISUID | libc::S_ISGID) == 0 {
+            return Ok(());
+        }
+        let relpath = path.strip_prefix(TAR_PATH_PREFIX_V0).unwrap_or(path);
+        if allowed
+            .iter()
+            .any(|p| p.as_str().trim_start_matches('/') == relpath.as_str())
+        {
+            return Ok(());
+        }
+        anyhow::bail!("Found setuid or setgid file /{relpath} with mode {mode:#o}");
+    }
+
+    /// Like [`Self::check_setuid`], for the content object `checksum` at `path`,
+    /// so that it can be checked before the object is written.
+    fn check_content_setuid(&self, path: &Utf8Path, checksum: &str) -> Result<()> {
+        if self.options.forbid_setuid.is_none() {
+            return Ok(());
+        }
+        let (_, meta, _) = load_content(self.repo, checksum)?;
+        self.check_setuid(path, self.filter_mode(meta.attribute_uint32("unix::mode")))
+    }
+
+    /// Set the user and group names in the header from [`ExportOptions::owner_names`].
+    fn set_owner_names(&self, h: &mut tar::Header) -> Result<()> {
+        let Some(names) = self.options.owner_names.as_ref() else {
+            return Ok(());
+        };
+        if let Some(name) = names.get(&(h.uid()? as u32)) {
+            h.set_username(name)?;
+        }
+        if let Some(name) = names.get(&(h.gid()? as u32)) {
+            h.set_groupname(name)?;
+        }
+        Ok(())
+    }
+
+    /// Return whether `path` in the checkout view is within [`ExportOptions::subpath`]
+    /// and [`ExportOptions::include_paths`], or with `ancestors`, whether it
+    /// may also be an ancestor of such a path.
+    fn is_included(&self, path: &Utf8Path, ancestors: bool) -> bool {
+        let path = path.strip_prefix(TAR_PATH_PREFIX_V0).unwrap_or(path);
+        let matches = |p: &Utf8PathBuf| {
+            let p = p
+                .components()
+                .filter(|c| matches!(c, Utf8Component::Normal(_)))
+                .collect::<Utf8PathBuf>();
+            let p = map_path_v1(&p);
+            path.starts_with(p) || (ancestors && p.starts_with(path))
+        };
+        let options = &self.options;
+        options.subpath.as_ref().map_or(true, matches)
+            && options
+                .include_paths
+                .as_deref()
+                .map_or(true, |include| include.iter().any(matches))
+    }
+
+    /// Return whether the directory at `path` in the checkout view must be
+    /// traversed to write the included paths; see [`Self::is_included`].
+    fn is_traversed(&self, path: &Utf8Path) -> bool {
+        // `/etc` is written from `/usr/etc`
+        self.is_included(path, true)
+            || (path == Utf8Path::new("./usr") && self.is_included("etc".into(), true))
+    }
+
+    /// Record the extended attributes of a path in the checkout view, if writing
+    /// an xattrs sidecar.
+    fn record_sidecar_xattrs(&mut self, path: &Utf8Path, xattrs: &glib::Variant) {
+        if !self.options.xattrs_sidecar || xattrs.n_children() == 0 {
+            return;
+        }
+        let path = path.strip_prefix(TAR_PATH_PREFIX_V0).unwrap_or(path);
+        let path = if path.as_str().is_empty() {
+            "."
+        } else {
+            path.as_str()
+        };
+        let entry = glib::Variant::tuple_from_iter([path.as_bytes().to_variant(), xattrs.clone()]);
+        self.sidecar_xattrs.push(entry);
+    }
+
+    /// Fail with [`ExportError::Cancelled`] if the export has been stopped.
+    fn check_stopped(&self) -> Result<()> {
+        let stopped = self
+            .options
+            .stop_flag
+            .as_ref()
+            .map_or(false, |f| f.is_stopped());
+        let cancelled = self
+            .options
+            .cancellable
+            .as_ref()
+            .map_or(false, |c| c.is_cancelled());
+        if stopped || cancelled {
+            return Err(ExportError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Send a progress update if at least [`ExportOptions::progress_interval_bytes`]
+    /// of content have been written since the last one, or if `last` is set.
+    fn report_progress(&mut self, last: bool) {
+        let Some(progress) = self.options.progress.as_ref() else {
+            return;
+        };
+        let written = self.stats.content_bytes;
+        let interval = self.options.progress_interval_bytes.unwrap_or(0).max(1);
+        if !last && written - self.progress_reported < interval {
+            return;
+        }
+        self.progress_reported = written;
+        progress.0.send_replace(written);
+    }
+
+    /// Account for a new entry at `path`; this must be called before writing it.
+    /// Returns an error if this would exceed [`ExportOptions::max_entries`].
+    fn count_entry(&mut self, path: &Utf8Path) -> Result<()> {
+        let entries = self.stats.entries + 1;
+        if let Some(max) = self.options.max_entries {
+            if entries > max {
+                anyhow::bail!("Exceeded maximum of {max} tar entries at {path}");
+            }
+        }
+        self.stats.entries = entries;
+        Ok(())
+    }
+
+    /// Set the modification time in the header from [`ExportOptions::mtime`].
+    fn set_mtime(&self, h: &mut tar::Header) {
+        if let Some(mtime) = self.options.mtime {
+            h.set_mtime(mtime);
+        }
+    }
+
+    /// Write a PAX global extended header containing `records`.
+    fn append_pax_global(&mut self, records: &[(String, String)]) -> Result<()> {
+        let mut data = Vec::new();
+        for (k, v) in records {
+            ensure!(
+                !k.is_empty() && !k.contains('='),
+                "Invalid PAX header key: {k:?}"
+            );
+            data.extend_from_slice(&pax_record(k, v));
+        }
+        let path = Utf8Path::new(PAX_GLOBAL_HEADER_PATH);
+        self.count_entry(path)?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::XGlobalHeader);
+        h.set_uid(0);
+        h.set_gid(0);
+        h.set_mode(0o644);
+        h.set_mtime(0);
+        self.set_mtime(&mut h);
+        h.set_size(data.len() as u64);
+        self.out
+            .append_data(&mut h, path, data.as_slice())
+            .map_err(ExportError::Io)?;
+        Ok(())
+    }
+
+    /// Write a PAX extended header marking the following entry at `path` as
+    /// gzip compressed.
+    fn append_compression_marker(&mut self, path: &Utf8Path) -> Result<()> {
+        let data = pax_record(COMPRESSION_PAX_KEY, "gzip");
+        let name = Utf8Path::new(PAX_HEADER_DIR).join(path.file_name().unwrap_or_default());
+        self.count_entry(&name)?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::XHeader);
+        h.set_uid(0);
+        h.set_gid(0);
+        h.set_mode(0o644);
+        h.set_mtime(0);
+        self.set_mtime(&mut h);
+        h.set_size(data.len() as u64);
+        self.out
+            .append_data(&mut h, &name, data.as_slice())
+            .map_err(ExportError::Io)?;
+        Ok(())
+    }
+
+    /// Write a PAX extended header for the following entry at `path`, sized such
+    /// that the data of that entry starts at a multiple of `align`; see
+    /// [`ExportOptions::align_content`].  The padding depends only on the current
+    /// offset in the stream.
+    fn append_alignment_padding(&mut self, path: &Utf8Path, align: u64) -> Result<()> {
+        // SAFETY: Checked when starting the export
+        let position = self.position.as_ref().unwrap().get();
+        // The data follows the header of the entry itself
+        let gap = (align - (position + TAR_BLOCK_SIZE) % align) % align;
+        if gap == 0 {
+            return Ok(());
+        }
+        debug_assert_eq!(gap % TAR_BLOCK_SIZE, 0);
+        // The padding entry is a header, and data filling the remaining blocks.
+        let len = (gap - TAR_BLOCK_SIZE) as usize;
+        let data = if len == 0 {
+            Vec::new()
+        } else {
+            // The space, `=` and trailing newline
+            let fixed = PADDING_PAX_KEY.as_bytes().len() + 3 + len.to_string().len();
+            pax_record(PADDING_PAX_KEY, &"0".repeat(len - fixed))
+        };
+        debug_assert_eq!(data.len(), len);
+        let name = Utf8Path::new(PAX_HEADER_DIR).join(path.file_name().unwrap_or_default());
+        self.count_entry(&name)?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::XHeader);
+        h.set_uid(0);
+        h.set_gid(0);
+        h.set_mode(0o644);
+        h.set_mtime(0);
+        self.set_mtime(&mut h);
+        h.set_size(data.len() as u64);
+        self.out
+            .append_data(&mut h, &name, data.as_slice())
+            .map_err(ExportError::Io)?;
+        Ok(())
+    }
+
     /// Add a directory entry with default permissions (root/root 0755)
     fn append_default_dir(&mut self, path: &Utf8Path) -> Result<()> {
+        self.count_entry(path)?;
         let mut h = tar::Header::new_gnu();
         h.set_entry_type(tar::EntryType::Directory);
         h.set_uid(0);
         h.set_gid(0);
         h.set_mode(0o755);
         h.set_size(0);
-        self.out.append_data(&mut h, path, &mut std::io::empty())?;
+        self.set_mtime(&mut h);
+        self.out
+            .append_data(&mut h, path, &mut std::io::empty())
+            .map_err(ExportError::Io)?;
         Ok(())
     }
 
     /// Add a regular file entry with default permissions (root/root 0644)
     fn append_default_data(&mut self, path: &Utf8Path, buf: &[u8]) -> Result<()> {
-        tar_append_default_data(self.out, path, buf)
+        self.count_entry(path)?;
+        tar_append_default_data(self.out, path, buf, self.options.mtime)
     }
 
-    /// Add an hardlink entry with default permissions (root/root 0644)
-    fn append_default_hardlink(&mut self, path: &Utf8Path, link_target: &Utf8Path) -> Result<()> {
-        let mut h = tar::Header::new_gnu();
-        h.set_entry_type(tar::EntryType::Link);
-        h.set_uid(0);
-        h.set_gid(0);
-        h.set_mode(0o644);
-        h.set_size(0);
-        self.out.append_link(&mut h, path, link_target)?;
-        Ok(())
+    /// Whether the directory `name` at the root of the commit is omitted, as
+    /// it is replaced by the directory containing the repository.
+    fn is_omitted_root_dir(&self, name: &str) -> bool {
+        name == SYSROOT || (self.options.flat_ostree_dir && name == OSTREEDIR_FLAT)
     }
 
     /// Write the initial /sysroot/ostree/repo structure.
@@ -198,7 +763,8 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             return Ok(());
         }
 
-        let objdir: Utf8PathBuf = format!("{}/repo/objects", OSTREEDIR).into();
+        let ostreedir = self.options.ostreedir();
+        let objdir: Utf8PathBuf = format!("{}/repo/objects", ostreedir).into();
         // Add all parent directories
         let parent_dirs = {
             let mut parts: Vec<_> = objdir.ancestors().collect();
@@ -229,13 +795,13 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             "tmp/cache",
         ];
         for d in subdirs {
-            let path: Utf8PathBuf = format!("{}/repo/{}", OSTREEDIR, d).into();
+            let path: Utf8PathBuf = format!("{}/repo/{}", ostreedir, d).into();
             self.append_default_dir(&path)?;
         }
 
         // Repository configuration file.
         {
-            let path = format!("{}/repo/config", OSTREEDIR);
+            let path = format!("{}/repo/config", ostreedir);
             self.append_default_data(Utf8Path::new(&path), REPO_CONFIG.as_bytes())?;
         }
 
@@ -245,56 +811,150 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
 
     /// Recursively serialize a commit object to the target tar stream.
     fn write_commit(&mut self) -> Result<()> {
-        let cancellable = gio::Cancellable::NONE;
-
         let commit_bytes = self.commit_object.data_as_bytes();
         let commit_bytes = commit_bytes.try_as_aligned()?;
         let commit = gv_commit!().cast(commit_bytes);
         let commit = commit.to_tuple();
         let contents = hex::encode(commit.6);
         let metadata_checksum = &hex::encode(commit.7);
-        let metadata_v = self
-            .repo
-            .load_variant(ostree::ObjectType::DirMeta, metadata_checksum)?;
+        let metadata_v = load_metadata(self.repo, ostree::ObjectType::DirMeta, metadata_checksum)?;
         // Safety: We passed the correct variant type just above
         let metadata = &ostree::DirMetaParsed::from_variant(&metadata_v).unwrap();
         let rootpath = Utf8Path::new(TAR_PATH_PREFIX_V0);
 
+        ensure!(
+            self.options.align_content.is_none() || self.position.is_some(),
+            "Aligning content requires writing the whole archive; use export_commit()"
+        );
+        ensure!(
+            self.options.blocking_factor.is_none() || self.position.is_some(),
+            "A blocking factor requires writing the whole archive; use export_commit()"
+        );
+        ensure!(
+            self.options.compression.is_none() || self.position.is_some(),
+            "Compression requires writing the whole archive; use export_commit()"
+        );
+
+        if let Some(records) = self.options.pax_global.clone() {
+            self.append_pax_global(&records)?;
+        }
+
         // We need to write the root directory, before we write any objects.  This should be the very
         // first thing.
         self.append_dir(rootpath, metadata)?;
-
-        // Now, we create sysroot/ and everything under it
-        self.write_repo_structure()?;
-
-        self.append_commit_object()?;
+        self.record_sidecar_xattrs(rootpath, &metadata_v.child_value(3));
+
+        // Now, we create sysroot/ and everything under it, unless only the checkout
+        // view is written.
+        if !self.options.xattrs_sidecar {
+            self.write_repo_structure()?;
+            self.append_commit_object()?;
+            self.append_parent_commits()?;
+        }
 
         // The ostree dirmeta object for the root.
         self.append(ostree::ObjectType::DirMeta, metadata_checksum, &metadata_v)?;
 
+        // If requested, write the content objects in a specific order up front.
+        let content_sort = self.options.content_sort;
+        if !self.structure_only
+            && (self.options.object_order.is_some() || content_sort != ContentSort::TreeOrder)
+        {
+            let mut all_content = IndexSet::new();
+            let rootpath = Utf8Path::new(TAR_PATH_PREFIX_V0);
+            self.collect_content(rootpath, &contents, 0, &mut all_content)?;
+            let ordered = if let Some(order) = self.options.object_order.as_deref() {
+                let ordered = order.iter().filter(|c| all_content.contains(c.as_str()));
+                let ordered = ordered.cloned().collect::<Vec<_>>();
+                ordered.into_iter().chain(all_content).collect::<Vec<_>>()
+            } else {
+                self.sort_content_by_size(all_content, content_sort)?
+            };
+            for checksum in ordered.iter() {
+                self.append_content(checksum)?;
+            }
+        }
+
         // Recurse and write everything else.
-        self.append_dirtree(
-            Utf8Path::new(TAR_PATH_PREFIX_V0),
-            contents,
-            true,
-            cancellable,
-        )?;
+        self.append_dirtree(Utf8Path::new(TAR_PATH_PREFIX_V0), contents, 0)?;
+
+        self.append_standard_var()?;
+
+        if self.options.xattrs_sidecar {
+            let xattrs = std::mem::take(&mut self.sidecar_xattrs);
+            let xattrs = glib::Variant::array_from_iter_with_type(
+                glib::VariantTy::new(XATTRS_SIDECAR_ENTRY_TYPE).unwrap(),
+                xattrs,
+            );
+            let path = Utf8Path::new(TAR_PATH_PREFIX_V0).join(XATTRS_SIDECAR_PATH);
+            self.append_default_data(&path, &xattrs.data_as_bytes())?;
+        }
+
+        if let Some(manifest) = self.integrity_manifest.take() {
+            let path = Utf8Path::new(self.options.ostreedir())
+                .join("repo")
+                .join(INTEGRITY_MANIFEST_PATH);
+            self.append_default_data(&path, manifest.as_bytes())?;
+        }
+
+        if let Some(path) = self.options.emit_object_list.as_deref() {
+            let objects = self.object_list.take().unwrap_or_default();
+            let mut list = String::new();
+            for (objtype, checksum) in objects {
+                let objtype = objtype_name(objtype);
+                list.push_str(&format!("{objtype} {checksum}\n"));
+            }
+            std::fs::write(path, list).with_context(|| format!("Writing {path}"))?;
+        }
 
-        self.append_standard_var(cancellable)?;
+        self.report_progress(true);
 
         Ok(())
     }
 
+    /// Record an object in the object list, if one is being written.
+    fn record_object(&mut self, objtype: ostree::ObjectType, checksum: &str) {
+        if let Some(list) = self.object_list.as_mut() {
+            list.push((objtype, checksum.to_string()));
+        }
+    }
+
+    /// Record the SHA-256 of the object at `path` in the integrity manifest, if
+    /// one is being written.
+    fn record_integrity(&mut self, path: &Utf8Path, digest: &[u8; 32]) {
+        if let Some(manifest) = self.integrity_manifest.as_mut() {
+            manifest.push_str(&format!("{}  {path}\n", hex::encode(digest)));
+        }
+    }
+
     fn append_commit_object(&mut self) -> Result<()> {
         self.append(
             ostree::ObjectType::Commit,
             self.commit_checksum,
             &self.commit_object.clone(),
         )?;
-        if let Some(commitmeta) = self
-            .repo
-            .read_commit_detached_metadata(self.commit_checksum, gio::Cancellable::NONE)?
-        {
+        let commitmeta = self.commitmeta.clone();
+        let commitmeta = match (commitmeta, self.options.commit_metadata_filter.as_ref()) {
+            (Some(commitmeta), Some(filter)) => {
+                let meta = glib::VariantDict::new(Some(&commitmeta));
+                for key in filter {
+                    meta.remove(key);
+                }
+                Some(meta.end())
+            }
+            (commitmeta, _) => commitmeta,
+        };
+        let commitmeta = if self.options.composefs {
+            let max_depth = self.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+            let digest =
+                super::composefs::commit_digest(self.repo, &self.commit_object, max_depth)?;
+            let meta = glib::VariantDict::new(commitmeta.as_ref());
+            meta.insert_value(COMPOSEFS_DIGEST_KEY, &digest.as_slice().to_variant());
+            Some(meta.end())
+        } else {
+            commitmeta
+        };
+        if let Some(commitmeta) = commitmeta {
             self.append(
                 ostree::ObjectType::CommitMeta,
                 self.commit_checksum,
@@ -304,12 +964,39 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         Ok(())
     }
 
+    /// Write the commit objects of up to [`ExportOptions::include_parents`]
+    /// ancestors of the commit, stopping early at the first one not in the repository.
+    fn append_parent_commits(&mut self) -> Result<()> {
+        let Some(depth) = self.options.include_parents else {
+            return Ok(());
+        };
+        let mut commit = self.commit_object.clone();
+        for _ in 0..depth {
+            let Some(parent) = ostree::commit_get_parent(&commit) else {
+                break;
+            };
+            let Some(parent_commit) = self
+                .repo
+                .load_variant_if_exists(ostree::ObjectType::Commit, &parent)?
+            else {
+                break;
+            };
+            self.append(ostree::ObjectType::Commit, &parent, &parent_commit)?;
+            commit = parent_commit;
+        }
+        Ok(())
+    }
+
     fn append(
         &mut self,
         objtype: ostree::ObjectType,
         checksum: &str,
         v: &glib::Variant,
     ) -> Result<()> {
+        // Only the checkout view is written in this case
+        if self.options.xattrs_sidecar {
+            return Ok(());
+        }
         let set = match objtype {
             ostree::ObjectType::Commit | ostree::ObjectType::CommitMeta => None,
             ostree::ObjectType::DirTree => Some(&mut self.wrote_dirtree),
@@ -322,12 +1009,21 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             }
             let inserted = set.insert(checksum.to_string());
             debug_assert!(inserted);
+            self.stats.metadata_objects += 1;
         }
 
         let data = v.data_as_bytes();
         let data = data.as_ref();
-        self.append_default_data(&object_path(objtype, checksum), data)
+        let path = object_path_in(
+            self.options.ostreedir(),
+            self.options.object_layout,
+            objtype,
+            checksum,
+        );
+        self.append_default_data(&path, data)
             .with_context(|| format!("Writing object {checksum}"))?;
+        self.record_object(objtype, checksum);
+        self.record_integrity(&path, &openssl::sha::sha256(data));
         Ok(())
     }
 
@@ -342,41 +1038,83 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             hex::encode(digest)
         };
 
-        let path = v1_xattrs_object_path(&xattrs_checksum);
+        let layout = self.options.object_layout;
+        let path = v1_xattrs_object_path(self.options.ostreedir(), layout, &xattrs_checksum);
         // Write xattrs content into a separate `.file-xattrs` object.
         if !self.wrote_xattrs.contains(&xattrs_checksum) {
             let inserted = self.wrote_xattrs.insert(xattrs_checksum);
             debug_assert!(inserted);
-            self.append_default_data(&path, xattrs_data)?;
+            self.count_entry(&path)?;
+            let mtime = self.options.mtime;
+            match self.xattrs_out.as_mut() {
+                Some(out) => tar_append_default_data(out, &path, xattrs_data, mtime)?,
+                None => tar_append_default_data(self.out, &path, xattrs_data, mtime)?,
+            }
+            self.stats.xattrs_objects_written += 1;
         }
         // Write a `.file-xattrs-link` which links the file object to
         // the corresponding detached xattrs.
         {
-            let link_obj_path = v1_xattrs_link_object_path(checksum);
-            self.append_default_hardlink(&link_obj_path, &path)?;
+            let link_obj_path =
+                v1_xattrs_link_object_path(self.options.ostreedir(), layout, checksum);
+            self.count_entry(&link_obj_path)?;
+            let mtime = self.options.mtime;
+            match self.xattrs_out.as_mut() {
+                Some(out) => tar_append_default_hardlink(out, &link_obj_path, &path, mtime)?,
+                None => tar_append_default_hardlink(self.out, &link_obj_path, &path, mtime)?,
+            }
+            self.stats.xattrs_hardlinks_written += 1;
         }
 
         Ok(true)
     }
 
     /// Write a content object, returning the path/header that should be used
-    /// as a hard link to it in the target path. This matches how ostree checkouts work.
-    fn append_content(&mut self, checksum: &str) -> Result<(Utf8PathBuf, tar::Header)> {
-        let path = object_path(ostree::ObjectType::File, checksum);
+    /// as a hard link to it in the target path, along with the symlink target
+    /// if the object is a symbolic link. This matches how ostree checkouts work.
+    fn append_content(
+        &mut self,
+        checksum: &str,
+    ) -> Result<(Utf8PathBuf, tar::Header, Option<String>)> {
+        let path = object_path_in(
+            self.options.ostreedir(),
+            self.options.object_layout,
+            ostree::ObjectType::File,
+            checksum,
+        );
 
-        let (instream, meta, xattrs) = self.repo.load_file(checksum, gio::Cancellable::NONE)?;
+        let (instream, meta, xattrs) = load_content(self.repo, checksum)?;
 
         let mut h = tar::Header::new_gnu();
         h.set_uid(meta.attribute_uint32("unix::uid") as u64);
         h.set_gid(meta.attribute_uint32("unix::gid") as u64);
+        self.set_owner_names(&mut h)?;
+        self.set_mtime(&mut h);
         let mode = meta.attribute_uint32("unix::mode");
         h.set_mode(self.filter_mode(mode));
-        if instream.is_some() {
+        let symlink_target = if instream.is_some() {
+            ensure!(meta.file_type() == gio::FileType::Regular);
+            h.set_entry_type(tar::EntryType::Regular);
             h.set_size(meta.size() as u64);
-        }
+            None
+        } else {
+            ensure!(meta.file_type() == gio::FileType::SymbolicLink);
+            let target = meta
+                .symlink_target()
+                .ok_or_else(|| anyhow!("Missing symlink target"))?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid UTF-8 symlink target: {target:?}"))?;
+            h.set_entry_type(tar::EntryType::Symlink);
+            h.set_size(0);
+            Some(target.to_owned())
+        };
         if !self.wrote_content.contains(checksum) {
+            self.check_stopped()?;
             let inserted = self.wrote_content.insert(checksum.to_string());
             debug_assert!(inserted);
+            self.stats.content_objects += 1;
+            self.record_object(ostree::ObjectType::File, checksum);
 
             // The xattrs objects need to be exported before the regular object they
             // refer to. Otherwise the importing logic won't have the xattrs available
@@ -384,62 +1122,206 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             self.append_xattrs(checksum, &xattrs)?;
 
             if let Some(instream) = instream {
-                ensure!(meta.file_type() == gio::FileType::Regular);
-
-                h.set_entry_type(tar::EntryType::Regular);
-                h.set_size(meta.size() as u64);
+                self.count_entry(&path)?;
+                self.stats.content_bytes += meta.size() as u64;
+                let mut h = h.clone();
                 let mut instream = BufReader::with_capacity(BUF_CAPACITY, instream.into_read());
-                self.out
-                    .append_data(&mut h, &path, &mut instream)
-                    .with_context(|| format!("Writing regfile {}", checksum))?;
-            } else {
-                ensure!(meta.file_type() == gio::FileType::SymbolicLink);
-
-                let target = meta
-                    .symlink_target()
-                    .ok_or_else(|| anyhow!("Missing symlink target"))?;
-                let target = target
-                    .to_str()
-                    .ok_or_else(|| anyhow!("Invalid UTF-8 symlink target: {target:?}"))?;
-                let context = || format!("Writing content symlink: {}", checksum);
-                h.set_entry_type(tar::EntryType::Symlink);
-                h.set_size(0);
-                // Handle //chkconfig, see above
-                if symlink_is_denormal(target) {
-                    h.set_link_name_literal(target).with_context(context)?;
-                    self.out
-                        .append_data(&mut h, &path, &mut std::io::empty())
-                        .with_context(context)?;
+                if self.options.sparse && meta.size() as usize > SPARSE_BLOCK_SIZE {
+                    self.append_regfile_sparse(&mut h, &path, checksum, instream)
+                        .with_context(|| format!("Writing sparse regfile {}", checksum))?;
                 } else {
+                    if let Some(align) = self.options.align_content {
+                        self.append_alignment_padding(&path, align as u64)?;
+                    }
                     self.out
-                        .append_link(&mut h, &path, target)
-                        .with_context(context)?;
+                        .append_data(&mut h, &path, &mut instream)
+                        .map_err(ExportError::Io)
+                        .with_context(|| format!("Writing regfile {}", checksum))?;
+                }
+                self.report_progress(false);
+                if self.integrity_manifest.is_some() {
+                    let digest = content_sha256(self.repo, checksum)?;
+                    self.record_integrity(&path, &digest);
                 }
+            } else if let Some(target) = symlink_target.as_deref() {
+                let mut h = h.clone();
+                let context = || format!("Writing content symlink: {}", checksum);
+                self.append_symlink(&mut h, &path, target)
+                    .with_context(context)?;
+                self.record_integrity(&path, &openssl::sha::sha256(target.as_bytes()));
             }
         }
 
-        Ok((path, h))
-    }
-
-    /// Write a directory using the provided metadata.
-    fn append_dir(&mut self, dirpath: &Utf8Path, meta: &ostree::DirMetaParsed) -> Result<()> {
-        let mut header = tar::Header::new_gnu();
-        header.set_entry_type(tar::EntryType::Directory);
-        header.set_size(0);
-        header.set_uid(meta.uid as u64);
-        header.set_gid(meta.gid as u64);
-        header.set_mode(self.filter_mode(meta.mode));
-        self.out
-            .append_data(&mut header, dirpath, std::io::empty())?;
-        Ok(())
+        Ok((path, h, symlink_target))
     }
 
-    /// Given a source object (in e.g. ostree/repo/objects/...), write a hardlink to it
-    /// in its expected target path (e.g. `usr/bin/bash`).
-    fn append_content_hardlink(
+    /// Write a content object directly at its path in the checkout view, passing
+    /// regular file content through the rewriter (if any).
+    fn append_content_inline(
         &mut self,
-        srcpath: &Utf8Path,
+        checksum: &str,
+        path: &Utf8Path,
+        rewriter: Option<&ContentRewriter>,
+    ) -> Result<()> {
+        self.check_stopped()?;
+        let (instream, meta, xattrs) = load_content(self.repo, checksum)?;
+        self.record_sidecar_xattrs(path, &xattrs);
+        let mut h = tar::Header::new_gnu();
+        h.set_uid(meta.attribute_uint32("unix::uid") as u64);
+        h.set_gid(meta.attribute_uint32("unix::gid") as u64);
+        self.set_owner_names(&mut h)?;
+        self.set_mtime(&mut h);
+        h.set_mode(self.filter_mode(meta.attribute_uint32("unix::mode")));
+        self.check_setuid(path, h.mode()?)?;
+        if let Some(instream) = instream {
+            ensure!(meta.file_type() == gio::FileType::Regular);
+            let mut buf = Vec::with_capacity(meta.size() as usize);
+            instream.into_read().read_to_end(&mut buf)?;
+            let relpath = path.strip_prefix(TAR_PATH_PREFIX_V0).unwrap_or(path);
+            let buf = rewriter
+                .and_then(|rewriter| (rewriter.0)(relpath, &buf))
+                .unwrap_or(buf);
+            self.stats.content_bytes += buf.len() as u64;
+            let compress = self.options.compress_files
+                && !relpath
+                    .extension()
+                    .is_some_and(|ext| self.options.skip_compress_extensions.contains(ext));
+            let buf = if compress {
+                self.append_compression_marker(path)?;
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                enc.write_all(&buf)?;
+                enc.finish()?
+            } else {
+                buf
+            };
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_size(buf.len() as u64);
+            self.count_entry(path)?;
+            self.out
+                .append_data(&mut h, path, buf.as_slice())
+                .map_err(ExportError::Io)
+                .with_context(|| format!("Writing regfile {path}"))?;
+            self.report_progress(false);
+        } else {
+            ensure!(meta.file_type() == gio::FileType::SymbolicLink);
+            let target = meta
+                .symlink_target()
+                .ok_or_else(|| anyhow!("Missing symlink target"))?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid UTF-8 symlink target: {target:?}"))?;
+            h.set_entry_type(tar::EntryType::Symlink);
+            h.set_size(0);
+            self.append_symlink(&mut h, path, target)
+                .with_context(|| format!("Writing symlink {path}"))?;
+        }
+        Ok(())
+    }
+
+    /// Write a regular file as a GNU sparse entry if it contains holes, otherwise
+    /// as a normal entry. The `instream` is used to scan for holes, and the object is
+    /// then read again to write the data.
+    fn append_regfile_sparse(
+        &mut self,
+        h: &mut tar::Header,
+        path: &Utf8Path,
+        checksum: &str,
+        instream: impl std::io::Read,
+    ) -> Result<()> {
+        let size = h.size()?;
+        let segments = find_data_segments(instream)?;
+        let instream = load_content(self.repo, checksum)?
+            .0
+            .ok_or_else(|| anyhow!("Missing content stream"))?;
+        let instream = BufReader::with_capacity(BUF_CAPACITY, instream.into_read());
+        if segments[..] == [(0, size)] {
+            self.out
+                .append_data(h, path, instream)
+                .map_err(ExportError::Io)?;
+            return Ok(());
+        }
+        // The list of blocks; if the file ends in a hole, a final empty block
+        // is used to encode that.
+        let mut blocks = segments.clone();
+        if blocks.last().map(|(offset, len)| offset + len) != Some(size) {
+            blocks.push((size, 0));
+        }
+        h.set_entry_type(tar::EntryType::GNUSparse);
+        h.set_size(segments.iter().map(|(_, len)| len).sum());
+        let gnu = h
+            .as_gnu_mut()
+            .ok_or_else(|| anyhow!("Expected GNU header"))?;
+        gnu.set_real_size(size);
+        let (first, rest) = blocks.split_at(blocks.len().min(gnu.sparse.len()));
+        for (ent, &(offset, len)) in gnu.sparse.iter_mut().zip(first) {
+            ent.set_offset(offset);
+            ent.set_length(len);
+        }
+        gnu.set_is_extended(!rest.is_empty());
+        // Any further blocks go in extension headers, which directly follow the main
+        // header and precede the data.
+        let mut ext_headers = Vec::new();
+        let mut chunks = rest.chunks(21).peekable();
+        while let Some(chunk) = chunks.next() {
+            let mut ext = tar::GnuExtSparseHeader::new();
+            for (ent, &(offset, len)) in ext.sparse_mut().iter_mut().zip(chunk) {
+                ent.set_offset(offset);
+                ent.set_length(len);
+            }
+            ext.set_is_extended(chunks.peek().is_some());
+            ext_headers.extend_from_slice(ext.as_bytes());
+        }
+        let data =
+            std::io::Cursor::new(ext_headers).chain(SparseDataReader::new(instream, segments));
+        self.out
+            .append_data(h, path, data)
+            .map_err(ExportError::Io)?;
+        Ok(())
+    }
+
+    /// Append a symbolic link entry.
+    fn append_symlink(&mut self, h: &mut tar::Header, path: &Utf8Path, target: &str) -> Result<()> {
+        self.count_entry(path)?;
+        // Handle //chkconfig, see above
+        if symlink_is_denormal(target) {
+            h.set_link_name_literal(target)?;
+            self.out
+                .append_data(h, path, &mut std::io::empty())
+                .map_err(ExportError::Io)?;
+        } else {
+            self.out
+                .append_link(h, path, target)
+                .map_err(ExportError::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Write a directory using the provided metadata.
+    fn append_dir(&mut self, dirpath: &Utf8Path, meta: &ostree::DirMetaParsed) -> Result<()> {
+        self.count_entry(dirpath)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_uid(meta.uid as u64);
+        header.set_gid(meta.gid as u64);
+        self.set_owner_names(&mut header)?;
+        self.set_mtime(&mut header);
+        header.set_mode(self.filter_mode(meta.mode));
+        self.out
+            .append_data(&mut header, dirpath, std::io::empty())
+            .map_err(ExportError::Io)?;
+        Ok(())
+    }
+
+    /// Given a source object (in e.g. ostree/repo/objects/...), write a hardlink to it
+    /// in its expected target path (e.g. `usr/bin/bash`).  If configured via
+    /// [`ExportOptions::checkout_link_type`], a relative symbolic link is written instead.
+    fn append_content_hardlink(
+        &mut self,
+        srcpath: &Utf8Path,
         mut h: tar::Header,
+        symlink_target: Option<&str>,
         dest: &Utf8Path,
     ) -> Result<()> {
         // Query the original size first
@@ -451,46 +1333,159 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         // a hardlink of size zero, as this is what is normal.
         h.set_size(0);
         if h.entry_type() == tar::EntryType::Regular && size == 0 {
-            self.out.append_data(&mut h, dest, &mut std::io::empty())?;
+            self.count_entry(dest)?;
+            self.out
+                .append_data(&mut h, dest, &mut std::io::empty())
+                .map_err(ExportError::Io)?;
+        } else if self.options.checkout_link_type == CheckoutLinkType::Symlink {
+            if let Some(target) = symlink_target {
+                // A symlink pointing at a symlink object would be resolved relative
+                // to the object directory, so just copy the original link.
+                self.append_symlink(&mut h, dest, target)?;
+            } else {
+                let target = relative_link_target(dest, srcpath);
+                self.count_entry(dest)?;
+                h.set_entry_type(tar::EntryType::Symlink);
+                h.set_mode(0o777);
+                self.out
+                    .append_link(&mut h, dest, &target)
+                    .map_err(ExportError::Io)?;
+            }
         } else {
+            self.count_entry(dest)?;
             h.set_entry_type(tar::EntryType::Link);
             h.set_link_name(srcpath)?;
-            self.out.append_data(&mut h, dest, &mut std::io::empty())?;
+            self.out
+                .append_data(&mut h, dest, &mut std::io::empty())
+                .map_err(ExportError::Io)?;
         }
         Ok(())
     }
 
-    /// Write a dirtree object.
-    fn append_dirtree<C: IsA<gio::Cancellable>>(
-        &mut self,
+    /// Gather the checksums of all content objects referenced by a dirtree
+    /// (recursively), in the same order as they would be written by [`Self::append_dirtree`].
+    fn collect_content(
+        &self,
         dirpath: &Utf8Path,
-        checksum: String,
-        is_root: bool,
-        cancellable: Option<&C>,
+        checksum: &str,
+        depth: u32,
+        out: &mut IndexSet<String>,
     ) -> Result<()> {
-        let v = &self
-            .repo
-            .load_variant(ostree::ObjectType::DirTree, &checksum)?;
+        self.check_depth(dirpath, depth)?;
+        let v = &load_metadata(self.repo, ostree::ObjectType::DirTree, checksum)?;
+        let v = v.data_as_bytes();
+        let v = v.try_as_aligned()?;
+        let v = gv_dirtree!().cast(v);
+        let (files, dirs) = v.to_tuple();
+        for file in files {
+            let (name, csum) = file.to_tuple();
+            let path = dirpath.join(name.to_str());
+            let path = map_path(&path);
+            if !self.is_included(&path, false) {
+                continue;
+            }
+            let checksum = hex::encode(csum);
+            // This content is written before the directory walk, so check it here.
+            self.check_content_setuid(&path, &checksum)?;
+            out.insert(checksum);
+        }
+        for item in dirs {
+            let (name, contents_csum, _) = item.to_tuple();
+            let name = name.to_str();
+            if depth == 0 && self.is_omitted_root_dir(name) {
+                continue;
+            }
+            let subpath = &dirpath.join(name);
+            if !self.is_traversed(&map_path(subpath)) {
+                continue;
+            }
+            self.collect_content(subpath, &hex::encode(contents_csum), depth + 1, out)?;
+        }
+        Ok(())
+    }
+
+    /// Order content objects by the size of their content; symbolic links have
+    /// size zero.  Objects of the same size remain in tree order.
+    fn sort_content_by_size(
+        &self,
+        content: IndexSet<String>,
+        sort: ContentSort,
+    ) -> Result<Vec<String>> {
+        let mut sized = content
+            .into_iter()
+            .map(|checksum| {
+                let (instream, meta, _) = load_content(self.repo, &checksum)?;
+                let size = if instream.is_some() {
+                    meta.size() as u64
+                } else {
+                    0
+                };
+                Ok((size, checksum))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        match sort {
+            ContentSort::TreeOrder => {}
+            ContentSort::SizeAscending => sized.sort_by_key(|(size, _)| *size),
+            ContentSort::SizeDescending => sized.sort_by_key(|(size, _)| Reverse(*size)),
+        }
+        Ok(sized.into_iter().map(|(_, checksum)| checksum).collect())
+    }
+
+    /// Return an error if `depth` exceeds the configured maximum directory depth.
+    fn check_depth(&self, dirpath: &Utf8Path, depth: u32) -> Result<()> {
+        let max_depth = self.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+        if depth > max_depth {
+            anyhow::bail!("Exceeded maximum directory depth {max_depth} at {dirpath}");
+        }
+        Ok(())
+    }
+
+    /// Write a dirtree object; `depth` is zero for the root.
+    fn append_dirtree(&mut self, dirpath: &Utf8Path, checksum: String, depth: u32) -> Result<()> {
+        self.check_stopped()?;
+        self.check_depth(dirpath, depth)?;
+        let is_root = depth == 0;
+        let v = &load_metadata(self.repo, ostree::ObjectType::DirTree, &checksum)?;
         self.append(ostree::ObjectType::DirTree, &checksum, v)?;
         drop(checksum);
         let v = v.data_as_bytes();
         let v = v.try_as_aligned()?;
         let v = gv_dirtree!().cast(v);
         let (files, dirs) = v.to_tuple();
-
-        if let Some(c) = cancellable {
-            c.set_error_if_cancelled()?;
+        let mut files = files.into_iter().map(|f| f.to_tuple()).collect::<Vec<_>>();
+        let mut dirs = dirs.into_iter().map(|d| d.to_tuple()).collect::<Vec<_>>();
+        if self.options.stable_order {
+            files.sort_by(|a, b| a.0.to_str().cmp(b.0.to_str()));
+            dirs.sort_by(|a, b| a.0.to_str().cmp(b.0.to_str()));
         }
 
         if !self.structure_only {
-            for file in files {
-                let (name, csum) = file.to_tuple();
+            for (name, csum) in files {
                 let name = name.to_str();
                 let checksum = &hex::encode(csum);
-                let (objpath, h) = self.append_content(checksum)?;
                 let subpath = &dirpath.join(name);
                 let subpath = map_path(subpath);
-                self.append_content_hardlink(&objpath, h, &subpath)?;
+                if !self.is_included(&subpath, false) {
+                    continue;
+                }
+                let rewriter = self.options.content_rewriter.clone();
+                if rewriter.is_some() || self.options.xattrs_sidecar {
+                    if self.omit_checkout {
+                        continue;
+                    }
+                    self.append_content_inline(checksum, &subpath, rewriter.as_ref())?;
+                    continue;
+                }
+                self.check_content_setuid(&subpath, checksum)?;
+                let (objpath, h, target) = self.append_content(checksum)?;
+                if self.omit_checkout {
+                    continue;
+                }
+                // The usual `ostree -> sysroot/ostree` symlink would conflict
+                if is_root && self.options.flat_ostree_dir && name == OSTREEDIR_FLAT {
+                    continue;
+                }
+                self.append_content_hardlink(&objpath, h, target.as_deref(), &subpath)?;
             }
         }
 
@@ -500,27 +1495,45 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             self.wrote_vartmp = true;
         }
 
-        for item in dirs {
-            let (name, contents_csum, meta_csum) = item.to_tuple();
+        for (name, contents_csum, meta_csum) in dirs {
             let name = name.to_str();
-            let metadata = {
-                let meta_csum = &hex::encode(meta_csum);
-                let meta_v = &self
-                    .repo
-                    .load_variant(ostree::ObjectType::DirMeta, meta_csum)?;
-                self.append(ostree::ObjectType::DirMeta, meta_csum, meta_v)?;
-                // Safety: We passed the correct variant type just above
-                ostree::DirMetaParsed::from_variant(meta_v).unwrap()
-            };
-            // Special hack because tar stream for containers can't have duplicates.
-            if is_root && name == SYSROOT {
-                continue;
-            }
             let dirtree_csum = hex::encode(contents_csum);
             let subpath = &dirpath.join(name);
             let subpath = map_path(subpath);
-            self.append_dir(&subpath, &metadata)?;
-            self.append_dirtree(&subpath, dirtree_csum, false, cancellable)?;
+            // Nothing is written for directories outside of the exported paths,
+            // not even their metadata object.
+            if !self.is_traversed(&subpath) {
+                continue;
+            }
+            let meta_csum = &hex::encode(meta_csum);
+            let meta_v = &load_metadata(self.repo, ostree::ObjectType::DirMeta, meta_csum)?;
+            self.append(ostree::ObjectType::DirMeta, meta_csum, meta_v)?;
+            // Safety: We passed the correct variant type just above
+            let metadata = ostree::DirMetaParsed::from_variant(meta_v).unwrap();
+            // Special hack because tar stream for containers can't have duplicates.
+            if is_root && self.is_omitted_root_dir(name) {
+                continue;
+            }
+            let included = self.is_included(&subpath, true);
+            if included && !self.omit_checkout {
+                self.append_dir(&subpath, &metadata)?;
+                self.record_sidecar_xattrs(&subpath, &meta_v.child_value(3));
+            }
+            if is_root && name == VAR && dirtree_csum != EMPTY_DIRTREE_CHECKSUM {
+                match self.options.var_policy {
+                    VarPolicy::Include => {}
+                    VarPolicy::Exclude => {
+                        let prev = std::mem::replace(&mut self.omit_checkout, true);
+                        self.append_dirtree(&subpath, dirtree_csum, depth + 1)?;
+                        self.omit_checkout = prev;
+                        continue;
+                    }
+                    VarPolicy::Error => {
+                        anyhow::bail!("Commit contains content in /{VAR}");
+                    }
+                }
+            }
+            self.append_dirtree(&subpath, dirtree_csum, depth + 1)?;
         }
 
         Ok(())
@@ -532,26 +1545,146 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
     /// e.g. `systemd-tmpfiles`.  But, systemd doesn't run in Docker-style containers by default.
     ///
     /// So, this function creates a few critical directories in `/var` by default.
-    fn append_standard_var(&mut self, cancellable: Option<&gio::Cancellable>) -> Result<()> {
+    fn append_standard_var(&mut self) -> Result<()> {
         // If the commit included /var/tmp, then it's already in the tar stream.
-        if self.wrote_vartmp {
+        if self.wrote_vartmp || !self.is_included("var/tmp".into(), true) {
             return Ok(());
         }
-        if let Some(c) = cancellable {
-            c.set_error_if_cancelled()?;
-        }
+        self.check_stopped()?;
+        self.count_entry(Utf8Path::new("var/tmp"))?;
         let mut header = tar::Header::new_gnu();
         header.set_entry_type(tar::EntryType::Directory);
         header.set_size(0);
         header.set_uid(0);
         header.set_gid(0);
         header.set_mode(self.filter_mode(libc::S_IFDIR | 0o1777));
+        self.set_mtime(&mut header);
         self.out
-            .append_data(&mut header, "var/tmp", std::io::empty())?;
+            .append_data(&mut header, "var/tmp", std::io::empty())
+            .map_err(ExportError::Io)?;
         Ok(())
     }
 }
 
+/// Check for incompatible export options.
+fn validate_options(options: &ExportOptions) -> Result<()> {
+    if options.xattrs_sidecar {
+        ensure!(
+            options.checkout_link_type == CheckoutLinkType::Hardlink,
+            "An xattrs sidecar is incompatible with symbolic link checkouts"
+        );
+        ensure!(
+            !options.sparse,
+            "An xattrs sidecar is incompatible with sparse export"
+        );
+        ensure!(
+            options.object_order.is_none(),
+            "An xattrs sidecar is incompatible with an object order"
+        );
+        ensure!(
+            options.content_sort == ContentSort::TreeOrder,
+            "An xattrs sidecar is incompatible with a content sort"
+        );
+        ensure!(
+            !options.composefs,
+            "An xattrs sidecar is incompatible with a composefs digest"
+        );
+        ensure!(
+            !options.emit_integrity_manifest,
+            "An xattrs sidecar is incompatible with an integrity manifest"
+        );
+    }
+    if let Some(p) = options.subpath.as_deref() {
+        ensure!(
+            !p.components().any(|c| c == Utf8Component::ParentDir),
+            "Invalid subpath {p}"
+        );
+    }
+    if let Some(include) = options.include_paths.as_deref() {
+        ensure!(
+            options.xattrs_sidecar,
+            "Included paths require an xattrs sidecar"
+        );
+        if let Some(p) = include
+            .iter()
+            .find(|p| p.components().any(|c| c == Utf8Component::ParentDir))
+        {
+            anyhow::bail!("Invalid included path {p}");
+        }
+    }
+    if options.content_rewriter.is_some() {
+        ensure!(
+            options.checkout_link_type == CheckoutLinkType::Hardlink,
+            "A content rewriter is incompatible with symbolic link checkouts"
+        );
+        ensure!(
+            !options.sparse,
+            "A content rewriter is incompatible with sparse export"
+        );
+        ensure!(
+            options.object_order.is_none(),
+            "A content rewriter is incompatible with an object order"
+        );
+        ensure!(
+            options.content_sort == ContentSort::TreeOrder,
+            "A content rewriter is incompatible with a content sort"
+        );
+        ensure!(
+            !options.composefs,
+            "A content rewriter is incompatible with a composefs digest"
+        );
+    }
+    ensure!(
+        options.object_order.is_none() || options.content_sort == ContentSort::TreeOrder,
+        "An object order is incompatible with a content sort"
+    );
+    if let Some(align) = options.align_content {
+        ensure!(
+            align > 0 && align as u64 % TAR_BLOCK_SIZE == 0,
+            "Content alignment {align} is not a multiple of {TAR_BLOCK_SIZE}"
+        );
+        ensure!(
+            !options.sparse,
+            "Content alignment is incompatible with sparse export"
+        );
+        ensure!(
+            !options.xattrs_sidecar && options.content_rewriter.is_none(),
+            "Content alignment requires content objects to be written"
+        );
+    }
+    ensure!(
+        options.blocking_factor != Some(0),
+        "The blocking factor must be positive"
+    );
+    match options.compression {
+        Some(Compression::Gzip(level)) => {
+            ensure!(level <= 9, "Invalid gzip compression level {level}");
+        }
+        Some(Compression::Zstd(level)) => {
+            let range = zstd::compression_level_range();
+            ensure!(
+                range.contains(&level),
+                "Invalid zstd compression level {level}, expected {}..={}",
+                range.start(),
+                range.end()
+            );
+        }
+        None => {}
+    }
+    if options.compress_files {
+        ensure!(
+            options.xattrs_sidecar || options.content_rewriter.is_some(),
+            "Per-file compression requires file content to be written inline"
+        );
+    } else {
+        ensure!(
+            options.skip_compress_extensions.is_empty(),
+            "Extensions to skip compressing require per-file compression"
+        );
+    }
+    Ok(())
+}
+
 /// Recursively walk an OSTree commit and generate data into a `[tar::Builder]`
 /// which contains all of the metadata objects, as well as a hardlinked
 /// stream that looks like a checkout.  Extended attributes are stored specially out
@@ -560,30 +1693,1163 @@ fn impl_export<W: std::io::Write>(
     repo: &ostree::Repo,
     commit_checksum: &str,
     out: &mut tar::Builder<W>,
+    position: Option<Rc<Cell<u64>>>,
     options: ExportOptions,
-) -> Result<()> {
+) -> Result<ExportStats> {
+    ensure!(
+        !options.self_check,
+        "Verifying the export requires re-readable output; use export_commit_to_path()"
+    );
+    validate_options(&options)?;
     let writer = &mut OstreeTarWriter::new(repo, commit_checksum, out, options)?;
+    writer.position = position;
     writer.write_commit()?;
-    Ok(())
+    Ok(std::mem::take(&mut writer.stats))
+}
+
+/// Statistics from a tar export.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ExportStats {
+    /// Number of metadata objects (dirtree, dirmeta) written.
+    pub metadata_objects: u64,
+    /// Number of content objects (regular files and symlinks) written.
+    pub content_objects: u64,
+    /// Total size of the regular file content written.
+    pub content_bytes: u64,
+    /// Number of unique extended attribute (`.file-xattrs`) objects written.
+    pub xattrs_objects_written: u64,
+    /// Number of links from content objects to their extended attributes
+    /// (`.file-xattrs-link`) written; comparing this with
+    /// [`Self::xattrs_objects_written`] shows how much sharing occurred.
+    pub xattrs_hardlinks_written: u64,
+    /// Total number of tar entries written.
+    pub entries: u64,
+}
+
+/// How entries in the checkout view of a tar export refer to the
+/// objects in the embedded repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckoutLinkType {
+    /// Use hard links; this is the default.
+    #[default]
+    Hardlink,
+    /// Use relative symbolic links, e.g. `../sysroot/ostree/repo/objects/...`.
+    /// This is useful for consumers which extract the tar stream onto a filesystem
+    /// without hard link support.
+    Symlink,
+}
+
+/// How to handle content in `/var` in the exported commit.
+///
+/// In the ostree model, `/var` is machine-local state which starts out empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VarPolicy {
+    /// Export `/var` content as is; this is the default.
+    #[default]
+    Include,
+    /// Omit the content of `/var` from the checkout view.  Note that the objects
+    /// are still written to the embedded ostree repository, so that the commit
+    /// remains complete.
+    Exclude,
+    /// Fail the export if `/var` has content.
+    Error,
+}
+
+/// The order in which content objects are written to a tar export.
+///
+/// By default, each content object is written to the embedded repository just
+/// before the first checkout entry which links to it.  The other orders require
+/// a pass over the whole commit to gather the size of each object, and then
+/// write all content objects up front, before the checkout view (which is still
+/// written in tree order).  This costs an extra read of every dirtree and file
+/// header, and means consumers see no checkout entries until all content has
+/// been written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentSort {
+    /// Write content objects in tree order; this is the default.
+    #[default]
+    TreeOrder,
+    /// Write the smallest content objects first, which lets streaming importers
+    /// make fast initial progress.
+    SizeAscending,
+    /// Write the largest content objects first, which lets streaming importers
+    /// issue large writes early and finish with small ones.
+    SizeDescending,
+}
+
+/// The layout of the object paths in the embedded repository; see
+/// [`ExportOptions::object_layout`].
+///
+/// Only the default layout is that of an ostree repository; the others are
+/// experimental, and are only understood by the importers in this crate.  In
+/// particular, the embedded repository cannot be used directly with ostree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum ObjectLayout {
+    /// `objects/XX/<rest>.<type>`, sharded by the first two characters of the
+    /// checksum as in an ostree repository; this is the default.
+    #[default]
+    Default,
+    /// `objects/XX/YY/<rest>.<type>`, sharded by the first two and next two
+    /// characters of the checksum, for repositories with very many objects.
+    /// Only the first level of directories is written up front.
+    TwoLevel,
+}
+
+impl ObjectLayout {
+    /// The path of an object relative to the `objects` directory, without the
+    /// suffix for its type.
+    fn object_stem(self, checksum: &str) -> String {
+        let (first, rest) = checksum.split_at(2);
+        match self {
+            Self::Default => format!("{first}/{rest}"),
+            Self::TwoLevel => {
+                let (second, rest) = rest.split_at(2);
+                format!("{first}/{second}/{rest}")
+            }
+        }
+    }
+}
+
+/// The compression applied to a whole tar export; see [`ExportOptions::compression`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, with the given level from 0 (none) to 9 (best).
+    Gzip(u32),
+    /// zstd, with the given level, e.g. 3 (the zstd default) or 19; the
+    /// supported range depends on the zstd library, and 0 selects its default.
+    Zstd(i32),
 }
 
 /// Configuration for tar export.
-#[derive(Debug, PartialEq, Eq, Default)]
-pub struct ExportOptions;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// How to link checkout entries to repository objects.
+    pub checkout_link_type: CheckoutLinkType,
+    /// Detect runs of zeros in regular files and write them as GNU sparse
+    /// entries, which avoids storing the holes in the tar stream.
+    pub sparse: bool,
+    /// If set, content objects are written in this order (by checksum), before
+    /// any other content.  Objects in the commit which are not in this list are
+    /// written afterwards; checksums not in the commit are ignored.  This can be
+    /// used to keep the layout of the stream stable relative to a prior export.
+    pub object_order: Option<Vec<String>>,
+    /// How to handle content in the top-level `/var` directory.
+    pub var_policy: VarPolicy,
+    /// If set, regular files are passed through this hook, which may replace
+    /// their content.  See [`ContentRewriter`].
+    pub content_rewriter: Option<ContentRewriter>,
+    /// After writing the archive, import it into a scratch repository and verify
+    /// that it yields the source commit, failing the export otherwise.
+    ///
+    /// This requires the output to be re-read, and is hence only supported by
+    /// [`export_commit_to_path`].  Note that this is expensive: the whole archive
+    /// is read back and every object is written again and checksummed.
+    pub self_check: bool,
+    /// Names for numeric user and group IDs, written to the `uname` and `gname`
+    /// fields of directory and content entries.  IDs not in the map are written
+    /// without a name.
+    pub owner_names: Option<HashMap<u32, String>>,
+    /// Maximum directory nesting depth below the root; exporting a commit
+    /// with deeper directories fails.  Defaults to [`DEFAULT_MAX_DEPTH`].
+    pub max_depth: Option<u32>,
+    /// Keys to remove from the detached commit metadata before it is written.
+    /// The commit object itself is covered by its checksum and is never modified.
+    pub commit_metadata_filter: Option<HashSet<String>>,
+    /// Write only the checkout view, with file content inline rather than in an
+    /// embedded ostree repository, and the extended attributes of all files and
+    /// directories in a sidecar at [`XATTRS_SIDECAR_PATH`].  The result cannot
+    /// be imported as an ostree commit; use [`super::extract_with_xattrs_sidecar`]
+    /// to unpack it.  See the module documentation for the format of the sidecar.
+    pub xattrs_sidecar: bool,
+    /// If set, only the files and directories at or below these paths in the
+    /// checkout view (e.g. `/usr/bin/bash` or `/etc`, i.e. after `/usr/etc` is
+    /// mapped to `/etc`) are written, along with their ancestor directories.
+    /// This can be used to build a minimal image from a few files of a commit.
+    ///
+    /// The result is not a complete commit, so this requires
+    /// [`Self::xattrs_sidecar`], which omits the embedded repository; the sidecar
+    /// only covers the paths written.
+    pub include_paths: Option<Vec<Utf8PathBuf>>,
+    /// If set, only the subtree of the commit at this path in the checkout view
+    /// (e.g. `/usr/lib`, or `/etc` for the content of `/usr/etc`) is written,
+    /// along with its ancestor directories.  The path may also be a single file.
+    ///
+    /// Unlike [`Self::include_paths`], the embedded repository is still written,
+    /// but with only the objects needed for the subtree.  The result is not a
+    /// complete commit, and is only useful for debugging or partial mirroring;
+    /// this is incompatible with [`Self::self_check`].
+    pub subpath: Option<Utf8PathBuf>,
+    /// Maximum number of tar entries to write; the export fails before writing
+    /// the entry which would exceed it.
+    pub max_entries: Option<u64>,
+    /// If set, the modification time (in seconds since the epoch) of every
+    /// entry written, e.g. for reproducible builds.  By default, the
+    /// modification time is left unset.
+    pub mtime: Option<u64>,
+    /// If set, a PAX global extended header with these records (in order) is
+    /// written as the first entry of the stream.  The header itself has fixed
+    /// metadata, so the output only depends on the records.  If unset, no global
+    /// header is written.
+    pub pax_global: Option<Vec<(String, String)>>,
+    /// Compress the content of each regular file individually with gzip.  Each
+    /// compressed entry is preceded by a PAX extended header with the record
+    /// [`COMPRESSION_PAX_KEY`]`=gzip`, and its size is the compressed size;
+    /// consumers must decompress such entries when extracting, as
+    /// [`super::extract_with_xattrs_sidecar`] does.
+    ///
+    /// This requires file content to be written inline in the checkout view, i.e.
+    /// [`Self::xattrs_sidecar`] or [`Self::content_rewriter`] must be set.
+    pub compress_files: bool,
+    /// With [`Self::compress_files`], regular files whose name has one of these
+    /// extensions (without the leading `.`, e.g. `gz`) are stored uncompressed,
+    /// which avoids spending CPU on content that is already compressed.  Setting
+    /// this without [`Self::compress_files`] is an error.
+    pub skip_compress_extensions: HashSet<String>,
+    /// Write the files and subdirectories of each directory sorted by name,
+    /// rather than in the order they appear in the dirtree object.  These are
+    /// normally the same, as ostree sorts dirtree entries; this guarantees the
+    /// order regardless.  Defaults to true.
+    pub stable_order: bool,
+    /// The order in which content objects are written; see [`ContentSort`].
+    /// Sorting by size is incompatible with [`Self::object_order`],
+    /// [`Self::xattrs_sidecar`] and [`Self::content_rewriter`].
+    pub content_sort: ContentSort,
+    /// Also write the commit objects of up to this many ancestors of the commit,
+    /// following the parent of each in turn and stopping at the first which is not
+    /// in the repository.  Only the commit objects are written, not their detached
+    /// metadata or trees; the importer writes them as partial commits.
+    pub include_parents: Option<u32>,
+    /// Compute the composefs image digest of the commit and store it in the
+    /// exported detached commit metadata under [`COMPOSEFS_DIGEST_KEY`], which
+    /// lets importers enable composefs mounts without recomputing it.
+    ///
+    /// The digest is that printed by `mkcomposefs --print-digest-only`, which
+    /// must be installed: the fs-verity digest (SHA-256, with a block size of
+    /// 4096) of the composefs image of the whole tree of the commit, where each
+    /// regular file refers to its object (e.g. `ab/cdef....file`) and carries
+    /// the fs-verity digest of its content, and all modification times are
+    /// zero.  This requires an additional pass which reads all file content.
+    ///
+    /// The commit object itself is covered by its checksum and is not modified.
+    /// This is incompatible with [`Self::xattrs_sidecar`] and
+    /// [`Self::content_rewriter`].
+    pub composefs: bool,
+    /// If set, exporting a file with the setuid or setgid bit set fails unless
+    /// its path is in this set, e.g. `/usr/bin/sudo`.  This guards against
+    /// accidentally shipping privileged executables.
+    pub forbid_setuid: Option<HashSet<Utf8PathBuf>>,
+    /// If set, a list of the objects written to the embedded repository is
+    /// written to this file once the export completes, with one line of the
+    /// form `<objtype> <checksum>` (e.g. `dirtree 1a2b...`) per object, in the
+    /// order they appear in the stream.  The object types are `commit`,
+    /// `commitmeta`, `dirtree`, `dirmeta` and `file`; the auxiliary extended
+    /// attribute entries are not listed.
+    pub emit_object_list: Option<Utf8PathBuf>,
+    /// If set, the data of each regular file content object starts at an offset
+    /// in the stream which is a multiple of this (e.g. 4096), so that it can
+    /// be used directly from a memory mapping of the archive.  This must be a
+    /// multiple of the tar block size (512).  Where needed, a PAX extended
+    /// header with an `OSTREE.padding` record is written before the object;
+    /// tar readers ignore it.
+    ///
+    /// The padding depends only on the preceding output, so the export remains
+    /// reproducible.  This is only supported by [`export_commit`] (and functions
+    /// using it), and is incompatible with [`Self::sparse`],
+    /// [`Self::xattrs_sidecar`] and [`Self::content_rewriter`].
+    pub align_content: Option<usize>,
+    /// If set, the export is stopped once this flag is raised, e.g. from another
+    /// thread.  It is checked before each directory and content object is written;
+    /// the export then fails with [`ExportError::Cancelled`], leaving the output
+    /// truncated at an entry boundary.
+    pub stop_flag: Option<StopFlag>,
+    /// Like [`Self::stop_flag`], the export is stopped with
+    /// [`ExportError::Cancelled`] once this is cancelled, e.g. from another thread.
+    pub cancellable: Option<gio::Cancellable>,
+    /// Write the embedded repository to `ostree/repo` rather than the default
+    /// `sysroot/ostree/repo`, for consumers which do not use the usual
+    /// `ostree -> sysroot/ostree` symbolic link.  That link (or any other
+    /// `ostree` entry at the root of the commit) is then omitted from the
+    /// checkout view, as it would conflict with the repository.
+    ///
+    /// The result cannot be imported with [`import_tar`](super::import_tar),
+    /// and hence this is incompatible with [`Self::self_check`].
+    pub flat_ostree_dir: bool,
+    /// The layout of the object paths in the embedded repository.  Layouts
+    /// other than [`ObjectLayout::Default`] are not native to ostree; see
+    /// [`ObjectLayout`].
+    pub object_layout: ObjectLayout,
+    /// If set, progress updates are sent here while the commit is written.
+    pub progress: Option<ExportProgress>,
+    /// The minimum number of bytes of file content to write between updates
+    /// to [`Self::progress`]; by default an update is sent after each non-empty
+    /// regular file.  A final update is always sent once the commit has been
+    /// written.
+    pub progress_interval_bytes: Option<u64>,
+    /// If set, the archive is padded with zeros after the end-of-archive marker
+    /// so that its length is a multiple of this many 512-byte blocks (i.e. a
+    /// record), for tools which expect a particular blocking factor, such as
+    /// 20 for the traditional 10240-byte records.  This is only supported by
+    /// [`export_commit`] (and functions using it).
+    pub blocking_factor: Option<u16>,
+    /// Write a manifest of the objects in the embedded repository as the last
+    /// entry of the repository, at [`INTEGRITY_MANIFEST_PATH`] within it, so that
+    /// the objects can be checked without a full `ostree fsck`.  As it follows
+    /// the objects, it can only be used for verification after the fact, e.g.
+    /// of an extracted archive; it is not checked by
+    /// [`import_tar`](super::import_tar) or the container import, which ignore it.
+    ///
+    /// The manifest is in the format of `sha256sum`: one line of the form
+    /// `<sha256>  <path>` per object, in the order they appear in the stream,
+    /// where the path is that of the entry (e.g.
+    /// `sysroot/ostree/repo/objects/ab/cdef....dirtree`).  The digest is that of
+    /// the entry data for metadata objects, of the (uncompressed, non-sparse)
+    /// content for regular file content objects, and of the link target for
+    /// symbolic links.  The auxiliary extended attribute entries are not listed.
+    /// This requires an additional pass which reads all file content.
+    ///
+    /// The manifest is not signed; callers which need this can sign it, e.g.
+    /// after extracting it from the archive.  This is incompatible with
+    /// [`Self::xattrs_sidecar`].
+    pub emit_integrity_manifest: bool,
+    /// If set, the whole archive is compressed (e.g. to write a `.tar.zst`);
+    /// by default, it is uncompressed.  The archive must be decompressed before
+    /// it is imported.  Any [`Self::blocking_factor`] padding applies to the
+    /// uncompressed archive.  This is only supported by [`export_commit`] (and
+    /// functions using it).
+    pub compression: Option<Compression>,
+}
 
-/// Export an ostree commit to an (uncompressed) tar archive stream.
-#[context("Exporting commit")]
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            checkout_link_type: Default::default(),
+            sparse: false,
+            object_order: None,
+            var_policy: Default::default(),
+            content_rewriter: None,
+            self_check: false,
+            owner_names: None,
+            max_depth: None,
+            commit_metadata_filter: None,
+            xattrs_sidecar: false,
+            include_paths: None,
+            subpath: None,
+            max_entries: None,
+            mtime: None,
+            pax_global: None,
+            compress_files: false,
+            skip_compress_extensions: Default::default(),
+            stable_order: true,
+            content_sort: Default::default(),
+            include_parents: None,
+            composefs: false,
+            forbid_setuid: None,
+            emit_object_list: None,
+            align_content: None,
+            stop_flag: None,
+            cancellable: None,
+            flat_ostree_dir: false,
+            object_layout: ObjectLayout::Default,
+            progress: None,
+            progress_interval_bytes: None,
+            blocking_factor: None,
+            emit_integrity_manifest: false,
+            compression: None,
+        }
+    }
+}
+
+impl ExportOptions {
+    /// The directory containing the repository, i.e. `sysroot/ostree` by default.
+    fn ostreedir(&self) -> &'static str {
+        if self.flat_ostree_dir {
+            OSTREEDIR_FLAT
+        } else {
+            OSTREEDIR
+        }
+    }
+}
+
+/// Reports the progress of an export as the number of bytes of file content
+/// written so far; see [`ExportOptions::progress`].  Clones send to the same
+/// channel.
+#[derive(Debug, Clone)]
+pub struct ExportProgress(Arc<tokio::sync::watch::Sender<u64>>);
+
+impl ExportProgress {
+    /// Create a new progress channel, returning the receiver for its updates.
+    pub fn new() -> (Self, tokio::sync::watch::Receiver<u64>) {
+        let (tx, rx) = tokio::sync::watch::channel(0);
+        (Self(Arc::new(tx)), rx)
+    }
+}
+
+impl PartialEq for ExportProgress {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ExportProgress {}
+
+/// A flag which can be raised to stop an in-progress export; see
+/// [`ExportOptions::stop_flag`].  Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct StopFlag(Arc<AtomicBool>);
+
+impl StopFlag {
+    /// Create a new flag, which is not raised.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Raise the flag, stopping any export using it.
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the flag has been raised.
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl From<Arc<AtomicBool>> for StopFlag {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        Self(flag)
+    }
+}
+
+impl PartialEq for StopFlag {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for StopFlag {}
+
+/// The signature of a [`ContentRewriter`] hook.
+pub type ContentRewriteFn = dyn Fn(&Utf8Path, &[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// A hook to rewrite the content of regular files during export, for example to
+/// scrub secrets or normalize embedded timestamps.
+///
+/// The hook is called with the path of each regular file in the checkout
+/// (e.g. `usr/bin/foo`) and its content; returning `Some` replaces the content,
+/// and `None` leaves it unchanged.
+///
+/// Rewritten content no longer matches the checksum of its ostree object, so when
+/// a rewriter is set, regular files and symbolic links are written inline in the
+/// checkout view and the content objects are omitted from the embedded repository.
+/// The resulting tar stream hence cannot be imported as an ostree commit.  This is
+/// incompatible with [`CheckoutLinkType::Symlink`], [`ExportOptions::sparse`],
+/// [`ExportOptions::object_order`] and [`ExportOptions::content_sort`]; setting
+/// any of those is an error.
+#[derive(Clone)]
+pub struct ContentRewriter(Arc<ContentRewriteFn>);
+
+impl ContentRewriter {
+    /// Create a new content rewriter from the provided function.
+    pub fn new(f: impl Fn(&Utf8Path, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for ContentRewriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentRewriter").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for ContentRewriter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ContentRewriter {}
+
+/// A writer which tracks the number of bytes written to it.
+struct PositionWriter<W> {
+    inner: W,
+    position: Rc<Cell<u64>>,
+}
+
+impl<W: std::io::Write> std::io::Write for PositionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.position.set(self.position.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Export an ostree commit to a tar archive stream, which is uncompressed
+/// unless [`ExportOptions::compression`] is set.
 pub fn export_commit(
     repo: &ostree::Repo,
     rev: &str,
     out: impl std::io::Write,
     options: Option<ExportOptions>,
 ) -> Result<()> {
+    match options.as_ref().and_then(|o| o.compression) {
+        None => export_commit_uncompressed(repo, rev, out, options),
+        Some(Compression::Gzip(level)) => {
+            let mut enc = flate2::write::GzEncoder::new(out, flate2::Compression::new(level));
+            export_commit_uncompressed(repo, rev, &mut enc, options)?;
+            enc.finish().map_err(ExportError::Io)?;
+            Ok(())
+        }
+        Some(Compression::Zstd(level)) => {
+            let mut enc = zstd::stream::write::Encoder::new(out, level).map_err(ExportError::Io)?;
+            export_commit_uncompressed(repo, rev, &mut enc, options)?;
+            enc.finish().map_err(ExportError::Io)?;
+            Ok(())
+        }
+    }
+}
+
+/// Export an ostree commit to an uncompressed tar archive stream; any
+/// [`ExportOptions::compression`] is applied by the caller.
+fn export_commit_uncompressed(
+    repo: &ostree::Repo,
+    rev: &str,
+    out: impl std::io::Write,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    let position = Rc::new(Cell::new(0));
+    let out = PositionWriter {
+        inner: out,
+        position: Rc::clone(&position),
+    };
+    let blocking_factor = options.as_ref().and_then(|o| o.blocking_factor);
+    let mut tar = tar::Builder::new(out);
+    impl_export_commit_into(repo, rev, &mut tar, Some(Rc::clone(&position)), options)?;
+    tar.finish().map_err(ExportError::Io)?;
+    if let Some(factor) = blocking_factor {
+        let record = u64::from(factor) * TAR_BLOCK_SIZE;
+        let padding = (record - position.get() % record) % record;
+        let mut out = tar.into_inner().map_err(ExportError::Io)?;
+        std::io::copy(&mut std::io::repeat(0).take(padding), &mut out).map_err(ExportError::Io)?;
+    }
+    Ok(())
+}
+
+/// A writer which copies all data to each of a set of writers in turn.
+struct TeeWriter<'a, 'b> {
+    writers: &'a mut [&'b mut dyn std::io::Write],
+}
+
+impl TeeWriter<'_, '_> {
+    /// Invoke `f` on each writer, identifying the writer which failed (by its
+    /// index) in the error.
+    fn try_each(
+        &mut self,
+        mut f: impl FnMut(&mut dyn std::io::Write) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        for (i, w) in self.writers.iter_mut().enumerate() {
+            f(&mut **w).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("Writing to output {i}: {e}"))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl std::io::Write for TeeWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Each writer must accept all of the data, so that they stay in sync.
+        self.try_each(|w| w.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.try_each(|w| w.flush())
+    }
+}
+
+/// Export an ostree commit to a tar archive stream like [`export_commit`],
+/// writing the same stream to each of `writers`; for example, to write the archive to
+/// disk while computing its digest.  This avoids a temporary file or a second
+/// export.
+///
+/// Each chunk of data is written completely to every writer in order.  If a
+/// writer fails, the export is aborted, and the error message identifies the
+/// failed writer by its index in `writers` (e.g. `Writing to output 1`); the
+/// writers may have received different amounts of data at that point.
+pub fn export_commit_tee(
+    repo: &ostree::Repo,
+    rev: &str,
+    writers: &mut [&mut dyn std::io::Write],
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    export_commit(repo, rev, TeeWriter { writers }, options)
+}
+
+/// Export an ostree commit to an asynchronous tar archive stream, like
+/// [`export_commit`].  The export runs on a blocking thread, writing to `out`
+/// through a bridge; any error from either is returned.
+///
+/// Unless [`ExportOptions::cancellable`] is set, dropping the returned future
+/// stops the export, leaving `out` truncated.
+pub async fn export_commit_async(
+    repo: &ostree::Repo,
+    rev: &str,
+    out: impl tokio::io::AsyncWrite + Send + Unpin + 'static,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    let repo = repo.clone();
+    let rev = rev.to_owned();
+    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+        let mut options = options.unwrap_or_default();
+        options
+            .cancellable
+            .get_or_insert_with(|| cancellable.clone());
+        let mut out = tokio_util::io::SyncIoBridge::new(out);
+        export_commit(&repo, &rev, &mut out, Some(options))?;
+        out.shutdown().map_err(ExportError::Io)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Export an ostree commit as two (uncompressed) tar archive streams: all of
+/// the `.file-xattrs` objects and their `.file-xattrs-link` hardlinks are
+/// written to `xattrs_out`, and everything else to `main_out`.  Each stream is
+/// a valid tar archive on its own.
+///
+/// Use [`import_tar_split_xattrs`] to import both streams.  The main stream alone
+/// cannot be imported with [`import_tar`], which requires an xattrs reference for
+/// each content object.  With an empty xattrs stream, [`import_tar_split_xattrs`]
+/// writes the content objects without extended attributes; because the checksum
+/// of a content object covers them, this only succeeds if no file in the commit
+/// has any.  Directory metadata is not affected.
+///
+/// This is incompatible with [`ExportOptions::xattrs_sidecar`] and
+/// [`ExportOptions::content_rewriter`], which do not write xattrs objects.
+///
+/// [`import_tar_split_xattrs`]: super::import_tar_split_xattrs
+/// [`import_tar`]: super::import_tar
+#[context("Exporting commit with split xattrs")]
+pub fn export_split_xattrs(
+    repo: &ostree::Repo,
+    rev: &str,
+    main_out: impl std::io::Write,
+    mut xattrs_out: impl std::io::Write,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    let options = options.unwrap_or_default();
+    ensure!(
+        !options.xattrs_sidecar,
+        "Splitting xattrs is incompatible with an xattrs sidecar"
+    );
+    ensure!(
+        options.content_rewriter.is_none(),
+        "Splitting xattrs is incompatible with a content rewriter"
+    );
+    ensure!(
+        !options.self_check,
+        "Verifying the export requires re-readable output; use export_commit_to_path()"
+    );
+    validate_options(&options)?;
     let commit = repo.require_rev(rev)?;
+    let mut tar = tar::Builder::new(main_out);
+    {
+        let writer = &mut OstreeTarWriter::new(repo, commit.as_str(), &mut tar, options)?;
+        let xattrs_out: &mut dyn std::io::Write = &mut xattrs_out;
+        writer.xattrs_out = Some(tar::Builder::new(xattrs_out));
+        writer.write_commit()?;
+        // SAFETY: This was set just above
+        let mut xattrs_tar = writer.xattrs_out.take().unwrap();
+        xattrs_tar.finish().map_err(ExportError::Io)?;
+    }
+    tar.finish().map_err(ExportError::Io)?;
+    Ok(())
+}
+
+/// The number of chunks of the tar stream (each up to [`BUF_CAPACITY`] bytes)
+/// buffered between the writer and reader threads of [`export_entries`].
+const ENTRIES_PIPE_DEPTH: usize = 4;
+
+/// A writer which sends the data written to it over a channel, to a [`ChannelReader`].
+struct ChannelWriter(std::sync::mpsc::SyncSender<Vec<u8>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Tar entry reader exited")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader for the data sent by a [`ChannelWriter`]; the end of the stream is
+/// reached once the writer has been dropped.
+struct ChannelReader {
+    rx: std::sync::mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let Ok(buf) = self.rx.recv() else {
+                return Ok(0);
+            };
+            self.buf = buf;
+            self.pos = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// An entry of the tar stream generated by [`export_entries`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ExportEntry {
+    /// The full path of the entry; this may differ from the path in the header,
+    /// which is truncated for long paths.
+    pub path: Utf8PathBuf,
+    /// The full link target of a hard or symbolic link.
+    pub link_name: Option<Utf8PathBuf>,
+    /// The tar header.
+    pub header: tar::Header,
+    /// The content of the entry.
+    pub data: Vec<u8>,
+}
+
+/// Parse the entries of a tar stream, sending each one to `tx` until the
+/// receiver goes away.
+fn send_entries(
+    src: impl Read,
+    tx: &std::sync::mpsc::SyncSender<Result<ExportEntry>>,
+) -> Result<()> {
+    let mut archive = tar::Archive::new(src);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = Utf8PathBuf::try_from(entry.path()?.into_owned())?;
+        let link_name = entry
+            .link_name()?
+            .map(|p| Utf8PathBuf::try_from(p.into_owned()))
+            .transpose()?;
+        let header = entry.header().clone();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        let entry = ExportEntry {
+            path,
+            link_name,
+            header,
+            data,
+        };
+        if tx.send(Ok(entry)).is_err() {
+            return Ok(());
+        }
+    }
+    // Consume the padding after the end of the archive, so that the writer does
+    // not fail.
+    std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+    Ok(())
+}
+
+/// Export an ostree commit as a sequence of tar entries, as generated by
+/// [`export_commit`], so that the export can be filtered or transformed
+/// inline without writing the archive.
+///
+/// The archive is generated on a separate thread, and parsed on another.  The
+/// content of each entry is read into memory, so memory usage is proportional to
+/// the size of the largest file, in addition to a bounded buffer of the archive
+/// stream; the export blocks until the consumer takes the next entry.  Dropping
+/// the iterator cancels the export.  An error exporting the commit is returned as
+/// the last item.
+pub fn export_entries(
+    repo: &ostree::Repo,
+    rev: &str,
+    options: Option<ExportOptions>,
+) -> Result<impl Iterator<Item = Result<ExportEntry>>> {
+    let commit = repo.require_rev(rev)?.to_string();
+    let repo = repo.clone();
+    let (data_tx, data_rx) = std::sync::mpsc::sync_channel(ENTRIES_PIPE_DEPTH);
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    let exporter = std::thread::Builder::new()
+        .name("tar-export".into())
+        .spawn(move || {
+            let mut out = std::io::BufWriter::with_capacity(BUF_CAPACITY, ChannelWriter(data_tx));
+            export_commit(&repo, &commit, &mut out, options)?;
+            out.flush()?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+    std::thread::Builder::new()
+        .name("tar-export-entries".into())
+        .spawn(move || {
+            let src = ChannelReader {
+                rx: data_rx,
+                buf: Vec::new(),
+                pos: 0,
+            };
+            let r = send_entries(src, &tx);
+            let exported = exporter
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Export thread panicked")));
+            // An error exporting is the cause of any error parsing the stream.
+            if let Err(e) = exported.and(r) {
+                // The receiver may have gone away, in which case there is no one to tell.
+                let _ = tx.send(Err(e));
+            }
+        })?;
+    Ok(rx.into_iter())
+}
+
+/// Export an ostree commit into an existing tar archive builder.
+///
+/// Unlike [`export_commit`], the archive is not finished, so the caller may add
+/// further entries before and after the ostree content.  Note however that the
+/// root directory (`./`) written by this function must be the first entry of the
+/// ostree content; the caller must not add entries for the root directory or
+/// within `sysroot/` before it.
+///
+/// [`ExportOptions::align_content`] is not supported, as the offset in the
+/// archive is unknown; use [`export_commit`].
+pub fn export_commit_into<W: std::io::Write>(
+    repo: &ostree::Repo,
+    rev: &str,
+    builder: &mut tar::Builder<W>,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    impl_export_commit_into(repo, rev, builder, None, options)
+}
+
+#[context("Exporting commit")]
+fn impl_export_commit_into<W: std::io::Write>(
+    repo: &ostree::Repo,
+    rev: &str,
+    builder: &mut tar::Builder<W>,
+    position: Option<Rc<Cell<u64>>>,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    let commit = repo.require_rev(rev)?;
+    let options = options.unwrap_or_default();
+    impl_export(repo, commit.as_str(), builder, position, options)?;
+    Ok(())
+}
+
+/// Return an error if the object is not in the repository.
+fn require_object(
+    repo: &ostree::Repo,
+    objtype: ostree::ObjectType,
+    checksum: String,
+) -> Result<()> {
+    if !repo.has_object(objtype, &checksum, gio::Cancellable::NONE)? {
+        return Err(ExportError::MissingObject { checksum }.into());
+    }
+    Ok(())
+}
+
+/// Return an error for the first object referenced by the dirtree `checksum`
+/// (recursively) which is not in the repository.
+fn check_dirtree_objects(
+    repo: &ostree::Repo,
+    checksum: &str,
+    seen: &mut HashSet<String>,
+) -> Result<()> {
+    if !seen.insert(checksum.to_string()) {
+        return Ok(());
+    }
+    let v = load_metadata(repo, ostree::ObjectType::DirTree, checksum)?;
+    let v = v.data_as_bytes();
+    let v = v.try_as_aligned()?;
+    let (files, dirs) = gv_dirtree!().cast(v).to_tuple();
+    for file in files {
+        let (_, csum) = file.to_tuple();
+        require_object(repo, ostree::ObjectType::File, hex::encode(csum))?;
+    }
+    for dir in dirs {
+        let (_, contents_csum, meta_csum) = dir.to_tuple();
+        require_object(repo, ostree::ObjectType::DirMeta, hex::encode(meta_csum))?;
+        check_dirtree_objects(repo, &hex::encode(contents_csum), seen)?;
+    }
+    Ok(())
+}
+
+/// Export a commit object which is provided directly instead of being read from
+/// the repository (e.g. one synthesized or rewritten in memory) to an
+/// (uncompressed) tar archive stream, along with the provided detached metadata
+/// (of type `a{sv}`).  The objects the commit references are read from the
+/// repository; they are all checked to be present before anything is written,
+/// erroring with [`ExportError::MissingObject`] for the first missing one.
+///
+/// Returns the checksum of the commit object.
+#[context("Exporting commit variant")]
+pub fn export_commit_variant(
+    repo: &ostree::Repo,
+    commit: &glib::Variant,
+    detached_metadata: Option<&glib::Variant>,
+    out: impl std::io::Write,
+    options: Option<ExportOptions>,
+) -> Result<String> {
+    let commit_ty = ostree::CommitVariantType::static_variant_type();
+    ensure!(
+        commit.is_type(&commit_ty),
+        "Expected commit of type {commit_ty}, found {}",
+        commit.type_()
+    );
+    if let Some(meta) = detached_metadata {
+        ensure!(
+            meta.type_() == glib::VariantTy::VARDICT,
+            "Expected detached metadata of type a{{sv}}, found {}",
+            meta.type_()
+        );
+    }
+    let options = options.unwrap_or_default();
+    ensure!(
+        !options.self_check,
+        "Verifying the export requires re-readable output; use export_commit_to_path()"
+    );
+    validate_options(&options)?;
+    let commit = commit.normal_form();
+    let checksum = hex::encode(openssl::sha::sha256(&commit.data_as_bytes()));
+    {
+        let commit_bytes = commit.data_as_bytes();
+        let commit_bytes = commit_bytes.try_as_aligned()?;
+        let commit_tuple = gv_commit!().cast(commit_bytes).to_tuple();
+        require_object(
+            repo,
+            ostree::ObjectType::DirMeta,
+            hex::encode(commit_tuple.7),
+        )?;
+        let contents = hex::encode(commit_tuple.6);
+        check_dirtree_objects(repo, &contents, &mut HashSet::new())?;
+    }
+
     let mut tar = tar::Builder::new(out);
+    {
+        let writer = &mut OstreeTarWriter::new_for_commit_object(
+            repo,
+            &checksum,
+            commit,
+            detached_metadata.cloned(),
+            &mut tar,
+            options,
+        );
+        writer.write_commit()?;
+    }
+    tar.finish().map_err(ExportError::Io)?;
+    Ok(checksum)
+}
+
+/// Export multiple ostree commits, each to its own (uncompressed) tar archive stream.
+///
+/// A new output stream is created via `new_writer` for each revision.  Because a
+/// failure may occur partway through writing an archive, each revision uses an
+/// independent writer; an error exporting one revision does not stop the
+/// export of the others.  On error, the output stream for that revision may
+/// contain a truncated archive, and it is the caller's responsibility to discard it.
+///
+/// Returns the result for each revision, in the same order as the input.
+pub fn export_commits_lenient<W: std::io::Write>(
+    repo: &ostree::Repo,
+    revs: &[&str],
+    mut new_writer: impl FnMut(&str) -> Result<W>,
+    options: &ExportOptions,
+) -> Vec<(String, Result<ExportStats>)> {
+    revs.iter()
+        .map(|&rev| {
+            let r = (|| -> Result<ExportStats> {
+                let commit = repo.require_rev(rev)?;
+                let mut tar = tar::Builder::new(new_writer(rev)?);
+                let stats = impl_export(repo, commit.as_str(), &mut tar, None, options.clone())?;
+                tar.into_inner()?.flush()?;
+                Ok(stats)
+            })()
+            .with_context(|| format!("Exporting {rev}"));
+            (rev.to_string(), r)
+        })
+        .collect()
+}
+
+/// Export an ostree commit to a tar archive at the target path.
+///
+/// The archive is first written to a temporary file alongside the destination
+/// (with a `.tmp` suffix), which is synced to disk and then atomically renamed
+/// into place.  On error, the temporary file is removed.  Hence, a truncated
+/// archive will never be visible at `dest`.
+#[context("Exporting commit to {dest}")]
+pub fn export_commit_to_path(
+    repo: &ostree::Repo,
+    rev: &str,
+    dest: &Utf8Path,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    let mut options = options.unwrap_or_default();
+    let self_check = std::mem::take(&mut options.self_check);
+    if self_check {
+        ensure!(
+            options.content_rewriter.is_none(),
+            "Verifying the export is incompatible with a content rewriter"
+        );
+        ensure!(
+            !options.flat_ostree_dir,
+            "Verifying the export is incompatible with a flat ostree directory"
+        );
+        ensure!(
+            options.subpath.is_none(),
+            "Verifying the export is incompatible with a subpath"
+        );
+    }
+    let compression = options.compression;
+    let tmp: Utf8PathBuf = format!("{dest}.tmp").into();
+    let r = (|| -> Result<()> {
+        let commit = repo.require_rev(rev)?;
+        let f = std::fs::File::create(&tmp)?;
+        let mut w = std::io::BufWriter::with_capacity(BUF_CAPACITY, f);
+        export_commit(repo, &commit, &mut w, Some(options))?;
+        let f = w.into_inner().map_err(|e| e.into_error())?;
+        f.sync_all()?;
+        if self_check {
+            verify_export(repo, &commit, &tmp, compression)?;
+        }
+        std::fs::rename(&tmp, dest)?;
+        Ok(())
+    })();
+    if r.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    r
+}
+
+/// Import the tar archive at `path` into a scratch repository, and verify that it
+/// yields the commit `checksum`.
+#[context("Verifying exported archive")]
+fn verify_export(
+    repo: &ostree::Repo,
+    checksum: &str,
+    path: &Utf8Path,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let td = tempfile::tempdir_in(format!("/proc/self/fd/{}/tmp", repo.dfd()))?;
+    let scratch = ostree::Repo::new_for_path(td.path());
+    scratch.create(ostree::RepoMode::Archive, cancellable)?;
+    let src = BufReader::with_capacity(BUF_CAPACITY, std::fs::File::open(path)?);
+    let src: Box<dyn Read + Send> = match compression {
+        None => Box::new(src),
+        Some(Compression::Gzip(_)) => Box::new(flate2::bufread::GzDecoder::new(src)),
+        Some(Compression::Zstd(_)) => Box::new(zstd::stream::read::Decoder::with_buffer(src)?),
+    };
+    let mut archive = tar::Archive::new(src);
+    let txn = scratch.auto_transaction(cancellable)?;
+    let mut importer = super::import::Importer::new_for_commit(&scratch, None);
+    importer.import_commit(&mut archive, cancellable)?;
+    let imported = importer.finish_import_commit();
+    txn.commit(cancellable)?;
+    ensure!(
+        imported == checksum,
+        "Exported archive contains commit {imported}, expected {checksum}"
+    );
+    Ok(())
+}
+
+/// Export the objects of an ostree commit as individual files in the existing
+/// directory `dest`, in the same layout as the repository embedded in a tar
+/// export, i.e. `objects/XX/...`, with extended attributes stored in separate
+/// objects.  This is useful to inspect or diff the objects of a commit; the
+/// result is not a complete ostree repository.
+///
+/// File ownership is not preserved.  Options which omit the embedded repository
+/// (i.e. [`ExportOptions::xattrs_sidecar`] and [`ExportOptions::content_rewriter`])
+/// are not supported.
+#[context("Exporting objects of {rev} to {dest}")]
+pub fn export_objects_to_dir(
+    repo: &ostree::Repo,
+    rev: &str,
+    dest: &Utf8Path,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    use cap_std_ext::cap_std;
+
     let options = options.unwrap_or_default();
-    impl_export(repo, commit.as_str(), &mut tar, options)?;
-    tar.finish()?;
+    ensure!(
+        !options.xattrs_sidecar && options.content_rewriter.is_none(),
+        "Exporting objects requires the embedded repository"
+    );
+    ensure!(
+        !options.self_check,
+        "Verifying the export is not supported when exporting objects"
+    );
+    validate_options(&options)?;
+    let repodir = Utf8Path::new(options.ostreedir()).join("repo");
+    let dest = cap_std::fs::Dir::open_ambient_dir(dest, cap_std::ambient_authority())?;
+    let commit = repo.require_rev(rev)?;
+
+    // Write the objects (but not the checkout view) to a temporary tar stream, and
+    // then unpack them.
+    let mut tmpf = tempfile::tempfile()?;
+    {
+        let w = std::io::BufWriter::with_capacity(BUF_CAPACITY, &mut tmpf);
+        let mut out = tar::Builder::new(w);
+        {
+            let writer = &mut OstreeTarWriter::new(repo, &commit, &mut out, options)?;
+            writer.omit_checkout = true;
+            writer.write_commit()?;
+        }
+        out.into_inner()?.flush()?;
+    }
+    tmpf.seek(std::io::SeekFrom::Start(0))?;
+    let mut archive = tar::Archive::new(BufReader::with_capacity(BUF_CAPACITY, tmpf));
+    let relpath = |path: &std::path::Path| -> Result<Option<Utf8PathBuf>> {
+        let path =
+            Utf8Path::from_path(path).ok_or_else(|| anyhow!("Invalid non-UTF8 path: {path:?}"))?;
+        let path = path.strip_prefix(TAR_PATH_PREFIX_V0).unwrap_or(path);
+        Ok(path
+            .strip_prefix(&repodir)
+            .ok()
+            .filter(|p| p.starts_with("objects"))
+            .map(ToOwned::to_owned))
+    };
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let Some(path) = relpath(&entry.path()?)? else {
+            continue;
+        };
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => {
+                dest.create_dir_all(&path)?;
+            }
+            tar::EntryType::Regular | tar::EntryType::GNUSparse => {
+                let mode = entry.header().mode()?;
+                let mut f = dest.create(&path)?;
+                std::io::copy(&mut entry, &mut f).with_context(|| format!("Writing {path}"))?;
+                rustix::fs::fchmod(&f, rustix::fs::Mode::from_raw_mode(mode))?;
+            }
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("Missing symlink target for {path}"))?;
+                dest.symlink(target, &path)?;
+            }
+            tar::EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("Missing hardlink target for {path}"))?;
+                let target = relpath(&target)?
+                    .ok_or_else(|| anyhow!("Invalid hardlink target for {path}"))?;
+                dest.hard_link(&target, &dest, &path)?;
+            }
+            o => anyhow::bail!("Unexpected entry type {o:?} for {path}"),
+        }
+    }
     Ok(())
 }
 
@@ -600,11 +2866,11 @@ fn write_chunk<W: std::io::Write>(
     chunk: chunking::ChunkMapping,
 ) -> Result<()> {
     for (checksum, (_size, paths)) in chunk.into_iter() {
-        let (objpath, h) = writer.append_content(checksum.borrow())?;
+        let (objpath, h, target) = writer.append_content(checksum.borrow())?;
         for path in paths.iter() {
             let path = path_for_tar_v1(path);
             let h = h.clone();
-            writer.append_content_hardlink(&objpath, h, path)?;
+            writer.append_content_hardlink(&objpath, h, target.as_deref(), path)?;
         }
     }
     Ok(())
@@ -618,8 +2884,7 @@ pub(crate) fn export_chunk<W: std::io::Write>(
     out: &mut tar::Builder<W>,
 ) -> Result<()> {
     // For chunking, we default to format version 1
-    #[allow(clippy::needless_update)]
-    let opts = ExportOptions;
+    let opts = ExportOptions::default();
     let writer = &mut OstreeTarWriter::new(repo, commit, out, opts)?;
     writer.write_repo_structure()?;
     write_chunk(writer, chunk)
@@ -633,7 +2898,7 @@ pub(crate) fn export_final_chunk<W: std::io::Write>(
     remainder: chunking::Chunk,
     out: &mut tar::Builder<W>,
 ) -> Result<()> {
-    let options = ExportOptions;
+    let options = ExportOptions::default();
     let writer = &mut OstreeTarWriter::new(repo, commit_checksum, out, options)?;
     // For the final chunk, output the commit object, plus all ostree metadata objects along with
     // the containing directories.
@@ -643,6 +2908,278 @@ pub(crate) fn export_final_chunk<W: std::io::Write>(
     write_chunk(writer, remainder.content)
 }
 
+/// The prefix used for whiteout entries in container image layers.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Write a file from a commit as a plain filesystem entry (as in a non-ostree
+/// container image layer).  If `recurse` is set, the contents of directories
+/// are also written.
+fn append_delta_entry<W: std::io::Write>(
+    out: &mut tar::Builder<W>,
+    f: &gio::File,
+    path: &Utf8Path,
+    recurse: bool,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let queryflags = gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
+    let info = f.query_info("standard::*,unix::*", queryflags, cancellable)?;
+    let mut h = tar::Header::new_gnu();
+    h.set_uid(info.attribute_uint32("unix::uid") as u64);
+    h.set_gid(info.attribute_uint32("unix::gid") as u64);
+    h.set_mode(info.attribute_uint32("unix::mode") & !libc::S_IFMT);
+    h.set_size(0);
+    match info.file_type() {
+        gio::FileType::Directory => {
+            h.set_entry_type(tar::EntryType::Directory);
+            out.append_data(&mut h, path, std::io::empty())?;
+            if !recurse {
+                return Ok(());
+            }
+            let children = f.enumerate_children("standard::name", queryflags, cancellable)?;
+            while let Some(child_info) = children.next_file(cancellable)? {
+                let child = children.child(&child_info);
+                let name = child_info.name();
+                let name = name.to_str().expect("UTF-8 ostree name");
+                append_delta_entry(out, &child, &path.join(name), true)?;
+            }
+        }
+        gio::FileType::Regular => {
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_size(info.size() as u64);
+            let instream = f.read(cancellable)?.into_read();
+            let instream = BufReader::with_capacity(BUF_CAPACITY, instream);
+            out.append_data(&mut h, path, instream)
+                .with_context(|| format!("Writing regfile {path}"))?;
+        }
+        gio::FileType::SymbolicLink => {
+            let target = info
+                .symlink_target()
+                .ok_or_else(|| anyhow!("Missing symlink target for {path}"))?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid UTF-8 symlink target: {target:?}"))?;
+            h.set_entry_type(tar::EntryType::Symlink);
+            if symlink_is_denormal(target) {
+                h.set_link_name_literal(target)?;
+                out.append_data(&mut h, path, std::io::empty())?;
+            } else {
+                out.append_link(&mut h, path, target)?;
+            }
+        }
+        o => anyhow::bail!("Unhandled file type {o:?} for {path}"),
+    }
+    Ok(())
+}
+
+/// Export the changes between two ostree commits as a plain filesystem tar
+/// stream, suitable for use as a container image layer on top of `base_rev`.
+///
+/// Added and changed files and directories are written in full from `target_rev`,
+/// and removed paths are represented by `.wh.` whiteout entries.  Extended
+/// attributes are not included.
+#[context("Exporting delta from {base_rev} to {target_rev}")]
+pub fn export_commit_delta(
+    repo: &ostree::Repo,
+    base_rev: &str,
+    target_rev: &str,
+    out: impl std::io::Write,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let subdir: Option<&str> = None;
+    let diff = crate::diff::diff(repo, base_rev, target_rev, subdir)?;
+    let (target_root, _) = repo.read_commit(target_rev, cancellable)?;
+    let mut out = tar::Builder::new(out);
+
+    for removed in diff.removed_files.iter().chain(diff.removed_dirs.iter()) {
+        let removed = Utf8Path::new(removed.trim_start_matches('/'));
+        let name = removed
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid path {removed}"))?;
+        let whiteout = removed
+            .parent()
+            .unwrap_or(Utf8Path::new(""))
+            .join(format!("{WHITEOUT_PREFIX}{name}"));
+        tar_append_default_data(&mut out, &whiteout, &[], None)?;
+    }
+    // Directories whose metadata changed are written without their contents;
+    // any changed content is covered by the file sets.
+    for changed in diff.changed_dirs.iter() {
+        let path = Utf8Path::new(changed.trim_start_matches('/'));
+        let f = target_root.resolve_relative_path(path);
+        append_delta_entry(&mut out, &f, path, false)?;
+    }
+    for p in diff
+        .added_dirs
+        .iter()
+        .chain(diff.added_files.iter())
+        .chain(diff.changed_files.iter())
+    {
+        let path = Utf8Path::new(p.trim_start_matches('/'));
+        let f = target_root.resolve_relative_path(path);
+        append_delta_entry(&mut out, &f, path, true)?;
+    }
+
+    out.finish()?;
+    Ok(())
+}
+
+/// Compute the SHA-256 of the content of the regular file content object `checksum`.
+fn content_sha256(repo: &ostree::Repo, checksum: &str) -> Result<[u8; 32]> {
+    let instream = load_content(repo, checksum)?
+        .0
+        .ok_or_else(|| anyhow!("Missing content stream"))?;
+    let mut r = HashingReader::new(BufReader::with_capacity(BUF_CAPACITY, instream.into_read()));
+    std::io::copy(&mut r, &mut std::io::sink())
+        .with_context(|| format!("Reading content object {checksum}"))?;
+    Ok(r.hasher.finish())
+}
+
+/// A reader which computes the SHA-256 of the data read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: openssl::sha::Sha256,
+}
+
+impl<R: std::io::Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: openssl::sha::Sha256::new(),
+        }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// The identity of an entry written by [`merge_exports`], used to detect
+/// conflicting entries for the same path.
+#[derive(Debug, PartialEq, Eq)]
+struct MergedEntry {
+    entry_type: tar::EntryType,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    link_name: Option<Utf8PathBuf>,
+    sha256: [u8; 32],
+}
+
+/// Remove any `.` components from a path in a tar stream.
+fn normalize_merge_path(p: &Utf8Path) -> Utf8PathBuf {
+    p.components()
+        .filter(|c| !matches!(c, Utf8Component::CurDir))
+        .collect()
+}
+
+/// Merge multiple tar streams generated by ostree export (for example, the layers of
+/// a chunked container image) into a single stream.
+///
+/// Entries are written in input order.  An entry whose path was already written is
+/// skipped if it is identical (type, mode, ownership, link target and content), and
+/// is an error otherwise.  A hardlink must follow its target.  At most one commit
+/// object may be present, and the input which contains it must come before any other
+/// objects are written, as required when importing the result with [`import_tar`].
+///
+/// GNU sparse entries are written as regular files.
+///
+/// [`import_tar`]: super::import_tar
+#[context("Merging exports")]
+pub fn merge_exports<R: std::io::Read>(inputs: &mut [R], out: impl std::io::Write) -> Result<()> {
+    let objects = Utf8Path::new(OSTREEDIR).join("repo/objects");
+    let mut out = tar::Builder::new(out);
+    let mut written: HashMap<Utf8PathBuf, MergedEntry> = HashMap::new();
+    let mut have_objects = false;
+    let mut commit: Option<Utf8PathBuf> = None;
+    for (i, input) in inputs.iter_mut().enumerate() {
+        let mut archive = tar::Archive::new(input);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let orig_path = entry.path()?.into_owned();
+            let orig_path = Utf8PathBuf::try_from(orig_path)
+                .map_err(|e| anyhow!("Invalid non-UTF8 path in input {i}: {e}"))?;
+            let path = normalize_merge_path(&orig_path);
+            let header = entry.header();
+            let entry_type = match header.entry_type() {
+                tar::EntryType::GNUSparse => tar::EntryType::Regular,
+                o => o,
+            };
+            let link_name = entry
+                .link_name()?
+                .map(|l| {
+                    Utf8PathBuf::try_from(l.into_owned())
+                        .map_err(|_| anyhow!("Invalid non-UTF8 link target for {path}"))
+                })
+                .transpose()?;
+            let mut merged = MergedEntry {
+                entry_type,
+                mode: header.mode()?,
+                uid: header.uid()?,
+                gid: header.gid()?,
+                link_name,
+                sha256: [0u8; 32],
+            };
+            if entry_type == tar::EntryType::Link {
+                let target = merged.link_name.as_deref().map(normalize_merge_path);
+                let target = target.ok_or_else(|| anyhow!("Missing hardlink target for {path}"))?;
+                ensure!(
+                    written.contains_key(&target),
+                    "Hardlink {path} precedes its target {target}"
+                );
+            }
+            let mut reader = HashingReader::new(entry);
+            if let Some(prev) = written.get(&path) {
+                std::io::copy(&mut reader, &mut std::io::sink())?;
+                merged.sha256 = reader.hasher.finish();
+                ensure!(
+                    prev == &merged,
+                    "Conflicting entries for {path} in input {i}"
+                );
+                continue;
+            }
+            if entry_type != tar::EntryType::Directory && path.starts_with(&objects) {
+                if path.extension() == Some("commit") {
+                    if let Some(prev) = commit.as_ref() {
+                        anyhow::bail!("Found multiple commit objects: {prev} and {path}");
+                    }
+                    ensure!(
+                        !have_objects,
+                        "Commit object {path} must precede all other objects"
+                    );
+                    commit = Some(path.clone());
+                }
+                have_objects = true;
+            }
+            let mut h = reader.inner.header().clone();
+            h.set_entry_type(entry_type);
+            h.set_size(if entry_type == tar::EntryType::Regular {
+                reader.inner.size()
+            } else {
+                0
+            });
+            match merged.link_name.as_deref() {
+                Some(target)
+                    if entry_type == tar::EntryType::Symlink
+                        && symlink_is_denormal(target.as_str()) =>
+                {
+                    h.set_link_name_literal(target.as_str())?;
+                    out.append_data(&mut h, &orig_path, std::io::empty())?;
+                }
+                Some(target) => out.append_link(&mut h, &orig_path, target)?,
+                None => out.append_data(&mut h, &orig_path, &mut reader)?,
+            }
+            merged.sha256 = reader.hasher.finish();
+            written.insert(path, merged);
+        }
+    }
+    out.finish()?;
+    Ok(())
+}
+
 /// Process an exported tar stream, and update the detached metadata.
 #[allow(clippy::while_let_on_iterator)]
 #[context("Replacing detached metadata")]
@@ -665,7 +3202,7 @@ pub(crate) fn reinject_detached_metadata<C: IsA<gio::Cancellable>>(
         let path = entry.path()?;
         let path: &Utf8Path = (&*path).try_into()?;
         if !(header.entry_type() == tar::EntryType::Regular && path.as_str().ends_with(".commit")) {
-            crate::tar::write::copy_entry(entry, dest, None)?;
+            crate::tar::write::copy_entry(entry, dest, None, NonUtf8Policy::Error)?;
         } else {
             commit_ent = Some(entry);
             break;
@@ -677,12 +3214,12 @@ pub(crate) fn reinject_detached_metadata<C: IsA<gio::Cancellable>>(
         .ok_or_else(|| anyhow!("Invalid non-utf8 path {:?}", commit_path))?;
     let (checksum, objtype) = crate::tar::import::Importer::parse_metadata_entry(commit_path)?;
     assert_eq!(objtype, ostree::ObjectType::Commit); // Should have been verified above
-    crate::tar::write::copy_entry(commit_ent, dest, None)?;
+    crate::tar::write::copy_entry(commit_ent, dest, None, NonUtf8Policy::Error)?;
 
     // If provided, inject our new detached metadata object
     if let Some(detached_buf) = detached_buf {
         let detached_path = object_path(ostree::ObjectType::CommitMeta, &checksum);
-        tar_append_default_data(dest, &detached_path, detached_buf)?;
+        tar_append_default_data(dest, &detached_path, detached_buf, None)?;
     }
 
     // If the next entry is detached metadata, then drop it since we wrote a new one
@@ -693,7 +3230,7 @@ pub(crate) fn reinject_detached_metadata<C: IsA<gio::Cancellable>>(
     let next_ent_path: &Utf8Path = (&*next_ent_path).try_into()?;
     let objtype = crate::tar::import::Importer::parse_metadata_entry(next_ent_path)?.1;
     if objtype != ostree::ObjectType::CommitMeta {
-        crate::tar::write::copy_entry(next_ent, dest, None)?;
+        crate::tar::write::copy_entry(next_ent, dest, None, NonUtf8Policy::Error)?;
     }
 
     // Finally, copy all remaining entries.
@@ -701,7 +3238,7 @@ pub(crate) fn reinject_detached_metadata<C: IsA<gio::Cancellable>>(
         if let Some(c) = cancellable {
             c.set_error_if_cancelled()?;
         }
-        crate::tar::write::copy_entry(entry?, dest, None)?;
+        crate::tar::write::copy_entry(entry?, dest, None, NonUtf8Policy::Error)?;
     }
 
     Ok(())
@@ -724,6 +3261,18 @@ pub fn update_detached_metadata<D: std::io::Write, C: IsA<gio::Cancellable>>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_pax_record() {
+        assert_eq!(pax_record("a", "b"), b"6 a=b\n");
+        assert_eq!(pax_record("k", "vvvv"), b"9 k=vvvv\n");
+        // The length field itself grows from one to two digits here
+        assert_eq!(pax_record("k", "vvvvv"), b"11 k=vvvvv\n");
+        let long = "x".repeat(95);
+        let r = pax_record("k", &long);
+        assert_eq!(r.len(), 102);
+        assert!(r.starts_with(b"102 k=x"));
+    }
+
     #[test]
     fn test_map_path() {
         assert_eq!(map_path("/".into()), Utf8Path::new("/"));
@@ -754,11 +3303,68 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_relative_link_target() {
+        let target = Utf8Path::new("sysroot/ostree/repo/objects/ab/cdef.file");
+        let cases = [
+            (
+                "./usr/bin/bash",
+                "../../sysroot/ostree/repo/objects/ab/cdef.file",
+            ),
+            (
+                "usr/bin/bash",
+                "../../sysroot/ostree/repo/objects/ab/cdef.file",
+            ),
+            (
+                "./etc/foo.conf",
+                "../sysroot/ostree/repo/objects/ab/cdef.file",
+            ),
+            ("toplevel", "sysroot/ostree/repo/objects/ab/cdef.file"),
+            (
+                "usr/lib/modules/5.10/vmlinuz",
+                "../../../../sysroot/ostree/repo/objects/ab/cdef.file",
+            ),
+        ];
+        for (dest, expected) in cases {
+            assert_eq!(relative_link_target(dest.into(), target), expected);
+        }
+    }
+
+    #[test]
+    fn test_sparse_segments() {
+        let blksize = SPARSE_BLOCK_SIZE as u64;
+        let mut data = vec![0u8; SPARSE_BLOCK_SIZE * 8 + 100];
+        assert!(find_data_segments(data.as_slice()).unwrap().is_empty());
+        data[10] = 1;
+        data[SPARSE_BLOCK_SIZE * 3 + 5] = 2;
+        data[SPARSE_BLOCK_SIZE * 4] = 3;
+        let segments = find_data_segments(data.as_slice()).unwrap();
+        assert_eq!(segments, [(0, blksize), (3 * blksize, 2 * blksize)]);
+        // A trailing partial block
+        data[SPARSE_BLOCK_SIZE * 8 + 50] = 4;
+        let segments = find_data_segments(data.as_slice()).unwrap();
+        assert_eq!(
+            segments,
+            [(0, blksize), (3 * blksize, 2 * blksize), (8 * blksize, 100)]
+        );
+
+        let mut buf = Vec::new();
+        SparseDataReader::new(data.as_slice(), segments.clone())
+            .read_to_end(&mut buf)
+            .unwrap();
+        let expected = segments
+            .iter()
+            .flat_map(|&(offset, len)| &data[offset as usize..(offset + len) as usize])
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(buf, expected);
+    }
+
     #[test]
     fn test_v1_xattrs_object_path() {
         let checksum = "b8627e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7";
         let expected = "sysroot/ostree/repo/objects/b8/627e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7.file-xattrs";
-        let output = v1_xattrs_object_path(checksum);
+        let output = v1_xattrs_object_path(OSTREEDIR, ObjectLayout::Default, checksum);
         assert_eq!(&output, expected);
     }
 
@@ -766,7 +3372,30 @@ mod tests {
     fn test_v1_xattrs_link_object_path() {
         let checksum = "b8627e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7";
         let expected = "sysroot/ostree/repo/objects/b8/627e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7.file-xattrs-link";
-        let output = v1_xattrs_link_object_path(checksum);
+        let output = v1_xattrs_link_object_path(OSTREEDIR, ObjectLayout::Default, checksum);
         assert_eq!(&output, expected);
     }
+
+    #[test]
+    fn test_object_path_layouts() {
+        let checksum = "b8627e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7";
+        let cases = [
+            (
+                ObjectLayout::Default,
+                "sysroot/ostree/repo/objects/b8/627e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7",
+            ),
+            (
+                ObjectLayout::TwoLevel,
+                "sysroot/ostree/repo/objects/b8/62/7e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7",
+            ),
+        ];
+        for (layout, expected) in cases {
+            let output = object_path_in(OSTREEDIR, layout, ostree::ObjectType::DirTree, checksum);
+            assert_eq!(output, format!("{expected}.dirtree"));
+            let output = v1_xattrs_object_path(OSTREEDIR, layout, checksum);
+            assert_eq!(output, format!("{expected}.file-xattrs"));
+            let output = v1_xattrs_link_object_path(OSTREEDIR, layout, checksum);
+            assert_eq!(output, format!("{expected}.file-xattrs-link"));
+        }
+    }
 }
@@ -9,9 +9,10 @@ use gio::glib;
 use gio::prelude::*;
 use glib::Variant;
 use ostree::gio;
-use std::collections::BTreeSet;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
 use tracing::{event, instrument, Level};
 
 /// Arbitrary limit on xattrs to avoid RAM exhaustion attacks. The actual filesystem limits are often much smaller.
@@ -36,6 +37,19 @@ struct ImportStats {
     regfile_small: u32,
     regfile_large: u32,
     symlinks: u32,
+    /// Objects which were already present
+    skipped: u32,
+    /// Objects already seen earlier in the same import; see [`Importer::seen_objects`].
+    duplicates: u32,
+    /// Intermediate transaction commits; see [`Importer::commit_batch_size`].
+    flushes: u32,
+}
+
+impl ImportStats {
+    /// The number of dirtree, dirmeta and content objects written.
+    fn written(&self) -> u32 {
+        self.dirtree + self.dirmeta + self.regfile_small + self.regfile_large + self.symlinks
+    }
 }
 
 enum ImporterMode {
@@ -43,15 +57,36 @@ enum ImporterMode {
     ObjectSet(BTreeSet<String>),
 }
 
-/// Importer machine.
-pub(crate) struct Importer {
-    repo: ostree::Repo,
-    remote: Option<String>,
+/// Tracks the xattrs objects in a tar stream, and which of them applies
+/// to the next content object.
+#[derive(Debug, Default)]
+struct XattrsCache {
     // Cache of xattrs, keyed by their content checksum.
     xattrs: HashMap<String, glib::Variant>,
     // Reusable buffer for xattrs references. It maps a file checksum (.0)
     // to an xattrs checksum (.1) in the `xattrs` cache above.
     next_xattrs: Option<(String, String)>,
+    // Xattrs references read ahead of the content objects, from a separate
+    // stream as written by `export_split_xattrs`.
+    preloaded: HashMap<String, String>,
+    // Whether the xattrs were read from a separate stream; only then may a
+    // content object lack an xattrs reference.
+    split: bool,
+}
+
+/// The error for xattrs in the v0 format, which is not supported with a separate
+/// xattrs stream.
+const V0_SPLIT_UNSUPPORTED: &str =
+    "The v0 xattrs format is not supported with a separate xattrs stream";
+
+/// The names of the objects seen by the importers of a multi-layer import.
+pub(crate) type SeenObjects = Arc<Mutex<HashSet<String>>>;
+
+/// Importer machine.
+pub(crate) struct Importer {
+    repo: ostree::Repo,
+    remote: Option<String>,
+    xattrs: XattrsCache,
 
     // Reusable buffer for reads.  See also https://github.com/rust-lang/rust/issues/78485
     buf: Vec<u8>,
@@ -60,6 +95,26 @@ pub(crate) struct Importer {
 
     /// Additional state depending on whether we're importing an object set or a commit.
     data: ImporterMode,
+
+    /// The parent of the last commit object imported, which may follow it in the stream.
+    next_parent: Option<String>,
+
+    /// How to handle objects which are already in the repository.
+    on_existing: OnExisting,
+
+    /// Invoked for each object in the stream.
+    object_callback: Option<ImportObjectCallback>,
+
+    /// If set, the transaction is committed (and a new one started) each time
+    /// this many objects have been written.
+    commit_batch_size: Option<usize>,
+    /// The number of objects written at the last intermediate commit.
+    written_at_flush: u32,
+
+    /// If set, objects in this set are skipped without consulting the
+    /// repository, and the objects in the stream are added to it.  This is
+    /// shared across the layers of an image.
+    seen_objects: Option<SeenObjects>,
 }
 
 /// Validate size/type of a tar header for OSTree metadata object.
@@ -118,16 +173,9 @@ fn entry_to_variant<R: std::io::Read, T: StaticVariantType>(
 /// Parse an object path into (parent, rest, objtype).
 ///
 /// Normal ostree object paths look like 00/1234.commit.
-/// In the tar format, we may also see 00/1234.file.xattrs.
-fn parse_object_entry_path(path: &Utf8Path) -> Result<(&str, &Utf8Path, &str)> {
-    // The "sharded" commit directory.
-    let parentname = path
-        .parent()
-        .and_then(|p| p.file_name())
-        .ok_or_else(|| anyhow!("Invalid path (no parent) {}", path))?;
-    if !(parentname.is_ascii() && parentname.as_bytes().len() == 2) {
-        return Err(anyhow!("Invalid checksum parent {}", parentname));
-    }
+/// In the tar format, we may also see 00/1234.file.xattrs, and with
+/// [`super::ObjectLayout::TwoLevel`], 00/12/34.commit; the parent is then `0012`.
+fn parse_object_entry_path(path: &Utf8Path) -> Result<(String, &Utf8Path, &str)> {
     let name = path
         .file_name()
         .map(Utf8Path::new)
@@ -135,6 +183,25 @@ fn parse_object_entry_path(path: &Utf8Path) -> Result<(&str, &Utf8Path, &str)> {
     let objtype = name
         .extension()
         .ok_or_else(|| anyhow!("Invalid objpath {}", path))?;
+    // With `ObjectLayout::TwoLevel`, the name is shorter by a second level of
+    // sharding.
+    let rest_len = name
+        .file_stem()
+        .map_or(0, |s| s.trim_end_matches(".file").as_bytes().len());
+    let levels = if rest_len == 60 { 2 } else { 1 };
+    // The "sharded" commit directories.
+    let mut parentname = String::new();
+    let mut dir = path.parent();
+    for _ in 0..levels {
+        let shard = dir
+            .and_then(|p| p.file_name())
+            .ok_or_else(|| anyhow!("Invalid path (no parent) {}", path))?;
+        if !(shard.is_ascii() && shard.as_bytes().len() == 2) {
+            return Err(anyhow!("Invalid checksum parent {}", shard));
+        }
+        parentname.insert_str(0, shard);
+        dir = dir.and_then(|p| p.parent());
+    }
 
     Ok((parentname, name, objtype))
 }
@@ -146,7 +213,8 @@ fn parse_checksum(parent: &str, name: &Utf8Path) -> Result<String> {
     // Also take care of the double extension on `.file.xattrs`.
     let checksum_rest = checksum_rest.trim_end_matches(".file");
 
-    if !(checksum_rest.is_ascii() && checksum_rest.as_bytes().len() == 62) {
+    if !(checksum_rest.is_ascii() && parent.as_bytes().len() + checksum_rest.as_bytes().len() == 64)
+    {
         return Err(anyhow!("Invalid checksum part {}", checksum_rest));
     }
     let reassembled = format!("{}{}", parent, checksum_rest);
@@ -156,7 +224,219 @@ fn parse_checksum(parent: &str, name: &Utf8Path) -> Result<String> {
 /// Parse a `.file-xattrs-link` link target into the corresponding checksum.
 fn parse_xattrs_link_target(path: &Utf8Path) -> Result<String> {
     let (parent, rest, _objtype) = parse_object_entry_path(path)?;
-    parse_checksum(parent, rest)
+    parse_checksum(&parent, rest)
+}
+
+impl XattrsCache {
+    /// Pop the queued xattrs reference, which must be for the content object `checksum`,
+    /// returning the checksum of its xattrs.  With a separate xattrs stream, the
+    /// preloaded references are used instead, and a content object without any
+    /// reference has no xattrs.
+    fn take_next(&mut self, checksum: &str) -> Result<Option<String>> {
+        let Some((file_csum, xattrs_csum)) = self.next_xattrs.take() else {
+            if self.split {
+                return Ok(self.preloaded.remove(checksum));
+            }
+            bail!("Missing xattrs reference");
+        };
+        if checksum != file_csum {
+            return Err(anyhow!("Object mismatch, found xattrs for {}", file_csum));
+        }
+        Ok(Some(xattrs_csum))
+    }
+
+    /// Retrieve xattrs content from the cache; no checksum means empty xattrs.
+    fn get(&self, xattrs_csum: Option<&str>) -> Result<glib::Variant> {
+        let Some(xattrs_csum) = xattrs_csum else {
+            return Ok(Vec::<(&[u8], &[u8])>::new().to_variant());
+        };
+        self.xattrs
+            .get(xattrs_csum)
+            .cloned()
+            .ok_or_else(|| anyhow!("Failed to find xattrs content {}", xattrs_csum,))
+    }
+
+    /// Process a `.file-xattrs` object (v1).
+    #[context("Processing file xattrs")]
+    fn process_file_xattrs(
+        &mut self,
+        entry: tar::Entry<impl std::io::Read>,
+        checksum: String,
+    ) -> Result<()> {
+        self.cache_xattrs_content(entry, Some(checksum))?;
+        Ok(())
+    }
+
+    /// Process a `.file-xattrs-link` object (v1).
+    ///
+    /// This is an hardlink that contains extended attributes for a content object.
+    /// When the max hardlink count is reached, this object may also be encoded as
+    /// a regular file instead.
+    #[context("Processing xattrs link")]
+    fn process_file_xattrs_link(
+        &mut self,
+        entry: tar::Entry<impl std::io::Read>,
+        checksum: String,
+    ) -> Result<()> {
+        use tar::EntryType::{Link, Regular};
+        if let Some(prev) = &self.next_xattrs {
+            bail!(
+                "Found previous dangling xattrs for file object '{}'",
+                prev.0
+            );
+        }
+
+        // Extract the xattrs checksum from the link target or from the content (v1).
+        // Later, it will be used as the key for a lookup into the `self.xattrs` cache.
+        let xattrs_checksum = match entry.header().entry_type() {
+            Link => {
+                let link_target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("No xattrs link content for {}", checksum))?;
+                let xattr_target = Utf8Path::from_path(&link_target)
+                    .ok_or_else(|| anyhow!("Invalid non-UTF8 xattrs link {}", checksum))?;
+                parse_xattrs_link_target(xattr_target)?
+            }
+            Regular => self.cache_xattrs_content(entry, None)?,
+            x => bail!("Unexpected xattrs type '{:?}' found for {}", x, checksum),
+        };
+
+        // Now xattrs are properly cached for the next content object in the stream,
+        // which should match `checksum`.
+        self.next_xattrs = Some((checksum, xattrs_checksum));
+
+        Ok(())
+    }
+
+    /// Read all of the xattrs objects and links of a stream written separately
+    /// from the content objects they apply to, as by `export_split_xattrs`.
+    #[context("Reading xattrs stream")]
+    fn preload(&mut self, archive: &mut tar::Archive<impl Read>) -> Result<()> {
+        self.split = true;
+        for entry in archive.entries()? {
+            let Some((entry, path)) = Importer::filter_entry(entry?)? else {
+                continue;
+            };
+            let path = path
+                .strip_prefix("objects/")
+                .map_err(|_| anyhow!("Unexpected entry in xattrs stream: {path}"))?;
+            let (parentname, name, suffix) = parse_object_entry_path(path)?;
+            let checksum = parse_checksum(&parentname, name)?;
+            match suffix {
+                "file-xattrs" => self.process_file_xattrs(entry, checksum)?,
+                "file-xattrs-link" => {
+                    self.process_file_xattrs_link(entry, checksum)?;
+                    // SAFETY: This was set just above
+                    let (file_csum, xattrs_csum) = self.next_xattrs.take().unwrap();
+                    self.preloaded.insert(file_csum, xattrs_csum);
+                }
+                o => bail!("Unexpected object type in xattrs stream: {o}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Process a `.file.xattrs` entry (v0).
+    ///
+    /// This is an hardlink that contains extended attributes for a content object.
+    #[context("Processing xattrs reference")]
+    fn process_xattr_ref<R: std::io::Read>(
+        &mut self,
+        entry: tar::Entry<R>,
+        target: String,
+    ) -> Result<()> {
+        ensure!(!self.split, "{V0_SPLIT_UNSUPPORTED}");
+        if let Some(prev) = &self.next_xattrs {
+            bail!(
+                "Found previous dangling xattrs for file object '{}'",
+                prev.0
+            );
+        }
+
+        // Parse the xattrs checksum from the link target (v0).
+        // Later, it will be used as the key for a lookup into the `self.xattrs` cache.
+        let header = entry.header();
+        if header.entry_type() != tar::EntryType::Link {
+            bail!("Non-hardlink xattrs reference found for {}", target);
+        }
+        let xattr_target = entry
+            .link_name()?
+            .ok_or_else(|| anyhow!("No xattrs link content for {}", target))?;
+        let xattr_target = Utf8Path::from_path(&xattr_target)
+            .ok_or_else(|| anyhow!("Invalid non-UTF8 xattrs link {}", target))?;
+        let xattr_target = xattr_target
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid xattrs link {}", target))?
+            .to_string();
+        let xattrs_checksum = validate_sha256(xattr_target)?;
+
+        // Now xattrs are properly cached for the next content object in the stream,
+        // which should match `checksum`.
+        self.next_xattrs = Some((target, xattrs_checksum));
+
+        Ok(())
+    }
+
+    /// Process a special /xattrs/ entry, with checksum of xattrs content (v0).
+    fn process_split_xattrs_content<R: std::io::Read>(
+        &mut self,
+        entry: tar::Entry<R>,
+    ) -> Result<()> {
+        ensure!(!self.split, "{V0_SPLIT_UNSUPPORTED}");
+        let checksum = {
+            let path = entry.path()?;
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid xattrs dir: {:?}", path))?;
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid non-UTF8 xattrs name: {:?}", name))?;
+            validate_sha256(name.to_string())?
+        };
+        self.cache_xattrs_content(entry, Some(checksum))?;
+        Ok(())
+    }
+
+    /// Read an xattrs entry and cache its content, optionally validating its checksum.
+    ///
+    /// This returns the computed checksum for the successfully cached content.
+    fn cache_xattrs_content<R: std::io::Read>(
+        &mut self,
+        mut entry: tar::Entry<R>,
+        expected_checksum: Option<String>,
+    ) -> Result<String> {
+        let header = entry.header();
+        if header.entry_type() != tar::EntryType::Regular {
+            return Err(anyhow!(
+                "Invalid xattr entry of type {:?}",
+                header.entry_type()
+            ));
+        }
+        let n = header.size()?;
+        if n > MAX_XATTR_SIZE as u64 {
+            return Err(anyhow!("Invalid xattr size {}", n));
+        }
+
+        let mut contents = vec![0u8; n as usize];
+        entry.read_exact(contents.as_mut_slice())?;
+        let data: glib::Bytes = contents.as_slice().into();
+        let xattrs_checksum = {
+            let digest = openssl::hash::hash(openssl::hash::MessageDigest::sha256(), &data)?;
+            hex::encode(digest)
+        };
+        if let Some(input) = expected_checksum {
+            ensure!(
+                input == xattrs_checksum,
+                "Checksum mismatch, expected '{}' but computed '{}'",
+                input,
+                xattrs_checksum
+            );
+        }
+
+        let contents = Variant::from_bytes::<&[(&[u8], &[u8])]>(&data);
+        self.xattrs.insert(xattrs_checksum.clone(), contents);
+        Ok(xattrs_checksum)
+    }
 }
 
 impl Importer {
@@ -167,9 +447,14 @@ impl Importer {
             remote,
             buf: vec![0u8; 16384],
             xattrs: Default::default(),
-            next_xattrs: None,
             stats: Default::default(),
             data: ImporterMode::Commit(None),
+            next_parent: None,
+            on_existing: Default::default(),
+            object_callback: None,
+            commit_batch_size: None,
+            written_at_flush: 0,
+            seen_objects: None,
         }
     }
 
@@ -181,9 +466,14 @@ impl Importer {
             remote: None,
             buf: vec![0u8; 16384],
             xattrs: Default::default(),
-            next_xattrs: None,
             stats: Default::default(),
             data: ImporterMode::ObjectSet(Default::default()),
+            next_parent: None,
+            on_existing: Default::default(),
+            object_callback: None,
+            commit_batch_size: None,
+            written_at_flush: 0,
+            seen_objects: None,
         }
     }
 
@@ -215,7 +505,7 @@ impl Importer {
 
     pub(crate) fn parse_metadata_entry(path: &Utf8Path) -> Result<(String, ostree::ObjectType)> {
         let (parentname, name, objtype) = parse_object_entry_path(path)?;
-        let checksum = parse_checksum(parentname, name)?;
+        let checksum = parse_checksum(&parentname, name)?;
         let objtype = objtype_from_string(objtype)
             .ok_or_else(|| anyhow!("Invalid object type {}", objtype))?;
         Ok((checksum, objtype))
@@ -229,6 +519,10 @@ impl Importer {
         checksum: &str,
         objtype: ostree::ObjectType,
     ) -> Result<()> {
+        if self.skip_existing(objtype, checksum, gio::Cancellable::NONE)? {
+            self.notify_object(objtype, checksum);
+            return Ok(());
+        }
         let v = match objtype {
             ostree::ObjectType::DirTree => {
                 self.stats.dirtree += 1;
@@ -246,9 +540,83 @@ impl Importer {
             self.repo
                 .write_metadata(objtype, Some(checksum), &v, gio::Cancellable::NONE)?;
         assert_eq!(actual.to_hex(), checksum);
+        self.notify_object(objtype, checksum);
+        Ok(())
+    }
+
+    /// Set the callback invoked for each object in the stream.
+    pub(crate) fn set_object_callback(&mut self, callback: Option<ImportObjectCallback>) {
+        self.object_callback = callback;
+    }
+
+    /// Set the objects already seen by the importers of previous layers.
+    pub(crate) fn set_seen_objects(&mut self, seen: SeenObjects) {
+        self.seen_objects = Some(seen);
+    }
+
+    /// The number of objects skipped because they were already seen; see
+    /// [`Self::set_seen_objects`].
+    pub(crate) fn duplicate_objects_skipped(&self) -> u64 {
+        self.stats.duplicates.into()
+    }
+
+    /// Invoke the object callback, if any.
+    fn notify_object(&self, objtype: ostree::ObjectType, checksum: &str) {
+        if let Some(callback) = self.object_callback.as_ref() {
+            (callback.0)(objtype, checksum);
+        }
+    }
+
+    /// Commit the transaction and start a new one if [`Self::commit_batch_size`]
+    /// objects have been written since the last time.
+    fn flush_batch(&mut self, cancellable: Option<&gio::Cancellable>) -> Result<()> {
+        let Some(batch_size) = self.commit_batch_size else {
+            return Ok(());
+        };
+        let written = self.stats.written();
+        if ((written - self.written_at_flush) as usize) < batch_size {
+            return Ok(());
+        }
+        self.repo
+            .commit_transaction(cancellable)
+            .context("Committing batch")?;
+        self.repo.prepare_transaction(cancellable)?;
+        self.written_at_flush = written;
+        self.stats.flushes += 1;
         Ok(())
     }
 
+    /// Return true if the object is already in the repository, in which case
+    /// it should not be written.  With [`OnExisting::Verify`], the existing
+    /// object is checked first, and it is an error if it is corrupt.
+    fn skip_existing(
+        &mut self,
+        objtype: ostree::ObjectType,
+        checksum: &str,
+        cancellable: Option<&gio::Cancellable>,
+    ) -> Result<bool> {
+        if let Some(seen) = self.seen_objects.as_ref() {
+            let name = ostree::object_to_string(checksum, objtype).to_string();
+            if !seen.lock().unwrap().insert(name) {
+                self.stats.duplicates += 1;
+                return Ok(true);
+            }
+        }
+        if !self.repo.has_object(objtype, checksum, cancellable)? {
+            return Ok(false);
+        }
+        if self.on_existing == OnExisting::Verify {
+            self.repo
+                .fsck_object(objtype, checksum, cancellable)
+                .with_context(|| {
+                    let name = ostree::object_to_string(checksum, objtype);
+                    format!("Verifying existing object {name}")
+                })?;
+        }
+        self.stats.skipped += 1;
+        Ok(true)
+    }
+
     /// Import a content object, large regular file flavour.
     #[context("Importing regfile")]
     fn import_large_regfile_object<R: std::io::Read>(
@@ -352,42 +720,31 @@ impl Importer {
         checksum: &str,
         cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
-        let size: usize = entry.header().size()?.try_into()?;
+        // Note this is the real size, which differs from the header size for sparse entries.
+        let size: usize = entry.size().try_into()?;
 
-        // Pop the queued xattrs reference.
-        let (file_csum, xattrs_csum) = self
-            .next_xattrs
-            .take()
-            .ok_or_else(|| anyhow!("Missing xattrs reference"))?;
-        if checksum != file_csum {
-            return Err(anyhow!("Object mismatch, found xattrs for {}", file_csum));
-        }
+        let xattrs_csum = self.xattrs.take_next(checksum)?;
 
-        if self
-            .repo
-            .has_object(ostree::ObjectType::File, checksum, cancellable)?
-        {
+        if self.skip_existing(ostree::ObjectType::File, checksum, cancellable)? {
+            self.notify_object(ostree::ObjectType::File, checksum);
             return Ok(());
         }
 
-        // Retrieve xattrs content from the cache.
-        let xattrs = self
-            .xattrs
-            .get(&xattrs_csum)
-            .cloned()
-            .ok_or_else(|| anyhow!("Failed to find xattrs content {}", xattrs_csum,))?;
+        let xattrs = self.xattrs.get(xattrs_csum.as_deref())?;
 
         match entry.header().entry_type() {
-            tar::EntryType::Regular => {
+            tar::EntryType::Regular | tar::EntryType::GNUSparse => {
                 if size > SMALL_REGFILE_SIZE {
-                    self.import_large_regfile_object(entry, size, checksum, xattrs, cancellable)
+                    self.import_large_regfile_object(entry, size, checksum, xattrs, cancellable)?
                 } else {
-                    self.import_small_regfile_object(entry, size, checksum, xattrs, cancellable)
+                    self.import_small_regfile_object(entry, size, checksum, xattrs, cancellable)?
                 }
             }
-            tar::EntryType::Symlink => self.import_symlink_object(entry, checksum, xattrs),
-            o => Err(anyhow!("Invalid tar entry of type {:?}", o)),
+            tar::EntryType::Symlink => self.import_symlink_object(entry, checksum, xattrs)?,
+            o => return Err(anyhow!("Invalid tar entry of type {:?}", o)),
         }
+        self.notify_object(ostree::ObjectType::File, checksum);
+        Ok(())
     }
 
     /// Given a tar entry that looks like an object (its path is under ostree/repo/objects/),
@@ -400,10 +757,10 @@ impl Importer {
         cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
         let (parentname, name, suffix) = parse_object_entry_path(path)?;
-        let checksum = parse_checksum(parentname, name)?;
+        let checksum = parse_checksum(&parentname, name)?;
 
         match suffix {
-            "commit" => Err(anyhow!("Found multiple commit objects")),
+            "commit" => self.import_parent_commit(entry, &checksum, cancellable),
             "file" => {
                 self.import_content_object(entry, &checksum, cancellable)?;
                 // Track the objects we wrote
@@ -417,9 +774,9 @@ impl Importer {
                 }
                 Ok(())
             }
-            "file-xattrs" => self.process_file_xattrs(entry, checksum),
-            "file-xattrs-link" => self.process_file_xattrs_link(entry, checksum),
-            "xattrs" => self.process_xattr_ref(entry, checksum),
+            "file-xattrs" => self.xattrs.process_file_xattrs(entry, checksum),
+            "file-xattrs-link" => self.xattrs.process_file_xattrs_link(entry, checksum),
+            "xattrs" => self.xattrs.process_xattr_ref(entry, checksum),
             kind => {
                 let objtype = objtype_from_string(kind)
                     .ok_or_else(|| anyhow!("Invalid object type {}", kind))?;
@@ -438,159 +795,41 @@ impl Importer {
         }
     }
 
-    /// Process a `.file-xattrs` object (v1).
-    #[context("Processing file xattrs")]
-    fn process_file_xattrs(
+    /// Import the commit object of an ancestor of the commit, as written with
+    /// [`super::ExportOptions::include_parents`].  Its tree is not included, so
+    /// it is marked as partial.
+    fn import_parent_commit<R: std::io::Read>(
         &mut self,
-        entry: tar::Entry<impl std::io::Read>,
-        checksum: String,
+        entry: tar::Entry<'_, R>,
+        checksum: &str,
+        cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
-        self.cache_xattrs_content(entry, Some(checksum))?;
+        if self.next_parent.take().as_deref() != Some(checksum) {
+            anyhow::bail!("Found multiple commit objects");
+        }
+        let commit = entry_to_variant::<_, ostree::CommitVariantType>(entry, checksum)?;
+        // Only the commit object is imported; don't mark an existing (possibly
+        // complete) commit as partial.
+        if !self
+            .repo
+            .has_object(ostree::ObjectType::Commit, checksum, cancellable)?
+        {
+            self.repo.mark_commit_partial(checksum, true)?;
+        }
+        let actual = self.repo.write_metadata(
+            ostree::ObjectType::Commit,
+            Some(checksum),
+            &commit,
+            cancellable,
+        )?;
+        assert_eq!(actual.to_hex(), checksum);
+        event!(Level::DEBUG, "Imported parent {}.commit", checksum);
+        self.notify_object(ostree::ObjectType::Commit, checksum);
+        self.next_parent = ostree::commit_get_parent(&commit).map(|p| p.to_string());
         Ok(())
     }
 
-    /// Process a `.file-xattrs-link` object (v1).
-    ///
-    /// This is an hardlink that contains extended attributes for a content object.
-    /// When the max hardlink count is reached, this object may also be encoded as
-    /// a regular file instead.
-    #[context("Processing xattrs link")]
-    fn process_file_xattrs_link(
-        &mut self,
-        entry: tar::Entry<impl std::io::Read>,
-        checksum: String,
-    ) -> Result<()> {
-        use tar::EntryType::{Link, Regular};
-        if let Some(prev) = &self.next_xattrs {
-            bail!(
-                "Found previous dangling xattrs for file object '{}'",
-                prev.0
-            );
-        }
-
-        // Extract the xattrs checksum from the link target or from the content (v1).
-        // Later, it will be used as the key for a lookup into the `self.xattrs` cache.
-        let xattrs_checksum = match entry.header().entry_type() {
-            Link => {
-                let link_target = entry
-                    .link_name()?
-                    .ok_or_else(|| anyhow!("No xattrs link content for {}", checksum))?;
-                let xattr_target = Utf8Path::from_path(&link_target)
-                    .ok_or_else(|| anyhow!("Invalid non-UTF8 xattrs link {}", checksum))?;
-                parse_xattrs_link_target(xattr_target)?
-            }
-            Regular => self.cache_xattrs_content(entry, None)?,
-            x => bail!("Unexpected xattrs type '{:?}' found for {}", x, checksum),
-        };
-
-        // Now xattrs are properly cached for the next content object in the stream,
-        // which should match `checksum`.
-        self.next_xattrs = Some((checksum, xattrs_checksum));
-
-        Ok(())
-    }
-
-    /// Process a `.file.xattrs` entry (v0).
-    ///
-    /// This is an hardlink that contains extended attributes for a content object.
-    #[context("Processing xattrs reference")]
-    fn process_xattr_ref<R: std::io::Read>(
-        &mut self,
-        entry: tar::Entry<R>,
-        target: String,
-    ) -> Result<()> {
-        if let Some(prev) = &self.next_xattrs {
-            bail!(
-                "Found previous dangling xattrs for file object '{}'",
-                prev.0
-            );
-        }
-
-        // Parse the xattrs checksum from the link target (v0).
-        // Later, it will be used as the key for a lookup into the `self.xattrs` cache.
-        let header = entry.header();
-        if header.entry_type() != tar::EntryType::Link {
-            bail!("Non-hardlink xattrs reference found for {}", target);
-        }
-        let xattr_target = entry
-            .link_name()?
-            .ok_or_else(|| anyhow!("No xattrs link content for {}", target))?;
-        let xattr_target = Utf8Path::from_path(&xattr_target)
-            .ok_or_else(|| anyhow!("Invalid non-UTF8 xattrs link {}", target))?;
-        let xattr_target = xattr_target
-            .file_name()
-            .ok_or_else(|| anyhow!("Invalid xattrs link {}", target))?
-            .to_string();
-        let xattrs_checksum = validate_sha256(xattr_target)?;
-
-        // Now xattrs are properly cached for the next content object in the stream,
-        // which should match `checksum`.
-        self.next_xattrs = Some((target, xattrs_checksum));
-
-        Ok(())
-    }
-
-    /// Process a special /xattrs/ entry, with checksum of xattrs content (v0).
-    fn process_split_xattrs_content<R: std::io::Read>(
-        &mut self,
-        entry: tar::Entry<R>,
-    ) -> Result<()> {
-        let checksum = {
-            let path = entry.path()?;
-            let name = path
-                .file_name()
-                .ok_or_else(|| anyhow!("Invalid xattrs dir: {:?}", path))?;
-            let name = name
-                .to_str()
-                .ok_or_else(|| anyhow!("Invalid non-UTF8 xattrs name: {:?}", name))?;
-            validate_sha256(name.to_string())?
-        };
-        self.cache_xattrs_content(entry, Some(checksum))?;
-        Ok(())
-    }
-
-    /// Read an xattrs entry and cache its content, optionally validating its checksum.
-    ///
-    /// This returns the computed checksum for the successfully cached content.
-    fn cache_xattrs_content<R: std::io::Read>(
-        &mut self,
-        mut entry: tar::Entry<R>,
-        expected_checksum: Option<String>,
-    ) -> Result<String> {
-        let header = entry.header();
-        if header.entry_type() != tar::EntryType::Regular {
-            return Err(anyhow!(
-                "Invalid xattr entry of type {:?}",
-                header.entry_type()
-            ));
-        }
-        let n = header.size()?;
-        if n > MAX_XATTR_SIZE as u64 {
-            return Err(anyhow!("Invalid xattr size {}", n));
-        }
-
-        let mut contents = vec![0u8; n as usize];
-        entry.read_exact(contents.as_mut_slice())?;
-        let data: glib::Bytes = contents.as_slice().into();
-        let xattrs_checksum = {
-            let digest = openssl::hash::hash(openssl::hash::MessageDigest::sha256(), &data)?;
-            hex::encode(digest)
-        };
-        if let Some(input) = expected_checksum {
-            ensure!(
-                input == xattrs_checksum,
-                "Checksum mismatch, expected '{}' but computed '{}'",
-                input,
-                xattrs_checksum
-            );
-        }
-
-        let contents = Variant::from_bytes::<&[(&[u8], &[u8])]>(&data);
-        self.xattrs.insert(xattrs_checksum.clone(), contents);
-        Ok(xattrs_checksum)
-    }
-
-    fn import_objects_impl<'a>(
+    fn import_objects_impl<'a>(
         &mut self,
         ents: impl Iterator<Item = Result<(tar::Entry<'a, impl Read + Send + Unpin + 'a>, Utf8PathBuf)>>,
         cancellable: Option<&gio::Cancellable>,
@@ -599,8 +838,9 @@ impl Importer {
             let (entry, path) = entry?;
             if let Ok(p) = path.strip_prefix("objects/") {
                 self.import_object(entry, p, cancellable)?;
+                self.flush_batch(cancellable)?;
             } else if path.strip_prefix("xattrs/").is_ok() {
-                self.process_split_xattrs_content(entry)?;
+                self.xattrs.process_split_xattrs_content(entry)?;
             }
         }
         Ok(())
@@ -633,9 +873,9 @@ impl Importer {
             Err(e) => Some(Err(anyhow::Error::msg(e))),
         });
         // Read the commit object.
-        let (commit_ent, commit_path) = ents
-            .next()
-            .ok_or_else(|| anyhow!("Commit object not found"))??;
+        let (commit_ent, commit_path) = ents.next().ok_or_else(|| {
+            anyhow!("Commit object not found; not an ostree layer (no objects in {REPO_PREFIX})")
+        })??;
 
         if commit_ent.header().entry_type() != tar::EntryType::Regular {
             return Err(anyhow!(
@@ -648,6 +888,7 @@ impl Importer {
             return Err(anyhow!("Expected commit object, not {:?}", objtype));
         }
         let commit = entry_to_variant::<_, ostree::CommitVariantType>(commit_ent, &checksum)?;
+        self.next_parent = ostree::commit_get_parent(&commit).map(|p| p.to_string());
 
         let (next_ent, nextent_path) = ents
             .next()
@@ -693,6 +934,7 @@ impl Importer {
                     .write_metadata(objtype, Some(&checksum), &commit, cancellable)?;
             assert_eq!(actual_checksum.to_hex(), checksum);
             event!(Level::DEBUG, "Imported {}.commit", checksum);
+            self.notify_object(objtype, &checksum);
 
             // Finally, write the detached metadata.
             self.repo
@@ -706,6 +948,7 @@ impl Importer {
                     .write_metadata(objtype, Some(&checksum), &commit, cancellable)?;
             assert_eq!(actual_checksum.to_hex(), checksum);
             event!(Level::DEBUG, "Imported {}.commit", checksum);
+            self.notify_object(objtype, &checksum);
 
             // Write the next object, whether it's commit metadata or not.
             let (meta_checksum, meta_objtype) = Self::parse_metadata_entry(&nextent_path)?;
@@ -786,6 +1029,148 @@ impl Importer {
     }
 }
 
+/// Checks the checksums of the objects in a tar stream, without writing them.
+#[derive(Debug, Default)]
+pub(crate) struct Verifier {
+    xattrs: XattrsCache,
+    report: TarVerifyReport,
+}
+
+impl Verifier {
+    /// Compute the checksum of a metadata object.
+    fn metadata_checksum<R: std::io::Read>(
+        entry: tar::Entry<R>,
+        checksum: &str,
+        objtype: ostree::ObjectType,
+    ) -> Result<String> {
+        let v = match objtype {
+            ostree::ObjectType::Commit => {
+                entry_to_variant::<_, ostree::CommitVariantType>(entry, checksum)?
+            }
+            ostree::ObjectType::DirTree => {
+                entry_to_variant::<_, ostree::TreeVariantType>(entry, checksum)?
+            }
+            ostree::ObjectType::DirMeta => {
+                entry_to_variant::<_, ostree::DirmetaVariantType>(entry, checksum)?
+            }
+            o => return Err(anyhow!("Invalid metadata object type; {:?}", o)),
+        };
+        let digest =
+            openssl::hash::hash(openssl::hash::MessageDigest::sha256(), &v.data_as_bytes())?;
+        Ok(hex::encode(digest))
+    }
+
+    /// Compute the checksum of a content object, using the xattrs queued for it.
+    #[context("Checksumming content object {}", checksum)]
+    fn content_checksum<R: std::io::Read>(
+        &mut self,
+        mut entry: tar::Entry<R>,
+        checksum: &str,
+    ) -> Result<String> {
+        let xattrs_csum = self.xattrs.take_next(checksum)?;
+        let xattrs = self.xattrs.get(xattrs_csum.as_deref())?;
+        let (uid, gid, mode) = header_attrs(entry.header())?;
+        let finfo = gio::FileInfo::new();
+        finfo.set_attribute_uint32("unix::uid", uid);
+        finfo.set_attribute_uint32("unix::gid", gid);
+        let input: Option<gio::InputStream> = match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::GNUSparse => {
+                // Note this is the real size, which differs from the header size for sparse entries.
+                let size = entry.size();
+                finfo.set_file_type(gio::FileType::Regular);
+                finfo.set_attribute_uint32("unix::mode", libc::S_IFREG | mode);
+                finfo.set_size(size.try_into()?);
+                // Avoid holding large files in memory.
+                if size > SMALL_REGFILE_SIZE as u64 {
+                    let mut tmpf = tempfile::tempfile()?;
+                    std::io::copy(&mut entry, &mut tmpf)?;
+                    tmpf.seek(std::io::SeekFrom::Start(0))?;
+                    Some(gio::ReadInputStream::new(tmpf).upcast())
+                } else {
+                    let mut buf = Vec::with_capacity(size as usize);
+                    entry.read_to_end(&mut buf)?;
+                    let buf = glib::Bytes::from_owned(buf);
+                    Some(gio::MemoryInputStream::from_bytes(&buf).upcast())
+                }
+            }
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("Invalid symlink"))?;
+                finfo.set_file_type(gio::FileType::SymbolicLink);
+                finfo.set_attribute_uint32("unix::mode", libc::S_IFLNK | 0o777);
+                finfo.set_symlink_target(target);
+                None
+            }
+            o => return Err(anyhow!("Invalid tar entry of type {:?}", o)),
+        };
+        let actual = ostree::checksum_file_from_input(
+            &finfo,
+            Some(&xattrs),
+            input.as_ref(),
+            ostree::ObjectType::File,
+            gio::Cancellable::NONE,
+        )
+        .map_err(|e| anyhow!("{e}"))?;
+        Ok(actual.to_hex())
+    }
+
+    /// Check a single object; `path` is relative to `objects/`.
+    #[context("Verifying object {}", path)]
+    fn verify_object<R: std::io::Read>(
+        &mut self,
+        entry: tar::Entry<'_, R>,
+        path: &Utf8Path,
+    ) -> Result<()> {
+        let (parentname, name, suffix) = parse_object_entry_path(path)?;
+        let checksum = parse_checksum(&parentname, name)?;
+        let actual = match suffix {
+            "file" => self.content_checksum(entry, &checksum)?,
+            "file-xattrs" => return self.xattrs.process_file_xattrs(entry, checksum),
+            "file-xattrs-link" => return self.xattrs.process_file_xattrs_link(entry, checksum),
+            "xattrs" => return self.xattrs.process_xattr_ref(entry, checksum),
+            // Detached metadata is named after its commit, and is not checksummed.
+            "commitmeta" => return Ok(()),
+            kind => {
+                let objtype = objtype_from_string(kind)
+                    .ok_or_else(|| anyhow!("Invalid object type {}", kind))?;
+                Self::metadata_checksum(entry, &checksum, objtype)?
+            }
+        };
+        self.report.objects_checked += 1;
+        if actual != checksum {
+            self.report.failures.push(ObjectVerifyFailure {
+                path: path.to_owned(),
+                expected: checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check all objects in the archive.
+    #[context("Verifying objects")]
+    pub(crate) fn verify(
+        mut self,
+        archive: &mut tar::Archive<impl Read + Send + Unpin>,
+    ) -> Result<TarVerifyReport> {
+        for entry in archive.entries()? {
+            let Some((entry, path)) = Importer::filter_entry(entry?)? else {
+                continue;
+            };
+            if let Ok(p) = path.strip_prefix("objects/") {
+                self.verify_object(entry, p)?;
+            } else if path.strip_prefix("xattrs/").is_ok() {
+                self.xattrs.process_split_xattrs_content(entry)?;
+            }
+        }
+        if let Some((file_csum, _)) = self.xattrs.next_xattrs.as_ref() {
+            bail!("Found dangling xattrs for file object '{}'", file_csum);
+        }
+        Ok(self.report)
+    }
+}
+
 fn validate_sha256(input: String) -> Result<String> {
     if input.len() != 64 {
         return Err(anyhow!("Invalid sha256 checksum (len) {}", input));
@@ -796,39 +1181,416 @@ fn validate_sha256(input: String) -> Result<String> {
     Ok(input)
 }
 
+/// How to handle objects in a tar stream which are already in the repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnExisting {
+    /// Skip the object; this is the default.
+    #[default]
+    Skip,
+    /// Check that the checksum of the existing object matches before skipping
+    /// it, failing the import otherwise.  This catches corruption in the target
+    /// repository, at the cost of reading every existing object.
+    Verify,
+}
+
 /// Configuration for tar import.
 #[derive(Debug, Default)]
 #[non_exhaustive]
 pub struct TarImportOptions {
     /// Name of the remote to use for signature verification.
     pub remote: Option<String>,
+    /// Additional metadata (must be of type `a{sv}`) which will be merged into the
+    /// detached metadata of the imported commit, overriding any existing keys.
+    /// This can be used to e.g. record the importer identity or the source.
+    ///
+    /// Because detached metadata is not part of the commit object, this does
+    /// not change the checksum of the imported commit.
+    pub extra_metadata: Option<glib::Variant>,
+    /// If set, a ref with this name pointing to the imported commit is written.
+    pub write_ref: Option<String>,
+    /// If set, this is prepended (with a `/` separator) to [`Self::write_ref`],
+    /// e.g. `mirror/example` to write `mirror/example/<name>`.  This can be used
+    /// to keep refs for images imported from different sources apart.  Requires
+    /// [`Self::write_ref`].
+    pub ref_prefix: Option<String>,
+    /// How to handle dirtree, dirmeta and content objects which are already in
+    /// the repository.
+    pub on_existing: OnExisting,
+    /// If set, invoked for each object in the stream.  See [`ImportObjectCallback`].
+    pub object_callback: Option<ImportObjectCallback>,
+    /// If the ref (see [`Self::write_ref`]) previously pointed to a different
+    /// commit, delete that commit and the objects of its tree which are no longer
+    /// reachable once the ref has been updated.  Objects reachable from other refs,
+    /// including from the history of the commits they point to, are kept; other
+    /// objects in the repository are not affected.  Requires [`Self::write_ref`].
+    ///
+    /// Objects are deleted without locking the repository, so this should be used
+    /// carefully if other processes may be concurrently writing to it.
+    pub prune_previous: bool,
+    /// The runtime used to spawn the blocking thread which performs the import,
+    /// and to drive the input stream from it.  If unset, the current runtime is
+    /// used, i.e. this must be called from within a Tokio runtime.
+    pub runtime: Option<tokio::runtime::Handle>,
+    /// If set, the repository transaction is committed (and a new one started)
+    /// each time this many objects have been written, instead of once at the
+    /// end; this must be at least 1.
+    ///
+    /// Committing syncs the objects written so far to disk, so this bounds the
+    /// amount of unsynced data (and the space used by the staging directory of
+    /// the transaction), and makes the objects written so far durable: if the
+    /// import is interrupted, they need not be written again, while the commit
+    /// stays marked as partial.  The cost is a sync per batch, so small batches
+    /// slow down the import.
+    pub commit_batch_size: Option<usize>,
+    /// Enable fs-verity on the regular file content objects of the imported
+    /// commit once they have been written, and record their fs-verity digests
+    /// (SHA-256, with a block size of 4096 and no salt) in the detached commit
+    /// metadata under [`FSVERITY_DIGESTS_KEY`].
+    ///
+    /// This requires a bare repository (i.e. not in `archive` mode) on a
+    /// filesystem with fs-verity support enabled, such as ext4 or btrfs; note
+    /// that once enabled, the objects can no longer be modified.  If the
+    /// filesystem does not support fs-verity, this is a no-op apart from a
+    /// warning, and no digests are recorded.
+    pub enable_fsverity: bool,
+}
+
+/// The key in the detached commit metadata holding the fs-verity digests of
+/// its content objects when [`TarImportOptions::enable_fsverity`] is set, as
+/// a map (`a{ss}`) from object checksum to hex-encoded digest.
+pub const FSVERITY_DIGESTS_KEY: &str = "ostree.tar.fsverity-digests";
+
+/// The signature of an [`ImportObjectCallback`].
+pub type ImportObjectFn = dyn Fn(ostree::ObjectType, &str) + Send + Sync;
+
+/// A hook invoked with the type and checksum of each commit, dirtree, dirmeta
+/// and content object in an imported tar stream, once it has been written (or
+/// found to be already present) in the repository.  This can be used to record
+/// the exact set of objects an import references.
+///
+/// The hook is called while the import transaction is open, so it should
+/// only record the object; in particular, it must not write to the same
+/// repository.  Objects are reported as they are written, so an import which
+/// later fails may already have reported some.
+#[derive(Clone)]
+pub struct ImportObjectCallback(Arc<ImportObjectFn>);
+
+impl ImportObjectCallback {
+    /// Create a new callback from the provided function.
+    pub fn new(f: impl Fn(ostree::ObjectType, &str) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for ImportObjectCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImportObjectCallback")
+            .finish_non_exhaustive()
+    }
+}
+
+/// The result of [`import_tar_with_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TarImport {
+    /// The imported commit.
+    pub commit: String,
+    /// Number of dirtree, dirmeta and content objects written.
+    pub objects_written: u64,
+    /// Number of dirtree, dirmeta and content objects which were already in
+    /// the repository, and were skipped.
+    pub objects_skipped: u64,
+    /// Number of objects pruned; see [`TarImportOptions::prune_previous`].
+    pub objects_pruned: u64,
+    /// Total size in bytes of the pruned objects.
+    pub bytes_pruned: u64,
+    /// Number of intermediate transaction commits; see
+    /// [`TarImportOptions::commit_batch_size`].
+    pub batches_committed: u64,
+}
+
+impl TarImportOptions {
+    /// Return the full name of the ref to write, if any, checking that it is valid.
+    fn target_ref(&self) -> Result<Option<String>> {
+        let name = match (self.ref_prefix.as_deref(), self.write_ref.as_deref()) {
+            (None, None) => return Ok(None),
+            (Some(_), None) => bail!("A ref prefix requires a ref to write"),
+            (None, Some(name)) => name.to_string(),
+            (Some(prefix), Some(name)) => format!("{}/{name}", prefix.trim_end_matches('/')),
+        };
+        ostree::validate_rev(&name).with_context(|| format!("Invalid ref name {name:?}"))?;
+        Ok(Some(name))
+    }
+}
+
+/// Merge the provided `a{sv}` into the detached metadata of a commit.
+#[context("Writing extra detached metadata")]
+fn merge_detached_metadata(
+    repo: &ostree::Repo,
+    checksum: &str,
+    extra: &glib::Variant,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<()> {
+    let existing = repo.read_commit_detached_metadata(checksum, cancellable)?;
+    let detached = glib::VariantDict::new(existing.as_ref());
+    for entry in extra.iter() {
+        let k = entry.child_value(0);
+        let k = k.str().ok_or_else(|| anyhow!("Invalid metadata key"))?;
+        let v = entry
+            .child_value(1)
+            .as_variant()
+            .ok_or_else(|| anyhow!("Invalid metadata value for {k}"))?;
+        detached.insert_value(k, &v);
+    }
+    repo.write_commit_detached_metadata(checksum, Some(&detached.end()), cancellable)?;
+    Ok(())
+}
+
+/// Enable fs-verity on the regular file content objects of `commit`, returning
+/// their digests, or `None` if the filesystem does not support fs-verity.
+#[context("Enabling fs-verity")]
+fn enable_fsverity_for_commit(
+    repo: &ostree::Repo,
+    commit: &str,
+    cancellable: &gio::Cancellable,
+) -> Result<Option<BTreeMap<String, String>>> {
+    use cap_std_ext::cap_std::fs::Dir;
+    use rustix::fs::{Mode, OFlags};
+
+    let objects = Dir::reopen_dir(&repo.dfd_borrow())?.open_dir("objects")?;
+    let reachable = repo.traverse_commit(commit, 0, Some(cancellable))?;
+    let mut digests = BTreeMap::new();
+    for name in reachable {
+        if name.object_type() != ostree::ObjectType::File {
+            continue;
+        }
+        cancellable.set_error_if_cancelled()?;
+        let checksum = name.checksum();
+        let (first, rest) = checksum.split_at(2);
+        let path = format!("{first}/{rest}.file");
+        // Symlinks are stored as such in bare repositories
+        let fd = match rustix::fs::openat(
+            &objects,
+            path.as_str(),
+            OFlags::RDONLY | OFlags::CLOEXEC | OFlags::NOFOLLOW,
+            Mode::empty(),
+        ) {
+            Ok(fd) => fd,
+            Err(rustix::io::Errno::LOOP) => continue,
+            Err(e) => return Err(std::io::Error::from(e)).context(path),
+        };
+        if !super::composefs::enable_fsverity(&fd).with_context(|| path.clone())? {
+            tracing::warn!("fs-verity is not supported by the filesystem of the repository");
+            return Ok(None);
+        }
+        let digest = super::composefs::fsverity_digest(std::fs::File::from(fd))
+            .with_context(|| format!("Computing fs-verity digest of {checksum}"))?;
+        digests.insert(checksum.to_string(), hex::encode(digest));
+    }
+    Ok(Some(digests))
+}
+
+/// Fail early with a clear error if the start of `src` looks like a JSON
+/// document instead of a tar stream.  This happens if e.g. the image
+/// configuration was mistakenly selected as a layer; parsing it as a tar
+/// stream would otherwise fail with an obscure error.
+pub(crate) fn check_not_json(src: &mut impl BufRead) -> Result<()> {
+    let buf = src.fill_buf()?;
+    if buf.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'{') {
+        bail!("Expected an ostree layer tar stream, found a JSON document (such as an image configuration)");
+    }
+    Ok(())
+}
+
+/// Transparently decompress `src` if it starts with the magic number of a gzip or
+/// zstd stream, such as written with [`ExportOptions::compression`].
+///
+/// [`ExportOptions::compression`]: super::ExportOptions::compression
+fn decompress_if_needed<'a>(
+    mut src: impl BufRead + Send + 'a,
+) -> Result<Box<dyn BufRead + Send + 'a>> {
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+    let buf = src.fill_buf()?;
+    let (gzip, zstd) = (buf.starts_with(GZIP_MAGIC), buf.starts_with(ZSTD_MAGIC));
+    let r: Box<dyn BufRead + Send + 'a> = if gzip {
+        let src = flate2::bufread::GzDecoder::new(src);
+        Box::new(std::io::BufReader::new(src))
+    } else if zstd {
+        let src = zstd::stream::read::Decoder::with_buffer(src)?;
+        Box::new(std::io::BufReader::new(src))
+    } else {
+        Box::new(src)
+    };
+    Ok(r)
 }
 
 /// Read the contents of a tarball and import the ostree commit inside.
 /// Returns the sha256 of the imported commit.
+///
+/// The tarball may be compressed with gzip or zstd.
 #[instrument(level = "debug", skip_all)]
 pub async fn import_tar(
     repo: &ostree::Repo,
     src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
     options: Option<TarImportOptions>,
 ) -> Result<String> {
+    Ok(import_tar_with_stats(repo, src, options).await?.commit)
+}
+
+/// Read the contents of a tarball and import the ostree commit inside, like
+/// [`import_tar`], additionally returning the number of objects written, skipped and pruned.
+#[instrument(level = "debug", skip_all)]
+pub async fn import_tar_with_stats(
+    repo: &ostree::Repo,
+    src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+    options: Option<TarImportOptions>,
+) -> Result<TarImport> {
+    impl_import_tar(repo, src, None, options).await
+}
+
+/// Import an ostree commit from the two tarballs written by [`export_split_xattrs`]:
+/// `src` with the commit, and `xattrs_src` with the xattrs of its content objects,
+/// which is read first.  Returns the sha256 of the imported commit.
+///
+/// Content objects without an entry in `xattrs_src` are imported without
+/// extended attributes.  Streams in the v0 format (which stores xattrs under
+/// `xattrs/`) are not supported.
+///
+/// [`export_split_xattrs`]: super::export_split_xattrs
+#[instrument(level = "debug", skip_all)]
+pub async fn import_tar_split_xattrs(
+    repo: &ostree::Repo,
+    src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+    xattrs_src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+    options: Option<TarImportOptions>,
+) -> Result<String> {
+    let xattrs_src = Box::new(xattrs_src);
+    Ok(impl_import_tar(repo, src, Some(xattrs_src), options)
+        .await?
+        .commit)
+}
+
+async fn impl_import_tar(
+    repo: &ostree::Repo,
+    src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+    xattrs_src: Option<Box<dyn tokio::io::AsyncRead + Send + Unpin>>,
+    options: Option<TarImportOptions>,
+) -> Result<TarImport> {
     let options = options.unwrap_or_default();
-    let src = tokio_util::io::SyncIoBridge::new(src);
+    if let Some(extra) = options.extra_metadata.as_ref() {
+        let ty = extra.type_();
+        if ty != glib::VariantTy::VARDICT {
+            bail!("Expected extra metadata of type a{{sv}}, found {ty}");
+        }
+    }
+    let target_ref = options.target_ref()?;
+    if options.prune_previous && target_ref.is_none() {
+        bail!("Pruning the previous commit requires a ref to write");
+    }
+    if options.commit_batch_size == Some(0) {
+        bail!("The commit batch size must be at least 1");
+    }
+    if options.enable_fsverity && repo.mode() == ostree::RepoMode::Archive {
+        bail!("Enabling fs-verity requires a bare repository");
+    }
+    let runtime = options.runtime.clone();
+    let runtime = runtime.as_ref();
+    let src = crate::tokio_util::sync_io_bridge(src, runtime);
+    let xattrs_src = xattrs_src.map(|s| crate::tokio_util::sync_io_bridge(s, runtime));
     let repo = repo.clone();
     // The tar code we use today is blocking, so we spawn a thread.
-    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+    crate::tokio_util::spawn_blocking_cancellable_flatten_on(runtime, move |cancellable| {
+        let previous = match target_ref.as_deref() {
+            Some(target_ref) if options.prune_previous => {
+                repo.resolve_rev(target_ref, true)?.map(|c| c.to_string())
+            }
+            _ => None,
+        };
+        let mut src = decompress_if_needed(std::io::BufReader::new(src))?;
+        check_not_json(&mut src)?;
         let mut archive = tar::Archive::new(src);
         let txn = repo.auto_transaction(Some(cancellable))?;
         let mut importer = Importer::new_for_commit(&repo, options.remote);
+        importer.on_existing = options.on_existing;
+        importer.commit_batch_size = options.commit_batch_size;
+        importer.set_object_callback(options.object_callback);
+        if let Some(xattrs_src) = xattrs_src {
+            importer
+                .xattrs
+                .preload(&mut tar::Archive::new(xattrs_src))?;
+        }
         importer.import_commit(&mut archive, Some(cancellable))?;
+        let objects_written = importer.stats.written().into();
+        let objects_skipped = importer.stats.skipped.into();
+        let batches_committed = importer.stats.flushes.into();
         let checksum = importer.finish_import_commit();
+        if let Some(target_ref) = target_ref.as_deref() {
+            repo.transaction_set_ref(None, target_ref, Some(checksum.as_str()));
+        }
         txn.commit(Some(cancellable))?;
+        if let Some(extra) = options.extra_metadata.as_ref() {
+            merge_detached_metadata(&repo, &checksum, extra, Some(cancellable))?;
+        }
+        if options.enable_fsverity {
+            if let Some(digests) = enable_fsverity_for_commit(&repo, &checksum, cancellable)? {
+                let extra = glib::VariantDict::new(None);
+                extra.insert_value(FSVERITY_DIGESTS_KEY, &digests.to_variant());
+                merge_detached_metadata(&repo, &checksum, &extra.end(), Some(cancellable))?;
+            }
+        }
         repo.mark_commit_partial(&checksum, false)?;
-        Ok::<_, anyhow::Error>(checksum)
+        let (objects_pruned, bytes_pruned) = match (previous, target_ref.as_deref()) {
+            (Some(previous), Some(target_ref)) if previous != checksum => {
+                prune_previous_commit(&repo, &previous, target_ref, cancellable)?
+            }
+            _ => (0, 0),
+        };
+        Ok::<_, anyhow::Error>(TarImport {
+            commit: checksum,
+            objects_written,
+            objects_skipped,
+            objects_pruned,
+            bytes_pruned,
+            batches_committed,
+        })
     })
     .await
 }
 
+/// Delete the commit `previous` and the objects of its tree which are not
+/// reachable from any ref, after `target_ref` was updated away from it; returns
+/// the number of objects deleted and their total size.
+///
+/// The history of other refs is kept, but not that of `target_ref`, which
+/// usually includes `previous` as the parent of its new commit.
+#[context("Pruning previous commit {previous}")]
+fn prune_previous_commit(
+    repo: &ostree::Repo,
+    previous: &str,
+    target_ref: &str,
+    cancellable: &gio::Cancellable,
+) -> Result<(u64, u64)> {
+    let mut candidates = repo.traverse_commit(previous, 0, Some(cancellable))?;
+    for (refname, rev) in repo.list_refs(None, Some(cancellable))? {
+        if candidates.is_empty() {
+            break;
+        }
+        let maxdepth = if refname == target_ref { 0 } else { -1 };
+        let reachable = repo.traverse_commit(&rev, maxdepth, Some(cancellable))?;
+        candidates.retain(|o| !reachable.contains(o));
+    }
+    let mut n_objects = 0u64;
+    let mut size = 0u64;
+    for object in candidates {
+        let (objtype, checksum) = (object.object_type(), object.checksum());
+        size += repo.query_object_storage_size(objtype, checksum, Some(cancellable))?;
+        repo.delete_object(objtype, checksum, Some(cancellable))?;
+        n_objects += 1;
+    }
+    Ok((n_objects, size))
+}
+
 /// Read the contents of a tarball and import the content objects inside.
 /// Generates a synthetic commit object referencing them.
 #[instrument(level = "debug", skip_all)]
@@ -840,6 +1602,8 @@ pub async fn import_tar_objects(
     let repo = repo.clone();
     // The tar code we use today is blocking, so we spawn a thread.
     crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+        let mut src = std::io::BufReader::new(src);
+        check_not_json(&mut src)?;
         let mut archive = tar::Archive::new(src);
         let mut importer = Importer::new_for_object_set(&repo);
         let txn = repo.auto_transaction(Some(cancellable))?;
@@ -851,6 +1615,202 @@ pub async fn import_tar_objects(
     .await
 }
 
+/// An object whose content does not match the checksum in its path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ObjectVerifyFailure {
+    /// Path of the object, relative to the `objects/` directory of the repository.
+    pub path: Utf8PathBuf,
+    /// The checksum found in the object path.
+    pub expected: String,
+    /// The checksum computed from the object content.
+    pub actual: String,
+}
+
+/// The result of [`verify_tar`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TarVerifyReport {
+    /// Number of objects whose checksum was computed.
+    pub objects_checked: u64,
+    /// Objects with a checksum mismatch.
+    pub failures: Vec<ObjectVerifyFailure>,
+}
+
+impl TarVerifyReport {
+    /// Returns true if no checksum mismatches were found.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Read the contents of a tarball (either a full commit or an object set), and
+/// recompute the checksum of each object, comparing it against its path.
+/// Nothing is written to a repository.
+///
+/// Checksum mismatches are recorded in the returned report; a malformed
+/// stream is an error.
+#[instrument(level = "debug", skip_all)]
+pub async fn verify_tar(
+    src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+) -> Result<TarVerifyReport> {
+    let src = tokio_util::io::SyncIoBridge::new(src);
+    // The tar code we use today is blocking, so we spawn a thread.
+    crate::tokio_util::spawn_blocking_cancellable_flatten(move |_cancellable| {
+        let mut archive = tar::Archive::new(src);
+        Verifier::default().verify(&mut archive)
+    })
+    .await
+}
+
+/// The value of the [`super::COMPRESSION_PAX_KEY`] record of an entry, if any.
+fn entry_compression(entry: &mut tar::Entry<impl std::io::Read>) -> Result<Option<String>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    for ext in extensions {
+        let ext = ext?;
+        if ext.key() == Ok(super::COMPRESSION_PAX_KEY) {
+            return Ok(Some(ext.value()?.to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Write a regular file entry compressed with [`super::ExportOptions::compress_files`]
+/// to `root`, decompressing its content.
+fn unpack_compressed_entry(
+    root: &cap_std_ext::cap_std::fs::Dir,
+    entry: &mut tar::Entry<impl std::io::Read>,
+    compression: &str,
+    preserve_ownership: bool,
+) -> Result<()> {
+    let path = entry.path()?;
+    let path = Utf8Path::from_path(&path)
+        .ok_or_else(|| anyhow!("Invalid non-UTF8 path: {path:?}"))?
+        .to_owned();
+    if !path
+        .components()
+        .all(|c| matches!(c, camino::Utf8Component::Normal(_)))
+    {
+        bail!("Invalid path for compressed entry: {path}");
+    }
+    ensure!(
+        entry.header().entry_type() == tar::EntryType::Regular,
+        "Unexpected compressed entry type {:?} for {path}",
+        entry.header().entry_type()
+    );
+    ensure!(
+        compression == "gzip",
+        "Unsupported compression {compression} for {path}"
+    );
+    let header = entry.header();
+    let mode = header.mode()?;
+    let (uid, gid) = (header.uid()?, header.gid()?);
+    let mtime = header.mtime()?;
+    if let Some(parent) = path.parent().filter(|p| !p.as_str().is_empty()) {
+        root.create_dir_all(parent)?;
+    }
+    let mut f = root.create(&path)?;
+    std::io::copy(&mut flate2::read::GzDecoder::new(entry), &mut f)
+        .with_context(|| format!("Decompressing {path}"))?;
+    if preserve_ownership {
+        std::os::unix::fs::fchown(&f, Some(uid.try_into()?), Some(gid.try_into()?))?;
+    }
+    rustix::fs::fchmod(&f, rustix::fs::Mode::from_raw_mode(mode))?;
+    let mtime = rustix::fs::Timespec {
+        tv_sec: mtime.try_into()?,
+        tv_nsec: 0,
+    };
+    let times = rustix::fs::Timestamps {
+        last_access: mtime,
+        last_modification: mtime,
+    };
+    rustix::fs::futimens(&f, &times)?;
+    Ok(())
+}
+
+/// Extract a tar stream written with [`super::ExportOptions::xattrs_sidecar`] into
+/// `dest`, then apply the extended attributes from the sidecar, which is removed.
+/// Entries compressed with [`super::ExportOptions::compress_files`] are decompressed.
+///
+/// File ownership is only preserved when running as root.
+#[context("Extracting with xattrs sidecar")]
+pub fn extract_with_xattrs_sidecar(src: impl std::io::Read, dest: &Utf8Path) -> Result<()> {
+    use cap_std_ext::cap_std;
+    use rustix::fd::AsRawFd;
+
+    let preserve_ownership = rustix::process::getuid().is_root();
+    let mut archive = tar::Archive::new(src);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(preserve_ownership);
+    std::fs::create_dir_all(dest).with_context(|| format!("Creating {dest}"))?;
+    let root = cap_std::fs::Dir::open_ambient_dir(dest, cap_std::ambient_authority())?;
+    // As with tar::Archive::unpack, directories are unpacked last so that
+    // their permissions don't prevent writing their contents.
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            directories.push(entry);
+        } else if let Some(compression) = entry_compression(&mut entry)? {
+            unpack_compressed_entry(&root, &mut entry, &compression, preserve_ownership)?;
+        } else {
+            entry.unpack_in(dest)?;
+        }
+    }
+    for mut entry in directories {
+        entry.unpack_in(dest)?;
+    }
+
+    let sidecar_path = super::XATTRS_SIDECAR_PATH;
+    let sidecar = root
+        .read(sidecar_path)
+        .with_context(|| format!("Reading {sidecar_path}"))?;
+    root.remove_file(sidecar_path)?;
+    let ty = format!("a{}", super::export::XATTRS_SIDECAR_ENTRY_TYPE);
+    let sidecar = Variant::from_data_with_type(sidecar, glib::VariantTy::new(&ty).unwrap());
+    ensure!(sidecar.is_normal_form(), "Invalid xattrs sidecar");
+    for entry in sidecar.iter() {
+        let path = entry.child_value(0).data_as_bytes();
+        let path = std::str::from_utf8(&path).context("Invalid path in xattrs sidecar")?;
+        let path = Utf8Path::new(path);
+        // Don't follow paths outside of the root
+        if !path
+            .components()
+            .all(|c| matches!(c, camino::Utf8Component::Normal(_)))
+            && path != "."
+        {
+            bail!("Invalid path in xattrs sidecar: {path}");
+        }
+        let xattrs = entry.child_value(1);
+        let set_xattr = |name: &std::ffi::CStr, value: &[u8]| -> Result<()> {
+            let flags = rustix::fs::XattrFlags::empty();
+            match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name_in_parent)) => {
+                    let parent = if parent.as_str().is_empty() {
+                        root.try_clone()?
+                    } else {
+                        root.open_dir(parent)?
+                    };
+                    let fdpath = format!("/proc/self/fd/{}/{name_in_parent}", parent.as_raw_fd());
+                    rustix::fs::lsetxattr(fdpath, name, value, flags)?;
+                }
+                _ => rustix::fs::fsetxattr(&root, name, value, flags)?,
+            }
+            Ok(())
+        };
+        for xattr in xattrs.iter() {
+            let name = xattr.child_value(0).data_as_bytes();
+            let name = std::ffi::CStr::from_bytes_with_nul(&name)
+                .with_context(|| format!("Invalid xattr name for {path}"))?;
+            let value = xattr.child_value(1).data_as_bytes();
+            set_xattr(name, &value).with_context(|| format!("Setting {name:?} on {path}"))?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -899,6 +1859,25 @@ mod tests {
         assert_eq!(output.2, expected_objtype);
     }
 
+    #[test]
+    fn test_parse_object_entry_path_two_level() {
+        let path =
+            "sysroot/ostree/repo/objects/b8/62/7e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7.file-xattrs";
+        let (parent, rest, objtype) = parse_object_entry_path(Utf8Path::new(path)).unwrap();
+        assert_eq!(parent, "b862");
+        assert_eq!(
+            rest,
+            "7e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7.file-xattrs"
+        );
+        assert_eq!(objtype, "file-xattrs");
+        let expected = "b8627e3ef0f255a322d2bd9610cfaaacc8f122b7f8d17c0e7e3caafa160f9fc7";
+        assert_eq!(parse_checksum(&parent, rest).unwrap(), expected);
+        assert_eq!(
+            parse_xattrs_link_target(Utf8Path::new(path)).unwrap(),
+            expected
+        );
+    }
+
     #[test]
     fn test_parse_checksum() {
         let parent = "b8";
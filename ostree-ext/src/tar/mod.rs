@@ -42,7 +42,19 @@
 //!  * `file-xattrs` as regular files storing (and de-duplicating) xattrs content.
 //!  * `file-xattrs-link` as hardlinks which associate a `file` object to its corresponding
 //!    `file-xattrs` object.
+//!
+//! # Extended attributes sidecar
+//!
+//! With [`ExportOptions::xattrs_sidecar`], only the checkout view is written, with the
+//! content of files inline.  The extended attributes are instead stored in a single
+//! file at [`XATTRS_SIDECAR_PATH`] at the end of the stream, which contains a GVariant
+//! of type `a(aya(ayay))`: for each path with extended attributes, its path relative to
+//! the root (`.` for the root itself) and its extended attributes, in the same format as
+//! used by ostree (i.e. the names include a trailing NUL byte).
+//! [`extract_with_xattrs_sidecar`] unpacks such a stream and applies the extended attributes.
 
+mod composefs;
+pub(crate) use composefs::fsverity_digest;
 mod import;
 pub use import::*;
 mod export;
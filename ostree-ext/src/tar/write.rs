@@ -7,6 +7,7 @@
 //! In the future, this may also evolve into parsing the tar
 //! stream in Rust, not in C.
 
+use crate::container::ExpansionLimit;
 use crate::Result;
 use anyhow::{anyhow, Context};
 use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
@@ -32,12 +33,101 @@ use tracing::instrument;
 // from being placed in the rootfs.
 const EXCLUDED_TOPLEVEL_PATHS: &[&str] = &["run", "tmp", "proc", "sys", "dev"];
 
+/// How to handle paths (including link targets) which are not valid UTF-8.
+///
+/// Such paths are legal on Linux, but the ostree data model is oriented
+/// around UTF-8: file names and symbolic link targets in a commit must be valid
+/// UTF-8.  Hence they cannot be represented exactly, and mapping them means that
+/// the content no longer round-trips.  For the same reason, exporting a commit
+/// never encounters such paths, so this only applies when importing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NonUtf8Policy {
+    /// Fail with an error.
+    #[default]
+    Error,
+    /// Replace invalid UTF-8 sequences with `U+FFFD REPLACEMENT CHARACTER`,
+    /// logging a warning.  Distinct paths may be mapped to the same path.
+    Lossy,
+    /// Omit the entry, logging a warning.
+    Skip,
+}
+
+impl NonUtf8Policy {
+    /// Convert `path` to UTF-8 according to this policy; `None` means that the
+    /// entry should be omitted.
+    pub(crate) fn apply(self, path: &Path) -> Result<Option<Cow<Utf8Path>>> {
+        if let Some(path) = Utf8Path::from_path(path) {
+            return Ok(Some(Cow::Borrowed(path)));
+        }
+        match self {
+            NonUtf8Policy::Error => Err(anyhow!("Invalid non-UTF8 path: {path:?}")),
+            NonUtf8Policy::Lossy => {
+                let mapped = Utf8PathBuf::from(path.to_string_lossy().into_owned());
+                tracing::warn!("Mapping non-UTF8 path {path:?} to {mapped}");
+                Ok(Some(Cow::Owned(mapped)))
+            }
+            NonUtf8Policy::Skip => {
+                tracing::warn!("Skipping non-UTF8 path {path:?}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// What to do with an entry of a layer; see [`LayerTransform`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LayerEntryAction {
+    /// Import the entry.
+    Keep,
+    /// Omit the entry.
+    Skip,
+}
+
+/// The signature of a [`LayerTransform`].
+pub type LayerTransformFn = dyn Fn(&Utf8Path, &tar::Header) -> LayerEntryAction + Send + Sync;
+
+/// A hook invoked with the path (relative, as found in the layer) and header
+/// of each entry of a layer written by [`write_tar`], before any other
+/// processing; it decides whether the entry is imported.  This is an escape
+/// hatch for advanced users, e.g. to strip a path from the layer.
+///
+/// Because the resulting commit is derived from the imported content, omitting
+/// entries changes its checksum, so it will differ from a commit built from the
+/// unmodified layer, and the image can no longer be verified against e.g. a
+/// signed or otherwise known checksum of its content.  Skipping an entry which
+/// is the target of a hard link in the layer makes the import fail.
+///
+/// The hook is called for every entry before its content is read, so an
+/// expensive hook directly slows down unpacking large layers.  It cannot
+/// inspect the content of an entry; only its path and header are available.
+#[derive(Clone)]
+pub struct LayerTransform(Arc<LayerTransformFn>);
+
+impl LayerTransform {
+    /// Create a new transformation from the provided function.
+    pub fn new(
+        f: impl Fn(&Utf8Path, &tar::Header) -> LayerEntryAction + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for LayerTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LayerTransform").finish_non_exhaustive()
+    }
+}
+
 /// Copy a tar entry to a new tar archive, optionally using a different filesystem path.
+/// Link targets which are not valid UTF-8 are handled according to `non_utf8`.
 #[context("Copying entry")]
 pub(crate) fn copy_entry(
     mut entry: tar::Entry<impl std::io::Read>,
     dest: &mut tar::Builder<impl std::io::Write>,
     path: Option<&Path>,
+    non_utf8: NonUtf8Policy,
 ) -> Result<()> {
     // Make copies of both the header and path, since that's required for the append APIs
     let path = if let Some(path) = path {
@@ -63,15 +153,19 @@ pub(crate) fn copy_entry(
         tar::EntryType::Symlink => {
             let target = entry.link_name()?.ok_or_else(|| anyhow!("Invalid link"))?;
             // Sanity check UTF-8 here too.
-            let target: &Utf8Path = (&*target).try_into()?;
-            dest.append_link(&mut header, path, target)
+            let Some(target) = non_utf8.apply(&target)? else {
+                return Ok(());
+            };
+            dest.append_link(&mut header, path, &*target)
         }
         tar::EntryType::Link => {
             let target = entry.link_name()?.ok_or_else(|| anyhow!("Invalid link"))?;
-            let target: &Utf8Path = (&*target).try_into()?;
+            let Some(target) = non_utf8.apply(&target)? else {
+                return Ok(());
+            };
             // We need to also normalize the target in order to handle hardlinked files in /etc
             // where we remap /etc to /usr/etc.
-            let target = remap_etc_path(target);
+            let target = remap_etc_path(&target);
             dest.append_link(&mut header, path, &*target)
         }
         _ => dest.append_data(&mut header, path, entry),
@@ -93,6 +187,21 @@ pub struct WriteTarOptions {
     /// If true, do not move content in /var to /usr/share/factory/var.  This should be used
     /// with ostree v2024.3 or newer.
     pub retain_var: bool,
+    /// Prefix for temporary directories created while writing the commit; this
+    /// can be used to correlate leftover temporary files with a specific import.
+    pub tmp_prefix: Option<String>,
+    /// How to handle paths and link targets which are not valid UTF-8.
+    pub non_utf8: NonUtf8Policy,
+    /// If set, invoked for each entry of the tar stream.  See [`LayerTransform`].
+    pub layer_transform: Option<LayerTransform>,
+    /// If set, fail with [`crate::container::ExpansionRatioExceeded`] if the
+    /// decompressed size of a compressed stream exceeds this ratio to its
+    /// compressed size.
+    pub max_expansion_ratio: Option<f64>,
+    /// The runtime used to spawn the `ostree commit` child process and the
+    /// blocking thread which filters the tar stream.  If unset, the current
+    /// runtime is used, i.e. this must be called from within a Tokio runtime.
+    pub runtime: Option<tokio::runtime::Handle>,
 }
 
 /// The result of writing a tar stream.
@@ -109,10 +218,18 @@ pub struct WriteTarResult {
 
 // Copy of logic from https://github.com/ostreedev/ostree/pull/2447
 // to avoid waiting for backport + releases
-fn sepolicy_from_base(repo: &ostree::Repo, base: &str) -> Result<tempfile::TempDir> {
+fn sepolicy_from_base(
+    repo: &ostree::Repo,
+    base: &str,
+    tmp_prefix: Option<&str>,
+) -> Result<tempfile::TempDir> {
     let cancellable = gio::Cancellable::NONE;
     let policypath = "usr/etc/selinux";
-    let tempdir = tempfile::tempdir()?;
+    let mut builder = tempfile::Builder::new();
+    if let Some(prefix) = tmp_prefix {
+        builder.prefix(prefix);
+    }
+    let tempdir = builder.tempdir()?;
     let (root, _) = repo.read_commit(base, cancellable)?;
     let policyroot = root.resolve_relative_path(policypath);
     if policyroot.query_exists(cancellable) {
@@ -134,10 +251,12 @@ enum NormalizedPathResult<'a> {
     Normal(Utf8PathBuf),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Default)]
 pub(crate) struct TarImportConfig {
     allow_nonusr: bool,
     remap_factory_var: bool,
+    non_utf8: NonUtf8Policy,
+    layer_transform: Option<LayerTransform>,
 }
 
 // If a path starts with /etc or ./etc or etc, remap it to be usr/etc.
@@ -271,9 +390,18 @@ pub(crate) fn filter_tar(
         let mut entry = entry?;
         let header = entry.header();
         let path = entry.path()?;
-        let path: &Utf8Path = (&*path).try_into()?;
+        let Some(path) = config.non_utf8.apply(&path)? else {
+            continue;
+        };
+        let path = &*path;
         // Force all paths to relative
         let path = path.strip_prefix("/").unwrap_or(path);
+        if let Some(transform) = config.layer_transform.as_ref() {
+            if (transform.0)(path, header) == LayerEntryAction::Skip {
+                tracing::debug!("Skipping {path} per layer transform");
+                continue;
+            }
+        }
 
         let is_modified = header.mtime().unwrap_or_default() > 0;
         let is_regular = header.entry_type() == tar::EntryType::Regular;
@@ -303,7 +431,10 @@ pub(crate) fn filter_tar(
             let target = header
                 .link_name()?
                 .ok_or_else(|| anyhow!("Invalid empty hardlink"))?;
-            let target: &Utf8Path = (&*target).try_into()?;
+            let Some(target) = config.non_utf8.apply(&target)? else {
+                continue;
+            };
+            let target = &*target;
             // Canonicalize to a relative path
             let target = path.strip_prefix("/").unwrap_or(target);
             // If this is a hardlink into /sysroot...
@@ -341,7 +472,12 @@ pub(crate) fn filter_tar(
             NormalizedPathResult::Normal(path) => path,
         };
 
-        copy_entry(entry, &mut dest, Some(normalized.as_std_path()))?;
+        copy_entry(
+            entry,
+            &mut dest,
+            Some(normalized.as_std_path()),
+            config.non_utf8,
+        )?;
     }
     dest.into_inner()?.flush()?;
     Ok(filtered)
@@ -355,14 +491,16 @@ async fn filter_tar_async(
     mut dest: impl AsyncWrite + Send + Unpin,
     config: &TarImportConfig,
     repo_tmpdir: Dir,
+    limit: Option<Arc<ExpansionLimit>>,
+    runtime: Option<&tokio::runtime::Handle>,
 ) -> Result<BTreeMap<String, u32>> {
     let (tx_buf, mut rx_buf) = tokio::io::duplex(8192);
     // The source must be moved to the heap so we know it is stable for passing to the worker thread
     let src = Box::pin(src);
     let config = config.clone();
-    let tar_transformer = crate::tokio_util::spawn_blocking_flatten(move || {
+    let tar_transformer = crate::tokio_util::spawn_blocking_flatten_on(runtime, move || {
         let src = tokio_util::io::SyncIoBridge::new(src);
-        let mut src = crate::container::decompressor(&media_type, src)?;
+        let mut src = crate::container::decompressor(&media_type, src, limit)?;
         let dest = tokio_util::io::SyncIoBridge::new(tx_buf);
 
         let r = filter_tar(&mut src, dest, &config, &repo_tmpdir);
@@ -398,7 +536,10 @@ pub async fn write_tar(
     let options = options.unwrap_or_default();
     let sepolicy = if options.selinux {
         if let Some(base) = options.base {
-            Some(sepolicy_from_base(&repo, &base).context("tar: Preparing sepolicy")?)
+            Some(
+                sepolicy_from_base(&repo, &base, options.tmp_prefix.as_deref())
+                    .context("tar: Preparing sepolicy")?,
+            )
         } else {
             None
         }
@@ -434,7 +575,10 @@ pub async fn write_tar(
     }
     let mut c = tokio::process::Command::from(c);
     c.kill_on_drop(true);
-    let mut r = c.spawn()?;
+    let mut r = {
+        let _guard = options.runtime.as_ref().map(|r| r.enter());
+        c.spawn()?
+    };
     tracing::trace!("Spawned ostree child process");
     // Safety: We passed piped() for all of these
     let child_stdin = r.stdin.take().unwrap();
@@ -444,12 +588,22 @@ pub async fn write_tar(
     let import_config = TarImportConfig {
         allow_nonusr: options.allow_nonusr,
         remap_factory_var: !options.retain_var,
+        non_utf8: options.non_utf8,
+        layer_transform: options.layer_transform,
     };
     let repo_tmpdir = Dir::reopen_dir(&repo.dfd_borrow())?
         .open_dir("tmp")
         .context("Getting repo tmpdir")?;
-    let filtered_result =
-        filter_tar_async(src, media_type, child_stdin, &import_config, repo_tmpdir);
+    let limit = options.max_expansion_ratio.map(ExpansionLimit::new);
+    let filtered_result = filter_tar_async(
+        src,
+        media_type,
+        child_stdin,
+        &import_config,
+        repo_tmpdir,
+        limit.clone(),
+        options.runtime.as_ref(),
+    );
     let output_copier = async move {
         // Gather stdout/stderr to buffers
         let mut child_stdout_buf = String::new();
@@ -478,6 +632,7 @@ pub async fn write_tar(
                 (filtered_result, child_stdout)
             }
             Err(e) => {
+                let e = ExpansionLimit::map_err(limit.as_deref(), e);
                 if let Ok((_, child_stderr)) = output_copier.await {
                     // Avoid trailing newline
                     let child_stderr = child_stderr.trim();
@@ -527,14 +682,17 @@ mod tests {
         let imp_default = &TarImportConfig {
             allow_nonusr: false,
             remap_factory_var: true,
+            ..Default::default()
         };
         let allow_nonusr = &TarImportConfig {
             allow_nonusr: true,
             remap_factory_var: true,
+            ..Default::default()
         };
         let composefs_and_new_ostree = &TarImportConfig {
             allow_nonusr: true,
             remap_factory_var: false,
+            ..Default::default()
         };
         let valid_all = &[
             ("/usr/bin/blah", "./usr/bin/blah"),
@@ -610,6 +768,8 @@ mod tests {
             &mut dest,
             &Default::default(),
             cap_tmpdir,
+            None,
+            None,
         )
         .await?;
         let dest = dest.as_slice();
@@ -620,4 +780,107 @@ mod tests {
         assert!(!destdir.join("blah").exists());
         Ok(())
     }
+
+    #[test]
+    fn test_filter_tar_non_utf8() -> Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tempd = tempfile::tempdir()?;
+        let tmpdir = Dir::open_ambient_dir(&tempd, cap_std::ambient_authority())?;
+        let mut src = tar::Builder::new(Vec::new());
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_mode(0o644);
+        h.set_size(4);
+        src.append_data(&mut h.clone(), "usr/bin/foo", "data".as_bytes())?;
+        let name = OsStr::from_bytes(b"usr/bin/b\xffr");
+        src.append_data(&mut h, name, "data".as_bytes())?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Symlink);
+        h.set_mode(0o777);
+        h.set_size(0);
+        src.append_link(&mut h, "usr/bin/link", name)?;
+        let src = src.into_inner()?;
+
+        let filter = |non_utf8| -> Result<Vec<String>> {
+            let config = TarImportConfig {
+                non_utf8,
+                ..Default::default()
+            };
+            let mut dest = Vec::new();
+            filter_tar(src.as_slice(), &mut dest, &config, &tmpdir)?;
+            let mut dest = tar::Archive::new(dest.as_slice());
+            dest.entries()?
+                .map(|e| {
+                    let e = e?;
+                    let path = e.path()?.to_str().unwrap().to_owned();
+                    Ok(match e.link_name()? {
+                        Some(target) => format!("{path} -> {}", target.to_str().unwrap()),
+                        None => path,
+                    })
+                })
+                .collect()
+        };
+
+        let e = filter(NonUtf8Policy::Error).unwrap_err();
+        assert!(format!("{e:#}").contains("Invalid non-UTF8 path"), "{e:#}");
+        assert_eq!(
+            filter(NonUtf8Policy::Lossy)?,
+            [
+                "./usr/bin/foo",
+                "./usr/bin/b\u{fffd}r",
+                "./usr/bin/link -> usr/bin/b\u{fffd}r"
+            ]
+        );
+        assert_eq!(filter(NonUtf8Policy::Skip)?, ["./usr/bin/foo"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_tar_layer_transform() -> Result<()> {
+        let tempd = tempfile::tempdir()?;
+        let tmpdir = Dir::open_ambient_dir(&tempd, cap_std::ambient_authority())?;
+        let mut src = tar::Builder::new(Vec::new());
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_mode(0o644);
+        h.set_size(4);
+        for path in ["usr/bin/foo", "usr/share/doc/foo/README", "etc/foo.conf"] {
+            src.append_data(&mut h.clone(), path, "data".as_bytes())?;
+        }
+        let src = src.into_inner()?;
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let transform = {
+            let seen = seen.clone();
+            LayerTransform::new(move |path, header| {
+                assert_eq!(header.entry_type(), tar::EntryType::Regular);
+                seen.lock().unwrap().push(path.to_string());
+                if path.starts_with("usr/share/doc") {
+                    LayerEntryAction::Skip
+                } else {
+                    LayerEntryAction::Keep
+                }
+            })
+        };
+        let config = TarImportConfig {
+            layer_transform: Some(transform),
+            ..Default::default()
+        };
+        let mut dest = Vec::new();
+        filter_tar(src.as_slice(), &mut dest, &config, &tmpdir)?;
+        let mut dest = tar::Archive::new(dest.as_slice());
+        let paths = dest
+            .entries()?
+            .map(|e| Ok(e?.path()?.to_str().unwrap().to_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(paths, ["./usr/bin/foo", "./usr/etc/foo.conf"]);
+        // The hook sees the paths as found in the layer
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["usr/bin/foo", "usr/share/doc/foo/README", "etc/foo.conf"]
+        );
+        Ok(())
+    }
 }
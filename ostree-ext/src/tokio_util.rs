@@ -46,7 +46,36 @@ where
     F: FnOnce(&gio::Cancellable) -> R + Send + 'static,
     R: Send + 'static,
 {
-    tokio::task::spawn_blocking(move || {
+    spawn_blocking_cancellable_on(None, f)
+}
+
+/// Like [`tokio::task::spawn_blocking`], but using the provided runtime if any,
+/// rather than the current one.
+fn spawn_blocking_on<F, R>(
+    runtime: Option<&tokio::runtime::Handle>,
+    f: F,
+) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match runtime {
+        Some(runtime) => runtime.spawn_blocking(f),
+        None => tokio::task::spawn_blocking(f),
+    }
+}
+
+/// Like [`spawn_blocking_cancellable`], but using the provided runtime if any,
+/// rather than the current one.
+pub(crate) fn spawn_blocking_cancellable_on<F, R>(
+    runtime: Option<&tokio::runtime::Handle>,
+    f: F,
+) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce(&gio::Cancellable) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    spawn_blocking_on(runtime, move || {
         let dropper = CancelOnDrop(gio::Cancellable::new());
         f(&dropper.0)
     })
@@ -73,13 +102,50 @@ where
     spawn_blocking_cancellable(f).map(flatten_anyhow)
 }
 
+/// A wrapper around [`spawn_blocking_cancellable_on`] that flattens nested results.
+pub(crate) fn spawn_blocking_cancellable_flatten_on<F, T>(
+    runtime: Option<&tokio::runtime::Handle>,
+    f: F,
+) -> impl Future<Output = Result<T>>
+where
+    F: FnOnce(&gio::Cancellable) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_blocking_cancellable_on(runtime, f).map(flatten_anyhow)
+}
+
 /// A wrapper around [`tokio::task::spawn_blocking`] that flattens nested results.
 pub fn spawn_blocking_flatten<F, T>(f: F) -> impl Future<Output = Result<T>>
 where
     F: FnOnce() -> Result<T> + Send + 'static,
     T: Send + 'static,
 {
-    tokio::task::spawn_blocking(f).map(flatten_anyhow)
+    spawn_blocking_flatten_on(None, f)
+}
+
+/// A wrapper around [`tokio::task::spawn_blocking`] that flattens nested results,
+/// using the provided runtime if any, rather than the current one.
+pub(crate) fn spawn_blocking_flatten_on<F, T>(
+    runtime: Option<&tokio::runtime::Handle>,
+    f: F,
+) -> impl Future<Output = Result<T>>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_blocking_on(runtime, f).map(flatten_anyhow)
+}
+
+/// Create a [`tokio_util::io::SyncIoBridge`] for `src`, using the provided runtime
+/// if any, rather than the current one.
+pub(crate) fn sync_io_bridge<T: Unpin>(
+    src: T,
+    runtime: Option<&tokio::runtime::Handle>,
+) -> tokio_util::io::SyncIoBridge<T> {
+    match runtime {
+        Some(runtime) => tokio_util::io::SyncIoBridge::new_with_handle(src, runtime.clone()),
+        None => tokio_util::io::SyncIoBridge::new(src),
+    }
 }
 
 #[cfg(test)]